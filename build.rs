@@ -4,6 +4,11 @@ use std::env;
 
 fn main()
 {
+    // The native client doesn't need libca
+    if env::var_os("CARGO_FEATURE_NATIVE").is_some() {
+        return;
+    }
+
     let lib_path = env::var("EPICS_LIB_PATH")
         .expect("Must define EPICS_LIB_PATH");
     println!("cargo:rustc-link-search={:}", lib_path);
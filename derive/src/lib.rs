@@ -0,0 +1,234 @@
+// Derive macro for mapping Rust enums onto enum PVs
+//
+// #[derive(CaEnumType)] on an enum of unit variants implements CaEnumType
+// together with CA and CaPut, so the enum can be read and written directly:
+//
+//      #[derive(CaEnumType)]
+//      enum Shutter {
+//          Closed,
+//          #[ca(rename = "Fully Open")]
+//          Open,
+//      }
+//
+// By default each variant is matched to the state string of the same name, or
+// to the string given by rename.  With #[ca(by_index)] on the enum variants are
+// matched to the state index instead, taken from the discriminant if given.
+// No two variants may be matched to the same state.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Error, Expr, ExprLit, Fields, Lit, LitStr};
+
+
+#[proc_macro_derive(CaEnumType, attributes(ca))]
+pub fn derive_ca_enum_type(input: TokenStream) -> TokenStream
+{
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    expand(&input).unwrap_or_else(Error::into_compile_error).into()
+}
+
+
+// Attributes on the enum itself
+fn by_index(input: &DeriveInput) -> syn::Result<bool>
+{
+    let mut by_index = false;
+    for attr in input.attrs.iter().filter(|attr| attr.path().is_ident("ca")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("by_index") {
+                by_index = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `by_index`"))
+            }
+        })?;
+    }
+    Ok(by_index)
+}
+
+// Attributes on a variant, returning any rename
+fn rename(variant: &syn::Variant) -> syn::Result<Option<LitStr>>
+{
+    let mut rename = None;
+    for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("ca")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                rename = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `rename = \"...\"`"))
+            }
+        })?;
+    }
+    Ok(rename)
+}
+
+// The state index of a variant given the index of the previous variant, which
+// follows the Rust rules for discriminants.
+fn index(variant: &syn::Variant, previous: Option<u16>) -> syn::Result<u16>
+{
+    match &variant.discriminant {
+        Some((_, Expr::Lit(ExprLit { lit: Lit::Int(value), .. }))) =>
+            value.base10_parse(),
+        Some((_, expr)) => Err(Error::new_spanned(expr,
+            "state index must be an integer literal")),
+        None => match previous {
+            Some(previous) => previous.checked_add(1).ok_or_else(||
+                Error::new_spanned(variant, "state index out of range")),
+            None => Ok(0),
+        },
+    }
+}
+
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2>
+{
+    let name = &input.ident;
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => return Err(Error::new_spanned(input,
+            "CaEnumType can only be derived for enums")),
+    };
+    if data.variants.is_empty() {
+        return Err(Error::new_spanned(input,
+            "CaEnumType cannot be derived for empty enums"));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(&input.generics,
+            "CaEnumType cannot be derived for generic enums"));
+    }
+    let by_index = by_index(input)?;
+
+    let mut variants = Vec::new();
+    let mut labels = Vec::new();
+    let mut indices = Vec::new();
+    for variant in &data.variants {
+        if !matches!(variant.fields, Fields::Unit) {
+            return Err(Error::new_spanned(variant,
+                "CaEnumType variants cannot have fields"));
+        }
+        // Two variants mapped to the same state could never both be read
+        let rename = rename(variant)?;
+        if by_index {
+            if let Some(rename) = rename {
+                return Err(Error::new_spanned(rename,
+                    "rename cannot be used with by_index"));
+            }
+            let index = index(variant, indices.last().copied())?;
+            if let Some(other) = indices.iter().position(|&i| i == index) {
+                return Err(Error::new_spanned(variant, format!(
+                    "state index {} is already used by {}",
+                    index, variants[other])));
+            }
+            indices.push(index);
+        } else {
+            let label = rename.map_or_else(
+                || variant.ident.to_string(), |rename| rename.value());
+            if let Some(other) = labels.iter().position(|l| *l == label) {
+                return Err(Error::new_spanned(variant, format!(
+                    "state \"{}\" is already used by {}",
+                    label, variants[other])));
+            }
+            labels.push(label);
+        }
+        variants.push(&variant.ident);
+    }
+
+    let mapping = if by_index {
+        quote! { ::epics_ca::EnumMapping::Indices(&[#(#indices),*]) }
+    } else {
+        quote! { ::epics_ca::EnumMapping::Labels(&[#(#labels),*]) }
+    };
+    let positions = 0..variants.len();
+    let positions_again = positions.clone();
+    let name_string = name.to_string();
+
+    Ok(quote! {
+        impl ::epics_ca::CaEnumType for #name {
+            const NAME: &'static str = #name_string;
+            const MAPPING: ::epics_ca::EnumMapping = #mapping;
+
+            fn from_variant(variant: usize) -> Self
+            {
+                match variant {
+                    #(#positions => #name::#variants,)*
+                    _ => panic!("Invalid variant {} for {}", variant, #name_string),
+                }
+            }
+
+            fn variant(&self) -> usize
+            {
+                match self {
+                    #(#name::#variants => #positions_again,)*
+                }
+            }
+        }
+
+        #[::epics_ca::async_trait(?Send)]
+        impl ::epics_ca::CA for #name {
+            async fn try_caget(pv: &str)
+                -> ::std::result::Result<Self, ::epics_ca::CaError>
+            {
+                ::epics_ca::caget_enum_type(pv).await
+            }
+        }
+
+        #[::epics_ca::async_trait(?Send)]
+        impl ::epics_ca::CaPut for #name {
+            async fn try_caput(pv: &str, value: Self)
+                -> ::std::result::Result<(), ::epics_ca::CaError>
+            {
+                ::epics_ca::caput_enum_type(pv, value, true).await
+            }
+
+            async fn try_caput_nowait(pv: &str, value: Self)
+                -> ::std::result::Result<(), ::epics_ca::CaError>
+            {
+                ::epics_ca::caput_enum_type(pv, value, false).await
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use syn::{DeriveInput, parse_quote};
+
+    use super::expand;
+
+    fn error(input: DeriveInput) -> String
+    {
+        expand(&input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn duplicate_label()
+    {
+        assert_eq!(error(parse_quote! {
+            enum Shutter {
+                Open,
+                #[ca(rename = "Open")]
+                Opened,
+            }
+        }), "state \"Open\" is already used by Open");
+    }
+
+    #[test]
+    fn duplicate_index()
+    {
+        assert_eq!(error(parse_quote! {
+            #[ca(by_index)]
+            enum Mode { Off, On, Standby = 1 }
+        }), "state index 1 is already used by On");
+    }
+
+    #[test]
+    fn distinct_states()
+    {
+        assert!(expand(&parse_quote! {
+            #[ca(by_index)]
+            enum Mode { Off = 1, On = 0, Standby = 2 }
+        }).is_ok());
+    }
+}
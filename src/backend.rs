@@ -0,0 +1,273 @@
+// Channel Access backends
+//
+// All channel operations go through a Backend.  Normally this is the CA library
+// (or the default native Context), but another backend such as MockBackend
+// can be installed for the current thread, for instance to test code built on
+// the CA trait without an IOC.
+
+use std::{cell, marker, sync};
+use libc::{c_int, c_long, c_short, c_ulong, c_void};
+
+use crate::cadef;
+use crate::cadef::{ChanId, EvId};
+use crate::protocol::{CA_ACCESS_READ, CA_ACCESS_WRITE};
+#[cfg(not(feature = "native"))]
+use crate::protocol::status;
+
+
+// Arguments passed to the connection handler.  Unlike the libca handler the user
+// pointer is passed directly.
+#[derive(Debug)]
+pub struct ConnectArgs {
+    pub usr: *const c_void,
+    pub chid: ChanId,
+    pub op: c_long,
+}
+
+pub type ConnectHandler = fn(args: ConnectArgs);
+pub type EventHandler = extern "C" fn(args: cadef::event_handler_args);
+
+
+// Completion handler for puts where nobody is waiting
+extern "C" fn ignore_put(_args: cadef::event_handler_args) { }
+
+// The channel operations we need, following the corresponding cadef.h entry
+// points.  Callbacks may be invoked from any thread, and may be invoked before
+// the call requesting them returns.
+#[allow(clippy::too_many_arguments)]
+pub trait Backend: Send + Sync {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int;
+    fn clear_channel(&self, id: ChanId) -> c_int;
+    fn field_type(&self, id: ChanId) -> c_short;
+    fn element_count(&self, id: ChanId) -> c_ulong;
+    // The server hosting a connected channel and our access rights to it, as
+    // CA_ACCESS_READ and CA_ACCESS_WRITE bits.  Backends without a server
+    // report an empty host name and full access.
+    fn host_name(&self, _id: ChanId) -> String
+    {
+        String::new()
+    }
+    fn access_rights(&self, _id: ChanId) -> u32
+    {
+        CA_ACCESS_READ | CA_ACCESS_WRITE
+    }
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int;
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int;
+    // A put without notification of completion.  By default this is a put
+    // with callback where the completion is ignored.
+    fn array_put(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void) -> c_int
+    {
+        self.array_put_callback(
+            datatype, count, id, value, ignore_put, std::ptr::null())
+    }
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int;
+    fn clear_subscription(&self, evid: EvId) -> c_int;
+    fn flush_io(&self) -> c_int;
+}
+
+impl std::fmt::Debug for dyn Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "Backend")
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// The CA library
+
+#[cfg(not(feature = "native"))]
+struct CaLibrary;
+
+// libca only passes the channel id to the connection handler, so we register
+// our own handler with this as the user pointer.
+#[cfg(not(feature = "native"))]
+struct ConnectTarget {
+    on_connect: ConnectHandler,
+    usr: *const c_void,
+}
+
+#[cfg(not(feature = "native"))]
+extern "C" fn ca_on_connect(args: cadef::ca_connection_handler_args)
+{
+    let target: &ConnectTarget =
+        unsafe { cadef::voidp_to_ref(cadef::ca_puser(args.chid)) };
+    (target.on_connect)(ConnectArgs {
+        usr: target.usr, chid: args.chid, op: args.op });
+}
+
+// Code to ensure that the context is valid
+#[cfg(not(feature = "native"))]
+static CA_CONTEXT_CREATE: sync::Once = sync::Once::new();
+#[cfg(not(feature = "native"))]
+fn context_create()
+{
+    CA_CONTEXT_CREATE.call_once(|| {
+        unsafe { cadef::ca_context_create(
+            cadef::ca_preemptive_callback_select
+                ::ca_enable_preemptive_callback) };
+    });
+}
+
+#[cfg(not(feature = "native"))]
+impl Backend for CaLibrary {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int
+    {
+        context_create();
+        // Names are checked by Channel::new, but cannot be passed on at all if
+        // they contain NUL
+        let cpv = match std::ffi::CString::new(pv) {
+            Ok(cpv) => cpv,
+            Err(_) => return status::ECA_BADSTR as c_int,
+        };
+        let target = Box::into_raw(Box::new(ConnectTarget { on_connect, usr }));
+        let rc = unsafe { cadef::ca_create_channel(
+            cpv.as_ptr(), ca_on_connect, target as *const c_void, 0, id) };
+        if rc != cadef::ECA_NORMAL {
+            drop(unsafe { Box::from_raw(target) });
+        }
+        rc
+    }
+
+    fn clear_channel(&self, id: ChanId) -> c_int
+    {
+        unsafe {
+            let target = cadef::ca_puser(id) as *mut ConnectTarget;
+            let rc = cadef::ca_clear_channel(id);
+            drop(Box::from_raw(target));
+            rc
+        }
+    }
+
+    fn field_type(&self, id: ChanId) -> c_short
+    {
+        unsafe { cadef::ca_field_type(id) }
+    }
+
+    fn element_count(&self, id: ChanId) -> c_ulong
+    {
+        unsafe { cadef::ca_element_count(id) }
+    }
+
+    fn host_name(&self, id: ChanId) -> String
+    {
+        let name = unsafe { std::ffi::CStr::from_ptr(cadef::ca_host_name(id)) };
+        name.to_string_lossy().into_owned()
+    }
+
+    fn access_rights(&self, id: ChanId) -> u32
+    {
+        let (read, write) = unsafe {
+            (cadef::ca_read_access(id), cadef::ca_write_access(id)) };
+        (if read != 0 { CA_ACCESS_READ } else { 0 }) |
+        (if write != 0 { CA_ACCESS_WRITE } else { 0 })
+    }
+
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        unsafe {
+            cadef::ca_array_get_callback(datatype, count, id, handler, usr) }
+    }
+
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        unsafe { cadef::ca_array_put_callback(
+            datatype, count, id, value, handler, usr) }
+    }
+
+    fn array_put(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void) -> c_int
+    {
+        unsafe { cadef::ca_array_put(datatype, count, id, value) }
+    }
+
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int
+    {
+        unsafe { cadef::ca_create_subscription(
+            datatype, count, id, mask, handler, usr, evid) }
+    }
+
+    fn clear_subscription(&self, evid: EvId) -> c_int
+    {
+        unsafe { cadef::ca_clear_subscription(evid) }
+    }
+
+    fn flush_io(&self) -> c_int
+    {
+        unsafe { cadef::ca_flush_io() }
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Backend selection
+
+thread_local! {
+    static CURRENT: cell::RefCell<Option<sync::Arc<dyn Backend>>> =
+        cell::RefCell::new(None);
+}
+
+#[cfg(not(feature = "native"))]
+fn default_backend() -> sync::Arc<dyn Backend>
+{
+    static DEFAULT: sync::OnceLock<sync::Arc<dyn Backend>> =
+        sync::OnceLock::new();
+    DEFAULT.get_or_init(|| sync::Arc::new(CaLibrary)).clone()
+}
+
+#[cfg(feature = "native")]
+fn default_backend() -> sync::Arc<dyn Backend>
+{
+    crate::native::default_context()
+}
+
+// Returns the backend for new channels created on this thread
+pub fn current() -> sync::Arc<dyn Backend>
+{
+    CURRENT.with(|current| current.borrow().clone())
+        .unwrap_or_else(default_backend)
+}
+
+// Installs a backend for all channels subsequently created on this thread until
+// the returned guard is dropped.  Channels already created keep their backend.
+pub fn install(backend: sync::Arc<dyn Backend>) -> BackendGuard
+{
+    let previous =
+        CURRENT.with(|current| current.borrow_mut().replace(backend));
+    BackendGuard { previous, _thread: marker::PhantomData }
+}
+
+pub struct BackendGuard {
+    previous: Option<sync::Arc<dyn Backend>>,
+    // The guard restores the previous backend of this thread, so must stay here
+    _thread: marker::PhantomData<*const ()>,
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self)
+    {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
@@ -0,0 +1,355 @@
+// caget: reads and prints the values of PVs
+//
+// A replacement for the caget tool from EPICS base, accepting the same options
+// and producing the same output.
+
+mod common;
+
+use std::time::Duration;
+use futures::executor::block_on;
+use futures::future::join_all;
+use epics_ca::*;
+
+use common::{
+    Format, format_epics_time, status_name, severity_name, dbr_type_name};
+
+
+const USAGE: &str = "\
+Usage: caget [options] <PV name> ...
+
+  -h: Help: Print this message
+Channel Access options:
+  -w <sec>: Wait time, specifies CA timeout, default is 1.000000 second(s)
+Format options:
+      Default output format is \"name value\"
+  -t: Terse mode - print only value, without name
+  -a: Wide mode \"name timestamp value stat sevr\" (read PVs as DBR_TIME_xxx)
+  -d <type>: Request specific dbr type; use string (DBR_ prefix may be omitted)
+      or number of one of the following types:
+ DBR_STRING     0  DBR_STS_FLOAT    9  DBR_TIME_LONG   19  DBR_CTRL_SHORT    29
+ DBR_INT        1  DBR_STS_ENUM    10  DBR_TIME_DOUBLE 20  DBR_CTRL_INT      29
+ DBR_SHORT      1  DBR_STS_CHAR    11  DBR_GR_STRING   21  DBR_CTRL_FLOAT    30
+ DBR_FLOAT      2  DBR_STS_LONG    12  DBR_GR_SHORT    22  DBR_CTRL_ENUM     31
+ DBR_ENUM       3  DBR_STS_DOUBLE  13  DBR_GR_INT      22  DBR_CTRL_CHAR     32
+ DBR_CHAR       4  DBR_TIME_STRING 14  DBR_GR_FLOAT    23  DBR_CTRL_LONG     33
+ DBR_LONG       5  DBR_TIME_INT    15  DBR_GR_ENUM     24  DBR_CTRL_DOUBLE   34
+ DBR_DOUBLE     6  DBR_TIME_SHORT  15  DBR_GR_CHAR     25
+ DBR_STS_STRING 7  DBR_TIME_FLOAT  16  DBR_GR_LONG     26
+ DBR_STS_SHORT  8  DBR_TIME_ENUM   17  DBR_GR_DOUBLE   27
+ DBR_STS_INT    8  DBR_TIME_CHAR   18  DBR_CTRL_STRING 28
+Arrays: Value format: print number of requested values, then list of values
+  -# <no>: Request and print up to <no> elements
+Floating point type format:
+  -s: Get value as string (honors server-side precision)
+";
+
+
+// The classes of DBR type in the order of their type codes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Class { Plain, Sts, Time, Gr, Ctrl }
+
+const CLASS_NAMES: [&str; 5] = ["", "STS_", "TIME_", "GR_", "CTRL_"];
+
+const BASIC_NAMES: [&str; 7] =
+    ["STRING", "SHORT", "FLOAT", "ENUM", "CHAR", "LONG", "DOUBLE"];
+
+// A type requested with -d, as a class and basic type index
+#[derive(Clone, Copy, Debug)]
+struct Request {
+    class: Class,
+    basic: usize,
+}
+
+impl Request {
+    fn parse(arg: &str) -> Option<Request>
+    {
+        if let Ok(code) = arg.parse::<usize>() {
+            let class = [Class::Plain, Class::Sts, Class::Time,
+                Class::Gr, Class::Ctrl].get(code / 7)?;
+            return Some(Request { class: *class, basic: code % 7 });
+        }
+
+        let name = arg.to_uppercase();
+        let name = name.strip_prefix("DBR_").unwrap_or(&name);
+        let (class, basic) = CLASS_NAMES.iter().enumerate().skip(1)
+            .find_map(|(class, prefix)|
+                name.strip_prefix(prefix).map(|basic| (class, basic)))
+            .unwrap_or((0, name));
+        let basic = if basic == "INT" { "SHORT" } else { basic };
+        let basic = BASIC_NAMES.iter().position(|&n| n == basic)?;
+        let class = [Class::Plain, Class::Sts, Class::Time,
+            Class::Gr, Class::Ctrl][class];
+        Some(Request { class, basic })
+    }
+
+    fn name(&self) -> String
+    {
+        format!("DBR_{}{}",
+            CLASS_NAMES[self.class as usize], BASIC_NAMES[self.basic])
+    }
+}
+
+
+// Everything we read for a PV
+struct Reading {
+    value: CaUnionVec,
+    alarm: Option<StatusSeverity>,
+    time: Option<EpicsTime>,
+    ctrl: Option<PvCtrl>,
+    native_type: &'static str,
+}
+
+impl Reading {
+    fn new(value: CaUnionVec) -> Reading
+    {
+        let native_type = dbr_type_name(union_type(&value));
+        Reading { value, alarm: None, time: None, ctrl: None, native_type }
+    }
+}
+
+
+// Reads the PV as the requested type of the given class, taking the native
+// type from the same channel, and reading at most count elements
+macro_rules! get_as {
+    { plain $pv:expr, $count:expr, $type:ty } => {
+        <WithNativeType<Vec<$type>>>::try_caget_count($pv, $count).await
+            .map(|got| (got.native_type, Reading::new(got.value.into())))
+    };
+    { time $pv:expr, $count:expr, $type:ty } => {
+        <WithNativeType<(Vec<$type>, StatusSeverity, EpicsTime)>>
+            ::try_caget_count($pv, $count).await.map(|got| {
+                let (value, alarm, time) = got.value;
+                (got.native_type, Reading {
+                    alarm: Some(alarm),
+                    time: Some(time),
+                    .. Reading::new(value.into())
+                })
+            })
+    };
+    { ctrl $pv:expr, $count:expr, $type:ty } => {
+        <WithNativeType<(Vec<$type>, StatusSeverity, CaCtrl<_>)>>
+            ::try_caget_count($pv, $count).await.map(|got| {
+                let (value, alarm, CaCtrl(ctrl)) = got.value;
+                (got.native_type, Reading {
+                    alarm: Some(alarm),
+                    ctrl: Some(ctrl.into()),
+                    .. Reading::new(value.into())
+                })
+            })
+    };
+    { $pv:expr, $count:expr, $class:expr, $type:ty } => {
+        match $class {
+            Class::Plain => get_as!(plain $pv, $count, $type),
+            Class::Sts | Class::Time => get_as!(time $pv, $count, $type),
+            Class::Gr | Class::Ctrl => get_as!(ctrl $pv, $count, $type),
+        }
+    };
+}
+
+// Strings have no control information, so for these GR and CTRL requests are
+// the same as STS.
+async fn get_request(pv: &str, request: Request, count: usize)
+    -> Result<Reading, CaError>
+{
+    let class = request.class;
+    let (native_type, mut reading) = match request.basic {
+        0 => match class {
+            Class::Plain => get_as!(plain pv, count, String),
+            _ => get_as!(time pv, count, String),
+        },
+        1 => get_as!(pv, count, class, i16),
+        2 => get_as!(pv, count, class, f32),
+        3 => get_as!(pv, count, class, CaEnum),
+        4 => get_as!(pv, count, class, u8),
+        5 => get_as!(pv, count, class, i32),
+        _ => get_as!(pv, count, class, f64),
+    }?;
+    if request.class != Class::Time {
+        reading.time = None;
+    }
+    reading.native_type = dbr_type_name(native_type);
+    Ok(reading)
+}
+
+// Reads at most count elements of the PV in its native type with timestamp and
+// alarm, or as strings if requested.  Enum strings are read separately.
+async fn get_native(pv: &str, count: usize, as_string: bool, format: &Format)
+    -> Result<Reading, CaError>
+{
+    let (value, alarm, time) = if as_string {
+        let (value, alarm, time) =
+            <(Vec<String>, StatusSeverity, EpicsTime)>
+                ::try_caget_count(pv, count).await?;
+        (value.into(), alarm, time)
+    } else {
+        <(CaUnionVec, StatusSeverity, EpicsTime)>
+            ::try_caget_count(pv, count).await?
+    };
+    let mut reading = Reading {
+        alarm: Some(alarm),
+        time: Some(time),
+        .. Reading::new(value)
+    };
+    if let CaUnionVec::CaEnum(_) = reading.value {
+        if !format.enum_as_number {
+            let (_, _, CaCtrl(strings)) =
+                <(CaEnum, StatusSeverity, CaCtrl<_>)>::try_caget(pv).await?;
+            reading.ctrl = Some(strings.into());
+        }
+    }
+    Ok(reading)
+}
+
+
+#[derive(Clone, Copy, Debug)]
+enum Mode { Normal, Terse, Wide, Detail(Request) }
+
+struct Options {
+    mode: Mode,
+    count: Option<usize>,
+    as_string: bool,
+    timeout: Duration,
+    format: Format,
+}
+
+fn print_reading(pv: &str, reading: &Reading, options: &Options)
+{
+    let show_count =
+        options.count.is_some() || union_len(&reading.value) != 1;
+    let value =
+        options.format.format(&reading.value, reading.ctrl.as_ref(), show_count);
+
+    match options.mode {
+        Mode::Normal => println!("{:<30} {}", pv, value),
+        Mode::Terse => println!("{}", value),
+        Mode::Wide => {
+            let time = reading.time.map(format_epics_time).unwrap_or_default();
+            let mut line = format!("{:<30} {} {}", pv, time, value);
+            if let Some(alarm) = reading.alarm {
+                if alarm.status != 0 || alarm.severity != 0 {
+                    line += &format!(" {} {}",
+                        status_name(alarm.status),
+                        severity_name(alarm.severity));
+                }
+            }
+            println!("{}", line);
+        },
+        Mode::Detail(request) => print_detail(pv, reading, request, options),
+    }
+}
+
+fn print_detail(pv: &str, reading: &Reading, request: Request, options: &Options)
+{
+    println!("{}", pv);
+    println!("    Native data type: DBF_{}", reading.native_type);
+    println!("    Request type:     {}", request.name());
+    println!("    Element count:    {}", union_len(&reading.value));
+    let values =
+        options.format.format_values(&reading.value, reading.ctrl.as_ref());
+    println!("    Value:            {}", values.join(" "));
+    if let Some(alarm) = reading.alarm {
+        println!("    Status:           {}", status_name(alarm.status));
+        println!("    Severity:         {}", severity_name(alarm.severity));
+    }
+    if let Some(time) = reading.time {
+        println!("    Timestamp:        {}", format_epics_time(time));
+    }
+    if let Some(ctrl) = &reading.ctrl {
+        if let CaUnionVec::CaEnum(_) = reading.value {
+            println!("    Enums:            ({:3})", ctrl.enum_strings.len());
+            for (i, string) in ctrl.enum_strings.iter().enumerate() {
+                println!("                      [{:2}] {}", i, string);
+            }
+        } else {
+            let limits = &ctrl.limits;
+            let limit = common::format_number;
+            println!("    Units:            {}", ctrl.units);
+            if let CaUnionVec::CaFloat(_) | CaUnionVec::CaDouble(_) =
+                reading.value
+            {
+                println!("    Precision:        {}", ctrl.precision);
+            }
+            println!("    Lo disp limit:    {}", limit(limits.lower_disp_limit));
+            println!("    Hi disp limit:    {}", limit(limits.upper_disp_limit));
+            println!("    Lo alarm limit:   {}", limit(limits.lower_alarm_limit));
+            println!("    Lo warn limit:    {}",
+                limit(limits.lower_warning_limit));
+            println!("    Hi warn limit:    {}",
+                limit(limits.upper_warning_limit));
+            println!("    Hi alarm limit:   {}", limit(limits.upper_alarm_limit));
+            if request.class == Class::Ctrl {
+                println!("    Lo ctrl limit:    {}",
+                    limit(limits.lower_ctrl_limit));
+                println!("    Hi ctrl limit:    {}",
+                    limit(limits.upper_ctrl_limit));
+            }
+        }
+    }
+}
+
+
+fn main()
+{
+    let usage = format!("{}{}", USAGE, common::FORMAT_USAGE);
+    let (parsed, pvs) = common::getopt(&usage, "tad:#:Sse:f:g:l:nw:");
+    let mut options = Options {
+        mode: Mode::Normal,
+        count: None,
+        as_string: false,
+        timeout: Duration::from_secs(1),
+        format: Format::default(),
+    };
+    for (option, arg) in parsed {
+        let arg = arg.as_deref();
+        if options.format.parse_option(option, arg) {
+            continue;
+        }
+        let arg = arg.unwrap_or("");
+        match option {
+            't' => options.mode = Mode::Terse,
+            'a' => options.mode = Mode::Wide,
+            'd' => match Request::parse(arg) {
+                Some(request) => options.mode = Mode::Detail(request),
+                None => common::usage_error(&format!(
+                    "Requested dbr type out of range or invalid: '{}'", arg)),
+            },
+            '#' => options.count = Some(common::parse_number(option, arg)),
+            's' => options.as_string = true,
+            'w' => options.timeout = common::parse_timeout(option, arg),
+            _ => unreachable!(),
+        }
+    }
+    if pvs.is_empty() {
+        common::usage_error("No pv name specified.");
+    }
+
+    // All the PVs are read concurrently, each within the timeout
+    let results = block_on(join_all(pvs.iter().map(|pv| {
+        let options = &options;
+        let count = options.count.unwrap_or(0);
+        timeout(options.timeout, async move {
+            match options.mode {
+                Mode::Detail(request) =>
+                    get_request(pv, request, count).await,
+                _ => get_native(
+                    pv, count, options.as_string, &options.format).await,
+            }
+        })
+    })));
+
+    let mut ok = true;
+    for (pv, result) in pvs.iter().zip(results) {
+        match result {
+            Some(Ok(reading)) => print_reading(pv, &reading, &options),
+            Some(Err(error)) => {
+                eprintln!("Read operation failed for PV '{}': {}", pv, error);
+                ok = false;
+            },
+            None => {
+                eprintln!("Channel connect timed out: '{}' not found.", pv);
+                ok = false;
+            },
+        }
+    }
+    std::process::exit(if ok { 0 } else { 1 });
+}
@@ -0,0 +1,149 @@
+// cainfo: prints information about PVs
+//
+// A replacement for the cainfo tool from EPICS base.  As well as the channel
+// information printed by the C tool the record type and the control fields are
+// shown.
+
+mod common;
+
+use std::fmt::Display;
+use std::time::Duration;
+use futures::executor::block_on;
+use futures::future::join_all;
+use epics_ca::*;
+
+
+const USAGE: &str = "\
+Usage: cainfo [options] <PV name> ...
+
+  -h: Help: Print this message
+Channel Access options:
+  -w <sec>: Wait time, specifies CA timeout, default is 1.000000 second(s)
+";
+
+
+fn state_name(state: ConnectionState) -> &'static str
+{
+    match state {
+        ConnectionState::NeverConnected => "never connected",
+        ConnectionState::Disconnected => "previously connected",
+        ConnectionState::Connected => "connected",
+    }
+}
+
+fn access_name(read: bool, write: bool) -> String
+{
+    format!("{}read, {}write",
+        if read { "" } else { "no " }, if write { "" } else { "no " })
+}
+
+
+fn print_limits<T>(units: &str, limits: &CtrlLimits<T>)
+    where T: Copy + Send + Display
+{
+    println!("    Units:            {}", units);
+    println!("    Display limits:   {} .. {}",
+        limits.lower_disp_limit, limits.upper_disp_limit);
+    println!("    Alarm limits:     {} .. {}",
+        limits.lower_alarm_limit, limits.upper_alarm_limit);
+    println!("    Warning limits:   {} .. {}",
+        limits.lower_warning_limit, limits.upper_warning_limit);
+    println!("    Control limits:   {} .. {}",
+        limits.lower_ctrl_limit, limits.upper_ctrl_limit);
+}
+
+fn print_fixed<T>(ctrl: &FixedCtrl<T>)
+    where T: Copy + Send + Display
+{
+    print_limits(&ctrl.units, &ctrl.limits);
+}
+
+fn print_float<T>(ctrl: &FloatCtrl<T>)
+    where T: Copy + Send + Display
+{
+    println!("    Precision:        {}", ctrl.precision);
+    print_limits(&ctrl.units, &ctrl.limits);
+}
+
+fn print_info(info: &CaInfo)
+{
+    let datatype = common::dbr_type_name(info.native_type);
+    println!("{}", info.name);
+    println!("    State:            {}", state_name(info.state));
+    println!("    Host:             {}", info.host);
+    println!("    Access:           {}",
+        access_name(info.read_access, info.write_access));
+    println!("    Native data type: DBF_{}", datatype);
+    println!("    Request type:     DBR_{}", datatype);
+    println!("    Element count:    {}", info.element_count);
+    if let Some(record_type) = &info.record_type {
+        println!("    Record type:      {}", record_type);
+    }
+
+    match &info.ctrl {
+        CaUnionCtrl::CaString(..) => { },
+        CaUnionCtrl::CaEnum(_, strings) => {
+            println!("    Enum states:      {}", strings.len());
+            for (index, string) in strings.iter().enumerate() {
+                println!("      [{:2}] {}", index, string);
+            }
+        },
+        CaUnionCtrl::CaChar(_, ctrl) => print_fixed(ctrl),
+        CaUnionCtrl::CaShort(_, ctrl) => print_fixed(ctrl),
+        CaUnionCtrl::CaLong(_, ctrl) => print_fixed(ctrl),
+        CaUnionCtrl::CaFloat(_, ctrl) => print_float(ctrl),
+        CaUnionCtrl::CaDouble(_, ctrl) => print_float(ctrl),
+    }
+}
+
+// As for the C tool a channel which doesn't connect is still reported
+fn print_not_connected(pv: &str, state: ConnectionState)
+{
+    println!("{}", pv);
+    println!("    State:            {}", state_name(state));
+    println!("    Host:             <disconnected>");
+    println!("    Access:           {}", access_name(false, false));
+    println!("    Native data type: TYPENOTCONN");
+    println!("    Request type:     TYPENOTCONN");
+    println!("    Element count:    0");
+}
+
+
+fn main()
+{
+    let (parsed, pvs) = common::getopt(USAGE, "w:");
+    let mut timeout = Duration::from_secs(1);
+    for (option, arg) in parsed {
+        match option {
+            'w' => timeout =
+                common::parse_timeout(option, &arg.unwrap_or_default()),
+            _ => unreachable!(),
+        }
+    }
+    if pvs.is_empty() {
+        common::usage_error("No pv name specified.");
+    }
+
+    // PVs are reported in the order given, whatever order they connect in
+    let results = block_on(join_all(pvs.iter().map(|pv|
+        try_cainfo_timeout(pv, timeout))));
+    let mut ok = true;
+    for (pv, result) in pvs.iter().zip(results) {
+        match result {
+            Ok(info) => print_info(&info),
+            Err(CaError::Timeout(state))
+                if state != ConnectionState::Connected =>
+            {
+                print_not_connected(pv, state);
+                ok = false;
+            },
+            Err(error) => {
+                eprintln!("Read operation failed for PV '{}': {}", pv, error);
+                ok = false;
+            },
+        }
+    }
+    if !ok {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,425 @@
+// camonitor: prints updates from PVs as they arrive
+//
+// A replacement for the camonitor tool from EPICS base.  As well as the classic
+// line format updates can be printed as JSON lines, one object per update, or
+// as CSV.
+
+mod common;
+
+use std::cell::RefCell;
+use std::time::{Duration, SystemTime};
+use futures::StreamExt;
+use futures::executor::block_on;
+use futures::future::join_all;
+use humantime::format_rfc3339_micros;
+use epics_ca::*;
+
+use common::{
+    Format, format_time, status_name, severity_name, UNDEFINED_TIME};
+
+
+const USAGE: &str = "\
+Usage: camonitor [options] <PV name> ...
+
+  -h: Help: Print this message
+Channel Access options:
+  -w <sec>: Wait time, specifies CA timeout, default is 1.000000 second(s)
+  -m <msk>: Specify CA event mask to use.  <msk> is any combination of
+            'v' (value), 'a' (alarm), 'l' (log/archive), 'p' (property).
+            Default event mask is 'va'
+Timestamps:
+  -t <key>: Timestamp source (key: 's'=server, 'c'=client, 'n'=no timestamp),
+            default 's', and optional modifier ('r'=relative to first update,
+            'i'=incremental, 'I'=incremental per channel)
+Output format:
+  -o <fmt>: 'classic' (default) \"name timestamp value stat sevr\",
+            'json' for one JSON object per line, or 'csv'
+Arrays: Value format: print number of values, then list of values
+  -# <no>: Print up to <no> elements
+";
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source { Server, Client, Nothing }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Modifier { Absolute, Relative, Incremental, ChannelIncremental }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Output { Classic, Json, Csv }
+
+struct Options {
+    mask: u32,
+    source: Source,
+    modifier: Modifier,
+    output: Output,
+    count: Option<usize>,
+    timeout: Duration,
+    format: Format,
+}
+
+
+// A timestamp ready for printing: either absolute, or seconds relative to some
+// earlier update.  Server timestamps of records which have never processed are
+// undefined.
+enum Stamp {
+    None,
+    Undefined,
+    Absolute(SystemTime),
+    Seconds(f64),
+}
+
+// Shared by all channels for relative and incremental timestamps
+#[derive(Default)]
+struct Clock {
+    first: Option<SystemTime>,
+    last: Option<SystemTime>,
+}
+
+fn seconds_since(time: SystemTime, since: Option<SystemTime>) -> f64
+{
+    match since.map(|since| time.duration_since(since)) {
+        Some(Ok(duration)) => duration.as_secs_f64(),
+        Some(Err(error)) => -error.duration().as_secs_f64(),
+        None => 0.0,
+    }
+}
+
+impl Options {
+    fn stamp(&self, server_time: EpicsTime, clock: &RefCell<Clock>,
+        channel_last: &mut Option<SystemTime>) -> Stamp
+    {
+        let time = match self.source {
+            Source::Server if !server_time.is_valid() =>
+                return Stamp::Undefined,
+            Source::Server => server_time.into(),
+            Source::Client => SystemTime::now(),
+            Source::Nothing => return Stamp::None,
+        };
+        let mut clock = clock.borrow_mut();
+        let first = *clock.first.get_or_insert(time);
+        let last = clock.last.replace(time);
+        let channel = channel_last.replace(time);
+        match self.modifier {
+            Modifier::Absolute => Stamp::Absolute(time),
+            Modifier::Relative =>
+                Stamp::Seconds(seconds_since(time, Some(first))),
+            Modifier::Incremental => Stamp::Seconds(seconds_since(time, last)),
+            Modifier::ChannelIncremental =>
+                Stamp::Seconds(seconds_since(time, channel)),
+        }
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Output formats
+
+fn json_string(string: &str) -> String
+{
+    let mut result = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            '\n' => result += "\\n",
+            '\r' => result += "\\r",
+            '\t' => result += "\\t",
+            c if (c as u32) < 0x20 =>
+                result += &format!("\\u{:04x}", c as u32),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+// Numbers are written as JSON numbers where they are valid as such, so values
+// formatted in hex for example are written as strings.
+fn json_value(value: &CaUnionVec, values: &[String], as_string: bool) -> String
+{
+    let numeric = !matches!(value,
+        CaUnionVec::CaString(_) | CaUnionVec::CaEnum(_));
+    let element = |text: &String|
+        if numeric && text.parse::<f64>().is_ok_and(f64::is_finite) {
+            text.clone()
+        } else {
+            json_string(text)
+        };
+    if values.len() == 1 && (as_string || union_len(value) == 1) {
+        element(&values[0])
+    } else {
+        format!("[{}]",
+            values.iter().map(element).collect::<Vec<_>>().join(","))
+    }
+}
+
+fn csv_field(field: &str) -> String
+{
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn print_update(
+    pv: &str, value: &CaUnionVec, alarm: StatusSeverity, stamp: Stamp,
+    ctrl: Option<&PvCtrl>, options: &Options)
+{
+    let format = &options.format;
+    let as_string =
+        format.char_as_string && matches!(value, CaUnionVec::CaChar(_));
+    let show_count =
+        options.count.is_some() || union_len(value) != 1;
+    let has_alarm = alarm.status != 0 || alarm.severity != 0;
+
+    match options.output {
+        Output::Classic => {
+            let mut line = format!("{:<30}", pv);
+            match stamp {
+                Stamp::None => { },
+                Stamp::Undefined => line += &format!(" {}", UNDEFINED_TIME),
+                Stamp::Absolute(time) =>
+                    line += &format!(" {}", format_time(time)),
+                Stamp::Seconds(secs) => line += &format!(" {:.6}", secs),
+            }
+            line += " ";
+            line += &format.format(value, ctrl, show_count);
+            if has_alarm {
+                line += &format!(" {} {}",
+                    status_name(alarm.status), severity_name(alarm.severity));
+            }
+            println!("{}", line);
+        },
+        Output::Json => {
+            let mut fields = vec![format!("\"name\":{}", json_string(pv))];
+            match stamp {
+                Stamp::None => { },
+                Stamp::Undefined => fields.push("\"timestamp\":null".into()),
+                Stamp::Absolute(time) => fields.push(format!(
+                    "\"timestamp\":\"{}\"", format_rfc3339_micros(time))),
+                Stamp::Seconds(secs) =>
+                    fields.push(format!("\"timestamp\":{:.6}", secs)),
+            }
+            let values = format.format_values(value, ctrl);
+            fields.push(format!(
+                "\"value\":{}", json_value(value, &values, as_string)));
+            fields.push(format!(
+                "\"status\":{}", json_string(&status_name(alarm.status))));
+            fields.push(format!("\"severity\":{}",
+                json_string(&severity_name(alarm.severity))));
+            println!("{{{}}}", fields.join(","));
+        },
+        Output::Csv => {
+            let stamp = match stamp {
+                Stamp::None | Stamp::Undefined => String::new(),
+                Stamp::Absolute(time) =>
+                    format_rfc3339_micros(time).to_string(),
+                Stamp::Seconds(secs) => format!("{:.6}", secs),
+            };
+            let value = format.format_values(value, ctrl).join(" ");
+            println!("{},{},{},{},{}",
+                csv_field(pv), stamp, csv_field(&value),
+                status_name(alarm.status), severity_name(alarm.severity));
+        },
+    }
+}
+
+
+// Loss and return of the connection to a PV, shown in place of a value
+fn print_connection(pv: &str, event: &str, options: &Options)
+{
+    match options.output {
+        Output::Classic => println!("{:<30} *** {}", pv, event),
+        Output::Json => println!("{{\"name\":{},\"event\":{}}}",
+            json_string(pv), json_string(event)),
+        Output::Csv => println!("{},,{},,",
+            csv_field(pv), csv_field(&format!("*** {}", event))),
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Monitoring
+
+// Returns false if the PV could not be monitored
+async fn monitor(pv: &str, options: &Options, clock: &RefCell<Clock>) -> bool
+{
+    let monitor =
+        <(CaUnionVec, StatusSeverity, EpicsTime)>::try_camonitor_events_mask(
+            pv, options.mask);
+    let monitor = common::warn_after(options.timeout, monitor, ||
+        println!("{:<30} *** Not connected (PV not found)", pv)).await;
+    let mut monitor = match monitor {
+        Ok(monitor) => monitor,
+        Err(error) => {
+            eprintln!("Monitor failed for PV '{}': {}", pv, error);
+            return false;
+        },
+    };
+
+    let mut ctrl: Option<PvCtrl> = None;
+    let mut channel_last = None;
+    while let Some(event) = monitor.next().await {
+        let (mut value, alarm, time) = match event {
+            MonitorEvent::Update(update) => update,
+            MonitorEvent::Disconnected => {
+                print_connection(pv, "disconnected", options);
+                continue;
+            },
+            // The enum strings may have changed while disconnected
+            MonitorEvent::Reconnected(_, _) => {
+                ctrl = None;
+                print_connection(pv, "reconnected", options);
+                continue;
+            },
+        };
+        // Enum strings are read on the first update after connecting
+        if let CaUnionVec::CaEnum(_) = value {
+            if ctrl.is_none() && !options.format.enum_as_number {
+                let strings = <(CaEnum, StatusSeverity, CaCtrl<_>)>
+                    ::try_caget(pv).await;
+                if let Ok((_, _, CaCtrl(strings))) = strings {
+                    ctrl = Some(strings.into());
+                }
+            }
+        }
+        if let Some(count) = options.count.filter(|&count| count > 0) {
+            let count = count.min(union_len(&value));
+            resize(&mut value, count);
+        }
+        let stamp = options.stamp(time, clock, &mut channel_last);
+        print_update(pv, &value, alarm, stamp, ctrl.as_ref(), options);
+    }
+    true
+}
+
+
+fn main()
+{
+    let usage = format!("{}{}", USAGE, common::FORMAT_USAGE);
+    let (parsed, pvs) = common::getopt(&usage, "w:m:t:o:#:Sne:f:g:l:");
+    let mut options = Options {
+        mask: DBE_VALUE | DBE_ALARM,
+        source: Source::Server,
+        modifier: Modifier::Absolute,
+        output: Output::Classic,
+        count: None,
+        timeout: Duration::from_secs(1),
+        format: Format::default(),
+    };
+    for (option, arg) in parsed {
+        let arg = arg.as_deref();
+        if options.format.parse_option(option, arg) {
+            continue;
+        }
+        let arg = arg.unwrap_or("");
+        match option {
+            'w' => options.timeout = common::parse_timeout(option, arg),
+            'm' => {
+                options.mask = 0;
+                for c in arg.chars() {
+                    options.mask |= match c {
+                        'v' => DBE_VALUE,
+                        'a' => DBE_ALARM,
+                        'l' => DBE_LOG,
+                        'p' => DBE_PROPERTY,
+                        _ => usage_error(option, arg),
+                    };
+                }
+                if options.mask == 0 {
+                    usage_error(option, arg);
+                }
+            },
+            't' => for c in arg.chars() {
+                match c {
+                    's' => options.source = Source::Server,
+                    'c' => options.source = Source::Client,
+                    'n' => options.source = Source::Nothing,
+                    'r' => options.modifier = Modifier::Relative,
+                    'i' => options.modifier = Modifier::Incremental,
+                    'I' => options.modifier = Modifier::ChannelIncremental,
+                    _ => usage_error(option, arg),
+                }
+            },
+            'o' => options.output = match arg {
+                "classic" => Output::Classic,
+                "json" => Output::Json,
+                "csv" => Output::Csv,
+                _ => usage_error(option, arg),
+            },
+            '#' => options.count = Some(common::parse_number(option, arg)),
+            _ => unreachable!(),
+        }
+    }
+    if pvs.is_empty() {
+        common::usage_error("No pv name specified.");
+    }
+
+    if options.output == Output::Csv {
+        println!("name,timestamp,value,status,severity");
+    }
+    let clock = RefCell::new(Clock::default());
+    let results = block_on(join_all(
+        pvs.iter().map(|pv| monitor(pv, &options, &clock))));
+    if results.contains(&false) {
+        std::process::exit(1);
+    }
+}
+
+fn usage_error(option: char, arg: &str) -> !
+{
+    common::usage_error(&format!(
+        "Invalid argument '{}' for option '-{}'", arg, option))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String>
+    {
+        values.iter().map(|&value| value.to_owned()).collect()
+    }
+
+    #[test]
+    fn json_quoting()
+    {
+        assert_eq!(json_string("a\"b\\c\n\t\u{1}"),
+            r#""a\"b\\c\n\t\u0001""#);
+
+        // Numbers are only written as JSON numbers if valid as such
+        let double = CaUnionVec::CaDouble(vec![1.5]);
+        assert_eq!(json_value(&double, &strings(&["1.5"]), false), "1.5");
+        assert_eq!(json_value(&double, &strings(&["nan"]), false), r#""nan""#);
+        assert_eq!(json_value(&double, &strings(&["inf"]), false), r#""inf""#);
+        assert_eq!(json_value(&double, &strings(&["0x2"]), false), r#""0x2""#);
+        let array = CaUnionVec::CaLong(vec![1, 2]);
+        assert_eq!(json_value(&array, &strings(&["1", "2"]), false), "[1,2]");
+
+        // Strings and enums are always strings
+        let string = CaUnionVec::CaString(strings(&["12"]));
+        assert_eq!(json_value(&string, &strings(&["12"]), false), r#""12""#);
+        let enums = CaUnionVec::CaEnum(vec![CaEnum(0), CaEnum(1)]);
+        assert_eq!(json_value(&enums, &strings(&["Off", "On"]), false),
+            r#"["Off","On"]"#);
+
+        // A char array printed as a string is a single value
+        let chars = CaUnionVec::CaChar(b"say \"hi\"\0".to_vec());
+        assert_eq!(json_value(&chars, &strings(&["say \"hi\""]), true),
+            r#""say \"hi\"""#);
+    }
+
+    #[test]
+    fn csv_quoting()
+    {
+        assert_eq!(csv_field("TEST:AI"), "TEST:AI");
+        assert_eq!(csv_field("1 2 3"), "1 2 3");
+        assert_eq!(csv_field("a,b"), r#""a,b""#);
+        assert_eq!(csv_field(r#"say "hi""#), r#""say ""hi""""#);
+        assert_eq!(csv_field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(csv_field("cr\r"), "\"cr\r\"");
+    }
+}
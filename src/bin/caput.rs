@@ -0,0 +1,301 @@
+// caput: writes a value to a PV
+//
+// A replacement for the caput tool from EPICS base.  The value given is
+// converted to the native type of the PV, enums can be written by label, and
+// the old and new values are printed.
+
+mod common;
+
+use std::time::Duration;
+use futures::executor::block_on;
+use epics_ca::*;
+
+use common::{Format, usage_error};
+
+
+const USAGE: &str = "\
+Usage: caput [options] <PV name> <PV value>
+       caput -a [options] <PV name> <no of values> <PV value> ...
+
+  -h: Help: Print this message
+Channel Access options:
+  -w <sec>: Wait time, specifies CA timeout, default is 1.000000 second(s)
+  -c: Asynchronous put (use ca_put_callback and wait for completion)
+Format options:
+  -t: Terse mode - print only successfully written value, without name
+Arrays:
+  -a: Put array
+      Value format: number of requested values, then list of values
+";
+
+
+struct Options {
+    terse: bool,
+    array: bool,
+    wait: bool,
+    timeout: Duration,
+    format: Format,
+}
+
+// The current value of a PV, with its native type and enum strings
+struct Current {
+    value: CaUnionVec,
+    native_type: BasicDbrType,
+    ctrl: Option<PvCtrl>,
+}
+
+async fn get_current(pv: &str) -> Result<Current, CaError>
+{
+    let got = <WithNativeType<CaUnionVec>>::try_caget(pv).await?;
+    let ctrl = if got.native_type == BasicDbrType::DbrEnum {
+        let (_, _, CaCtrl(strings)) =
+            <(CaEnum, StatusSeverity, CaCtrl<_>)>::try_caget(pv).await?;
+        Some(PvCtrl::from(strings))
+    } else {
+        None
+    };
+    Ok(Current { value: got.value, native_type: got.native_type, ctrl })
+}
+
+fn parse_values<T: std::str::FromStr>(values: &[String]) -> Result<Vec<T>, String>
+{
+    values.iter().map(|value|
+        value.trim().parse().map_err(|_|
+            format!("Value '{}' is not a valid number", value))
+    ).collect()
+}
+
+// Converts the values given on the command line to the native type of the PV,
+// as reported by the channel its current value was read from.
+fn convert_values(current: &Current, values: &[String], options: &Options)
+    -> Result<CaUnionVec, String>
+{
+    Ok(match current.native_type {
+        BasicDbrType::DbrString => CaUnionVec::CaString(values.to_vec()),
+        BasicDbrType::DbrEnum => {
+            let strings = current.ctrl.as_ref()
+                .map_or(&[][..], |ctrl| &ctrl.enum_strings[..]);
+            let values = values.iter().map(|value| {
+                let label = strings.iter().position(|s| s == value)
+                    .filter(|_| !options.format.enum_as_number);
+                match label {
+                    Some(index) => Ok(CaEnum(index as u16)),
+                    None => value.trim().parse().map(CaEnum).map_err(|_|
+                        format!("Enum index value '{}' is not a number or \
+                            valid label", value)),
+                }
+            }).collect::<Result<_, _>>()?;
+            CaUnionVec::CaEnum(values)
+        },
+        BasicDbrType::DbrChar if options.format.char_as_string => {
+            // Written with a null terminator, as long as it fits
+            let mut chars = values.join(" ").into_bytes();
+            chars.push(0);
+            CaUnionVec::CaChar(chars)
+        },
+        BasicDbrType::DbrChar => CaUnionVec::CaChar(parse_values(values)?),
+        BasicDbrType::DbrShort => CaUnionVec::CaShort(parse_values(values)?),
+        BasicDbrType::DbrLong => CaUnionVec::CaLong(parse_values(values)?),
+        BasicDbrType::DbrFloat => CaUnionVec::CaFloat(parse_values(values)?),
+        BasicDbrType::DbrDouble =>
+            CaUnionVec::CaDouble(parse_values(values)?),
+    })
+}
+
+// For -a the values follow their count, which must match
+fn array_values(values: &[String]) -> Result<Vec<String>, String>
+{
+    let count: usize = values[0].parse().map_err(|_| format!(
+        "Invalid element count '{}' for array put", values[0]))?;
+    if count == values.len() - 1 {
+        Ok(values[1..].to_vec())
+    } else {
+        Err(format!("Expected {} values for array put, got {}",
+            count, values.len() - 1))
+    }
+}
+
+fn print_value(prefix: &str, pv: &str, current: &Current, options: &Options)
+{
+    let show_count = union_len(&current.value) != 1;
+    let value = options.format.format(
+        &current.value, current.ctrl.as_ref(), show_count);
+    if options.terse {
+        println!("{}", value);
+    } else {
+        println!("{}{:<30} {}", prefix, pv, value);
+    }
+}
+
+async fn caput(pv: &str, values: &[String], options: &Options)
+    -> Result<(), String>
+{
+    let timeout = options.timeout;
+    let old = epics_ca::timeout(timeout, get_current(pv)).await
+        .ok_or_else(|| format!("Channel connect timed out: '{}' not found.", pv))?
+        .map_err(|error| format!("Read operation failed: {}", error))?;
+    let value = convert_values(&old, values, options)?;
+
+    let put = if options.wait {
+        epics_ca::timeout(timeout, CaUnionVec::try_caput(pv, value)).await
+            .ok_or_else(|| "Write operation timed out".to_owned())?
+    } else {
+        CaUnionVec::try_caput_nowait(pv, value).await
+    };
+    put.map_err(|error| format!("Error from put operation: {}", error))?;
+
+    let new = epics_ca::timeout(timeout, get_current(pv)).await
+        .ok_or_else(|| "Read operation timed out".to_owned())?
+        .map_err(|error| format!("Read operation failed: {}", error))?;
+    if !options.terse {
+        print_value("Old : ", pv, &old, options);
+    }
+    print_value("New : ", pv, &new, options);
+    Ok(())
+}
+
+
+fn main()
+{
+    let usage = format!("{}{}", USAGE, common::FORMAT_USAGE);
+    let (parsed, args) = common::getopt(&usage, "tacSne:f:g:l:w:");
+    let mut options = Options {
+        terse: false,
+        array: false,
+        wait: false,
+        timeout: Duration::from_secs(1),
+        format: Format::default(),
+    };
+    for (option, arg) in parsed {
+        let arg = arg.as_deref();
+        if options.format.parse_option(option, arg) {
+            continue;
+        }
+        match option {
+            't' => options.terse = true,
+            'a' => options.array = true,
+            'c' => options.wait = true,
+            'w' => options.timeout =
+                common::parse_timeout(option, arg.unwrap_or("")),
+            _ => unreachable!(),
+        }
+    }
+
+    let (pv, values) = match args.split_first() {
+        Some((pv, values)) => (pv, values),
+        None => usage_error("No pv name specified."),
+    };
+    if values.is_empty() {
+        usage_error("No value specified.");
+    }
+    let values = if options.array {
+        array_values(values).unwrap_or_else(|error| usage_error(&error))
+    } else {
+        // As for the C tool a value containing spaces can be given as
+        // separate arguments
+        vec![values.join(" ")]
+    };
+
+    if let Err(error) = block_on(caput(pv, &values, &options)) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String>
+    {
+        values.iter().map(|&value| value.to_owned()).collect()
+    }
+
+    fn options(format: Format) -> Options
+    {
+        Options {
+            terse: false,
+            array: false,
+            wait: true,
+            timeout: Duration::from_secs(1),
+            format,
+        }
+    }
+
+    fn convert(pv: &str, values: &[&str], format: Format)
+        -> Result<CaUnionVec, String>
+    {
+        let current = block_on(get_current(pv)).unwrap();
+        convert_values(&current, &strings(values), &options(format))
+    }
+
+    fn enums(result: Result<CaUnionVec, String>) -> Vec<u16>
+    {
+        match result {
+            Ok(CaUnionVec::CaEnum(values)) =>
+                values.iter().map(|value| value.0).collect(),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    // Enum values are labels where they match one, otherwise indices, and -n
+    // always takes them as indices
+    #[test]
+    fn enum_label_or_index()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:MBBO", CaEnum(0));
+        mock.set_ctrl("TEST:MBBO", strings(&["Off", "On", "0"]));
+
+        assert_eq!(enums(convert("TEST:MBBO", &["On"], Format::default())),
+            [1]);
+        assert_eq!(enums(convert("TEST:MBBO", &["1"], Format::default())),
+            [1]);
+        assert_eq!(enums(convert("TEST:MBBO", &["0"], Format::default())),
+            [2]);
+        let by_index = Format { enum_as_number: true, .. Format::default() };
+        assert_eq!(enums(convert("TEST:MBBO", &["0"], by_index)),
+            [0]);
+        assert_eq!(convert("TEST:MBBO", &["On"], by_index).unwrap_err(),
+            "Enum index value 'On' is not a number or valid label");
+
+        block_on(caput("TEST:MBBO", &strings(&["On"]),
+            &options(Format::default()))).unwrap();
+        assert_eq!(mock.get::<CaEnum>("TEST:MBBO").0, 1);
+    }
+
+    #[test]
+    fn array_count()
+    {
+        assert_eq!(array_values(&strings(&["2", "1.5", "2.5"])).unwrap(),
+            strings(&["1.5", "2.5"]));
+        assert_eq!(array_values(&strings(&["3", "1.5", "2.5"])).unwrap_err(),
+            "Expected 3 values for array put, got 2");
+        assert_eq!(array_values(&strings(&["x", "1.5"])).unwrap_err(),
+            "Invalid element count 'x' for array put");
+    }
+
+    // With -S a char array is written as a null terminated string, otherwise
+    // each value is a number
+    #[test]
+    fn char_array_string()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:CHARS", vec![0u8; 16]);
+
+        let as_string = Format { char_as_string: true, .. Format::default() };
+        block_on(caput("TEST:CHARS", &strings(&["hello world"]),
+            &options(as_string))).unwrap();
+        assert_eq!(mock.get::<Vec<u8>>("TEST:CHARS"), b"hello world\0");
+
+        match convert("TEST:CHARS", &["104", "105"], Format::default()) {
+            Ok(CaUnionVec::CaChar(chars)) => assert_eq!(chars, b"hi"),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(convert("TEST:CHARS", &["hi"], Format::default())
+            .unwrap_err(), "Value 'hi' is not a valid number");
+    }
+}
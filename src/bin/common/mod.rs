@@ -0,0 +1,317 @@
+// Support shared by the command line tools
+//
+// Option parsing follows getopt as used by the C tools from EPICS base, and the
+// value formatting options are shared so that all the tools print values in
+// the same way.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use epics_ca::{
+    BasicDbrType, CaUnionVec, DisplayFormat, EpicsTime, Notation, PvCtrl,
+    ALARM_STATUS_NAMES, ALARM_SEVERITY_NAMES};
+
+
+// -----------------------------------------------------------------------------
+// Option parsing
+
+// Parses the command line following getopt: option letters followed by ':' in
+// the option string take an argument, which may be attached to the option or
+// be the following argument.  Options stop at the first non option or at "--".
+// Prints the usage and exits on -h or on any error.
+pub fn getopt(usage: &str, optstring: &str)
+    -> (Vec<(char, Option<String>)>, Vec<String>)
+{
+    let mut args = std::env::args().skip(1);
+    let mut options = Vec::new();
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if !arg.starts_with('-') || arg == "-" {
+            rest.push(arg);
+            break;
+        }
+
+        let mut letters = arg[1..].chars();
+        while let Some(option) = letters.next() {
+            if option == 'h' {
+                print!("{}", usage);
+                std::process::exit(0);
+            }
+            match optstring.find(option) {
+                Some(index) if option != ':' =>
+                    if optstring[index + 1..].starts_with(':') {
+                        let attached: String = letters.by_ref().collect();
+                        let argument = if attached.is_empty() {
+                            args.next()
+                        } else {
+                            Some(attached)
+                        };
+                        match argument {
+                            Some(argument) =>
+                                options.push((option, Some(argument))),
+                            None => usage_error(&format!(
+                                "Option '-{}' requires an argument", option)),
+                        }
+                    } else {
+                        options.push((option, None));
+                    },
+                _ => usage_error(&format!("Invalid option '-{}'", option)),
+            }
+        }
+    }
+    rest.extend(args);
+    (options, rest)
+}
+
+pub fn usage_error(message: &str) -> !
+{
+    let tool = std::env::args().next().unwrap_or_default();
+    let tool = tool.rsplit('/').next().unwrap_or_default().to_owned();
+    eprintln!("{} ('{} -h' for help.)", message, tool);
+    std::process::exit(1)
+}
+
+// Parses a numeric option argument, exiting with an error if invalid
+pub fn parse_number<T: std::str::FromStr>(option: char, arg: &str) -> T
+{
+    arg.parse().unwrap_or_else(|_| usage_error(&format!(
+        "Invalid argument '{}' for option '-{}'", arg, option)))
+}
+
+
+// -----------------------------------------------------------------------------
+// Timeouts
+
+// Parses the argument to -w, a timeout in seconds, exiting with an error if it
+// is negative, not finite, or too long to represent.
+pub fn parse_timeout(option: char, arg: &str) -> Duration
+{
+    let seconds: f64 = parse_number(option, arg);
+    Duration::try_from_secs_f64(seconds).unwrap_or_else(|_| usage_error(
+        &format!("Invalid argument '{}' for option '-{}'", arg, option)))
+}
+
+// Completes with the result of the future, calling warn if the delay passes
+// first.
+pub async fn warn_after<F: Future>(
+    delay: Duration, future: F, warn: impl FnOnce()) -> F::Output
+{
+    let mut future = Box::pin(future);
+    match epics_ca::timeout(delay, future.as_mut()).await {
+        Some(result) => result,
+        None => {
+            warn();
+            future.await
+        },
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Names
+
+pub fn status_name(status: i16) -> String
+{
+    ALARM_STATUS_NAMES.get(status as usize)
+        .map_or_else(|| status.to_string(), |name| name.to_string())
+}
+
+pub fn severity_name(severity: i16) -> String
+{
+    ALARM_SEVERITY_NAMES.get(severity as usize)
+        .map_or_else(|| severity.to_string(), |name| name.to_string())
+}
+
+// Name of a basic type without the DBR_ or DBF_ prefix
+pub fn dbr_type_name(datatype: BasicDbrType) -> &'static str
+{
+    match datatype {
+        BasicDbrType::DbrString => "STRING",
+        BasicDbrType::DbrEnum   => "ENUM",
+        BasicDbrType::DbrChar   => "CHAR",
+        BasicDbrType::DbrShort  => "SHORT",
+        BasicDbrType::DbrLong   => "LONG",
+        BasicDbrType::DbrFloat  => "FLOAT",
+        BasicDbrType::DbrDouble => "DOUBLE",
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Timestamps
+
+// Formats the time in the local timezone as the C tools do, with microseconds
+pub fn format_time(time: SystemTime) -> String
+{
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+        tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday,
+        tm.tm_hour, tm.tm_min, tm.tm_sec, since_epoch.subsec_micros())
+}
+
+// Records which have never processed have a zero timestamp, which is shown as
+// undefined as by the tools from EPICS base.
+pub fn format_epics_time(time: EpicsTime) -> String
+{
+    if time.is_valid() {
+        format_time(time.into())
+    } else {
+        UNDEFINED_TIME.to_owned()
+    }
+}
+
+pub const UNDEFINED_TIME: &str = "<undefined>";
+
+
+// -----------------------------------------------------------------------------
+// Value formatting
+
+// Floating point values are shown as %g with 6 digits unless another format
+// is given.  Rounding to an integer with -l takes precedence over the floating
+// point format, as for the C tools.
+#[derive(Clone, Copy, Debug)]
+pub struct Format {
+    pub display: DisplayFormat,
+    pub char_as_string: bool,   // -S
+    pub enum_as_number: bool,   // -n
+}
+
+const DEFAULT_PRECISION: i16 = 6;
+
+pub const FORMAT_USAGE: &str = "\
+Enum format:
+  -n: Print DBF_ENUM values as numbers (default are enum strings)
+Arrays:
+  -S: Print char arrays as strings
+Floating point format:
+  -e <nr>: Use %e format, with a precision of <nr> digits
+  -f <nr>: Use %f format, with a precision of <nr> digits
+  -g <nr>: Use %g format, with a precision of <nr> digits
+Integer number format:
+  -lx: Round to long integer and print in hex
+  -lo: Round to long integer and print in octal
+  -lb: Round to long integer and print in binary
+";
+
+impl Default for Format {
+    fn default() -> Format
+    {
+        Format {
+            display: DisplayFormat {
+                notation: Notation::General,
+                units: false,
+                .. DisplayFormat::default()
+            },
+            char_as_string: false,
+            enum_as_number: false,
+        }
+    }
+}
+
+// Formats a limit or other number without control information as the C
+// tools do
+pub fn format_number(value: f64) -> String
+{
+    Format::default().float(value)
+}
+
+impl Format {
+    // Handles the formatting options described by FORMAT_USAGE, returning
+    // false for any other option.
+    pub fn parse_option(&mut self, option: char, arg: Option<&str>) -> bool
+    {
+        let arg = arg.unwrap_or("");
+        let display = &mut self.display;
+        let rounded = matches!(display.notation,
+            Notation::Hex | Notation::Octal | Notation::Binary);
+        match option {
+            'n' => self.enum_as_number = true,
+            'S' => self.char_as_string = true,
+            'e' | 'f' | 'g' => {
+                display.precision = Some(parse_number(option, arg));
+                if !rounded {
+                    display.notation = match option {
+                        'e' => Notation::Exponential,
+                        'f' => Notation::Fixed,
+                        _ => Notation::General,
+                    };
+                }
+            },
+            'l' => display.notation = match arg {
+                "x" => Notation::Hex,
+                "o" => Notation::Octal,
+                "b" => Notation::Binary,
+                _ => usage_error(&format!(
+                    "Invalid argument '{}' for option '-l'", arg)),
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    fn integer<T: Into<i64>>(&self, value: T) -> String
+    {
+        self.display.format_integer(value, "")
+    }
+
+    fn float(&self, value: f64) -> String
+    {
+        self.display.format_float(value, DEFAULT_PRECISION, "")
+    }
+
+    // Formats each element of the value.  Enums are shown using the given
+    // strings where possible, and a char array is a single string if -S was
+    // given.
+    pub fn format_values(&self, value: &CaUnionVec, ctrl: Option<&PvCtrl>)
+        -> Vec<String>
+    {
+        match value {
+            CaUnionVec::CaString(v) => v.clone(),
+            CaUnionVec::CaEnum(v) => {
+                let strings = match ctrl {
+                    Some(ctrl) if !self.enum_as_number => &ctrl.enum_strings,
+                    _ => &[][..],
+                };
+                v.iter().map(|e| self.display.format_enum(e, strings))
+                    .collect()
+            },
+            CaUnionVec::CaChar(v) if self.char_as_string => {
+                let length = v.iter().position(|&c| c == 0).unwrap_or(v.len());
+                vec![String::from_utf8_lossy(&v[..length]).into_owned()]
+            },
+            CaUnionVec::CaChar(v) =>
+                v.iter().map(|&x| self.integer(x)).collect(),
+            CaUnionVec::CaShort(v) =>
+                v.iter().map(|&x| self.integer(x)).collect(),
+            CaUnionVec::CaLong(v) =>
+                v.iter().map(|&x| self.integer(x)).collect(),
+            CaUnionVec::CaFloat(v) =>
+                v.iter().map(|&x| self.float(x as f64)).collect(),
+            CaUnionVec::CaDouble(v) =>
+                v.iter().map(|&x| self.float(x)).collect(),
+        }
+    }
+
+    // Formats the value as the C tools do: a single value on its own, or an
+    // array preceded by its length.  A char array printed as a string is a
+    // single value.
+    pub fn format(&self, value: &CaUnionVec, ctrl: Option<&PvCtrl>,
+        show_count: bool) -> String
+    {
+        let values = self.format_values(value, ctrl);
+        let is_string = self.char_as_string &&
+            matches!(value, CaUnionVec::CaChar(_));
+        if show_count && !is_string {
+            format!("{} {}", values.len(), values.join(" "))
+        } else {
+            values.join(" ")
+        }
+    }
+}
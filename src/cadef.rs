@@ -1,13 +1,16 @@
-use libc::{c_char, c_short, c_int, c_uint, c_long, c_ulong, c_void};
+use libc::{c_int, c_long, c_void};
+#[cfg(not(feature = "native"))]
+use libc::{c_char, c_short, c_uint, c_ulong};
 
 // Entry points from cadef.h
+#[cfg(not(feature = "native"))]
 #[link(name = "ca")]
 extern "C" {
     pub fn ca_context_create(
         select: ca_preemptive_callback_select) -> c_int;
     pub fn ca_create_channel(
         pv: *const c_char,
-        on_connect : extern fn(args: ca_connection_handler_args),
+        on_connect : extern "C" fn(args: ca_connection_handler_args),
         context: *const c_void,
         priority: c_uint,
         id: *mut ChanId) -> c_int;
@@ -15,10 +18,27 @@ extern "C" {
     pub fn ca_puser(channel: ChanId) -> *const c_void;
     pub fn ca_field_type(channel: ChanId) -> c_short;
     pub fn ca_element_count(channel: ChanId) -> c_ulong;
+    pub fn ca_host_name(channel: ChanId) -> *const c_char;
+    pub fn ca_read_access(channel: ChanId) -> c_uint;
+    pub fn ca_write_access(channel: ChanId) -> c_uint;
     pub fn ca_array_get_callback(
         channel_type: c_long, count: c_ulong, channel: ChanId,
-        handler: extern fn(args: event_handler_args),
+        handler: extern "C" fn(args: event_handler_args),
         context: *const c_void) -> c_int;
+    pub fn ca_array_put(
+        channel_type: c_long, count: c_ulong, channel: ChanId,
+        value: *const c_void) -> c_int;
+    pub fn ca_array_put_callback(
+        channel_type: c_long, count: c_ulong, channel: ChanId,
+        value: *const c_void,
+        handler: extern "C" fn(args: event_handler_args),
+        context: *const c_void) -> c_int;
+    pub fn ca_create_subscription(
+        channel_type: c_long, count: c_ulong, channel: ChanId, mask: c_long,
+        handler: extern "C" fn(args: event_handler_args),
+        context: *const c_void,
+        id: *mut EvId) -> c_int;
+    pub fn ca_clear_subscription(id: EvId) -> c_int;
     pub fn ca_flush_io() -> c_int;
 }
 
@@ -30,6 +50,7 @@ pub enum ca_preemptive_callback_select {
     ca_enable_preemptive_callback,
 }
 
+#[cfg(not(feature = "native"))]
 #[repr(C)]
 #[derive(Debug)]
 pub struct ca_connection_handler_args {
@@ -52,15 +73,27 @@ pub struct event_handler_args {
 pub const CA_OP_CONN_UP: c_long = 6;
 pub const CA_OP_CONN_DOWN: c_long = 7;
 
+// Status returned by successful calls and in successful callbacks
+pub const ECA_NORMAL: c_int = 1;
+
 // Opaque channel identifier
 #[repr(transparent)]
 #[derive(Debug)]
 #[derive(Clone, Copy)]
-pub struct ChanId(*const c_void);
+pub struct ChanId(pub *const c_void);
 unsafe impl Send for ChanId { }
 
 pub const CHAN_ID_VOID: ChanId = ChanId(0 as _);
 
+// Opaque subscription identifier
+#[repr(transparent)]
+#[derive(Debug)]
+#[derive(Clone, Copy)]
+pub struct EvId(pub *const c_void);
+unsafe impl Send for EvId { }
+
+pub const EV_ID_VOID: EvId = EvId(0 as _);
+
 
 // Helper methods for void* conversion
 
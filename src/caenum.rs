@@ -0,0 +1,415 @@
+// Enum values resolved to their state labels
+//
+// The state strings of an enum PV are only returned with its control
+// information, which carries no timestamp.  Here they are cached with each
+// channel: a get of the value and timestamp reads the labels with a second
+// request only when the channel has none cached or the value is not among them,
+// and a monitor reads them once and replaces them whenever the server reports a
+// property change, which includes the update on reconnection.
+//
+// The state strings are also used to map enum PVs onto Rust enums deriving
+// CaEnumType, checking that the states of the PV match the variants.
+
+use std::{fmt, pin, rc, sync, task};
+use std::time::SystemTime;
+use async_trait::async_trait;
+use futures::stream::{Stream, StreamExt};
+
+use crate::channel;
+use crate::caget::{CaGetCore, CaCtrl, CA};
+use crate::caput::{caput_core, caput_nowait_core};
+use crate::camonitor::{
+    CaMonitor, Monitor, SharedChannel, Subscription, DBE_PROPERTY};
+use crate::db_access::StatusSeverity;
+use crate::dbr::{CaEnum, CaEnumType, EnumMapping, ILLEGAL_VALUE};
+use crate::error::CaError;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelledEnum {
+    pub value: u16,
+    pub label: Option<String>,      // None if the value has no state string
+}
+
+impl LabelledEnum {
+    pub fn new(value: CaEnum, labels: &[String]) -> LabelledEnum
+    {
+        let label = value.label(labels).map(str::to_owned);
+        LabelledEnum { value: value.0, label }
+    }
+}
+
+// Values with no state string are shown as EPICS does
+impl fmt::Display for LabelledEnum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(self.label.as_deref().unwrap_or(ILLEGAL_VALUE))
+    }
+}
+
+
+type CtrlEnum = (CaEnum, StatusSeverity, CaCtrl<Vec<String>>);
+
+type Labels = sync::Arc<Vec<String>>;
+
+// Reads the labels, updating the cache of the channel
+async fn get_labels(channel: &channel::Channel) -> Result<Labels, CaError>
+{
+    let (_, _, CaCtrl(labels)) = CtrlEnum::caget_core(channel).await?;
+    Ok(channel.set_enum_labels(labels))
+}
+
+// Returns the cached labels if they include the given value
+async fn labels_for(channel: &channel::Channel, value: &CaEnum)
+    -> Result<Labels, CaError>
+{
+    match channel.enum_labels() {
+        Some(labels) if (value.0 as usize) < labels.len() => Ok(labels),
+        _ => get_labels(channel).await,
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// caget
+
+// The control request returns the value together with its labels
+#[async_trait(?Send)]
+impl CA for LabelledEnum {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        let (value, _, CaCtrl(labels)) =
+            CtrlEnum::caget_core(&channel).await?;
+        Ok(LabelledEnum::new(value, &channel.set_enum_labels(labels)))
+    }
+}
+
+// The value and timestamp are read together and labelled from the cache of the
+// channel, so the labels are only read if the value is not among them.
+#[async_trait(?Send)]
+impl CA for (LabelledEnum, StatusSeverity, SystemTime) {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        let (value, severity, time) =
+            <(CaEnum, StatusSeverity, SystemTime)>
+                ::caget_core(&channel).await?;
+        let labels = labels_for(&channel, &value).await?;
+        Ok((LabelledEnum::new(value, &labels), severity, time))
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor
+
+// Value updates are labelled from the labels cached with the channel, which are
+// replaced by any property update received first.
+struct LabelledSubscription {
+    channel: SharedChannel,
+    values: Subscription<(CaEnum, StatusSeverity, SystemTime)>,
+    properties: Subscription<CtrlEnum>,
+}
+
+impl LabelledSubscription {
+    async fn new(pv: &str, mask: u32)
+        -> Result<LabelledSubscription, CaError>
+    {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        let channel = rc::Rc::new(channel);
+        // If this fails the labels will follow with the first property update
+        let _ = get_labels(&channel).await;
+        Ok(LabelledSubscription {
+            properties: Subscription::new(channel.clone(), DBE_PROPERTY)?,
+            values: Subscription::new(channel.clone(), mask)?,
+            channel,
+        })
+    }
+}
+
+impl Stream for LabelledSubscription {
+    type Item = (LabelledEnum, StatusSeverity, SystemTime);
+
+    fn poll_next(self: pin::Pin<&mut Self>, context: &mut task::Context)
+        -> task::Poll<Option<Self::Item>>
+    {
+        let this = self.get_mut();
+        while let task::Poll::Ready(Some((_, _, CaCtrl(labels)))) =
+            this.properties.poll_next_unpin(context)
+        {
+            this.channel.set_enum_labels(labels);
+        }
+        let labels = this.channel.enum_labels().unwrap_or_default();
+        this.values.poll_next_unpin(context).map(|update|
+            update.map(|(value, severity, time)|
+                (LabelledEnum::new(value, &labels), severity, time)))
+    }
+}
+
+#[async_trait(?Send)]
+impl CaMonitor for LabelledEnum {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        Ok(Monitor::new(LabelledSubscription::new(pv.as_ref(), mask).await?
+            .map(|(value, _, _)| value)))
+    }
+}
+
+#[async_trait(?Send)]
+impl CaMonitor for (LabelledEnum, StatusSeverity, SystemTime) {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        Ok(Monitor::new(LabelledSubscription::new(pv.as_ref(), mask).await?))
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Rust enums
+
+// Checks the variants of T against the state strings of the PV, returning the
+// state index for each variant.  PVs without state strings can only be checked
+// when matching by label.
+fn state_indices<T: CaEnumType>(pv: &str, labels: &[String])
+    -> Result<Vec<u16>, CaError>
+{
+    match T::MAPPING {
+        EnumMapping::Labels(names) => names.iter().map(|name|
+            labels.iter().position(|label| label == name)
+                .map(|index| index as u16)
+                .ok_or_else(|| CaError::EnumMismatch(format!(
+                    "{} has no state \"{}\" for {}, its states are {:?}",
+                    pv, name, T::NAME, labels)))
+        ).collect(),
+        EnumMapping::Indices(indices) =>
+            match indices.iter().find(|&&index|
+                !labels.is_empty() && index as usize >= labels.len())
+            {
+                Some(index) => Err(CaError::EnumMismatch(format!(
+                    "{} has no state {} for {}, it has {} states",
+                    pv, index, T::NAME, labels.len()))),
+                None => Ok(indices.to_vec()),
+            },
+    }
+}
+
+pub async fn caget_enum_type<T: CaEnumType>(pv: &str) -> Result<T, CaError>
+{
+    let (channel, _datatype, _count) = channel::connect(pv).await?;
+    let (value, _, CaCtrl(labels)) = CtrlEnum::caget_core(&channel).await?;
+    let labels = channel.set_enum_labels(labels);
+    let indices = state_indices::<T>(pv, &labels)?;
+    let state = value.0;
+    match indices.iter().position(|&index| index == state) {
+        Some(variant) => Ok(T::from_variant(variant)),
+        None => Err(CaError::EnumMismatch(format!(
+            "{} is in state {} \"{}\" which is not a variant of {}",
+            pv, state, LabelledEnum::new(value, &labels), T::NAME))),
+    }
+}
+
+pub async fn caput_enum_type<T: CaEnumType>(pv: &str, value: T, wait: bool)
+    -> Result<(), CaError>
+{
+    let (channel, _datatype, _count) = channel::connect(pv).await?;
+    // Always read the labels afresh so as never to write a stale index
+    let labels = get_labels(&channel).await?;
+    let index = CaEnum(state_indices::<T>(pv, &labels)?[value.variant()]);
+    if wait {
+        caput_core(&channel, &[index]).await
+    } else {
+        caput_nowait_core(&channel, &[index])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    use super::{get_labels, labels_for, LabelledEnum};
+    use crate::caget::CA;
+    use crate::channel;
+    use crate::caput::CaPut;
+    use crate::camonitor::CaMonitor;
+    use crate::db_access::StatusSeverity;
+    use crate::CaEnumType;
+    use crate::dbr::CaEnum;
+    use crate::error::CaError;
+    use crate::mock::MockBackend;
+
+    #[derive(CaEnumType, Debug, PartialEq)]
+    enum Shutter {
+        Closed,
+        #[ca(rename = "Fully Open")]
+        Open,
+    }
+
+    #[derive(CaEnumType, Debug, PartialEq)]
+    #[ca(by_index)]
+    enum Level { Low = 1, High = 3 }
+
+    fn mismatch<T>(result: Result<T, CaError>) -> bool
+    {
+        matches!(result, Err(CaError::EnumMismatch(_)))
+    }
+
+    fn labels(labels: &[&str]) -> Vec<String>
+    {
+        labels.iter().map(|label| label.to_string()).collect()
+    }
+
+    fn get_label(pv: &str) -> Option<String>
+    {
+        let (value, _, _) = block_on(
+            <(LabelledEnum, StatusSeverity, SystemTime)>::caget(pv));
+        value.label
+    }
+
+    #[test]
+    fn labelled_get()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:MBBI", CaEnum(1));
+        mock.set_ctrl("TEST:MBBI", labels(&["Off", "On"]));
+        let value = block_on(LabelledEnum::caget("TEST:MBBI"));
+        assert_eq!(value.to_string(), "On");
+        assert_eq!(get_label("TEST:MBBI").as_deref(), Some("On"));
+        mock.set("TEST:MBBI", CaEnum(0));
+        assert_eq!(get_label("TEST:MBBI").as_deref(), Some("Off"));
+    }
+
+    // Each get reads the labels with a new channel
+    #[test]
+    fn labels_refreshed()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:MBBI", CaEnum(1));
+        mock.set_ctrl("TEST:MBBI", labels(&["Off", "On"]));
+        assert_eq!(get_label("TEST:MBBI").as_deref(), Some("On"));
+        mock.set_ctrl("TEST:MBBI", labels(&["Low", "Mid", "High"]));
+        mock.set("TEST:MBBI", CaEnum(2));
+        assert_eq!(get_label("TEST:MBBI").as_deref(), Some("High"));
+        mock.set("TEST:MBBI", CaEnum(3));
+        assert_eq!(get_label("TEST:MBBI"), None);
+    }
+
+    // The labels of a channel are only read again for a value not among them
+    #[test]
+    fn labels_cached()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:MBBI", CaEnum(1));
+        mock.set_ctrl("TEST:MBBI", labels(&["Off", "On"]));
+        block_on(async {
+            let (channel, _, _) = channel::connect("TEST:MBBI").await.unwrap();
+            get_labels(&channel).await.unwrap();
+            mock.set_ctrl("TEST:MBBI", labels(&["Closed", "Open", "Moving"]));
+            let cached = labels_for(&channel, &CaEnum(1)).await.unwrap();
+            assert_eq!(*cached, labels(&["Off", "On"]));
+            let read = labels_for(&channel, &CaEnum(2)).await.unwrap();
+            assert_eq!(*read, labels(&["Closed", "Open", "Moving"]));
+            assert_eq!(channel.enum_labels(), Some(read));
+        });
+    }
+
+    // Renamed labels are seen by new gets, by monitors when the server reports
+    // the change, and by monitors reconnecting after a change
+    #[test]
+    fn labels_renamed()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:MBBI", CaEnum(1));
+        mock.set_ctrl("TEST:MBBI", labels(&["Off", "On"]));
+        block_on(async {
+            let mut monitor = LabelledEnum::camonitor("TEST:MBBI").await;
+            assert_eq!(monitor.next().await.unwrap().to_string(), "On");
+
+            mock.set_ctrl("TEST:MBBI", labels(&["Closed", "Open"]));
+            mock.set("TEST:MBBI", CaEnum(1));
+            assert_eq!(monitor.next().await.unwrap().to_string(), "Open");
+            assert_eq!(LabelledEnum::caget("TEST:MBBI").await.to_string(),
+                "Open");
+
+            mock.disconnect("TEST:MBBI");
+            mock.set_ctrl("TEST:MBBI", labels(&["Out", "In"]));
+            mock.reconnect("TEST:MBBI");
+            assert_eq!(monitor.next().await.unwrap().to_string(), "In");
+            mock.set("TEST:MBBI", CaEnum(0));
+            assert_eq!(monitor.next().await.unwrap().to_string(), "Out");
+        });
+        assert_eq!(get_label("TEST:MBBI").as_deref(), Some("Out"));
+    }
+
+    // Variants are matched to states by label, whatever their order
+    #[test]
+    fn enum_type_by_label()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:SHUTTER", CaEnum(0));
+        mock.set_ctrl("TEST:SHUTTER", labels(&["Fully Open", "Closed"]));
+        block_on(async {
+            assert_eq!(Shutter::caget("TEST:SHUTTER").await, Shutter::Open);
+            Shutter::caput("TEST:SHUTTER", Shutter::Closed).await;
+            assert_eq!(mock.get::<CaEnum>("TEST:SHUTTER").0, 1);
+            assert_eq!(Shutter::caget("TEST:SHUTTER").await, Shutter::Closed);
+            Shutter::try_caput_nowait("TEST:SHUTTER", Shutter::Open).await
+                .unwrap();
+            assert_eq!(mock.get::<CaEnum>("TEST:SHUTTER").0, 0);
+        });
+    }
+
+    // Variants are matched to the states given by their discriminants
+    #[test]
+    fn enum_type_by_index()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:LEVEL", CaEnum(3));
+        mock.set_ctrl("TEST:LEVEL", labels(&["Zero", "One", "Two", "Three"]));
+        block_on(async {
+            assert_eq!(Level::caget("TEST:LEVEL").await, Level::High);
+            Level::caput("TEST:LEVEL", Level::Low).await;
+            assert_eq!(mock.get::<CaEnum>("TEST:LEVEL").0, 1);
+
+            mock.set("TEST:LEVEL", CaEnum(2));
+            assert!(mismatch(Level::try_caget("TEST:LEVEL").await));
+        });
+    }
+
+    // A PV missing a state for any variant is rejected, even when its value
+    // matches another variant, and nothing is written to it
+    #[test]
+    fn enum_type_mismatch()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:SHUTTER", CaEnum(1));
+        mock.set_ctrl("TEST:SHUTTER", labels(&["Closed", "Open"]));
+        mock.add_pv("TEST:LEVEL", CaEnum(1));
+        mock.set_ctrl("TEST:LEVEL", labels(&["Zero", "One"]));
+        block_on(async {
+            assert!(mismatch(Shutter::try_caget("TEST:SHUTTER").await));
+            assert!(mismatch(
+                Shutter::try_caput("TEST:SHUTTER", Shutter::Closed).await));
+            assert!(mismatch(Level::try_caget("TEST:LEVEL").await));
+            assert!(mismatch(Level::try_caput("TEST:LEVEL", Level::Low).await));
+        });
+        assert_eq!(mock.get::<CaEnum>("TEST:SHUTTER").0, 1);
+        assert_eq!(mock.get::<CaEnum>("TEST:LEVEL").0, 1);
+
+        // A state which is not a variant cannot be read
+        mock.set_ctrl("TEST:SHUTTER",
+            labels(&["Closed", "Fully Open", "Moving"]));
+        mock.set("TEST:SHUTTER", CaEnum(2));
+        assert!(mismatch(block_on(Shutter::try_caget("TEST:SHUTTER"))));
+    }
+}
@@ -1,14 +1,18 @@
 // Implementation of caget functionality
 
+use std::sync::Arc;
 use async_trait::async_trait;
+use futures::future::join_all;
 
 use crate::cadef;
 use crate::dbr;
 use crate::callback;
 use crate::channel;
+use crate::caunion::BasicDbrType;
+use crate::error::CaError;
 
-use std::time::SystemTime;
 use crate::db_access::StatusSeverity;
+use crate::timestamp::{EpicsTime, Timestamp};
 
 
 // Overloaded trait for returning the underlying Dbr value using one of the two
@@ -39,122 +43,255 @@ impl<R, D> GetResult<D> for Vec<R>
 
 
 
+type GetWaker<D, T> =
+    callback::AsyncWaker<Result<(T, <D as dbr::Dbr>::ExtraType), CaError>>;
+
 // Asynchronous callback invoked in response to ca_array_get_callback.  The two
 // type parameters are as follows:
 //
 //  D: the Dbr type for the returned data
 //  T: the actual type we're going to return, supported by GetResult<D>.
 //     In practice, this type is either D::ResultType or Vec<D::ResultType>.
-extern fn caget_callback<D, T>(args: cadef::event_handler_args)
+extern "C" fn caget_callback<D, T>(args: cadef::event_handler_args)
     where D: dbr::Dbr, T: GetResult<D>
 {
-    let waker: &callback::AsyncWaker::<(T, D::ExtraType)> =
-        unsafe { cadef::voidp_to_ref(args.usr) };
-    let dbr: &D = unsafe { cadef::voidp_to_ref(args.dbr) };
-    let result = (T::get_result(dbr, args.count as usize), dbr.get_extra());
+    let waker = unsafe { GetWaker::<D, T>::from_raw(args.usr) };
+    let result = CaError::check(args.status).map(|()| {
+        let dbr: &D = unsafe { cadef::voidp_to_ref(args.dbr) };
+        (T::get_result(dbr, args.count as usize), dbr.get_extra())
+    });
     waker.wake(result);
 }
 
 
-async fn caget_core<D, T>(channel: &channel::Channel) -> (T, D::ExtraType)
+// Arrays are read in full unless a count is given, scalars always as one value
+async fn caget_core<D, T>(channel: &channel::Channel, count: usize)
+    -> Result<(T, D::ExtraType), CaError>
     where D: dbr::Dbr, T: GetResult<D>
 {
-    let waker = callback::AsyncWaker::<(T, D::ExtraType)>::new();
-    let rc = unsafe { cadef::ca_array_get_callback(
-        D::DATATYPE as i64, T::COUNT, channel.id,
-        caget_callback::<D, T>, cadef::ref_to_voidp(&waker)) };
-    assert!(rc == 1);
-    unsafe { cadef::ca_flush_io() };
+    let count = if T::COUNT == 0 { count as u64 } else { T::COUNT };
+    let waker = Arc::new(GetWaker::<D, T>::new());
+    let usr = waker.into_raw();
+    let rc = channel.backend.array_get_callback(
+        D::DATATYPE as i64, count, channel.id,
+        caget_callback::<D, T>, usr);
+    if let Err(error) = CaError::check(rc) {
+        // No callback will come, so release its reference
+        drop(unsafe { GetWaker::<D, T>::from_raw(usr) });
+        return Err(error);
+    }
+    channel.backend.flush_io();
     waker.wait_for().await
 }
 
 
 // -----------------------------------------------------------------------------
 // Implementation of caget_core for all of the basic target types
-
-#[async_trait(?Send)]
-pub trait CaGetCore {
-    async fn caget_core(channel: &channel::Channel) -> Self;
+//
+// Each supported result type names the Dbr to request and assembles itself from
+// the value and extra data returned.  This is shared with camonitor.  Assembly
+// only fails for types converted from the value returned, see convert.rs.
+
+pub trait CaResult: Sized + Send {
+    type Dbr: dbr::Dbr;
+    type Value: GetResult<Self::Dbr>;
+    fn assemble(
+        value: Self::Value, extra: <Self::Dbr as dbr::Dbr>::ExtraType)
+        -> Result<Self, CaError>;
 }
 
-
-
-// caget_core of undecorated value, either as scalar or vector
-
 #[async_trait(?Send)]
-impl<T> CaGetCore for T where T: dbr::DbrMap {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        caget_core::<T::ValueDbr, _>(channel).await.0
+pub trait CaGetCore: Sized {
+    async fn caget_core(channel: &channel::Channel) -> Result<Self, CaError>;
+
+    // Reads at most count elements of an array, or all of them if count is
+    // zero.  Only arrays can be limited, anything else is read in full.
+    async fn caget_core_count(channel: &channel::Channel, _count: usize)
+        -> Result<Self, CaError>
+    {
+        Self::caget_core(channel).await
     }
 }
 
 #[async_trait(?Send)]
-impl<T> CaGetCore for Vec<T> where T: dbr::DbrMap {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        caget_core::<T::ValueDbr, _>(channel).await.0
+impl<T> CaGetCore for T where T: CaResult {
+    async fn caget_core(channel: &channel::Channel) -> Result<Self, CaError> {
+        Self::caget_core_count(channel, 0).await
+    }
+
+    async fn caget_core_count(channel: &channel::Channel, count: usize)
+        -> Result<Self, CaError>
+    {
+        let (value, extra) =
+            caget_core::<T::Dbr, T::Value>(channel, count).await?;
+        T::assemble(value, extra)
     }
 }
 
 
-// caget_core with severity and timestamp
 
-#[async_trait(?Send)]
-impl<T> CaGetCore for (T, StatusSeverity, SystemTime) where T: dbr::DbrMap {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        let (v, (s, t)) = caget_core::<T::TimeDbr, _>(channel).await;
-        (v, s, t)
+// Undecorated value, either as scalar or vector.  These are implemented type by
+// type, leaving the blanket implementations for FromCa in convert.rs.
+
+macro_rules! plain_result {
+    { $($type:ty),* } => { $(
+        impl CaResult for $type {
+            type Dbr = <$type as dbr::DbrMap>::ValueDbr;
+            type Value = $type;
+            fn assemble(value: $type, _extra: ()) -> Result<Self, CaError> {
+                Ok(value)
+            }
+        }
+
+        impl CaResult for Vec<$type> {
+            type Dbr = <$type as dbr::DbrMap>::ValueDbr;
+            type Value = Vec<$type>;
+            fn assemble(value: Vec<$type>, _extra: ())
+                -> Result<Self, CaError>
+            {
+                Ok(value)
+            }
+        }
+    )* }
+}
+
+plain_result!{String, u8, i16, i32, f32, f64, dbr::CaEnum}
+
+
+// Value with severity and timestamp, either as SystemTime or EpicsTime
+
+impl<T, Time> CaResult for (T, StatusSeverity, Time)
+    where T: dbr::DbrMap, Time: Timestamp
+{
+    type Dbr = T::TimeDbr;
+    type Value = T;
+    fn assemble(value: T, (s, t): (StatusSeverity, EpicsTime))
+        -> Result<Self, CaError>
+    {
+        Ok((value, s, Time::from_epics(t, &s)))
     }
 }
 
-#[async_trait(?Send)]
-impl<T> CaGetCore for (Vec<T>, StatusSeverity, SystemTime)
-    where T: dbr::DbrMap
+impl<T, Time> CaResult for (Vec<T>, StatusSeverity, Time)
+    where T: dbr::DbrMap, Time: Timestamp
 {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        let (v, (s, t)) = caget_core::<T::TimeDbr, _>(channel).await;
-        (v, s, t)
+    type Dbr = T::TimeDbr;
+    type Value = Vec<T>;
+    fn assemble(value: Vec<T>, (s, t): (StatusSeverity, EpicsTime))
+        -> Result<Self, CaError>
+    {
+        Ok((value, s, Time::from_epics(t, &s)))
     }
 }
 
 
-// caget_core with control field information
+// Value with control field information
 
 #[derive(Clone, Copy, Debug)]
 pub struct CaCtrl<T>(pub T);
 
-#[async_trait(?Send)]
-impl<T> CaGetCore for (T, StatusSeverity, CaCtrl<T::CtrlType>)
+impl<T> CaResult for (T, StatusSeverity, CaCtrl<T::CtrlType>)
     where T: dbr::DbrMap
 {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        let (v, (s, c)) = caget_core::<T::CtrlDbr, _>(channel).await;
-        (v, s, CaCtrl(c))
+    type Dbr = T::CtrlDbr;
+    type Value = T;
+    fn assemble(value: T, (s, c): (StatusSeverity, T::CtrlType))
+        -> Result<Self, CaError>
+    {
+        Ok((value, s, CaCtrl(c)))
     }
 }
 
-#[async_trait(?Send)]
-impl<T> CaGetCore for (Vec<T>, StatusSeverity, CaCtrl<T::CtrlType>)
+impl<T> CaResult for (Vec<T>, StatusSeverity, CaCtrl<T::CtrlType>)
     where T: dbr::DbrMap
 {
-    async fn caget_core(channel: &channel::Channel) -> Self {
-        let (v, (s, c)) = caget_core::<T::CtrlDbr, _>(channel).await;
-        (v, s, CaCtrl(c))
+    type Dbr = T::CtrlDbr;
+    type Value = Vec<T>;
+    fn assemble(value: Vec<T>, (s, c): (StatusSeverity, T::CtrlType))
+        -> Result<Self, CaError>
+    {
+        Ok((value, s, CaCtrl(c)))
     }
 }
 
 
 // -----------------------------------------------------------------------------
 // caget
+//
+// try_caget reports failure of the get, for instance if the channel disconnects
+// or the server cannot convert the value, while caget treats this as fatal.
+// Several PVs of the same type can be read concurrently with caget_all, which
+// returns their values in the order given.
 
 #[async_trait(?Send)]
-pub trait CA {
-    async fn caget(pv: &str) -> Self;
+pub trait CA: Sized {
+    async fn try_caget(pv: &str) -> Result<Self, CaError>;
+
+    async fn caget(pv: &str) -> Self {
+        match Self::try_caget(pv).await {
+            Ok(value) => value,
+            Err(error) => panic!("caget {} failed: {}", pv, error),
+        }
+    }
+
+    async fn try_caget_all(pvs: &[&str]) -> Vec<Result<Self, CaError>> {
+        join_all(pvs.iter().map(|pv| Self::try_caget(pv))).await
+    }
+
+    async fn caget_all(pvs: &[&str]) -> Vec<Self> {
+        join_all(pvs.iter().map(|pv| Self::caget(pv))).await
+    }
 }
 
 #[async_trait(?Send)]
 impl<T> CA for T where T: CaGetCore {
-    async fn caget(pv: &str) -> Self {
-        let (channel, _datatype, _count) = channel::connect(pv).await;
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
         T::caget_core(&channel).await
     }
 }
+
+
+// Value read together with the native type and element count reported by the
+// server on connection, both taken from the same channel.
+#[derive(Clone, Debug)]
+pub struct WithNativeType<T> {
+    pub value: T,
+    pub native_type: BasicDbrType,
+    pub count: usize,
+}
+
+#[async_trait(?Send)]
+impl<T> CA for WithNativeType<T> where T: CaGetCore {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, native_type, count) = channel::connect(pv).await?;
+        let value = T::caget_core(&channel).await?;
+        Ok(WithNativeType { value, native_type, count })
+    }
+}
+
+
+// Reads at most the given number of elements of an array, as for caget -#.  As
+// for the C tool a count of zero or beyond the element count of the channel
+// reads the whole array.
+#[async_trait(?Send)]
+pub trait CaGetCount: Sized {
+    async fn try_caget_count(pv: &str, count: usize) -> Result<Self, CaError>;
+}
+
+#[async_trait(?Send)]
+impl<T> CaGetCount for T where T: CaGetCore {
+    async fn try_caget_count(pv: &str, count: usize) -> Result<Self, CaError> {
+        let (channel, _datatype, elements) = channel::connect(pv).await?;
+        T::caget_core_count(&channel, count.min(elements)).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaGetCount for WithNativeType<T> where T: CaGetCore {
+    async fn try_caget_count(pv: &str, count: usize) -> Result<Self, CaError> {
+        let (channel, native_type, elements) = channel::connect(pv).await?;
+        let value = T::caget_core_count(&channel, count.min(elements)).await?;
+        Ok(WithNativeType { value, native_type, count: elements })
+    }
+}
@@ -0,0 +1,196 @@
+// Implementation of cainfo functionality
+//
+// Gathers everything known about a channel in one request:
+//
+//      let info = cainfo("SR-DI-DCCT-01:SIGNAL").await;
+//
+// This waits for the channel to connect.  try_cainfo_timeout instead gives up
+// after the given time, reporting the state the channel was left in:
+//
+//      match try_cainfo_timeout(pv, Duration::from_secs(1)).await {
+//          Ok(info) => ...,
+//          Err(CaError::Timeout(ConnectionState::NeverConnected)) => ...,
+//          ...
+//      }
+
+use std::time::Duration;
+use async_trait::async_trait;
+
+use crate::channel;
+use crate::channel::ConnectionState;
+use crate::caget::{CaGetCore, CA};
+use crate::caunion::{BasicDbrType, CaUnionCtrl, caget_ctrl};
+use crate::db_access::StatusSeverity;
+use crate::error::CaError;
+use crate::protocol::{CA_ACCESS_READ, CA_ACCESS_WRITE};
+use crate::timer;
+
+
+// The record type is read from the RTYP field of the record.  Not every server
+// provides this, and as there is no reply to a search for a missing PV we only
+// wait this long for it once the PV itself has connected.  This is searched for
+// alongside the PV so normally connects at the same time.
+const RECORD_TYPE_GRACE: Duration = Duration::from_millis(200);
+
+#[derive(Debug)]
+pub struct CaInfo {
+    pub name: String,
+    pub native_type: BasicDbrType,
+    pub element_count: usize,
+    pub host: String,
+    pub read_access: bool,
+    pub write_access: bool,
+    pub state: ConnectionState,
+    pub record_type: Option<String>,
+    pub status: StatusSeverity,
+    pub ctrl: CaUnionCtrl,
+}
+
+
+// The record part of a PV name, without any field or channel filter
+fn record_name(pv: &str) -> &str
+{
+    pv.split(['.', '{']).next().unwrap()
+}
+
+// Reads the record type from the RTYP channel, giving up on it if it has not
+// connected within RECORD_TYPE_GRACE.
+async fn get_record_type(rtyp: &channel::Channel) -> Option<String>
+{
+    timer::timeout(RECORD_TYPE_GRACE, rtyp.wait_connect()).await?;
+    String::caget_core(rtyp).await.ok()
+}
+
+
+// Gathers the information once the channel has connected
+async fn read_info(pv: &str, channel: &channel::Channel)
+    -> Result<CaInfo, CaError>
+{
+    let rtyp = channel::Channel::new(format!("{}.RTYP", record_name(pv)))?;
+    let (native_type, element_count) = channel.wait_connect().await;
+    let (ctrl, record_type) = futures::join!(
+        caget_ctrl(channel, native_type), get_record_type(&rtyp));
+    let (ctrl, status) = ctrl?;
+    let access = channel.access_rights();
+    Ok(CaInfo {
+        name: pv.to_owned(),
+        native_type,
+        element_count,
+        host: channel.host_name(),
+        read_access: access & CA_ACCESS_READ != 0,
+        write_access: access & CA_ACCESS_WRITE != 0,
+        state: channel.connection_state(),
+        record_type,
+        status,
+        ctrl,
+    })
+}
+
+
+#[async_trait(?Send)]
+impl CA for CaInfo {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        read_info(pv, &*channel::Channel::new(pv)?).await
+    }
+}
+
+pub async fn try_cainfo(pv: &str) -> Result<CaInfo, CaError>
+{
+    CaInfo::try_caget(pv).await
+}
+
+// Fails with Timeout if the information is not complete in time, reporting
+// whether the channel ever connected
+pub async fn try_cainfo_timeout(pv: &str, timeout: Duration)
+    -> Result<CaInfo, CaError>
+{
+    let channel = channel::Channel::new(pv)?;
+    match timer::timeout(timeout, read_info(pv, &channel)).await {
+        Some(result) => result,
+        None => Err(CaError::Timeout(channel.connection_state())),
+    }
+}
+
+pub async fn cainfo(pv: &str) -> CaInfo
+{
+    CaInfo::caget(pv).await
+}
+
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use futures::executor::block_on;
+
+    use super::{cainfo, try_cainfo, try_cainfo_timeout};
+    use crate::caunion::{BasicDbrType, CaUnionCtrl};
+    use crate::channel::ConnectionState;
+    use crate::error::CaError;
+    use crate::native::{ClientConfig, Context};
+    use crate::server::{Server, ServerConfig};
+    use crate::test_ioc::TestIoc;
+
+    const DB: &str = r#"
+        record(ai, "TEST:AI") {
+            field(VAL, "1.5")
+            field(EGU, "mA")
+            field(PREC, "3")
+        }
+    "#;
+
+    #[test]
+    fn record_info()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        let info = block_on(cainfo("TEST:AI"));
+        assert_eq!(info.native_type, BasicDbrType::DbrDouble);
+        assert_eq!(info.element_count, 1);
+        assert!(info.read_access && info.write_access);
+        assert_eq!(info.state, ConnectionState::Connected);
+        assert_eq!(info.record_type.as_deref(), Some("ai"));
+        match info.ctrl {
+            CaUnionCtrl::CaDouble(value, ctrl) => {
+                assert_eq!(value, 1.5);
+                assert_eq!(ctrl.units, "mA");
+                assert_eq!(ctrl.precision, 3);
+            },
+            ctrl => panic!("Unexpected ctrl {:?}", ctrl),
+        }
+    }
+
+    // A server with no RTYP field is given up on after the grace period
+    #[test]
+    fn no_record_type()
+    {
+        let server = Server::start(ServerConfig {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            beacon_addr_list: Vec::new(),
+        }).unwrap();
+        server.add_pv("TEST:NAME", String::from("value"));
+        let context = Context::new(ClientConfig {
+            addr_list: vec![
+                SocketAddr::from((Ipv4Addr::LOCALHOST, server.port()))],
+            on_error: None,
+        }).unwrap();
+        let _context = context.install();
+        let info = block_on(try_cainfo("TEST:NAME")).unwrap();
+        assert_eq!(info.native_type, BasicDbrType::DbrString);
+        assert_eq!(info.record_type, None);
+    }
+
+    #[test]
+    fn never_connected()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        let timeout = Duration::from_millis(100);
+        let result = block_on(try_cainfo_timeout("TEST:MISSING", timeout));
+        assert_eq!(result.unwrap_err(),
+            CaError::Timeout(ConnectionState::NeverConnected));
+        let info = block_on(try_cainfo_timeout("TEST:AI", timeout)).unwrap();
+        assert_eq!(info.state, ConnectionState::Connected);
+    }
+}
@@ -1,6 +1,7 @@
 // Simple async callback helper
 
-use std::{sync, future, pin, task};
+use std::{collections, sync, future, pin, task};
+use libc::c_void;
 
 
 enum WakerState<T> {
@@ -25,11 +26,20 @@ impl<T: Send> AsyncWaker<T> {
         let mut current_state = WakerState::Idle;
         std::mem::swap(&mut *wakeup, &mut current_state);
 
-        if let WakerState::Waiting(waker) = current_state {
-            *wakeup = WakerState::Ready(result);
-            waker.wake();
-        } else {
-            println!("Hmm.  Unexpected wakeup state");
+        match current_state {
+            WakerState::Waiting(waker) => {
+                *wakeup = WakerState::Ready(result);
+                waker.wake();
+            },
+            WakerState::Idle => {
+                // The callback has arrived before we started waiting
+                *wakeup = WakerState::Ready(result);
+            },
+            WakerState::Ready(_) => {
+                // A repeated callback before the first result was collected.
+                // Only the latest result is kept.
+                *wakeup = WakerState::Ready(result);
+            },
         }
     }
 }
@@ -57,7 +67,9 @@ impl<'a, T: Send> future::Future for Waiter<'a, T> {
         let mut current_state = WakerState::Idle;
         std::mem::swap(&mut *wakeup, &mut current_state);
         match current_state {
-            WakerState::Idle => {
+            // We can be polled again before the callback arrives if we share a
+            // task with other futures, in which case just update the waker.
+            WakerState::Idle | WakerState::Waiting(_) => {
                 *wakeup = WakerState::Waiting(context.waker().clone());
                 task::Poll::Pending
             },
@@ -65,9 +77,6 @@ impl<'a, T: Send> future::Future for Waiter<'a, T> {
                 *wakeup = WakerState::Idle;
                 task::Poll::Ready(result)
             },
-            WakerState::Waiting(_) => {
-                panic!("Already waiting!");
-            }
         }
     }
 }
@@ -78,3 +87,192 @@ impl<T: Send> AsyncWaker<T> {
         Waiter::new(self).await
     }
 }
+
+// A waker for a single callback holds a reference for the callback, so that the
+// future waiting for it can be dropped, for instance on a timeout, before the
+// callback arrives.  The callback takes over the reference with from_raw.  The
+// native client completes requests outstanding on a cleared channel, but libca
+// never calls them, so with libca this reference is then leaked.
+impl<T: Send> AsyncWaker<T> {
+    pub fn into_raw(self: &sync::Arc<Self>) -> *const c_void
+    {
+        sync::Arc::into_raw(self.clone()) as *const c_void
+    }
+
+    pub unsafe fn from_raw(usr: *const c_void) -> sync::Arc<Self>
+    {
+        unsafe { sync::Arc::from_raw(usr as *const Self) }
+    }
+}
+
+
+// Stream of values delivered by repeated callbacks.  The overflow policy
+// determines what happens to values which arrive faster than they are taken.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    #[default]
+    KeepLatest,         // Only the most recent undelivered value is kept
+    DropOldest(usize),  // Up to this many values are queued
+    Unbounded,          // Every value is queued
+}
+
+// Each queued value carries the number of values discarded before it.  Markers
+// are queued in the same way but are never discarded.
+struct Entry<T> {
+    value: T,
+    dropped: u64,
+    marker: bool,
+}
+
+// The number of queued values which are not markers is kept as they come and go
+struct StreamState<T> {
+    queue: collections::VecDeque<Entry<T>>,
+    values: usize,
+    dropped: u64,
+    waker: Option<task::Waker>,
+}
+
+pub struct AsyncStream<T: Send> {
+    overflow: Overflow,
+    state: sync::Mutex<StreamState<T>>,
+}
+
+impl<T: Send> AsyncStream<T> {
+    pub fn new() -> AsyncStream<T>
+    {
+        Self::with_overflow(Overflow::KeepLatest)
+    }
+
+    // Callers must reject a DropOldest queue of no values first
+    pub fn with_overflow(overflow: Overflow) -> AsyncStream<T>
+    {
+        assert!(overflow != Overflow::DropOldest(0),
+            "Monitor queue must hold at least one value");
+        AsyncStream {
+            overflow,
+            state: sync::Mutex::new(StreamState {
+                queue: collections::VecDeque::new(),
+                values: 0,
+                dropped: 0,
+                waker: None,
+            }),
+        }
+    }
+
+    pub fn push(&self, value: T)
+    {
+        self.push_entry(value, false);
+    }
+
+    // Pushes a value which the overflow policy will not discard, for instance
+    // to report a change in connection state.
+    pub fn push_marker(&self, value: T)
+    {
+        self.push_entry(value, true);
+    }
+
+    fn push_entry(&self, value: T, marker: bool)
+    {
+        let mut state = self.state.lock().unwrap();
+        if !marker {
+            let limit = match self.overflow {
+                Overflow::KeepLatest => Some(1),
+                Overflow::DropOldest(limit) => Some(limit),
+                Overflow::Unbounded => None,
+            };
+            if let Some(limit) = limit {
+                while state.values >= limit {
+                    state.discard_oldest();
+                }
+            }
+            state.values += 1;
+        }
+        let dropped = std::mem::take(&mut state.dropped);
+        state.queue.push_back(Entry { value, dropped, marker });
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // Returns the next value together with the number of values discarded
+    // since the previous one
+    pub fn poll_next_counted(&self, context: &mut task::Context)
+        -> task::Poll<Option<(T, u64)>>
+    {
+        let mut state = self.state.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(Entry { value, dropped, marker }) => {
+                if !marker {
+                    state.values -= 1;
+                }
+                task::Poll::Ready(Some((value, dropped)))
+            },
+            None => {
+                state.waker = Some(context.waker().clone());
+                task::Poll::Pending
+            },
+        }
+    }
+
+    pub fn poll_next(&self, context: &mut task::Context)
+        -> task::Poll<Option<T>>
+    {
+        self.poll_next_counted(context)
+            .map(|value| value.map(|(value, _dropped)| value))
+    }
+}
+
+impl<T> StreamState<T> {
+    // Discards the oldest value which is not a marker.  The count carried by
+    // the discarded value passes on to its successor.
+    fn discard_oldest(&mut self)
+    {
+        let index = self.queue.iter().position(|entry| !entry.marker).unwrap();
+        let Entry { dropped, .. } = self.queue.remove(index).unwrap();
+        self.values -= 1;
+        match self.queue.get_mut(index) {
+            Some(next) => next.dropped += dropped + 1,
+            None => self.dropped += dropped + 1,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::task;
+    use futures::task::noop_waker_ref;
+
+    use super::{AsyncStream, Overflow};
+
+    fn drain(stream: &AsyncStream<u32>) -> Vec<(u32, u64)>
+    {
+        let mut context = task::Context::from_waker(noop_waker_ref());
+        let mut values = Vec::new();
+        while let task::Poll::Ready(Some(value)) =
+            stream.poll_next_counted(&mut context)
+        {
+            values.push(value);
+        }
+        values
+    }
+
+    #[test]
+    fn markers_not_counted()
+    {
+        let stream = AsyncStream::with_overflow(Overflow::DropOldest(2));
+        stream.push(1);
+        stream.push_marker(10);
+        stream.push(2);
+        stream.push(3);
+        assert_eq!(drain(&stream), [(10, 1), (2, 0), (3, 0)]);
+        stream.push(4);
+        stream.push(5);
+        stream.push(6);
+        assert_eq!(drain(&stream), [(5, 1), (6, 0)]);
+
+        let stream = AsyncStream::with_overflow(Overflow::Unbounded);
+        (0..1000).for_each(|value| stream.push(value));
+        assert_eq!(drain(&stream).len(), 1000);
+    }
+}
@@ -0,0 +1,404 @@
+// Implementation of camonitor functionality
+
+use std::{pin, rc, sync, task};
+use async_trait::async_trait;
+use futures::stream::Stream;
+
+use crate::cadef;
+use crate::dbr::Dbr;
+use crate::callback;
+use crate::channel;
+use crate::caget::{CaResult, GetResult};
+use crate::caunion::BasicDbrType;
+use crate::error::CaError;
+
+
+// Event masks selecting which changes generate updates, as defined in
+// caeventmask.h
+pub const DBE_VALUE: u32 = 1;
+pub const DBE_LOG: u32 = 2;
+pub const DBE_ALARM: u32 = 4;
+pub const DBE_PROPERTY: u32 = 8;
+
+
+// Callback invoked for each update, delivering each value to a stream of items
+// made from T.  Failed updates are simply skipped, as are values which cannot
+// be converted to T.
+extern "C" fn camonitor_callback<T, I>(args: cadef::event_handler_args)
+    where T: CaResult, I: From<T> + Send
+{
+    let stream: &callback::AsyncStream::<I> =
+        unsafe { cadef::voidp_to_ref(args.usr) };
+    if args.status == cadef::ECA_NORMAL {
+        let dbr: &T::Dbr = unsafe { cadef::voidp_to_ref(args.dbr) };
+        let value = T::Value::get_result(dbr, args.count as usize);
+        if let Ok(value) = T::assemble(value, dbr.get_extra()) {
+            stream.push(I::from(value));
+        }
+    }
+}
+
+
+// A channel shared between several subscriptions.  The channel stays boxed as
+// its address was given to the backend when it was created.
+#[allow(clippy::redundant_allocation)]
+pub type SharedChannel = rc::Rc<Box<channel::Channel>>;
+
+// A single subscription on a channel, delivering items of type I made from
+// each update.  The subscription is cleared before the channel and the stream
+// receiving updates are released.
+pub struct Subscription<T: CaResult, I: Send = T> {
+    evid: cadef::EvId,
+    stream: sync::Arc<callback::AsyncStream<I>>,
+    channel: SharedChannel,
+    _result: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<T: CaResult> Subscription<T> {
+    pub fn new(channel: impl Into<SharedChannel>, mask: u32)
+        -> Result<Subscription<T>, CaError>
+    {
+        Self::with_overflow(channel, mask, callback::Overflow::KeepLatest)
+    }
+
+    // A queue which cannot hold a single value is rejected
+    pub fn with_overflow(
+        channel: impl Into<SharedChannel>, mask: u32,
+        overflow: callback::Overflow) -> Result<Subscription<T>, CaError>
+    {
+        if overflow == callback::Overflow::DropOldest(0) {
+            return Err(CaError::OutOfRange(
+                "Monitor queue must hold at least one value".to_owned()));
+        }
+        let stream = callback::AsyncStream::with_overflow(overflow);
+        Self::create(channel.into(), mask, sync::Arc::new(stream))
+    }
+}
+
+impl<T: CaResult + 'static> Subscription<T, MonitorEvent<T>> {
+    // Connection changes are reported in the stream as they happen
+    pub fn with_events(channel: impl Into<SharedChannel>, mask: u32)
+        -> Result<Subscription<T, MonitorEvent<T>>, CaError>
+    {
+        let channel = channel.into();
+        let stream = sync::Arc::new(callback::AsyncStream::new());
+        channel.watch(sync::Arc::downgrade(&stream) as _);
+        Self::create(channel, mask, stream)
+    }
+}
+
+impl<T: CaResult, I: From<T> + Send> Subscription<T, I> {
+    fn create(
+        channel: SharedChannel, mask: u32,
+        stream: sync::Arc<callback::AsyncStream<I>>)
+        -> Result<Subscription<T, I>, CaError>
+    {
+        let mut evid = cadef::EV_ID_VOID;
+        let rc = channel.backend.create_subscription(
+            T::Dbr::DATATYPE as i64, T::Value::COUNT, channel.id,
+            mask as i64, camonitor_callback::<T, I>,
+            cadef::ref_to_voidp(stream.as_ref()), &mut evid);
+        CaError::check(rc)?;
+        channel.backend.flush_io();
+        Ok(Subscription {
+            evid, stream, channel, _result: std::marker::PhantomData })
+    }
+}
+
+// As for the channel, failure to clear the subscription cannot be reported
+impl<T: CaResult, I: Send> Drop for Subscription<T, I> {
+    fn drop(&mut self)
+    {
+        let _ = self.channel.backend.clear_subscription(self.evid);
+    }
+}
+
+impl<T: CaResult, I: Send> Stream for Subscription<T, I> {
+    type Item = I;
+
+    fn poll_next(self: pin::Pin<&mut Self>, context: &mut task::Context)
+        -> task::Poll<Option<I>>
+    {
+        self.stream.poll_next(context)
+    }
+}
+
+
+// Item returned by camonitor_events.  A subscription survives the loss of its
+// connection, for instance when an IOC reboots, and is reinstated with a fresh
+// value when the channel reconnects.  Disconnected is reported when the
+// connection is lost, and Reconnected reports the field type and element count
+// on reconnection, either of which may have changed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MonitorEvent<T> {
+    Update(T),
+    Disconnected,
+    Reconnected(BasicDbrType, usize),
+}
+
+impl<T> From<T> for MonitorEvent<T> {
+    fn from(value: T) -> Self { MonitorEvent::Update(value) }
+}
+
+impl<T> MonitorEvent<T> {
+    // Converts the value of an update, passing connection changes through
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> MonitorEvent<U>
+    {
+        match self {
+            MonitorEvent::Update(value) => MonitorEvent::Update(f(value)),
+            MonitorEvent::Disconnected => MonitorEvent::Disconnected,
+            MonitorEvent::Reconnected(field_type, count) =>
+                MonitorEvent::Reconnected(field_type, count),
+        }
+    }
+}
+
+// Connection changes are never discarded by the stream
+impl<T: Send + 'static> channel::ConnectionWatcher
+    for callback::AsyncStream<MonitorEvent<T>>
+{
+    fn connection_changed(&self, event: channel::ConnectionEvent)
+    {
+        self.push_marker(match event {
+            channel::ConnectionEvent::Disconnected =>
+                MonitorEvent::Disconnected,
+            channel::ConnectionEvent::Connected(field_type, field_count) =>
+                MonitorEvent::Reconnected(field_type, field_count),
+        });
+    }
+}
+
+
+// Update returned by camonitor_queued, counting the updates discarded by the
+// overflow policy since the previous update.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorUpdate<T> {
+    pub value: T,
+    pub dropped: u64,
+}
+
+// The same subscription, delivering each update with its count
+struct CountedSubscription<T: CaResult>(Subscription<T>);
+
+impl<T: CaResult> Stream for CountedSubscription<T> {
+    type Item = MonitorUpdate<T>;
+
+    fn poll_next(self: pin::Pin<&mut Self>, context: &mut task::Context)
+        -> task::Poll<Option<MonitorUpdate<T>>>
+    {
+        self.0.stream.poll_next_counted(context).map(|update|
+            update.map(|(value, dropped)| MonitorUpdate { value, dropped }))
+    }
+}
+
+
+// Stream of updates returned by camonitor
+pub struct Monitor<T>(pin::Pin<Box<dyn Stream<Item = T>>>);
+
+impl<T> Monitor<T> {
+    pub fn new(stream: impl Stream<Item = T> + 'static) -> Monitor<T>
+    {
+        Monitor(Box::pin(stream))
+    }
+}
+
+impl<T> Stream for Monitor<T> {
+    type Item = T;
+
+    fn poll_next(self: pin::Pin<&mut Self>, context: &mut task::Context)
+        -> task::Poll<Option<T>>
+    {
+        self.get_mut().0.as_mut().poll_next(context)
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor
+//
+// As for caget, try_camonitor reports failure to create the subscription while
+// camonitor treats it as fatal.  Once created a monitor survives disconnection.
+// The PV can be named by a string or by a PvName with channel filters.
+
+#[async_trait(?Send)]
+pub trait CaMonitor: Sized {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>;
+
+    async fn try_camonitor(pv: impl AsRef<str>)
+        -> Result<Monitor<Self>, CaError>
+    {
+        Self::try_camonitor_mask(pv, DBE_VALUE | DBE_ALARM).await
+    }
+
+    async fn camonitor_mask(pv: impl AsRef<str>, mask: u32) -> Monitor<Self> {
+        let pv = pv.as_ref();
+        match Self::try_camonitor_mask(pv, mask).await {
+            Ok(monitor) => monitor,
+            Err(error) => panic!("camonitor {} failed: {}", pv, error),
+        }
+    }
+
+    async fn camonitor(pv: impl AsRef<str>) -> Monitor<Self> {
+        Self::camonitor_mask(pv, DBE_VALUE | DBE_ALARM).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaMonitor for T where T: CaResult + 'static {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        Ok(Monitor::new(Subscription::<T>::new(channel, mask)?))
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor_queued
+//
+// Monitors normally keep only the latest update if the consumer falls behind.
+// A queued monitor selects the overflow policy, and reports how many updates
+// were discarded before each one delivered.  A DropOldest queue must hold at
+// least one value:
+//
+//      let mut monitor = f64::camonitor_queued(
+//          pv, DBE_VALUE, Overflow::DropOldest(100)).await;
+//      while let Some(update) = monitor.next().await {
+//          let MonitorUpdate { value, dropped } = update;
+//          ...
+//      }
+
+#[async_trait(?Send)]
+pub trait CaMonitorQueued: Sized {
+    async fn try_camonitor_queued(
+        pv: impl AsRef<str>, mask: u32, overflow: callback::Overflow)
+        -> Result<Monitor<MonitorUpdate<Self>>, CaError>;
+
+    async fn camonitor_queued(
+        pv: impl AsRef<str>, mask: u32, overflow: callback::Overflow)
+        -> Monitor<MonitorUpdate<Self>>
+    {
+        let pv = pv.as_ref();
+        match Self::try_camonitor_queued(pv, mask, overflow).await {
+            Ok(monitor) => monitor,
+            Err(error) => panic!("camonitor {} failed: {}", pv, error),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaMonitorQueued for T where T: CaResult + 'static {
+    async fn try_camonitor_queued(
+        pv: impl AsRef<str>, mask: u32, overflow: callback::Overflow)
+        -> Result<Monitor<MonitorUpdate<Self>>, CaError>
+    {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        Ok(Monitor::new(CountedSubscription(
+            Subscription::<T>::with_overflow(channel, mask, overflow)?)))
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor_events
+//
+// Consumers which need to know about gaps in the updates, for instance to show
+// them on a plot, can monitor connection changes along with the values:
+//
+//      let mut monitor = f64::camonitor_events(pv).await;
+//      while let Some(event) = monitor.next().await {
+//          match event {
+//              MonitorEvent::Update(value) => ...,
+//              MonitorEvent::Disconnected => ...,
+//              MonitorEvent::Reconnected(field_type, count) => ...,
+//          }
+//      }
+
+#[async_trait(?Send)]
+pub trait CaMonitorEvents: Sized {
+    async fn try_camonitor_events_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<MonitorEvent<Self>>, CaError>;
+
+    async fn camonitor_events_mask(pv: impl AsRef<str>, mask: u32)
+        -> Monitor<MonitorEvent<Self>>
+    {
+        let pv = pv.as_ref();
+        match Self::try_camonitor_events_mask(pv, mask).await {
+            Ok(monitor) => monitor,
+            Err(error) => panic!("camonitor {} failed: {}", pv, error),
+        }
+    }
+
+    async fn camonitor_events(pv: impl AsRef<str>)
+        -> Monitor<MonitorEvent<Self>>
+    {
+        Self::camonitor_events_mask(pv, DBE_VALUE | DBE_ALARM).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaMonitorEvents for T where T: CaResult + 'static {
+    async fn try_camonitor_events_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<MonitorEvent<Self>>, CaError>
+    {
+        let (channel, _datatype, _count) = channel::connect(pv).await?;
+        Ok(Monitor::new(Subscription::<T, MonitorEvent<T>>
+            ::with_events(channel, mask)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    use super::{CaMonitorQueued, MonitorUpdate};
+    use crate::callback::Overflow;
+    use crate::error::CaError;
+    use crate::mock::MockBackend;
+
+    fn update(value: f64, dropped: u64) -> Option<MonitorUpdate<f64>>
+    {
+        Some(MonitorUpdate { value, dropped })
+    }
+
+    // Updates arriving before the consumer asks are counted as they are
+    // discarded, starting with the initial value
+    #[test]
+    fn queued_updates_counted()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 0.0);
+        block_on(async {
+            let mut latest = f64::camonitor_queued(
+                "TEST:AI", super::DBE_VALUE, Overflow::KeepLatest).await;
+            let mut oldest = f64::camonitor_queued(
+                "TEST:AI", super::DBE_VALUE, Overflow::DropOldest(2)).await;
+            for value in 1..=3 {
+                mock.set("TEST:AI", value as f64);
+            }
+            assert_eq!(latest.next().await, update(3.0, 3));
+            assert_eq!(oldest.next().await, update(2.0, 2));
+            assert_eq!(oldest.next().await, update(3.0, 0));
+
+            mock.set("TEST:AI", 4.0);
+            assert_eq!(latest.next().await, update(4.0, 0));
+            assert_eq!(oldest.next().await, update(4.0, 0));
+        });
+    }
+
+    #[test]
+    fn empty_queue_rejected()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 0.0);
+        let monitor = block_on(f64::try_camonitor_queued(
+            "TEST:AI", super::DBE_VALUE, Overflow::DropOldest(0)));
+        assert!(matches!(monitor, Err(CaError::OutOfRange(_))));
+    }
+}
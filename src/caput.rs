@@ -0,0 +1,113 @@
+// Implementation of caput functionality
+
+use std::sync::Arc;
+use async_trait::async_trait;
+
+use crate::cadef;
+use crate::dbr;
+use crate::dbr::Dbr;
+use crate::callback;
+use crate::channel;
+use crate::error::CaError;
+
+
+// Callback invoked when the put completes, we just pass the status back.
+extern "C" fn caput_callback(args: cadef::event_handler_args)
+{
+    let waker = unsafe { callback::AsyncWaker::<i32>::from_raw(args.usr) };
+    waker.wake(args.status);
+}
+
+
+pub async fn caput_core<T>(channel: &channel::Channel, values: &[T])
+    -> Result<(), CaError>
+    where T: dbr::DbrMap
+{
+    let buffer = T::put_buffer(values);
+    let waker = Arc::new(callback::AsyncWaker::<i32>::new());
+    let usr = waker.into_raw();
+    let rc = channel.backend.array_put_callback(
+        T::ValueDbr::DATATYPE as i64, values.len() as u64, channel.id,
+        buffer.as_ptr() as *const _, caput_callback, usr);
+    if let Err(error) = CaError::check(rc) {
+        // No callback will come, so release its reference
+        drop(unsafe { callback::AsyncWaker::<i32>::from_raw(usr) });
+        return Err(error);
+    }
+    channel.backend.flush_io();
+    CaError::check(waker.wait_for().await)
+}
+
+// Sends the put without waiting for it to complete
+pub fn caput_nowait_core<T>(channel: &channel::Channel, values: &[T])
+    -> Result<(), CaError>
+    where T: dbr::DbrMap
+{
+    let buffer = T::put_buffer(values);
+    let rc = channel.backend.array_put(
+        T::ValueDbr::DATATYPE as i64, values.len() as u64, channel.id,
+        buffer.as_ptr() as *const _);
+    CaError::check(rc)?;
+    CaError::check(channel.backend.flush_io())
+}
+
+
+// -----------------------------------------------------------------------------
+// caput
+//
+// The value written determines the datatype used for the put, and the put
+// completes when the server reports that processing has completed.  As for
+// caget, try_caput reports failure while caput treats it as fatal.
+//
+// try_caput_nowait completes as soon as the put has been sent, so only reports
+// failure to send it.
+
+#[async_trait(?Send)]
+pub trait CaPut: Sized {
+    async fn try_caput(pv: &str, value: Self) -> Result<(), CaError>;
+    async fn try_caput_nowait(pv: &str, value: Self) -> Result<(), CaError>;
+
+    async fn caput(pv: &str, value: Self) {
+        if let Err(error) = Self::try_caput(pv, value).await {
+            panic!("caput {} failed: {}", pv, error);
+        }
+    }
+}
+
+// Scalars and vectors are implemented type by type, leaving the blanket
+// implementations for ToCa in convert.rs.
+macro_rules! plain_put {
+    { $($type:ty),* } => { $(
+        #[async_trait(?Send)]
+        impl CaPut for $type {
+            async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_core(&channel, &[value]).await
+            }
+
+            async fn try_caput_nowait(pv: &str, value: Self)
+                -> Result<(), CaError>
+            {
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_nowait_core(&channel, &[value])
+            }
+        }
+
+        #[async_trait(?Send)]
+        impl CaPut for Vec<$type> {
+            async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_core(&channel, &value).await
+            }
+
+            async fn try_caput_nowait(pv: &str, value: Self)
+                -> Result<(), CaError>
+            {
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_nowait_core(&channel, &value)
+            }
+        }
+    )* }
+}
+
+plain_put!{String, u8, i16, i32, f32, f64, dbr::CaEnum}
@@ -1,17 +1,25 @@
 // Definitions for union type
 
+use std::convert::TryFrom;
+use std::fmt;
 use libc::c_short;
 use std::time::SystemTime;
 use async_trait::async_trait;
+use futures::stream::StreamExt;
 
 use crate::db_access::dbr_type_code;
 use crate::db_access::StatusSeverity;
-use crate::dbr::{CaEnum, FixedCtrl, FloatCtrl};
+use crate::dbr::{CaEnum, FixedCtrl, FloatCtrl, CtrlFormat, DisplayFormat};
 use crate::channel;
 use crate::caget::{CaGetCore, CaCtrl, CA};
+use crate::caput::CaPut;
+use crate::error::CaError;
+use crate::timestamp::Timestamp;
+use crate::camonitor::{
+    CaMonitor, CaMonitorEvents, Monitor, MonitorEvent, Subscription};
 
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BasicDbrType {
     DbrString,
     DbrEnum,
@@ -23,6 +31,7 @@ pub enum BasicDbrType {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CaUnion {
     CaString(String),
     CaEnum(CaEnum),
@@ -34,6 +43,7 @@ pub enum CaUnion {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CaUnionVec {
     CaString(Vec<String>),
     CaEnum(Vec<CaEnum>),
@@ -45,8 +55,12 @@ pub enum CaUnionVec {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CaUnionCtrl {
-    CaString(String, SystemTime),
+    CaString(String,
+        #[cfg_attr(feature = "serde",
+            serde(with = "crate::serialize::rfc3339"))]
+        SystemTime),
     CaEnum(CaEnum, Vec<String>),
     CaChar(u8, FixedCtrl<u8>),
     CaShort(i16, FixedCtrl<i16>),
@@ -56,8 +70,12 @@ pub enum CaUnionCtrl {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CaUnionCtrlVec {
-    CaString(Vec<String>, SystemTime),
+    CaString(Vec<String>,
+        #[cfg_attr(feature = "serde",
+            serde(with = "crate::serialize::rfc3339"))]
+        SystemTime),
     CaEnum(Vec<CaEnum>, Vec<String>),
     CaChar(Vec<u8>, FixedCtrl<u8>),
     CaShort(Vec<i16>, FixedCtrl<i16>),
@@ -67,6 +85,123 @@ pub enum CaUnionCtrlVec {
 }
 
 
+// Wrapping of values of each of the basic types
+macro_rules! union_from {
+    { $type:ty, $variant:ident } => {
+        impl From<$type> for CaUnion {
+            fn from(value: $type) -> Self { CaUnion::$variant(value) }
+        }
+
+        impl From<Vec<$type>> for CaUnionVec {
+            fn from(value: Vec<$type>) -> Self { CaUnionVec::$variant(value) }
+        }
+
+        // Any other variant is returned unchanged
+        impl TryFrom<CaUnionVec> for Vec<$type> {
+            type Error = CaUnionVec;
+            fn try_from(value: CaUnionVec) -> Result<Self, CaUnionVec> {
+                match value {
+                    CaUnionVec::$variant(value) => Ok(value),
+                    other => Err(other),
+                }
+            }
+        }
+    }
+}
+
+union_from!{String, CaString}
+union_from!{CaEnum, CaEnum}
+union_from!{u8, CaChar}
+union_from!{i16, CaShort}
+union_from!{i32, CaLong}
+union_from!{f32, CaFloat}
+union_from!{f64, CaDouble}
+
+
+// -----------------------------------------------------------------------------
+// Display
+//
+// Values with control information are shown with their precision, units and
+// enum labels as described by the DisplayFormat.  Arrays are shown as space
+// separated values followed by the units.
+
+impl fmt::Display for CaUnion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            CaUnion::CaString(x) => write!(f, "{}", x),
+            CaUnion::CaEnum(x)   => write!(f, "{}", x),
+            CaUnion::CaChar(x)   => write!(f, "{}", x),
+            CaUnion::CaShort(x)  => write!(f, "{}", x),
+            CaUnion::CaLong(x)   => write!(f, "{}", x),
+            CaUnion::CaFloat(x)  => write!(f, "{}", x),
+            CaUnion::CaDouble(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl CaUnionCtrl {
+    pub fn format(&self, format: &DisplayFormat) -> String
+    {
+        match self {
+            CaUnionCtrl::CaString(x, _) => format.format_string(x),
+            CaUnionCtrl::CaEnum(x, c)   => c.format_value(x, format),
+            CaUnionCtrl::CaChar(x, c)   => c.format_value(x, format),
+            CaUnionCtrl::CaShort(x, c)  => c.format_value(x, format),
+            CaUnionCtrl::CaLong(x, c)   => c.format_value(x, format),
+            CaUnionCtrl::CaFloat(x, c)  => c.format_value(x, format),
+            CaUnionCtrl::CaDouble(x, c) => c.format_value(x, format),
+        }
+    }
+}
+
+impl fmt::Display for CaUnionCtrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.format(&DisplayFormat::default()))
+    }
+}
+
+impl CaUnionCtrlVec {
+    pub fn format(&self, format: &DisplayFormat) -> String
+    {
+        let element = DisplayFormat { units: false, .. *format };
+        macro_rules! join {
+            ( $values:expr, $ctrl:expr ) => {
+                $values.iter()
+                    .map(|x| $ctrl.format_value(x, &element))
+                    .collect::<Vec<_>>().join(" ")
+            }
+        }
+
+        let (values, units) = match self {
+            CaUnionCtrlVec::CaString(x, _) => (
+                x.iter().map(|x| element.format_string(x))
+                    .collect::<Vec<_>>().join(" "),
+                ""),
+            CaUnionCtrlVec::CaEnum(x, c)   => (join!(x, c), ""),
+            CaUnionCtrlVec::CaChar(x, c)   => (join!(x, c), &c.units[..]),
+            CaUnionCtrlVec::CaShort(x, c)  => (join!(x, c), &c.units[..]),
+            CaUnionCtrlVec::CaLong(x, c)   => (join!(x, c), &c.units[..]),
+            CaUnionCtrlVec::CaFloat(x, c)  => (join!(x, c), &c.units[..]),
+            CaUnionCtrlVec::CaDouble(x, c) => (join!(x, c), &c.units[..]),
+        };
+        if format.units && !units.is_empty() {
+            format!("{} {}", values, units)
+        } else {
+            values
+        }
+    }
+}
+
+impl fmt::Display for CaUnionCtrlVec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}", self.format(&DisplayFormat::default()))
+    }
+}
+
+
 pub fn get_field_type(field_type: c_short) -> Option<BasicDbrType>
 {
     match field_type {
@@ -83,10 +218,25 @@ pub fn get_field_type(field_type: c_short) -> Option<BasicDbrType>
     }
 }
 
+impl BasicDbrType {
+    pub fn dbr_type(self) -> c_short
+    {
+        match self {
+            BasicDbrType::DbrString => dbr_type_code::DBR_STRING,
+            BasicDbrType::DbrEnum   => dbr_type_code::DBR_ENUM,
+            BasicDbrType::DbrChar   => dbr_type_code::DBR_CHAR,
+            BasicDbrType::DbrShort  => dbr_type_code::DBR_SHORT,
+            BasicDbrType::DbrLong   => dbr_type_code::DBR_LONG,
+            BasicDbrType::DbrFloat  => dbr_type_code::DBR_FLOAT,
+            BasicDbrType::DbrDouble => dbr_type_code::DBR_DOUBLE,
+        }
+    }
+}
+
 
 macro_rules! map_caget_over_union {
     { $pv:expr, $action:ident } => {
-        let (channel, datatype, _count) = channel::connect($pv).await;
+        let (channel, datatype, _count) = channel::connect($pv).await?;
         match datatype {
             BasicDbrType::DbrString => $action!(channel, CaString),
             BasicDbrType::DbrEnum   => $action!(channel, CaEnum),
@@ -102,10 +252,10 @@ macro_rules! map_caget_over_union {
 
 #[async_trait(?Send)]
 impl CA for CaUnion {
-    async fn caget(pv: &str) -> Self {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
         macro_rules! do_caget {
             ( $channel:expr, $result:ident ) => {
-                CaUnion::$result(CaGetCore::caget_core(&$channel).await)
+                Ok(CaUnion::$result(CaGetCore::caget_core(&$channel).await?))
             }
         }
 
@@ -114,13 +264,13 @@ impl CA for CaUnion {
 }
 
 #[async_trait(?Send)]
-impl CA for (CaUnion, StatusSeverity, SystemTime) {
-    async fn caget(pv: &str) -> Self {
+impl<Time: Timestamp> CA for (CaUnion, StatusSeverity, Time) {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
         macro_rules! do_caget {
             ( $channel:expr, $result:ident ) => {
                 {
-                    let (v, s, t) = CaGetCore::caget_core(&$channel).await;
-                    (CaUnion::$result(v), s, t)
+                    let (v, s, t) = CaGetCore::caget_core(&$channel).await?;
+                    Ok((CaUnion::$result(v), s, t))
                 }
             }
         }
@@ -129,44 +279,108 @@ impl CA for (CaUnion, StatusSeverity, SystemTime) {
     }
 }
 
+// Arrays are read in the native type of the channel they are read from, so
+// these can be read together with the native type through WithNativeType, and
+// can be limited in length.
+macro_rules! map_caget_over_channel {
+    { $channel:expr, $action:ident } => {
+        match $channel.wait_connect().await.0 {
+            BasicDbrType::DbrString => $action!(CaString),
+            BasicDbrType::DbrEnum   => $action!(CaEnum),
+            BasicDbrType::DbrChar   => $action!(CaChar),
+            BasicDbrType::DbrShort  => $action!(CaShort),
+            BasicDbrType::DbrLong   => $action!(CaLong),
+            BasicDbrType::DbrFloat  => $action!(CaFloat),
+            BasicDbrType::DbrDouble => $action!(CaDouble),
+        }
+    }
+}
+
 #[async_trait(?Send)]
-impl CA for CaUnionVec {
-    async fn caget(pv: &str) -> Self {
+impl CaGetCore for CaUnionVec {
+    async fn caget_core(channel: &channel::Channel) -> Result<Self, CaError> {
+        Self::caget_core_count(channel, 0).await
+    }
+
+    async fn caget_core_count(channel: &channel::Channel, count: usize)
+        -> Result<Self, CaError>
+    {
         macro_rules! do_caget {
-            ( $channel:expr, $result:ident ) => {
-                CaUnionVec::$result(CaGetCore::caget_core(&$channel).await)
+            ( $result:ident ) => {
+                Ok(CaUnionVec::$result(
+                    CaGetCore::caget_core_count(channel, count).await?))
             }
         }
 
-        map_caget_over_union!{pv, do_caget}
+        map_caget_over_channel!{channel, do_caget}
     }
 }
 
 #[async_trait(?Send)]
-impl CA for (CaUnionVec, StatusSeverity, SystemTime) {
-    async fn caget(pv: &str) -> Self {
+impl<Time: Timestamp> CaGetCore for (CaUnionVec, StatusSeverity, Time) {
+    async fn caget_core(channel: &channel::Channel) -> Result<Self, CaError> {
+        Self::caget_core_count(channel, 0).await
+    }
+
+    async fn caget_core_count(channel: &channel::Channel, count: usize)
+        -> Result<Self, CaError>
+    {
         macro_rules! do_caget {
-            ( $channel:expr, $result:ident ) => {
+            ( $result:ident ) => {
                 {
-                    let (v, s, t) = CaGetCore::caget_core(&$channel).await;
-                    (CaUnionVec::$result(v), s, t)
+                    let (v, s, t) =
+                        CaGetCore::caget_core_count(channel, count).await?;
+                    Ok((CaUnionVec::$result(v), s, t))
                 }
             }
         }
 
-        map_caget_over_union!{pv, do_caget}
+        map_caget_over_channel!{channel, do_caget}
+    }
+}
+
+// Reads the value with control information from a channel already connected
+// with the given native type.
+pub async fn caget_ctrl(channel: &channel::Channel, datatype: BasicDbrType)
+    -> Result<(CaUnionCtrl, StatusSeverity), CaError>
+{
+    macro_rules! do_caget {
+        ( $result:ident ) => {
+            {
+                let (v, s, CaCtrl(c)) = CaGetCore::caget_core(channel).await?;
+                Ok((CaUnionCtrl::$result(v, c), s))
+            }
+        }
+    }
+
+    match datatype {
+        BasicDbrType::DbrString => do_caget!(CaString),
+        BasicDbrType::DbrEnum   => do_caget!(CaEnum),
+        BasicDbrType::DbrChar   => do_caget!(CaChar),
+        BasicDbrType::DbrShort  => do_caget!(CaShort),
+        BasicDbrType::DbrLong   => do_caget!(CaLong),
+        BasicDbrType::DbrFloat  => do_caget!(CaFloat),
+        BasicDbrType::DbrDouble => do_caget!(CaDouble),
     }
 }
 
 #[async_trait(?Send)]
 impl CA for (CaUnionCtrl, StatusSeverity) {
-    async fn caget(pv: &str) -> Self {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, datatype, _count) = channel::connect(pv).await?;
+        caget_ctrl(&channel, datatype).await
+    }
+}
+
+#[async_trait(?Send)]
+impl CA for (CaUnionCtrlVec, StatusSeverity) {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
         macro_rules! do_caget {
             ( $channel:expr, $result:ident ) => {
                 {
                     let (v, s, CaCtrl(c)) =
-                        CaGetCore::caget_core(&$channel).await;
-                    (CaUnionCtrl::$result(v, c), s)
+                        CaGetCore::caget_core(&$channel).await?;
+                    Ok((CaUnionCtrlVec::$result(v, c), s))
                 }
             }
         }
@@ -175,19 +389,120 @@ impl CA for (CaUnionCtrl, StatusSeverity) {
     }
 }
 
+
 #[async_trait(?Send)]
-impl CA for (CaUnionCtrlVec, StatusSeverity) {
-    async fn caget(pv: &str) -> Self {
-        macro_rules! do_caget {
+impl CaMonitor for CaUnion {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        macro_rules! do_camonitor {
             ( $channel:expr, $result:ident ) => {
-                {
-                    let (v, s, CaCtrl(c)) =
-                        CaGetCore::caget_core(&$channel).await;
-                    (CaUnionCtrlVec::$result(v, c), s)
-                }
+                Ok(Monitor::new(
+                    Subscription::new($channel, mask)?.map(CaUnion::$result)))
             }
         }
 
-        map_caget_over_union!{pv, do_caget}
+        map_caget_over_union!{pv, do_camonitor}
+    }
+}
+
+#[async_trait(?Send)]
+impl<Time: Timestamp> CaMonitor for (CaUnion, StatusSeverity, Time) {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        macro_rules! do_camonitor {
+            ( $channel:expr, $result:ident ) => {
+                Ok(Monitor::new(
+                    Subscription::<(_, StatusSeverity, Time)>
+                        ::new($channel, mask)?
+                        .map(|(v, s, t)| (CaUnion::$result(v), s, t))))
+            }
+        }
+
+        map_caget_over_union!{pv, do_camonitor}
+    }
+}
+
+#[async_trait(?Send)]
+impl CaMonitor for CaUnionVec {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        macro_rules! do_camonitor {
+            ( $channel:expr, $result:ident ) => {
+                Ok(Monitor::new(
+                    Subscription::new($channel, mask)?
+                        .map(CaUnionVec::$result)))
+            }
+        }
+
+        map_caget_over_union!{pv, do_camonitor}
+    }
+}
+
+#[async_trait(?Send)]
+impl<Time: Timestamp> CaMonitor for (CaUnionVec, StatusSeverity, Time) {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        macro_rules! do_camonitor {
+            ( $channel:expr, $result:ident ) => {
+                Ok(Monitor::new(
+                    Subscription::<(_, StatusSeverity, Time)>
+                        ::new($channel, mask)?
+                        .map(|(v, s, t)| (CaUnionVec::$result(v), s, t))))
+            }
+        }
+
+        map_caget_over_union!{pv, do_camonitor}
+    }
+}
+
+#[async_trait(?Send)]
+impl<Time: Timestamp + 'static> CaMonitorEvents
+    for (CaUnionVec, StatusSeverity, Time)
+{
+    async fn try_camonitor_events_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<MonitorEvent<Self>>, CaError>
+    {
+        macro_rules! do_camonitor {
+            ( $channel:expr, $result:ident ) => {
+                Ok(Monitor::new(
+                    Subscription::<(_, StatusSeverity, Time), _>
+                        ::with_events($channel, mask)?
+                        .map(|event| event.map(|(v, s, t)|
+                            (CaUnionVec::$result(v), s, t)))))
+            }
+        }
+
+        map_caget_over_union!{pv, do_camonitor}
+    }
+}
+
+
+// The value is written in its own type, so the server converts it as necessary
+macro_rules! map_caput_over_union {
+    { $pv:expr, $value:expr, $action:ident } => {
+        match $value {
+            CaUnionVec::CaString(value) => CaPut::$action($pv, value).await,
+            CaUnionVec::CaEnum(value)   => CaPut::$action($pv, value).await,
+            CaUnionVec::CaChar(value)   => CaPut::$action($pv, value).await,
+            CaUnionVec::CaShort(value)  => CaPut::$action($pv, value).await,
+            CaUnionVec::CaLong(value)   => CaPut::$action($pv, value).await,
+            CaUnionVec::CaFloat(value)  => CaPut::$action($pv, value).await,
+            CaUnionVec::CaDouble(value) => CaPut::$action($pv, value).await,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CaPut for CaUnionVec {
+    async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+        map_caput_over_union!{pv, value, try_caput}
+    }
+
+    async fn try_caput_nowait(pv: &str, value: Self) -> Result<(), CaError> {
+        map_caput_over_union!{pv, value, try_caput_nowait}
     }
 }
@@ -1,12 +1,15 @@
 // Channel implementation
 
-use std::{ffi, sync, future, pin, task};
+use std::{sync, future, pin, task};
 use static_assertions::*;
 
 use crate::cadef as cadef;
 use crate::cadef::{ChanId, ref_to_voidp, voidp_to_ref};
+use crate::backend;
 use crate::caunion;
 use crate::caunion::BasicDbrType;
+use crate::error::CaError;
+use crate::pvname;
 
 
 // When we have a connected channel we snapshot the underlying data type and
@@ -18,16 +21,39 @@ enum ChannelConnection {
     Connected(BasicDbrType, usize),
 }
 
+// The connection state as reported to users
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    NeverConnected,
+    Disconnected,
+    Connected,
+}
+
+// Connection changes after the channel first connects are reported to any
+// watchers, with the field type and element count on each connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Disconnected,
+    Connected(BasicDbrType, usize),
+}
+
+pub trait ConnectionWatcher: Send + Sync {
+    fn connection_changed(&self, event: ConnectionEvent);
+}
+
 #[derive(Debug)]
 struct ChannelState {
     connection: ChannelConnection,
     wakers: Vec<task::Waker>,
+    watchers: Vec<sync::Weak<dyn ConnectionWatcher>>,
+    labels: Option<sync::Arc<Vec<String>>>,
 }
 
 #[derive(Debug)]
 pub struct Channel {
     pub name: String,
     pub id: ChanId,
+    pub backend: sync::Arc<dyn backend::Backend>,
     state: sync::Mutex<ChannelState>,
 }
 
@@ -36,31 +62,34 @@ pub struct Channel {
 assert_impl_all!(Channel: Send);
 
 
-fn get_field_type(id: ChanId) -> Option<BasicDbrType>
-{
-    caunion::get_field_type(unsafe { cadef::ca_field_type(id) })
-}
+impl Channel {
+    fn get_field_type(&self, id: ChanId) -> Option<BasicDbrType>
+    {
+        caunion::get_field_type(self.backend.field_type(id))
+    }
 
-fn get_element_count(id: ChanId) -> Option<usize>
-{
-    let count = unsafe { cadef::ca_element_count(id) };
-    if count == 0 {
-        // Treat this as disconnected
-        None
-    } else {
-        Some(count as usize)
+    fn get_element_count(&self, id: ChanId) -> Option<usize>
+    {
+        let count = self.backend.element_count(id);
+        if count == 0 {
+            // Treat this as disconnected
+            None
+        } else {
+            Some(count as usize)
+        }
     }
 }
 
 
 // Called whenever the associated channel connection state changes.
-extern fn on_connect(args: cadef::ca_connection_handler_args)
+fn on_connect(args: backend::ConnectArgs)
 {
-    let channel: &Channel = unsafe { voidp_to_ref(cadef::ca_puser(args.chid)) };
+    let channel: &Channel = unsafe { voidp_to_ref(args.usr) };
     let mut connected = false;
     let connection = match args.op {
         cadef::CA_OP_CONN_UP => {
-            match (get_field_type(args.chid), get_element_count(args.chid))
+            match (channel.get_field_type(args.chid),
+                   channel.get_element_count(args.chid))
             {
                 (Some(field_type), Some(field_count)) => {
                     connected = true;
@@ -83,65 +112,114 @@ extern fn on_connect(args: cadef::ca_connection_handler_args)
             ChannelConnection::Disconnected
         },
     };
+    let event = match connection {
+        ChannelConnection::Connected(field_type, field_count) =>
+            ConnectionEvent::Connected(field_type, field_count),
+        _ => ConnectionEvent::Disconnected,
+    };
+
     let mut state = channel.state.lock().unwrap();
+    let was_connected =
+        matches!(state.connection, ChannelConnection::Connected(..));
     state.connection = connection;
     if connected {
         for waker in state.wakers.drain(..) {
             waker.wake();
         }
     }
+
+    // Watchers are notified without holding the channel state
+    if connected || was_connected {
+        state.watchers.retain(|watcher| watcher.strong_count() > 0);
+        let watchers: Vec<_> =
+            state.watchers.iter().filter_map(sync::Weak::upgrade).collect();
+        drop(state);
+        for watcher in watchers {
+            watcher.connection_changed(event);
+        }
+    }
 }
 
 
 impl Channel {
-    pub fn new(pv: &str) -> Box<Channel>
+    pub fn new(pv: impl AsRef<str>) -> Result<Box<Channel>, CaError>
     {
-        context_create();
-
+        let pv = pv.as_ref();
+        pvname::check_channel_name(pv)?;
         let mut channel = Box::new(Channel {
             name: pv.to_owned(),
             id: cadef::CHAN_ID_VOID,
+            backend: backend::current(),
             state: sync::Mutex::new(ChannelState {
                 connection: ChannelConnection::Unconnected,
                 wakers: Vec::new(),
+                watchers: Vec::new(),
+                labels: None,
             }),
         });
 
-        let cpv = ffi::CString::new(pv).unwrap();
         let mut chan_id = cadef::CHAN_ID_VOID;
-        let rc = unsafe {
-            cadef::ca_create_channel(
-                cpv.as_ptr(), on_connect, ref_to_voidp(channel.as_ref()),
-                0, &mut chan_id as *mut ChanId) };
-        assert!(rc == 1);
-
+        let rc = channel.backend.create_channel(
+            pv, on_connect, ref_to_voidp(channel.as_ref()), &mut chan_id);
+        // On failure there is no channel to clear when this is dropped
+        CaError::check(rc)?;
         channel.id = chan_id;
-        channel
+        Ok(channel)
     }
 
     pub async fn wait_connect(&self) -> (BasicDbrType, usize)
     {
         ChannelWait::new(self).await
     }
-}
 
-// Code to ensure that the context is valid
-static CA_CONTEXT_CREATE: sync::Once = sync::Once::new();
-fn context_create()
-{
-    CA_CONTEXT_CREATE.call_once(|| {
-        unsafe { cadef::ca_context_create(
-            cadef::ca_preemptive_callback_select
-                ::ca_enable_preemptive_callback) };
-    });
-}
+    pub fn connection_state(&self) -> ConnectionState
+    {
+        match self.state.lock().unwrap().connection {
+            ChannelConnection::Unconnected => ConnectionState::NeverConnected,
+            ChannelConnection::Disconnected => ConnectionState::Disconnected,
+            ChannelConnection::Connected(..) => ConnectionState::Connected,
+        }
+    }
+
+    // The watcher is dropped from the channel once it has been released
+    pub fn watch(&self, watcher: sync::Weak<dyn ConnectionWatcher>)
+    {
+        self.state.lock().unwrap().watchers.push(watcher);
+    }
+
+    // The enum state strings last read for this channel, see caenum
+    pub(crate) fn enum_labels(&self) -> Option<sync::Arc<Vec<String>>>
+    {
+        self.state.lock().unwrap().labels.clone()
+    }
+
+    pub(crate) fn set_enum_labels(&self, labels: Vec<String>)
+        -> sync::Arc<Vec<String>>
+    {
+        let labels = sync::Arc::new(labels);
+        self.state.lock().unwrap().labels = Some(labels.clone());
+        labels
+    }
 
+    pub fn host_name(&self) -> String
+    {
+        self.backend.host_name(self.id)
+    }
 
+    pub fn access_rights(&self) -> u32
+    {
+        self.backend.access_rights(self.id)
+    }
+}
+
+// Failure to clear the channel cannot be reported from here, and the channel
+// is gone either way.
 impl Drop for Channel {
     fn drop(self: &mut Channel)
     {
-        let rc = unsafe { cadef::ca_clear_channel(self.id) };
-        assert!(rc == 1);
+        if !self.id.0.is_null() {
+            let _ = self.backend.clear_channel(self.id);
+        }
     }
 }
 
@@ -152,7 +230,7 @@ struct ChannelWait<'a> {
 }
 
 impl<'a> ChannelWait<'a> {
-    fn new(channel: &Channel) -> ChannelWait
+    fn new(channel: &Channel) -> ChannelWait<'_>
     {
         ChannelWait { channel }
     }
@@ -177,9 +255,10 @@ impl<'a> future::Future for ChannelWait<'a> {
     }
 }
 
-pub async fn connect(pv: &str) -> (Box<Channel>, BasicDbrType, usize)
+pub async fn connect(pv: impl AsRef<str>)
+    -> Result<(Box<Channel>, BasicDbrType, usize), CaError>
 {
-    let channel = Channel::new(pv);
+    let channel = Channel::new(pv)?;
     let (datatype, count) = channel.wait_connect().await;
-    (channel, datatype, count)
+    Ok((channel, datatype, count))
 }
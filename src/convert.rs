@@ -0,0 +1,630 @@
+// Conversions to types not carried by Channel Access
+//
+// Channel Access only carries strings, enums and five numeric types, so other
+// Rust types are read and written by converting from or to one of these:
+//
+//      u16             via DBR_LONG
+//      i8              via DBR_SHORT
+//      bool            via DBR_ENUM, as state 0 or 1
+//      u32, i64, u64, usize
+//                      via DBR_DOUBLE, which holds integers exactly to 2^53
+//
+// Conversions are checked: a value which cannot be represented in the target
+// type, including a fractional double, is reported as CaError::OutOfRange
+// rather than wrapped or truncated.  Control limits are only metadata, so
+// rather than failing the whole read they are saturated to the target type,
+// with doubles truncated towards zero.
+//
+// Types outside this library are supported in the same way by implementing
+// FromCa and ToCa, naming any type this library can read or write as the base
+// type to convert from or to:
+//
+//      struct Current(f64);
+//
+//      impl FromCa for Current {
+//          type Base = f64;
+//          fn from_ca(value: f64) -> Result<Self, CaError> {
+//              Ok(Current(value))
+//          }
+//      }
+//
+// after which Current::caget(pv) and Current::camonitor(pv) can be used, as can
+// Vec::<Current>::caget(pv) for a waveform, as the base type has a vector form.
+// Several PVs are read at once with Current::caget_all(&pvs).
+
+use std::fmt;
+use std::convert::TryFrom;
+use async_trait::async_trait;
+
+use crate::channel;
+use crate::caget::{CaResult, CaCtrl};
+use crate::caput::{caput_core, caput_nowait_core, CaPut};
+use crate::db_access::{StatusSeverity, CtrlLimits};
+use crate::dbr::{Dbr, DbrMap, CaEnum, FixedCtrl, FloatCtrl};
+use crate::error::CaError;
+use crate::timestamp::{EpicsTime, Timestamp};
+
+
+// Checked conversion, implemented in both directions between each type here
+// and the type carrying it.
+trait ConvertFrom<T>: Sized {
+    fn convert_from(value: T) -> Result<Self, CaError>;
+}
+
+// Saturating conversion for control limits
+trait SaturateFrom<T> {
+    fn saturate_from(value: T) -> Self;
+}
+
+fn out_of_range(value: impl fmt::Display, target: &str) -> CaError
+{
+    CaError::OutOfRange(
+        format!("{} cannot be represented as {}", value, target))
+}
+
+
+macro_rules! convert_integer {
+    { $($from:ty => $to:ty),* } => { $(
+        impl ConvertFrom<$from> for $to {
+            fn convert_from(value: $from) -> Result<Self, CaError>
+            {
+                <$to>::try_from(value)
+                    .map_err(|_| out_of_range(value, stringify!($to)))
+            }
+        }
+    )* }
+}
+
+convert_integer!{i32 => u16, u16 => i32, i16 => i8, i8 => i16}
+
+macro_rules! saturate_integer {
+    { $($from:ty => $to:ty),* } => { $(
+        impl SaturateFrom<$from> for $to {
+            fn saturate_from(value: $from) -> Self
+            {
+                <$to>::try_from(value).unwrap_or(
+                    if value < 0 { <$to>::MIN } else { <$to>::MAX })
+            }
+        }
+    )* }
+}
+
+saturate_integer!{i32 => u16, i16 => i8}
+
+macro_rules! convert_double {
+    { $($type:ty),* } => { $(
+        // Only whole numbers convert, which excludes NaN and infinities
+        impl ConvertFrom<f64> for $type {
+            fn convert_from(value: f64) -> Result<Self, CaError>
+            {
+                if value.fract() == 0.0 {
+                    <$type>::try_from(value as i128).ok()
+                } else {
+                    None
+                }.ok_or_else(|| out_of_range(value, stringify!($type)))
+            }
+        }
+
+        // Large values are rejected if rounded by the conversion
+        impl ConvertFrom<$type> for f64 {
+            fn convert_from(value: $type) -> Result<Self, CaError>
+            {
+                let double = value as f64;
+                if double as i128 == value as i128 {
+                    Ok(double)
+                } else {
+                    Err(out_of_range(value, "f64"))
+                }
+            }
+        }
+
+        // Casting from a double saturates, taking NaN to zero
+        impl SaturateFrom<f64> for $type {
+            fn saturate_from(value: f64) -> Self
+            {
+                value as $type
+            }
+        }
+    )* }
+}
+
+convert_double!{u32, i64, u64, usize}
+
+impl ConvertFrom<CaEnum> for bool {
+    fn convert_from(value: CaEnum) -> Result<Self, CaError>
+    {
+        match value.0 {
+            0 => Ok(false),
+            1 => Ok(true),
+            state => Err(out_of_range(state, "bool")),
+        }
+    }
+}
+
+impl ConvertFrom<bool> for CaEnum {
+    fn convert_from(value: bool) -> Result<Self, CaError>
+    {
+        Ok(CaEnum(value as u16))
+    }
+}
+
+
+// Control information is converted with its limits.  The precision of a double
+// has no meaning for integers and is dropped.
+
+fn convert_limits<T, U>(limits: CtrlLimits<T>) -> CtrlLimits<U>
+    where T: Copy + Send, U: Copy + Send + SaturateFrom<T>
+{
+    CtrlLimits {
+        upper_disp_limit: U::saturate_from(limits.upper_disp_limit),
+        lower_disp_limit: U::saturate_from(limits.lower_disp_limit),
+        upper_alarm_limit: U::saturate_from(limits.upper_alarm_limit),
+        upper_warning_limit: U::saturate_from(limits.upper_warning_limit),
+        lower_warning_limit: U::saturate_from(limits.lower_warning_limit),
+        lower_alarm_limit: U::saturate_from(limits.lower_alarm_limit),
+        upper_ctrl_limit: U::saturate_from(limits.upper_ctrl_limit),
+        lower_ctrl_limit: U::saturate_from(limits.lower_ctrl_limit),
+    }
+}
+
+impl<T, U> ConvertFrom<FixedCtrl<T>> for FixedCtrl<U>
+    where T: Copy + Send, U: Copy + Send + SaturateFrom<T>
+{
+    fn convert_from(ctrl: FixedCtrl<T>) -> Result<Self, CaError>
+    {
+        Ok(FixedCtrl {
+            units: ctrl.units,
+            limits: convert_limits(ctrl.limits),
+        })
+    }
+}
+
+impl<T, U> ConvertFrom<FloatCtrl<T>> for FixedCtrl<U>
+    where T: Copy + Send, U: Copy + Send + SaturateFrom<T>
+{
+    fn convert_from(ctrl: FloatCtrl<T>) -> Result<Self, CaError>
+    {
+        Ok(FixedCtrl {
+            units: ctrl.units,
+            limits: convert_limits(ctrl.limits),
+        })
+    }
+}
+
+// The state strings of an enum are kept as they are
+impl ConvertFrom<Vec<String>> for Vec<String> {
+    fn convert_from(labels: Vec<String>) -> Result<Self, CaError>
+    {
+        Ok(labels)
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// caget, camonitor and caput
+//
+// Each converted type supports the same forms as the types carried directly:
+// scalar and vector, undecorated, with timestamp, and with control information.
+
+macro_rules! converted_type {
+    { $type:ty, $wire:ty, $ctrl:ty } => {
+        impl CaResult for $type {
+            type Dbr = <$wire as DbrMap>::ValueDbr;
+            type Value = $wire;
+            fn assemble(value: $wire, _extra: ()) -> Result<Self, CaError> {
+                <$type>::convert_from(value)
+            }
+        }
+
+        impl CaResult for Vec<$type> {
+            type Dbr = <$wire as DbrMap>::ValueDbr;
+            type Value = Vec<$wire>;
+            fn assemble(value: Vec<$wire>, _extra: ())
+                -> Result<Self, CaError>
+            {
+                value.into_iter().map(<$type>::convert_from).collect()
+            }
+        }
+
+        impl<Time: Timestamp> CaResult for ($type, StatusSeverity, Time) {
+            type Dbr = <$wire as DbrMap>::TimeDbr;
+            type Value = $wire;
+            fn assemble(value: $wire, (s, t): (StatusSeverity, EpicsTime))
+                -> Result<Self, CaError>
+            {
+                let time = Time::from_epics(t, &s);
+                Ok((<$type>::convert_from(value)?, s, time))
+            }
+        }
+
+        impl<Time: Timestamp> CaResult
+            for (Vec<$type>, StatusSeverity, Time)
+        {
+            type Dbr = <$wire as DbrMap>::TimeDbr;
+            type Value = Vec<$wire>;
+            fn assemble(
+                value: Vec<$wire>, (s, t): (StatusSeverity, EpicsTime))
+                -> Result<Self, CaError>
+            {
+                let value = value.into_iter()
+                    .map(<$type>::convert_from).collect::<Result<_, _>>()?;
+                Ok((value, s, Time::from_epics(t, &s)))
+            }
+        }
+
+        impl CaResult for ($type, StatusSeverity, CaCtrl<$ctrl>) {
+            type Dbr = <$wire as DbrMap>::CtrlDbr;
+            type Value = $wire;
+            fn assemble(
+                value: $wire,
+                (s, c): (StatusSeverity, <$wire as DbrMap>::CtrlType))
+                -> Result<Self, CaError>
+            {
+                Ok((<$type>::convert_from(value)?, s,
+                    CaCtrl(<$ctrl>::convert_from(c)?)))
+            }
+        }
+
+        impl CaResult for (Vec<$type>, StatusSeverity, CaCtrl<$ctrl>) {
+            type Dbr = <$wire as DbrMap>::CtrlDbr;
+            type Value = Vec<$wire>;
+            fn assemble(
+                value: Vec<$wire>,
+                (s, c): (StatusSeverity, <$wire as DbrMap>::CtrlType))
+                -> Result<Self, CaError>
+            {
+                let value = value.into_iter()
+                    .map(<$type>::convert_from).collect::<Result<_, _>>()?;
+                Ok((value, s, CaCtrl(<$ctrl>::convert_from(c)?)))
+            }
+        }
+
+        #[async_trait(?Send)]
+        impl CaPut for $type {
+            async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+                let value = <$wire>::convert_from(value)?;
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_core(&channel, &[value]).await
+            }
+
+            async fn try_caput_nowait(pv: &str, value: Self)
+                -> Result<(), CaError>
+            {
+                let value = <$wire>::convert_from(value)?;
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_nowait_core(&channel, &[value])
+            }
+        }
+
+        #[async_trait(?Send)]
+        impl CaPut for Vec<$type> {
+            async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+                let value = value.into_iter()
+                    .map(<$wire>::convert_from).collect::<Result<Vec<_>, _>>()?;
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_core(&channel, &value).await
+            }
+
+            async fn try_caput_nowait(pv: &str, value: Self)
+                -> Result<(), CaError>
+            {
+                let value = value.into_iter()
+                    .map(<$wire>::convert_from).collect::<Result<Vec<_>, _>>()?;
+                let (channel, _datatype, _count) = channel::connect(pv).await?;
+                caput_nowait_core(&channel, &value)
+            }
+        }
+    }
+}
+
+converted_type!{u16, i32, FixedCtrl<u16>}
+converted_type!{i8, i16, FixedCtrl<i8>}
+converted_type!{bool, CaEnum, Vec<String>}
+converted_type!{u32, f64, FixedCtrl<u32>}
+converted_type!{i64, f64, FixedCtrl<i64>}
+converted_type!{u64, f64, FixedCtrl<u64>}
+converted_type!{usize, f64, FixedCtrl<usize>}
+
+
+// -----------------------------------------------------------------------------
+// User defined types
+//
+// The base type determines the request made, so for instance a base type of
+// (f64, StatusSeverity, CaCtrl<FloatCtrl<f64>>) gives access to units and
+// limits, and Vec<f64> to a whole waveform.  An error from from_ca is reported
+// by caget, and the update is skipped by camonitor.
+
+pub trait FromCa: Sized + Send {
+    type Base: CaResult;
+    fn from_ca(base: Self::Base) -> Result<Self, CaError>;
+}
+
+pub trait ToCa: Sized {
+    type Base: CaPut;
+    fn to_ca(self) -> Result<Self::Base, CaError>;
+}
+
+impl<T> CaResult for T where T: FromCa {
+    type Dbr = <T::Base as CaResult>::Dbr;
+    type Value = <T::Base as CaResult>::Value;
+    fn assemble(value: Self::Value, extra: <Self::Dbr as Dbr>::ExtraType)
+        -> Result<Self, CaError>
+    {
+        T::from_ca(T::Base::assemble(value, extra)?)
+    }
+}
+
+// A vector of user defined values is read as a vector of their base type,
+// which must be one with a vector form, and converted element by element.
+// Similarly for writing.
+impl<T> CaResult for Vec<T> where T: FromCa, Vec<T::Base>: CaResult {
+    type Dbr = <Vec<T::Base> as CaResult>::Dbr;
+    type Value = <Vec<T::Base> as CaResult>::Value;
+    fn assemble(value: Self::Value, extra: <Self::Dbr as Dbr>::ExtraType)
+        -> Result<Self, CaError>
+    {
+        Vec::<T::Base>::assemble(value, extra)?
+            .into_iter().map(T::from_ca).collect()
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaPut for T where T: ToCa {
+    async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+        T::Base::try_caput(pv, value.to_ca()?).await
+    }
+
+    async fn try_caput_nowait(pv: &str, value: Self) -> Result<(), CaError> {
+        T::Base::try_caput_nowait(pv, value.to_ca()?).await
+    }
+}
+
+fn to_ca_vec<T: ToCa>(values: Vec<T>) -> Result<Vec<T::Base>, CaError>
+{
+    values.into_iter().map(T::to_ca).collect()
+}
+
+#[async_trait(?Send)]
+impl<T> CaPut for Vec<T> where T: ToCa, Vec<T::Base>: CaPut {
+    async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+        Vec::<T::Base>::try_caput(pv, to_ca_vec(value)?).await
+    }
+
+    async fn try_caput_nowait(pv: &str, value: Self) -> Result<(), CaError> {
+        Vec::<T::Base>::try_caput_nowait(pv, to_ca_vec(value)?).await
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::{FromCa, ToCa};
+    use crate::caget::{CA, CaCtrl};
+    use crate::caput::CaPut;
+    use crate::db_access::{CtrlLimits, StatusSeverity};
+    use crate::dbr::{CaEnum, FixedCtrl, FloatCtrl};
+    use crate::error::CaError;
+    use crate::mock::MockBackend;
+    use crate::timestamp::EpicsTime;
+
+    #[derive(Debug, PartialEq)]
+    struct Current(f64);
+
+    impl FromCa for Current {
+        type Base = f64;
+        fn from_ca(value: f64) -> Result<Self, CaError> {
+            if value >= 0.0 {
+                Ok(Current(value))
+            } else {
+                Err(CaError::OutOfRange(format!("{} is negative", value)))
+            }
+        }
+    }
+
+    impl ToCa for Current {
+        type Base = f64;
+        fn to_ca(self) -> Result<f64, CaError> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn user_type()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:CURRENT", 1.5f64);
+        mock.add_pv("TEST:WAVEFORM", vec![1.0f64, 2.0, 3.0]);
+        block_on(async {
+            assert_eq!(Current::caget("TEST:CURRENT").await, Current(1.5));
+            assert_eq!(Vec::<Current>::caget("TEST:WAVEFORM").await,
+                [Current(1.0), Current(2.0), Current(3.0)]);
+            Vec::<Current>::caput("TEST:WAVEFORM",
+                vec![Current(4.0), Current(-5.0), Current(6.0)]).await;
+            assert!(matches!(Vec::<Current>::try_caget("TEST:WAVEFORM").await,
+                Err(CaError::OutOfRange(_))));
+        });
+        assert_eq!(mock.get::<Vec<f64>>("TEST:WAVEFORM"), [4.0, -5.0, 6.0]);
+    }
+
+    // Results are returned in the order of the PVs, each with its own error
+    #[test]
+    fn caget_all()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:A", 1.0f64);
+        mock.add_pv("TEST:B", -2.0f64);
+        mock.add_pv("TEST:C", 3.0f64);
+        let pvs = ["TEST:C", "TEST:B", "TEST:A"];
+        let results = block_on(Current::try_caget_all(&pvs));
+        assert_eq!(results[0], Ok(Current(3.0)));
+        assert!(matches!(results[1], Err(CaError::OutOfRange(_))));
+        assert_eq!(results[2], Ok(Current(1.0)));
+        assert_eq!(block_on(f64::caget_all(&pvs)), [3.0, -2.0, 1.0]);
+    }
+
+    // Limits out of range of the target type are saturated, while the value
+    // itself must convert exactly
+    #[test]
+    fn ctrl_limits_saturated()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AO", 5.0f64);
+        mock.set_ctrl("TEST:AO", FloatCtrl {
+            units: "counts".to_owned(),
+            precision: 0,
+            limits: CtrlLimits {
+                upper_disp_limit: 1e20, lower_disp_limit: -10.0,
+                upper_alarm_limit: 90.5, upper_warning_limit: 80.0,
+                lower_warning_limit: 20.0, lower_alarm_limit: 10.0,
+                upper_ctrl_limit: 100.0, lower_ctrl_limit: f64::NAN,
+            },
+        });
+        type Ctrl = (u32, StatusSeverity, CaCtrl<FixedCtrl<u32>>);
+        let (value, _, CaCtrl(ctrl)) = block_on(Ctrl::caget("TEST:AO"));
+        assert_eq!(value, 5);
+        assert_eq!(ctrl.units, "counts");
+        assert_eq!(ctrl.limits.upper_disp_limit, u32::MAX);
+        assert_eq!(ctrl.limits.lower_disp_limit, 0);
+        assert_eq!(ctrl.limits.upper_alarm_limit, 90);
+        assert_eq!(ctrl.limits.lower_ctrl_limit, 0);
+
+        mock.set("TEST:AO", -1.0f64);
+        assert!(matches!(block_on(Ctrl::try_caget("TEST:AO")),
+            Err(CaError::OutOfRange(_))));
+    }
+
+    fn out_of_range<T>(result: Result<T, CaError>) -> bool
+    {
+        matches!(result, Err(CaError::OutOfRange(_)))
+    }
+
+    // Each type is read through the DBR type carrying it
+    #[test]
+    fn converted_get()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:LONG", 65535i32);
+        mock.add_pv("TEST:SHORT", -128i16);
+        mock.add_pv("TEST:BI", CaEnum(1));
+        mock.add_pv("TEST:DOUBLE", 4e9f64);
+        block_on(async {
+            assert_eq!(u16::caget("TEST:LONG").await, 65535);
+            assert_eq!(i8::caget("TEST:SHORT").await, -128);
+            assert!(bool::caget("TEST:BI").await);
+            assert_eq!(u32::caget("TEST:DOUBLE").await, 4_000_000_000);
+            assert_eq!(i64::caget("TEST:DOUBLE").await, 4_000_000_000);
+            assert_eq!(u64::caget("TEST:DOUBLE").await, 4_000_000_000);
+            assert_eq!(usize::caget("TEST:DOUBLE").await, 4_000_000_000);
+
+            let (value, _, time) =
+                <(u16, StatusSeverity, EpicsTime)>::caget("TEST:LONG").await;
+            assert_eq!(value, 65535);
+            assert!(time.secs > 0);
+        });
+    }
+
+    // Values which cannot be represented are rejected, never wrapped
+    #[test]
+    fn get_out_of_range()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:LONG", -1i32);
+        mock.add_pv("TEST:SHORT", 200i16);
+        mock.add_pv("TEST:BI", CaEnum(2));
+        mock.add_pv("TEST:DOUBLE", 1.5f64);
+        block_on(async {
+            assert!(out_of_range(u16::try_caget("TEST:LONG").await));
+            mock.set("TEST:LONG", 65536i32);
+            assert!(out_of_range(u16::try_caget("TEST:LONG").await));
+            assert!(out_of_range(i8::try_caget("TEST:SHORT").await));
+            assert!(out_of_range(bool::try_caget("TEST:BI").await));
+
+            assert!(out_of_range(u32::try_caget("TEST:DOUBLE").await));
+            assert!(out_of_range(i64::try_caget("TEST:DOUBLE").await));
+            mock.set("TEST:DOUBLE", -1.0f64);
+            assert!(out_of_range(u64::try_caget("TEST:DOUBLE").await));
+            assert!(out_of_range(usize::try_caget("TEST:DOUBLE").await));
+            assert_eq!(i64::caget("TEST:DOUBLE").await, -1);
+            mock.set("TEST:DOUBLE", 5e9f64);
+            assert!(out_of_range(u32::try_caget("TEST:DOUBLE").await));
+            mock.set("TEST:DOUBLE", 1e20f64);
+            assert!(out_of_range(i64::try_caget("TEST:DOUBLE").await));
+            mock.set("TEST:DOUBLE", f64::NAN);
+            assert!(out_of_range(u64::try_caget("TEST:DOUBLE").await));
+        });
+    }
+
+    // Values are converted before writing, so one which cannot be carried
+    // exactly is rejected and the PV left unchanged
+    #[test]
+    fn put_out_of_range()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:LONG", 0i32);
+        mock.add_pv("TEST:SHORT", 0i16);
+        mock.add_pv("TEST:BI", CaEnum(0));
+        mock.add_pv("TEST:DOUBLE", 0.0f64);
+        block_on(async {
+            u16::caput("TEST:LONG", 65535).await;
+            i8::caput("TEST:SHORT", -128).await;
+            bool::caput("TEST:BI", true).await;
+            u64::caput("TEST:DOUBLE", 1 << 53).await;
+
+            assert!(out_of_range(
+                u64::try_caput("TEST:DOUBLE", u64::MAX).await));
+            assert!(out_of_range(
+                i64::try_caput("TEST:DOUBLE", (1 << 53) + 1).await));
+            assert!(out_of_range(
+                i64::try_caput("TEST:DOUBLE", i64::MIN + 1).await));
+        });
+        assert_eq!(mock.get::<i32>("TEST:LONG"), 65535);
+        assert_eq!(mock.get::<i16>("TEST:SHORT"), -128);
+        assert_eq!(mock.get::<CaEnum>("TEST:BI").0, 1);
+        assert_eq!(mock.get::<f64>("TEST:DOUBLE"), 9007199254740992.0);
+    }
+
+    // Arrays are converted element by element, failing as a whole if any
+    // element is out of range
+    #[test]
+    fn converted_arrays()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:LONGS", vec![1i32, 2, 65535]);
+        mock.add_pv("TEST:SHORTS", vec![-1i16, 0, 1]);
+        mock.add_pv("TEST:ENUMS", vec![CaEnum(0), CaEnum(1)]);
+        mock.add_pv("TEST:DOUBLES", vec![0.0f64, 1.0, 2.0]);
+        block_on(async {
+            assert_eq!(Vec::<u16>::caget("TEST:LONGS").await, [1, 2, 65535]);
+            assert_eq!(Vec::<i8>::caget("TEST:SHORTS").await, [-1, 0, 1]);
+            assert_eq!(Vec::<bool>::caget("TEST:ENUMS").await, [false, true]);
+            assert_eq!(Vec::<u64>::caget("TEST:DOUBLES").await, [0, 1, 2]);
+            let (values, _, CaCtrl(_)) =
+                <(Vec<usize>, StatusSeverity, CaCtrl<FixedCtrl<usize>>)>
+                    ::caget("TEST:DOUBLES").await;
+            assert_eq!(values, [0, 1, 2]);
+
+            Vec::<u16>::caput("TEST:LONGS", vec![4, 5, 6]).await;
+            Vec::<bool>::caput("TEST:ENUMS", vec![true, false]).await;
+            assert!(out_of_range(Vec::<u64>::try_caput(
+                "TEST:DOUBLES", vec![3, u64::MAX, 5]).await));
+
+            mock.set("TEST:SHORTS", vec![1i16, 300, 1]);
+            assert!(out_of_range(Vec::<i8>::try_caget("TEST:SHORTS").await));
+            mock.set("TEST:DOUBLES", vec![1.0f64, -2.0]);
+            assert!(out_of_range(Vec::<u32>::try_caget("TEST:DOUBLES").await));
+        });
+        assert_eq!(mock.get::<Vec<i32>>("TEST:LONGS"), [4, 5, 6]);
+        let enums = mock.get::<Vec<CaEnum>>("TEST:ENUMS");
+        assert_eq!(enums.iter().map(|value| value.0).collect::<Vec<_>>(),
+            [1, 0]);
+    }
+}
@@ -2,10 +2,10 @@
 //
 // These are all as defined in db_access.h in EPICS base
 
-const MAX_STRING_SIZE: usize = 40;
-const MAX_UNITS_SIZE: usize = 8;
-const MAX_ENUM_STRING_SIZE: usize = 26;
-const MAX_ENUM_STATES: usize = 16;
+pub const MAX_STRING_SIZE: usize = 40;
+pub const MAX_UNITS_SIZE: usize = 8;
+pub const MAX_ENUM_STRING_SIZE: usize = 26;
+pub const MAX_ENUM_STATES: usize = 16;
 
 
 #[repr(C, packed)]
@@ -21,8 +21,29 @@ pub struct StatusSeverity {
     pub severity: i16,
 }
 
+// Alarm status of a record whose value has never been set, from alarm.h
+pub const UDF_ALARM: i16 = 17;
+
+// The names of the alarm states and severities, indexed by their values, from
+// menuAlarmStat.dbd and menuAlarmSevr.dbd
+pub const ALARM_STATUS_NAMES: [&str; 22] = [
+    "NO_ALARM", "READ", "WRITE", "HIHI", "HIGH", "LOLO", "LOW", "STATE",
+    "COS", "COMM", "TIMEOUT", "HWLIMIT", "CALC", "SCAN", "LINK", "SOFT",
+    "BAD_SUB", "UDF", "DISABLE", "SIMM", "READ_ACCESS", "WRITE_ACCESS"];
+pub const ALARM_SEVERITY_NAMES: [&str; 4] =
+    ["NO_ALARM", "MINOR", "MAJOR", "INVALID"];
+
+impl StatusSeverity {
+    pub fn is_undefined(&self) -> bool
+    {
+        let status = self.status;
+        status == UDF_ALARM
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CtrlLimits<T: Copy + Send> {
     pub upper_disp_limit:     T,
     pub lower_disp_limit:     T,
@@ -5,15 +5,16 @@
 use std::time::*;
 
 use crate::db_access;
+use crate::timestamp::EpicsTime;
 use db_access::*;
 use db_access::dbr_type_code::*;
 
 
-fn from_epics_string(string: &[u8]) -> String
+pub fn from_epics_string(string: &[u8]) -> String
 {
     // Extract either a null terminated string or the entire string if not
     // null terminated.
-    let string = string.split(|x| *x == 0).next().unwrap_or(&string);
+    let string = string.split(|x| *x == 0).next().unwrap_or(string);
     // Convert into internal UTF8 string, with replacement characters where
     // required.
     String::from_utf8_lossy(string).into_owned()
@@ -23,25 +24,35 @@ fn from_epics_string(string: &[u8]) -> String
     //  String::from_utf8_lossy(&dbr.value[..length]).into_owned()
 }
 
+// Converts string to EPICS format, truncating if necessary to leave room for
+// the null terminator.
+fn to_epics_string(string: &str) -> [u8; MAX_STRING_SIZE]
+{
+    let mut result = [0; MAX_STRING_SIZE];
+    let bytes = string.as_bytes();
+    let length = bytes.len().min(MAX_STRING_SIZE - 1);
+    result[..length].copy_from_slice(&bytes[..length]);
+    result
+}
+
 #[allow(unused_unsafe)]
-unsafe fn c_array_to_vector<T: Copy>(array: &T, count: usize) -> Vec<T>
+unsafe fn c_array_to_vector<T: Copy>(array: *const T, count: usize) -> Vec<T>
 {
-    let ptr = unsafe { array as *const T };
-    let slice = unsafe { std::slice::from_raw_parts(ptr, count) };
-    slice.into()
+    // The DBR structures are packed, so the array may not be aligned
+    (0..count).map(|i| unsafe { array.add(i).read_unaligned() }).collect()
 }
 
 
-// The EPICS Epoch is based on 1st January 1990, and we want to convert all our
-// timestamps to the UNIX_EPOCH of 1st January 1970 to create SystemTime
-// instances.
-const EPICS_EPOCH_SECS: u64 = 631152000;    // Seconds from 1970 to 1990
+// Conversion between raw timestamps and SystemTime, see timestamp.rs
+pub fn from_raw_stamp(epics_time: &EpicsTimeStamp) -> SystemTime
+{
+    EpicsTime::from(epics_time).into()
+}
 
-fn from_raw_stamp(epics_time: &EpicsTimeStamp) -> SystemTime
+// Times before the EPICS epoch are clipped to the epoch
+pub fn to_raw_stamp(time: SystemTime) -> EpicsTimeStamp
 {
-    let duration = Duration::new(epics_time.secs as u64, epics_time.nsec);
-    let epics_epoch = Duration::new(EPICS_EPOCH_SECS, 0);
-    UNIX_EPOCH.checked_add(epics_epoch).unwrap().checked_add(duration).unwrap()
+    EpicsTime::from(time).into()
 }
 
 
@@ -70,10 +81,13 @@ pub trait Dbr {
 
 pub trait DbrMap: Sized + Send {
     type ValueDbr: Dbr<ResultType=Self, ExtraType=()>;
-    type TimeDbr: Dbr<ResultType=Self, ExtraType=(StatusSeverity, SystemTime)>;
+    type TimeDbr: Dbr<ResultType=Self, ExtraType=(StatusSeverity, EpicsTime)>;
     type CtrlType: Send;
     type CtrlDbr: Dbr<
         ResultType=Self, ExtraType=(StatusSeverity, Self::CtrlType)>;
+
+    // Converts an array of values into the raw ValueDbr format for caput
+    fn put_buffer(values: &[Self]) -> Vec<u8>;
 }
 
 
@@ -102,18 +116,40 @@ impl Dbr for dbr_string {
 
     string_get_values!{}
 
-    fn get_extra(&self) -> Self::ExtraType { () }
+    fn get_extra(&self) -> Self::ExtraType { }
 }
 
 impl Dbr for dbr_time_string {
     const DATATYPE: i16 = dbr_type_code::DBR_TIME_STRING;
     type ResultType = String;
-    type ExtraType = (StatusSeverity, SystemTime);
+    type ExtraType = (StatusSeverity, EpicsTime);
 
     string_get_values!{}
 
     fn get_extra(&self) -> Self::ExtraType {
-        (self.status_severity, from_raw_stamp(&self.raw_time))
+        (self.status_severity, EpicsTime::from(&self.raw_time))
+    }
+}
+
+// Strings have no control information, so a control request reads the
+// timestamp instead.  This is reported as SystemTime, EpicsTime is only
+// available through the timestamped request.
+#[repr(transparent)]
+pub struct StringCtrlDbr(dbr_time_string);
+
+impl Dbr for StringCtrlDbr {
+    const DATATYPE: i16 = dbr_type_code::DBR_TIME_STRING;
+    type ResultType = String;
+    type ExtraType = (StatusSeverity, SystemTime);
+
+    fn get_value(&self) -> Self::ResultType { self.0.get_value() }
+    fn get_value_vec(&self, count: usize) -> Vec<Self::ResultType>
+    {
+        self.0.get_value_vec(count)
+    }
+
+    fn get_extra(&self) -> Self::ExtraType {
+        (self.0.status_severity, from_raw_stamp(&self.0.raw_time))
     }
 }
 
@@ -122,7 +158,11 @@ impl DbrMap for String {
     type ValueDbr = dbr_string;
     type TimeDbr = dbr_time_string;
     type CtrlType = SystemTime;
-    type CtrlDbr = dbr_time_string;
+    type CtrlDbr = StringCtrlDbr;
+
+    fn put_buffer(values: &[Self]) -> Vec<u8> {
+        values.iter().flat_map(|s| to_epics_string(s).to_vec()).collect()
+    }
 }
 
 
@@ -131,14 +171,36 @@ impl DbrMap for String {
 
 #[repr(transparent)]
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct CaEnum(pub u16);
 
+// Shown in place of the label of an enum value with no corresponding state
+pub const ILLEGAL_VALUE: &str = "Illegal Value";
+
+impl CaEnum {
+    // Looks up the label for this value from the state strings of the PV
+    pub fn label<'a>(&self, strings: &'a [String]) -> Option<&'a str>
+    {
+        strings.get(self.0 as usize).map(String::as_str)
+    }
+}
+
+// Without the labels all we can show is the index
+impl std::fmt::Display for CaEnum {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        write!(f, "{}", self.0)
+    }
+}
+
 macro_rules! enum_get_values {
     {} => {
         fn get_value(&self) -> Self::ResultType { CaEnum(self.value) }
         fn get_value_vec(&self, count: usize) -> Vec<Self::ResultType>
         {
-            let values = unsafe { c_array_to_vector(&self.value, count) };
+            let value = std::ptr::addr_of!(self.value);
+            let values = unsafe { c_array_to_vector(value, count) };
             values.iter().map(|&x| CaEnum(x)).collect()
         }
     }
@@ -151,18 +213,18 @@ impl Dbr for dbr_enum {
 
     enum_get_values!{}
 
-    fn get_extra(&self) -> Self::ExtraType { () }
+    fn get_extra(&self) -> Self::ExtraType { }
 }
 
 impl Dbr for dbr_time_enum {
     const DATATYPE: i16 = dbr_type_code::DBR_TIME_ENUM;
     type ResultType = CaEnum;
-    type ExtraType = (StatusSeverity, SystemTime);
+    type ExtraType = (StatusSeverity, EpicsTime);
 
     enum_get_values!{}
 
     fn get_extra(&self) -> Self::ExtraType {
-        (self.status_severity, from_raw_stamp(&self.raw_time))
+        (self.status_severity, EpicsTime::from(&self.raw_time))
     }
 }
 
@@ -181,24 +243,46 @@ impl Dbr for dbr_ctrl_enum {
     }
 }
 
+// Rust enums mapped onto the states of an enum PV, normally implemented by
+// #[derive(CaEnumType)].  Variants are identified by their position in the
+// enum, and matched to states by label or by index.
+#[derive(Clone, Copy, Debug)]
+pub enum EnumMapping {
+    Labels(&'static [&'static str]),
+    Indices(&'static [u16]),
+}
+
+pub trait CaEnumType: Sized {
+    const NAME: &'static str;
+    const MAPPING: EnumMapping;
+    fn from_variant(variant: usize) -> Self;
+    fn variant(&self) -> usize;
+}
+
 impl DbrMap for CaEnum {
     type ValueDbr = dbr_enum;
     type TimeDbr = dbr_time_enum;
     type CtrlType = Vec<String>;
     type CtrlDbr = dbr_ctrl_enum;
+
+    fn put_buffer(values: &[Self]) -> Vec<u8> {
+        values.iter().flat_map(|e| e.0.to_ne_bytes().to_vec()).collect()
+    }
 }
 
 
 // -----------------------------------------------------------------------------
 // Scalar types
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FixedCtrl<T: Copy + Send> {
     pub units: String,
     pub limits: CtrlLimits<T>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FloatCtrl<T: Copy + Send> {
     pub units: String,
     pub precision: i16,
@@ -206,12 +290,238 @@ pub struct FloatCtrl<T: Copy + Send> {
 }
 
 
+// -----------------------------------------------------------------------------
+// Formatting values for display
+//
+// Values are formatted as operators expect from their control information:
+// floating point values to the precision (PREC) of the PV followed by the
+// units (EGU), and enums by their label.  So a PV with PREC=2 and EGU=mA is
+// shown as "123.45 mA".
+//
+// Floating point values are rounded to an integer for hex, octal and binary.
+// As with the C tools, negative values in these notations are shown as the
+// two's complement of the type of the value, so -1 is 0xFFFF for a DBF_SHORT
+// and 0xFFFFFFFF for a DBF_LONG.  Floating point values are taken as 32 bit
+// integers if they fit and as 64 bit integers otherwise, and values with no
+// integer equivalent are shown in fixed point.  Non finite values are "nan",
+// "inf" and "-inf" in every notation, as printed by the C tools.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Notation {
+    #[default]
+    Fixed,              // Fixed point, integers as they are
+    Exponential,        // As printf %e, integers as they are
+    General,            // As printf %g, integers as they are
+    Engineering,        // Mantissa with an exponent which is a multiple of 3
+    Hex,                // Rounded to an integer and shown in hexadecimal
+    Octal,              // Rounded to an integer and shown in octal
+    Binary,             // Rounded to an integer and shown in binary
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DisplayFormat {
+    pub notation: Notation,
+    pub precision: Option<usize>,   // Overrides the precision of the PV
+    pub width: Option<usize>,       // Number right aligned in this width
+    pub units: bool,                // Whether to show the units
+}
+
+impl Default for DisplayFormat {
+    fn default() -> Self
+    {
+        DisplayFormat {
+            notation: Notation::Fixed,
+            precision: None,
+            width: None,
+            units: true,
+        }
+    }
+}
+
+// Formats x with an exponent which is a multiple of 3 and the given number of
+// digits after the decimal point, for instance "12.35e-3".
+fn engineering(x: f64, precision: usize) -> String
+{
+    if x == 0.0 {
+        return format!("{:.*}", precision, x);
+    }
+    let mut exponent = (x.abs().log10() / 3.0).floor() as i32 * 3;
+    let mut mantissa = x / 10f64.powi(exponent);
+    // Rounding can carry the mantissa up to 1000
+    if format!("{:.*}", precision, mantissa.abs()).starts_with("1000") {
+        exponent += 3;
+        mantissa /= 1000.0;
+    }
+    format!("{:.*}e{:+}", precision, mantissa, exponent)
+}
+
+// Formats with printf %e conventions: the exponent has a sign and at least two
+// digits.
+fn exponential(x: f64, precision: usize) -> String
+{
+    let formatted = format!("{:.*e}", precision, x);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    format!("{}e{}{:02}", mantissa,
+        if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+// Formats with printf %g conventions: precision is the number of significant
+// digits, exponential format is used for very large or small numbers, and
+// trailing zeros are removed.
+fn general(x: f64, precision: usize) -> String
+{
+    let precision = precision.max(1);
+    if x == 0.0 {
+        return if x.is_sign_negative() { "-0" } else { "0" }.to_owned();
+    }
+
+    // The exponent is that of the value after rounding to the precision
+    let rounded = format!("{:.*e}", precision - 1, x);
+    let exponent: i32 = rounded.split_once('e').unwrap().1.parse().unwrap();
+    if exponent < -4 || exponent >= precision as i32 {
+        let formatted = exponential(x, precision - 1);
+        let (mantissa, exponent) = formatted.split_once('e').unwrap();
+        format!("{}e{}", trim_zeros(mantissa), exponent)
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        trim_zeros(&format!("{:.*}", decimals, x)).to_owned()
+    }
+}
+
+fn trim_zeros(number: &str) -> &str
+{
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+fn non_finite(x: f64) -> String
+{
+    if x.is_nan() {
+        "nan".to_owned()
+    } else if x > 0.0 {
+        "inf".to_owned()
+    } else {
+        "-inf".to_owned()
+    }
+}
+
+impl DisplayFormat {
+    fn finish(&self, number: String, units: &str) -> String
+    {
+        let number = match self.width {
+            Some(width) => format!("{:>1$}", number, width),
+            None => number,
+        };
+        if self.units && !units.is_empty() {
+            format!("{} {}", number, units)
+        } else {
+            number
+        }
+    }
+
+    fn is_integer(&self) -> bool
+    {
+        matches!(self.notation,
+            Notation::Hex | Notation::Octal | Notation::Binary)
+    }
+
+    // Formats an integer which is held in the given number of bits
+    fn integer(&self, x: i64, bits: u32) -> String
+    {
+        let unsigned = x as u64 & (u64::MAX >> (64 - bits));
+        match self.notation {
+            Notation::Hex => format!("0x{:X}", unsigned),
+            Notation::Octal => format!("0o{:o}", unsigned),
+            Notation::Binary => format!("0b{:b}", unsigned),
+            Notation::Engineering =>
+                engineering(x as f64, self.precision.unwrap_or(0)),
+            _ => x.to_string(),
+        }
+    }
+
+    // Formats a floating point value with the given display precision
+    pub fn format_float(&self, x: f64, precision: i16, units: &str) -> String
+    {
+        let precision = self.precision.unwrap_or(precision.max(0) as usize);
+        let number = match self.notation {
+            _ if !x.is_finite() => non_finite(x),
+            Notation::Exponential => exponential(x, precision),
+            Notation::General => general(x, precision),
+            Notation::Engineering => engineering(x, precision),
+            _ if self.is_integer() && x.abs() < 2f64.powi(63) => {
+                let x = x.round() as i64;
+                let bits = if x as i32 as i64 == x { 32 } else { 64 };
+                self.integer(x, bits)
+            },
+            _ => format!("{:.*}", precision, x),
+        };
+        self.finish(number, units)
+    }
+
+    // Integers are only shown with a fractional part in engineering notation
+    pub fn format_integer<T>(&self, x: T, units: &str) -> String
+        where T: Into<i64>
+    {
+        let bits = 8 * std::mem::size_of::<T>() as u32;
+        self.finish(self.integer(x.into(), bits), units)
+    }
+
+    pub fn format_string(&self, x: &str) -> String
+    {
+        self.finish(x.to_owned(), "")
+    }
+
+    // Enums are shown by label, or by index if the PV has no labels or the
+    // index is wanted in hex, octal or binary
+    pub fn format_enum(&self, x: &CaEnum, strings: &[String]) -> String
+    {
+        if self.is_integer() || strings.is_empty() {
+            self.format_integer(x.0, "")
+        } else {
+            self.format_string(x.label(strings).unwrap_or(ILLEGAL_VALUE))
+        }
+    }
+}
+
+// Implemented by the control information for each type of value
+pub trait CtrlFormat<T> {
+    fn format_value(&self, value: &T, format: &DisplayFormat) -> String;
+}
+
+impl<T> CtrlFormat<T> for FixedCtrl<T> where T: Copy + Send + Into<i64> {
+    fn format_value(&self, value: &T, format: &DisplayFormat) -> String
+    {
+        format.format_integer(*value, &self.units)
+    }
+}
+
+impl<T> CtrlFormat<T> for FloatCtrl<T> where T: Copy + Send + Into<f64> {
+    fn format_value(&self, value: &T, format: &DisplayFormat) -> String
+    {
+        format.format_float((*value).into(), self.precision, &self.units)
+    }
+}
+
+// The control information for an enum is its list of labels
+impl CtrlFormat<CaEnum> for Vec<String> {
+    fn format_value(&self, value: &CaEnum, format: &DisplayFormat) -> String
+    {
+        format.format_enum(value, self)
+    }
+}
+
+
 macro_rules! scalar_get_values {
     {} => {
         fn get_value(&self) -> Self::ResultType { self.value }
         fn get_value_vec(&self, count: usize) -> Vec<Self::ResultType>
         {
-            unsafe { c_array_to_vector(&self.value, count) }
+            let value = std::ptr::addr_of!(self.value);
+            unsafe { c_array_to_vector(value, count) }
         }
     }
 }
@@ -248,18 +558,18 @@ macro_rules! scalar_dbr {
 
             scalar_get_values!{}
 
-            fn get_extra(&self) -> Self::ExtraType { () }
+            fn get_extra(&self) -> Self::ExtraType { }
         }
 
         impl Dbr for $time_dbr {
             const DATATYPE: i16 = $time_const;
             type ResultType = $type;
-            type ExtraType = (StatusSeverity, SystemTime);
+            type ExtraType = (StatusSeverity, EpicsTime);
 
             scalar_get_values!{}
 
             fn get_extra(&self) -> Self::ExtraType {
-                (self.status_severity, from_raw_stamp(&self.raw_time))
+                (self.status_severity, EpicsTime::from(&self.raw_time))
             }
         }
 
@@ -280,6 +590,10 @@ macro_rules! scalar_dbr {
             type TimeDbr = $time_dbr;
             type CtrlType = $ctrl_type<$type>;
             type CtrlDbr = $ctrl_dbr;
+
+            fn put_buffer(values: &[Self]) -> Vec<u8> {
+                values.iter().flat_map(|v| v.to_ne_bytes().to_vec()).collect()
+            }
         }
     }
 }
@@ -305,3 +619,106 @@ scalar_dbr!{f64,
     DBR_DOUBLE,         dbr_double,
     DBR_TIME_DOUBLE,    dbr_time_double,
     DBR_CTRL_DOUBLE,    dbr_ctrl_double,    FloatCtrl, float_limits }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notation(notation: Notation) -> DisplayFormat
+    {
+        DisplayFormat { notation, .. DisplayFormat::default() }
+    }
+
+    #[test]
+    fn precision_and_units()
+    {
+        let ctrl = FloatCtrl {
+            units: "mA".to_owned(),
+            precision: 2,
+            limits: CtrlLimits {
+                upper_disp_limit: 0.0, lower_disp_limit: 0.0,
+                upper_alarm_limit: 0.0, upper_warning_limit: 0.0,
+                lower_warning_limit: 0.0, lower_alarm_limit: 0.0,
+                upper_ctrl_limit: 0.0, lower_ctrl_limit: 0.0,
+            },
+        };
+        let format = DisplayFormat::default();
+        assert_eq!(ctrl.format_value(&123.4512, &format), "123.45 mA");
+        let format =
+            DisplayFormat { precision: Some(0), units: false, .. format };
+        assert_eq!(ctrl.format_value(&123.4512, &format), "123");
+    }
+
+    #[test]
+    fn width()
+    {
+        let format =
+            DisplayFormat { width: Some(8), .. DisplayFormat::default() };
+        assert_eq!(format.format_float(1.5, 1, "V"), "     1.5 V");
+        assert_eq!(format.format_integer(-12i16, ""), "     -12");
+        assert_eq!(format.format_string("text"), "    text");
+    }
+
+    #[test]
+    fn notations()
+    {
+        let format = DisplayFormat {
+            precision: Some(2), .. notation(Notation::Engineering) };
+        assert_eq!(format.format_float(0.0123456, 0, ""), "12.35e-3");
+        assert_eq!(format.format_float(-4.5e7, 0, ""), "-45.00e+6");
+        // Rounding carries 999.999 up to the next exponent
+        assert_eq!(format.format_float(999.999, 0, ""), "1.00e+3");
+        assert_eq!(format.format_integer(1500i32, ""), "1.50e+3");
+
+        let format = notation(Notation::Exponential);
+        assert_eq!(format.format_float(1234.5, 2, ""), "1.23e+03");
+        assert_eq!(format.format_integer(1234i32, ""), "1234");
+        let format = notation(Notation::General);
+        assert_eq!(format.format_float(1234567.0, 6, ""), "1.23457e+06");
+        assert_eq!(format.format_float(0.000125, 6, ""), "0.000125");
+        assert_eq!(format.format_float(2.5, 6, ""), "2.5");
+    }
+
+    // Negative values are shown as the two's complement of their type and non
+    // finite values named in every notation
+    #[test]
+    fn signs_and_non_finite()
+    {
+        let hex = notation(Notation::Hex);
+        assert_eq!(hex.format_float(-1.0, 0, ""), "0xFFFFFFFF");
+        assert_eq!(hex.format_float(-1e10, 0, ""), "0xFFFFFFFDABF41C00");
+        assert_eq!(hex.format_integer(-1i16, ""), "0xFFFF");
+        assert_eq!(hex.format_integer(-1i32, ""), "0xFFFFFFFF");
+        assert_eq!(hex.format_integer(i32::MIN, ""), "0x80000000");
+        assert_eq!(hex.format_float(254.6, 0, ""), "0xFF");
+        assert_eq!(hex.format_integer(255u8, ""), "0xFF");
+        assert_eq!(notation(Notation::Octal).format_integer(-8i16, ""),
+            "0o177770");
+        assert_eq!(notation(Notation::Binary).format_float(5.0, 0, ""),
+            "0b101");
+        // Too large for an integer
+        assert_eq!(hex.format_float(1e20, 1, ""), "100000000000000000000.0");
+
+        for format in [
+            DisplayFormat::default(), hex,
+            notation(Notation::Engineering), notation(Notation::General),
+        ] {
+            assert_eq!(format.format_float(f64::NAN, 2, ""), "nan");
+            assert_eq!(format.format_float(f64::INFINITY, 2, ""), "inf");
+            assert_eq!(format.format_float(f64::NEG_INFINITY, 2, ""), "-inf");
+        }
+    }
+
+    #[test]
+    fn enums()
+    {
+        let labels = vec!["Off".to_owned(), "On".to_owned()];
+        let format = DisplayFormat::default();
+        assert_eq!(labels.format_value(&CaEnum(1), &format), "On");
+        assert_eq!(labels.format_value(&CaEnum(2), &format), ILLEGAL_VALUE);
+        assert_eq!(format.format_enum(&CaEnum(2), &[]), "2");
+        assert_eq!(notation(Notation::Hex).format_enum(&CaEnum(1), &labels),
+            "0x1");
+    }
+}
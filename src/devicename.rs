@@ -0,0 +1,303 @@
+// Device naming conventions, enabled by the device-names feature
+//
+// Facilities such as Diamond name PVs after the device they belong to, for
+// instance SR-DI-DCCT-01:SIGNAL names the SIGNAL of DCCT number 01 in the
+// diagnostics (DI) technical area of the storage ring (SR) domain.  Names are
+// parsed against a NamingGrammar, by default the Diamond convention:
+//
+//      let name = NamingGrammar::default().parse("SR-DI-DCCT-01:SIGNAL")?;
+//      assert_eq!(name.device, "DCCT");
+//
+// Lists of names can then be selected and grouped by their parts:
+//
+//      let names = DeviceNames::parse(&grammar, pvs)?;
+//      let dccts = names.select(&DeviceFilter {
+//          area: Some("DI".into()), device: Some("DCCT".into()),
+//          ..Default::default() });
+//      let by_domain = names.group_by(|name| name.domain.as_str());
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use crate::error::CaError;
+use crate::pvname::PvName;
+
+
+// The grammar names are checked against.  Domain, area and device are capital
+// letters and digits starting with a letter, and the instance is digits.
+#[derive(Clone, Debug)]
+pub struct NamingGrammar {
+    // Separates domain, area, device and instance
+    pub separator: char,
+    // Separates the device from the signal
+    pub signal_separator: char,
+    // The permitted domains and technical areas, or any if empty
+    pub domains: Vec<String>,
+    pub areas: Vec<String>,
+    // The number of digits in an instance, or any number if zero
+    pub instance_digits: usize,
+}
+
+// The Diamond convention of DOMAIN-AREA-DEVICE-NN:SIGNAL
+impl Default for NamingGrammar {
+    fn default() -> NamingGrammar
+    {
+        NamingGrammar {
+            separator: '-',
+            signal_separator: ':',
+            domains: Vec::new(),
+            areas: Vec::new(),
+            instance_digits: 2,
+        }
+    }
+}
+
+impl NamingGrammar {
+    // Parses the record part of a PV name, so any field or filters are ignored
+    pub fn parse(&self, name: &str) -> Result<DeviceName, CaError>
+    {
+        let pv = PvName::parse(name)?;
+        let ([domain, area, device, instance], signal) =
+            self.split(pv.record()).map_err(|reason|
+                CaError::InvalidName(format!(
+                    "PV name {:?} does not follow the naming convention: {}",
+                    name, reason)))?;
+        Ok(DeviceName {
+            domain: domain.to_owned(),
+            area: area.to_owned(),
+            device: device.to_owned(),
+            instance: instance.to_owned(),
+            signal: signal.to_owned(),
+            pv,
+        })
+    }
+
+    fn split<'a>(&self, record: &'a str)
+        -> Result<([&'a str; 4], &'a str), String>
+    {
+        let (device_name, signal) = record.split_once(self.signal_separator)
+            .ok_or_else(|| format!(
+                "no {:?} before the signal name", self.signal_separator))?;
+        if signal.is_empty() {
+            return Err("signal name is empty".to_owned());
+        }
+        let parts: Vec<&str> = device_name.split(self.separator).collect();
+        let [domain, area, device, instance] = <[&str; 4]>::try_from(parts)
+            .map_err(|_| format!(
+                "expected domain, area, device and instance separated by {:?}",
+                self.separator))?;
+
+        check_part("domain", domain, &self.domains)?;
+        check_part("area", area, &self.areas)?;
+        check_part("device", device, &[])?;
+        if instance.is_empty() ||
+            !instance.chars().all(|c| c.is_ascii_digit())
+        {
+            Err(format!("instance {:?} is not a number", instance))
+        } else if self.instance_digits > 0 &&
+            instance.len() != self.instance_digits
+        {
+            Err(format!("instance {:?} should have {} digits",
+                instance, self.instance_digits))
+        } else {
+            Ok(([domain, area, device, instance], signal))
+        }
+    }
+}
+
+fn check_part(part: &str, value: &str, permitted: &[String])
+    -> Result<(), String>
+{
+    if !value.starts_with(|c: char| c.is_ascii_uppercase()) ||
+        !value.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+    {
+        Err(format!(
+            "{} {:?} should be capital letters and digits", part, value))
+    } else if !permitted.is_empty() && !permitted.iter().any(|p| p == value) {
+        Err(format!(
+            "{} {:?} is not one of {}", part, value, permitted.join(", ")))
+    } else {
+        Ok(())
+    }
+}
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceName {
+    pub domain: String,
+    pub area: String,
+    pub device: String,
+    pub instance: String,
+    pub signal: String,
+    // The name as parsed, including any field or filters
+    pub pv: PvName,
+}
+
+impl DeviceName {
+    // The name of the device the signal belongs to, for instance SR-DI-DCCT-01
+    pub fn device_name(&self) -> &str
+    {
+        let record = self.pv.record();
+        &record[.. record.len() - self.signal.len() - 1]
+    }
+}
+
+
+// Selects names by any combination of their parts, with None matching anything
+#[derive(Clone, Debug, Default)]
+pub struct DeviceFilter {
+    pub domain: Option<String>,
+    pub area: Option<String>,
+    pub device: Option<String>,
+    pub instance: Option<String>,
+    pub signal: Option<String>,
+}
+
+impl DeviceFilter {
+    pub fn matches(&self, name: &DeviceName) -> bool
+    {
+        let matches = |filter: &Option<String>, value: &str|
+            filter.as_deref().is_none_or(|filter| filter == value);
+        matches(&self.domain, &name.domain)  &&
+        matches(&self.area, &name.area)  &&
+        matches(&self.device, &name.device)  &&
+        matches(&self.instance, &name.instance)  &&
+        matches(&self.signal, &name.signal)
+    }
+}
+
+
+// A list of names following the same grammar
+#[derive(Clone, Debug, Default)]
+pub struct DeviceNames {
+    pub names: Vec<DeviceName>,
+}
+
+impl DeviceNames {
+    // Fails on the first name not following the grammar
+    pub fn parse<I, S>(grammar: &NamingGrammar, names: I)
+        -> Result<DeviceNames, CaError>
+        where I: IntoIterator<Item = S>, S: AsRef<str>
+    {
+        let names = names.into_iter()
+            .map(|name| grammar.parse(name.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(DeviceNames { names })
+    }
+
+    pub fn select<'a>(&'a self, filter: &'a DeviceFilter)
+        -> impl Iterator<Item = &'a DeviceName>
+    {
+        self.names.iter().filter(move |name| filter.matches(name))
+    }
+
+    // Groups the names by the given key, for instance by device class with
+    // |name| name.device.as_str(), or by device with name.device_name()
+    pub fn group_by<'a, K: Ord>(&'a self, key: impl Fn(&'a DeviceName) -> K)
+        -> BTreeMap<K, Vec<&'a DeviceName>>
+    {
+        let mut groups: BTreeMap<K, Vec<&DeviceName>> = BTreeMap::new();
+        for name in &self.names {
+            groups.entry(key(name)).or_default().push(name);
+        }
+        groups
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceFilter, DeviceName, DeviceNames, NamingGrammar};
+    use crate::error::CaError;
+
+    fn records<'a>(names: impl Iterator<Item = &'a DeviceName>) -> Vec<&'a str>
+    {
+        names.map(|name| name.pv.record()).collect()
+    }
+
+    // The reason a name is rejected, or an empty string if it is accepted
+    fn rejection(grammar: &NamingGrammar, name: &str) -> String
+    {
+        match grammar.parse(name) {
+            Ok(_) => String::new(),
+            Err(CaError::InvalidName(reason)) => reason,
+            Err(error) => panic!("Unexpected error {:?}", error),
+        }
+    }
+
+    #[test]
+    fn diamond_name()
+    {
+        let name = NamingGrammar::default()
+            .parse("SR-DI-DCCT-01:SIGNAL.EGU").unwrap();
+        assert_eq!(
+            [&name.domain, &name.area, &name.device, &name.instance,
+                &name.signal],
+            ["SR", "DI", "DCCT", "01", "SIGNAL"]);
+        assert_eq!(name.device_name(), "SR-DI-DCCT-01");
+        assert_eq!(name.pv.record(), "SR-DI-DCCT-01:SIGNAL");
+    }
+
+    #[test]
+    fn names_rejected()
+    {
+        let grammar = NamingGrammar::default();
+        let rejected = |name, reason: &str| {
+            let rejection = rejection(&grammar, name);
+            assert!(rejection.contains(reason),
+                "{:?} rejected with {:?}", name, rejection);
+        };
+        rejected("SR-DI-DCCT-01", "no ':' before the signal name");
+        rejected("SR-DI-DCCT-01:", "signal name is empty");
+        rejected("SR-DI-DCCT:SIGNAL", "expected domain, area, device");
+        rejected("SR-DI-DCCT-01-02:SIGNAL", "expected domain, area, device");
+        rejected("sr-DI-DCCT-01:SIGNAL", "domain \"sr\" should be capital");
+        rejected("SR-Di-DCCT-01:SIGNAL", "area \"Di\" should be capital");
+        rejected("SR-DI-1DCCT-01:SIGNAL", "device \"1DCCT\" should be");
+        rejected("SR-DI-DCCT-A1:SIGNAL", "instance \"A1\" is not a number");
+        rejected("SR-DI-DCCT-:SIGNAL", "instance \"\" is not a number");
+        rejected("SR-DI-DCCT-001:SIGNAL", "should have 2 digits");
+
+        let grammar = NamingGrammar {
+            domains: vec!["SR".to_owned(), "BR".to_owned()],
+            areas: vec!["DI".to_owned()],
+            instance_digits: 0,
+            ..NamingGrammar::default()
+        };
+        assert_eq!(rejection(&grammar, "BR-DI-DCCT-001:SIGNAL"), "");
+        assert!(rejection(&grammar, "LI-DI-DCCT-01:SIGNAL")
+            .contains("domain \"LI\" is not one of SR, BR"));
+        assert!(rejection(&grammar, "SR-VA-GAUGE-01:P")
+            .contains("area \"VA\" is not one of DI"));
+    }
+
+    #[test]
+    fn select_and_group()
+    {
+        let names = DeviceNames::parse(&NamingGrammar::default(), [
+            "SR-DI-DCCT-01:SIGNAL", "SR-DI-DCCT-01:GAIN",
+            "SR-DI-DCCT-02:SIGNAL", "BR-DI-DCCT-01:SIGNAL",
+            "SR-VA-GAUGE-01:P",
+        ]).unwrap();
+        let filter = DeviceFilter {
+            domain: Some("SR".into()), device: Some("DCCT".into()),
+            signal: Some("SIGNAL".into()),
+            ..Default::default()
+        };
+        assert_eq!(records(names.select(&filter)),
+            ["SR-DI-DCCT-01:SIGNAL", "SR-DI-DCCT-02:SIGNAL"]);
+        assert_eq!(names.select(&DeviceFilter::default()).count(), 5);
+
+        let by_device = names.group_by(|name| name.device_name());
+        assert_eq!(by_device.keys().collect::<Vec<_>>(), [
+            &"BR-DI-DCCT-01", &"SR-DI-DCCT-01", &"SR-DI-DCCT-02",
+            &"SR-VA-GAUGE-01"]);
+        assert_eq!(records(by_device["SR-DI-DCCT-01"].iter().copied()),
+            ["SR-DI-DCCT-01:SIGNAL", "SR-DI-DCCT-01:GAIN"]);
+        let by_area = names.group_by(|name| name.area.as_str());
+        assert_eq!(by_area["DI"].len(), 4);
+
+        assert!(DeviceNames::parse(
+            &NamingGrammar::default(), ["SR-DI-DCCT-01:X", "bad"]).is_err());
+    }
+}
@@ -0,0 +1,74 @@
+// Errors reported by Channel Access operations
+
+use std::fmt;
+
+use crate::channel::ConnectionState;
+use crate::protocol::status::*;
+
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaError {
+    // The request failed with the given status code, as defined in caerr.h
+    Status(u32),
+    // The states of an enum PV don't match the Rust enum it is read into
+    EnumMismatch(String),
+    // A value cannot be represented in the type it is converted to
+    OutOfRange(String),
+    // A PV name is not valid, explaining why
+    InvalidName(String),
+    // Communication with a server failed, explaining why
+    Io(String),
+    // An error reported by a server, with its status and explanation
+    Server(u32, String),
+    // No reply in time, with the state of the channel when given up on
+    Timeout(ConnectionState),
+}
+
+impl CaError {
+    // Converts a status code as returned by the CA library, treating anything
+    // other than ECA_NORMAL as an error.
+    pub fn check(status: i32) -> Result<(), CaError>
+    {
+        if status as u32 == ECA_NORMAL {
+            Ok(())
+        } else {
+            Err(CaError::Status(status as u32))
+        }
+    }
+}
+
+impl fmt::Display for CaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            CaError::Status(status) => {
+                let message = match *status {
+                    ECA_BADTYPE => "The data type specified is invalid",
+                    ECA_GETFAIL => "Channel read request failed",
+                    ECA_PUTFAIL => "Channel write request failed",
+                    ECA_BADCOUNT => "Invalid element count requested",
+                    ECA_BADSTR => "Invalid string",
+                    ECA_DISCONN => "Virtual circuit disconnect",
+                    ECA_NORDACCESS => "Read access denied",
+                    ECA_NOWTACCESS => "Write access denied",
+                    _ => "Unexpected CA status",
+                };
+                write!(f, "{} (status {})", message, status)
+            },
+            CaError::EnumMismatch(message) => write!(f, "{}", message),
+            CaError::OutOfRange(message) => write!(f, "{}", message),
+            CaError::InvalidName(message) => write!(f, "{}", message),
+            CaError::Io(message) => write!(f, "{}", message),
+            CaError::Server(status, message) =>
+                write!(f, "{} (status {})", message, status),
+            CaError::Timeout(ConnectionState::NeverConnected) =>
+                write!(f, "Channel never connected"),
+            CaError::Timeout(ConnectionState::Disconnected) =>
+                write!(f, "Channel disconnected"),
+            CaError::Timeout(ConnectionState::Connected) =>
+                write!(f, "No reply from server"),
+        }
+    }
+}
+
+impl std::error::Error for CaError { }
@@ -2,15 +2,90 @@ mod cadef;
 mod db_access;
 mod dbr;
 
+mod error;
+mod backend;
 mod channel;
+mod pvname;
+#[cfg(feature = "device-names")]
+mod devicename;
 mod callback;
+mod timer;
+
+mod protocol;
+#[cfg(feature = "native")]
+mod native;
+#[cfg(feature = "native")]
+mod test_ioc;
 
 mod caunion;
 mod caget;
+mod caput;
+mod camonitor;
+mod sharedmonitor;
+mod cainfo;
+mod recordinfo;
+mod caenum;
+mod longstring;
+mod convert;
+mod timestamp;
+#[cfg(feature = "serde")]
+mod serialize;
+
+mod pv_value;
+mod server;
+mod mock;
+mod replay;
+
+// Lets code generated by #[derive(CaEnumType)] name this crate in its tests
+#[cfg(test)]
+extern crate self as epics_ca;
 
 
 pub use std::time::SystemTime;
-pub use db_access::{StatusSeverity, CtrlLimits};
-pub use dbr::CaEnum;
-pub use caunion::{CaUnion, CaUnionVec, CaUnionCtrl, CaUnionCtrlVec};
-pub use caget::{CA, CaCtrl};
+pub use timestamp::EpicsTime;
+pub use db_access::{StatusSeverity, CtrlLimits, UDF_ALARM};
+pub use db_access::{ALARM_STATUS_NAMES, ALARM_SEVERITY_NAMES};
+pub use dbr::{
+    CaEnum, FixedCtrl, FloatCtrl, CtrlFormat, DisplayFormat, Notation,
+    ILLEGAL_VALUE, CaEnumType, EnumMapping};
+pub use epics_ca_derive::CaEnumType;
+pub use caunion::{
+    BasicDbrType, CaUnion, CaUnionVec, CaUnionCtrl, CaUnionCtrlVec};
+pub use error::CaError;
+pub use protocol::status::{
+    ECA_NORMAL, ECA_BADTYPE, ECA_GETFAIL, ECA_PUTFAIL, ECA_BADCOUNT,
+    ECA_DISCONN, ECA_NORDACCESS, ECA_NOWTACCESS};
+pub use caget::{CA, CaCtrl, CaGetCount, WithNativeType};
+pub use caput::CaPut;
+pub use camonitor::{CaMonitor, Monitor, CaMonitorQueued, MonitorUpdate};
+pub use camonitor::{CaMonitorEvents, MonitorEvent};
+pub use callback::Overflow;
+pub use timer::{sleep, timeout};
+pub use sharedmonitor::CaSharedMonitor;
+pub use camonitor::{DBE_VALUE, DBE_LOG, DBE_ALARM, DBE_PROPERTY};
+pub use cainfo::{CaInfo, cainfo, try_cainfo, try_cainfo_timeout};
+pub use recordinfo::{RecordInfo, AlarmLimit};
+pub use caenum::LabelledEnum;
+pub use longstring::CaLongString;
+pub use convert::{FromCa, ToCa};
+#[cfg(feature = "serde")]
+pub use serialize::rfc3339;
+// Used by code generated by #[derive(CaEnumType)]
+#[doc(hidden)]
+pub use caenum::{caget_enum_type, caput_enum_type};
+#[doc(hidden)]
+pub use async_trait::async_trait;
+pub use channel::ConnectionState;
+pub use pvname::{PvName, SyncMode};
+#[cfg(feature = "device-names")]
+pub use devicename::{
+    NamingGrammar, DeviceName, DeviceFilter, DeviceNames};
+pub use pv_value::{PvCtrl, union_type, union_len, resize};
+pub use server::{Server, ServerConfig, ServerValue, PvHandle};
+pub use backend::BackendGuard;
+pub use mock::MockBackend;
+pub use replay::{Recorder, Replay};
+#[cfg(feature = "native")]
+pub use native::{Context, ClientConfig};
+#[cfg(feature = "native")]
+pub use test_ioc::TestIoc;
@@ -0,0 +1,263 @@
+// Long strings
+//
+// EPICS strings are limited to MAX_STRING_SIZE characters, so longer strings
+// are sent as arrays of DBR_CHAR, terminated by a NUL if shorter than the
+// array.  Channel Access gives this access to string and link fields of a
+// record when the field name is followed by $, as in "TS:ARCHIVE.NAME$".
+// Servers which don't support this never answer the search for the name with
+// $, so then the field is read as a plain string.
+
+use std::fmt;
+use std::time::{Duration, SystemTime};
+use async_trait::async_trait;
+use futures::stream::StreamExt;
+
+use crate::channel;
+use crate::caget::{CaGetCore, CA};
+use crate::caput::{caput_core, caput_nowait_core, CaPut};
+use crate::camonitor::{CaMonitor, Monitor, Subscription};
+use crate::caunion::BasicDbrType;
+use crate::db_access::StatusSeverity;
+use crate::dbr::from_epics_string;
+use crate::error::CaError;
+use crate::protocol::status::ECA_BADTYPE;
+use crate::timer;
+
+
+// How long to wait for the name with $ once the plain name has connected.  Both
+// are searched for together so normally connect at the same time.
+const LONG_STRING_GRACE: Duration = Duration::from_millis(200);
+
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaLongString(pub String);
+
+impl fmt::Display for CaLongString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<String> for CaLongString {
+    fn from(string: String) -> Self { CaLongString(string) }
+}
+
+impl From<&str> for CaLongString {
+    fn from(string: &str) -> Self { CaLongString(string.to_owned()) }
+}
+
+
+// The name with $ added after the field name, before any channel filter, or
+// None if the name has no field or already has the modifier.
+fn long_string_name(pv: &str) -> Option<String>
+{
+    let (name, filter) = pv.split_at(pv.find('{').unwrap_or(pv.len()));
+    if name.contains('.') && !name.ends_with('$') {
+        Some(format!("{}${}", name, filter))
+    } else {
+        None
+    }
+}
+
+// Connects to the PV, adding the $ modifier if the PV names a string field.
+// Records of string type with no field named are read as plain strings, as are
+// string fields on servers without support for $.
+async fn connect(pv: &str)
+    -> Result<(Box<channel::Channel>, BasicDbrType, usize), CaError>
+{
+    let name = match long_string_name(pv) {
+        Some(name) => name,
+        None => return channel::connect(pv).await,
+    };
+    let long = channel::Channel::new(&name)?;
+    let (channel, datatype, count) = channel::connect(pv).await?;
+    if datatype != BasicDbrType::DbrString {
+        return Ok((channel, datatype, count));
+    }
+    match timer::timeout(LONG_STRING_GRACE, long.wait_connect()).await {
+        Some((datatype, count)) => Ok((long, datatype, count)),
+        None => Ok((channel, datatype, count)),
+    }
+}
+
+// Converts the string to a NUL terminated array, truncated to fit the given
+// number of characters as for to_epics_string.
+fn to_chars(string: &str, count: usize) -> Vec<u8>
+{
+    let mut chars = string.as_bytes().to_vec();
+    chars.truncate(count.saturating_sub(1));
+    chars.push(0);
+    chars
+}
+
+
+// -----------------------------------------------------------------------------
+// caget and caput
+
+#[async_trait(?Send)]
+impl CA for CaLongString {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, datatype, _count) = connect(pv).await?;
+        match datatype {
+            BasicDbrType::DbrChar => {
+                let chars = Vec::<u8>::caget_core(&channel).await?;
+                Ok(CaLongString(from_epics_string(&chars)))
+            },
+            BasicDbrType::DbrString =>
+                Ok(CaLongString(String::caget_core(&channel).await?)),
+            _ => Err(CaError::Status(ECA_BADTYPE)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CA for (CaLongString, StatusSeverity, SystemTime) {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let (channel, datatype, _count) = connect(pv).await?;
+        match datatype {
+            BasicDbrType::DbrChar => {
+                let (chars, s, t) = <(Vec<u8>, StatusSeverity, SystemTime)>
+                    ::caget_core(&channel).await?;
+                Ok((CaLongString(from_epics_string(&chars)), s, t))
+            },
+            BasicDbrType::DbrString => {
+                let (string, s, t) = <(String, StatusSeverity, SystemTime)>
+                    ::caget_core(&channel).await?;
+                Ok((CaLongString(string), s, t))
+            },
+            _ => Err(CaError::Status(ECA_BADTYPE)),
+        }
+    }
+}
+
+macro_rules! long_string_put {
+    { $pv:expr, $value:expr, $core:ident $(, $await:ident)? } => {
+        {
+            let (channel, datatype, count) = connect($pv).await?;
+            match datatype {
+                BasicDbrType::DbrChar =>
+                    $core(&channel, &to_chars(&$value.0, count))$(.$await)?,
+                BasicDbrType::DbrString =>
+                    $core(&channel, &[$value.0])$(.$await)?,
+                _ => Err(CaError::Status(ECA_BADTYPE)),
+            }
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CaPut for CaLongString {
+    async fn try_caput(pv: &str, value: Self) -> Result<(), CaError> {
+        long_string_put!{pv, value, caput_core, await}
+    }
+
+    async fn try_caput_nowait(pv: &str, value: Self) -> Result<(), CaError> {
+        long_string_put!{pv, value, caput_nowait_core}
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor
+
+#[async_trait(?Send)]
+impl CaMonitor for CaLongString {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        let (channel, datatype, _count) = connect(pv.as_ref()).await?;
+        match datatype {
+            BasicDbrType::DbrChar =>
+                Ok(Monitor::new(Subscription::<Vec<u8>>::new(channel, mask)?
+                    .map(|chars| CaLongString(from_epics_string(&chars))))),
+            BasicDbrType::DbrString =>
+                Ok(Monitor::new(Subscription::<String>::new(channel, mask)?
+                    .map(CaLongString))),
+            _ => Err(CaError::Status(ECA_BADTYPE)),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl CaMonitor for (CaLongString, StatusSeverity, SystemTime) {
+    async fn try_camonitor_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        let (channel, datatype, _count) = connect(pv.as_ref()).await?;
+        match datatype {
+            BasicDbrType::DbrChar => Ok(Monitor::new(
+                Subscription::<(Vec<u8>, StatusSeverity, SystemTime)>
+                    ::new(channel, mask)?
+                    .map(|(chars, s, t)|
+                        (CaLongString(from_epics_string(&chars)), s, t)))),
+            BasicDbrType::DbrString => Ok(Monitor::new(
+                Subscription::<(String, StatusSeverity, SystemTime)>
+                    ::new(channel, mask)?
+                    .map(|(string, s, t)| (CaLongString(string), s, t)))),
+            _ => Err(CaError::Status(ECA_BADTYPE)),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::CaLongString;
+    use crate::caget::CA;
+    use crate::camonitor::CaMonitor;
+    use crate::error::CaError;
+    use crate::mock::MockBackend;
+    use crate::protocol::status::ECA_BADTYPE;
+
+    const LONG: &str =
+        "a string rather longer than the forty characters of a DBR_STRING";
+
+    fn chars(string: &str) -> Vec<u8>
+    {
+        let mut chars = string.as_bytes().to_vec();
+        chars.resize(100, 0);
+        chars
+    }
+
+    #[test]
+    fn long_string_field()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:REC.NAME", String::from(&LONG[..39]));
+        mock.add_pv("TEST:REC.NAME$", chars(LONG));
+        mock.add_pv("TEST:WAVEFORM", chars(LONG));
+        block_on(async {
+            assert_eq!(CaLongString::caget("TEST:REC.NAME").await.0, LONG);
+            assert_eq!(CaLongString::caget("TEST:WAVEFORM").await.0, LONG);
+        });
+    }
+
+    // Without support for $ the plain string is read after a short wait
+    #[test]
+    fn no_long_string_support()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:REC.NAME", String::from(&LONG[..39]));
+        let value = block_on(CaLongString::caget("TEST:REC.NAME"));
+        assert_eq!(value.0, &LONG[..39]);
+    }
+
+    #[test]
+    fn monitor_bad_type()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.5f64);
+        block_on(async {
+            assert!(matches!(CaLongString::try_camonitor("TEST:AI").await,
+                Err(CaError::Status(ECA_BADTYPE))));
+            assert_eq!(CaLongString::try_caget("TEST:AI").await,
+                Err(CaError::Status(ECA_BADTYPE)));
+        });
+    }
+}
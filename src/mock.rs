@@ -0,0 +1,813 @@
+// In-memory mock backend
+//
+// Serves PVs preloaded by the test with their values, alarm states and control
+// metadata, so that code built on the CA traits can be tested without an IOC.
+// Connections can be dropped and restored, gets can be held in flight and
+// failures can be scripted.  All callbacks are invoked synchronously by the
+// call which triggers them.
+//
+// For example:
+//
+//  let mock = MockBackend::new();
+//  let _guard = mock.install();
+//  mock.add_pv("TEST:VALUE", 1.5f64);
+//  assert_eq!(block_on(f64::caget("TEST:VALUE")), 1.5);
+
+use std::collections::HashMap;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use libc::{c_int, c_long, c_short, c_ulong, c_void};
+
+use crate::backend::{
+    Backend, BackendGuard, ConnectArgs, ConnectHandler, EventHandler};
+use crate::cadef::{
+    ChanId, EvId, event_handler_args, CA_OP_CONN_UP, CA_OP_CONN_DOWN};
+use crate::protocol::{dbr_size_n, dbr_to_host, swap_dbr};
+use crate::protocol::status::*;
+use crate::pv_value::{PvValue, PvCtrl, convert};
+use crate::db_access::StatusSeverity;
+use crate::server::ServerValue;
+use crate::camonitor::{DBE_VALUE, DBE_LOG, DBE_ALARM, DBE_PROPERTY};
+
+
+// Value of field_type for a disconnected channel
+const TYPENOTCONN: c_short = -1;
+
+
+// User context pointer passed through to callbacks
+#[derive(Clone, Copy)]
+struct UserPointer(*const c_void);
+unsafe impl Send for UserPointer { }
+
+struct MockPv {
+    value: PvValue,
+    count: Option<usize>,       // Reported element count, if not value length
+    field_type: Option<c_short>,    // Reported type, if not type of value
+    connected: bool,
+    get_error: Option<u32>,
+    put_error: Option<u32>,
+    subscribe_error: Option<u32>,
+    hold_gets: bool,
+}
+
+struct MockChannel {
+    name: String,
+    on_connect: ConnectHandler,
+    usr: UserPointer,
+}
+
+struct MockSubscription {
+    chid: usize,
+    datatype: u16,
+    count: usize,
+    mask: u32,
+    handler: EventHandler,
+    usr: UserPointer,
+}
+
+// A get left unanswered while gets of its PV are held
+struct HeldGet {
+    chid: usize,
+    datatype: u16,
+    count: usize,
+    handler: EventHandler,
+    usr: UserPointer,
+}
+
+#[derive(Default)]
+struct MockState {
+    pvs: HashMap<String, MockPv>,
+    channels: HashMap<usize, MockChannel>,
+    subscriptions: HashMap<usize, MockSubscription>,
+    held_gets: Vec<HeldGet>,
+    next_id: usize,
+}
+
+// Callbacks are gathered while the state is locked and invoked afterwards
+enum Callback {
+    Connect(ConnectHandler, ConnectArgs),
+    Event(EventHandler, UserPointer, ChanId, u32, u16, usize, Option<Vec<u64>>),
+}
+
+unsafe impl Send for Callback { }
+
+
+fn chan_id(id: usize) -> ChanId
+{
+    ChanId(id as *const c_void)
+}
+
+fn connect_callback(id: usize, channel: &MockChannel, op: c_long) -> Callback
+{
+    Callback::Connect(channel.on_connect, ConnectArgs {
+        usr: channel.usr.0, chid: chan_id(id), op })
+}
+
+// Encodes the value in host byte order, or reports the given error
+fn event_callback(
+    pv: &MockPv, error: Option<u32>, datatype: u16, count: usize,
+    handler: EventHandler, usr: UserPointer, chid: usize) -> Callback
+{
+    let (status, count, dbr) = match error {
+        Some(status) => (status, count, None),
+        None => match pv.value.encode(datatype, count) {
+            Some((dbr, count)) => (ECA_NORMAL, count,
+                dbr_to_host(datatype, count as u32, &dbr)),
+            None => (ECA_GETFAIL, count, None),
+        },
+    };
+    Callback::Event(handler, usr, chan_id(chid), status, datatype, count, dbr)
+}
+
+fn invoke(callbacks: Vec<Callback>)
+{
+    for callback in callbacks {
+        match callback {
+            Callback::Connect(handler, args) => handler(args),
+            Callback::Event(
+                handler, usr, channel, status, datatype, count, dbr) =>
+            {
+                handler(event_handler_args {
+                    usr: usr.0,
+                    channel,
+                    datatype: datatype as c_long,
+                    count: count as c_long,
+                    dbr: match &dbr {
+                        Some(dbr) => dbr.as_ptr() as *const c_void,
+                        None => ptr::null(),
+                    },
+                    status: status as c_int,
+                })
+            },
+        }
+    }
+}
+
+
+pub struct MockBackend {
+    state: Mutex<MockState>,
+    // Held while callbacks are invoked so that clearing a channel or
+    // subscription waits for any callback in progress.
+    callback: Mutex<()>,
+}
+
+impl MockBackend {
+    pub fn new() -> Arc<MockBackend>
+    {
+        Arc::new(MockBackend {
+            state: Mutex::new(MockState::default()),
+            callback: Mutex::new(()),
+        })
+    }
+
+    // Uses this mock for all channels created on this thread until the guard
+    // is dropped.
+    pub fn install(self: &Arc<Self>) -> BackendGuard
+    {
+        crate::backend::install(self.clone())
+    }
+
+    // Runs an update on the named PV which returns the events it raises, and
+    // posts the callbacks this generates.  Panics if the PV doesn't exist.
+    fn update(&self, pv: &str, update: impl FnOnce(&mut MockPv) -> u32)
+    {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let mock_pv = state.pvs.get_mut(pv)
+                .unwrap_or_else(|| panic!("PV {} not defined", pv));
+            let events = update(mock_pv);
+            let mock_pv = &*mock_pv;
+            let channels = &state.channels;
+            state.subscriptions.values()
+                .filter(|s| s.mask & events != 0  &&  mock_pv.connected)
+                .filter(|s| channels[&s.chid].name == pv)
+                .map(|s| event_callback(mock_pv, None,
+                    s.datatype, s.count, s.handler, s.usr, s.chid))
+                .collect()
+        };
+        self.invoke(callbacks);
+    }
+
+    // Changes the connection state of a PV, notifying all its channels.  On
+    // reconnection all subscriptions receive the current value.
+    fn set_connected(&self, pv: &str, connected: bool)
+    {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            let mock_pv = match state.pvs.get_mut(pv) {
+                Some(mock_pv) => mock_pv,
+                None => return,
+            };
+            if mock_pv.connected == connected {
+                return;
+            }
+            mock_pv.connected = connected;
+
+            let state = &*state;
+            let mock_pv = &state.pvs[pv];
+            let op = if connected { CA_OP_CONN_UP } else { CA_OP_CONN_DOWN };
+            let mut callbacks: Vec<Callback> = state.channels.iter()
+                .filter(|(_, channel)| channel.name == pv)
+                .map(|(id, channel)| connect_callback(*id, channel, op))
+                .collect();
+            if connected {
+                callbacks.extend(state.subscriptions.values()
+                    .filter(|s| state.channels[&s.chid].name == pv)
+                    .map(|s| event_callback(mock_pv, None,
+                        s.datatype, s.count, s.handler, s.usr, s.chid)));
+            }
+            callbacks
+        };
+        self.invoke(callbacks);
+    }
+
+    fn invoke(&self, callbacks: Vec<Callback>)
+    {
+        let _callback = self.callback.lock().unwrap();
+        invoke(callbacks);
+    }
+
+    // Adds a connected PV with the given value, or replaces an existing PV.
+    // The native type and element count are taken from the value.
+    pub fn add_pv<T: ServerValue>(&self, pv: &str, value: T)
+    {
+        self.state.lock().unwrap().pvs.insert(pv.to_owned(), MockPv {
+            value: PvValue::new(value.into_union()),
+            count: None,
+            field_type: None,
+            connected: false,
+            get_error: None,
+            put_error: None,
+            subscribe_error: None,
+            hold_gets: false,
+        });
+        self.set_connected(pv, true);
+    }
+
+    // Disconnects all channels to the PV and forgets it
+    pub fn remove_pv(&self, pv: &str)
+    {
+        self.set_connected(pv, false);
+        self.state.lock().unwrap().pvs.remove(pv);
+    }
+
+    // Simulates loss and recovery of the connection to the server of a PV
+    pub fn disconnect(&self, pv: &str)
+    {
+        self.set_connected(pv, false);
+    }
+
+    pub fn reconnect(&self, pv: &str)
+    {
+        self.set_connected(pv, true);
+    }
+
+    // Returns the current value, for instance to check the effect of a put
+    pub fn get<T: ServerValue>(&self, pv: &str) -> T
+    {
+        let state = self.state.lock().unwrap();
+        let value = state.pvs[pv].value.value.clone();
+        T::from_union(value).expect("PV has unexpected type")
+    }
+
+    // Updates the value with the current time, leaving the alarm unchanged
+    pub fn set<T: ServerValue>(&self, pv: &str, value: T)
+    {
+        self.set_with_time(pv, value, None, SystemTime::now());
+    }
+
+    pub fn set_alarm<T: ServerValue>(
+        &self, pv: &str, value: T, alarm: StatusSeverity)
+    {
+        self.set_with_time(pv, value, Some(alarm), SystemTime::now());
+    }
+
+    pub fn set_with_time<T: ServerValue>(
+        &self, pv: &str, value: T, alarm: Option<StatusSeverity>,
+        timestamp: SystemTime)
+    {
+        let value = value.into_union();
+        self.update(pv, |mock_pv| {
+            let current = &mut mock_pv.value;
+            let mut events = DBE_VALUE | DBE_LOG;
+            if let Some(alarm) = alarm {
+                let status = current.status;
+                if alarm.status != status.status
+                    ||  alarm.severity != status.severity
+                {
+                    events |= DBE_ALARM;
+                }
+                current.status = alarm;
+            }
+            current.value = value;
+            current.timestamp = timestamp;
+            events
+        });
+    }
+
+    // Updates the control metadata: units, precision, limits or enum strings
+    pub fn set_ctrl(&self, pv: &str, ctrl: impl Into<PvCtrl>)
+    {
+        let ctrl = ctrl.into();
+        self.update(pv, |mock_pv| {
+            mock_pv.value.ctrl = ctrl;
+            DBE_PROPERTY
+        });
+    }
+
+    // Overrides the element count reported on connection, as for a waveform
+    // holding fewer elements than its capacity.  This only takes effect for
+    // channels connecting after the call.
+    pub fn set_count(&self, pv: &str, count: usize)
+    {
+        self.update(pv, |mock_pv| {
+            mock_pv.count = Some(count);
+            0
+        });
+    }
+
+    // Makes all subsequent gets or puts fail with the given CA status code, or
+    // restores normal operation when passed None.  Monitor updates are not
+    // affected.
+    pub fn fail_gets(&self, pv: &str, status: Option<u32>)
+    {
+        self.update(pv, |mock_pv| {
+            mock_pv.get_error = status;
+            0
+        });
+    }
+
+    pub fn fail_puts(&self, pv: &str, status: Option<u32>)
+    {
+        self.update(pv, |mock_pv| {
+            mock_pv.put_error = status;
+            0
+        });
+    }
+
+    // Makes creation of subsequent subscriptions fail with the given status
+    pub fn fail_subscriptions(&self, pv: &str, status: Option<u32>)
+    {
+        self.update(pv, |mock_pv| {
+            mock_pv.subscribe_error = status;
+            0
+        });
+    }
+
+    // Leaves subsequent gets unanswered, for instance to test giving up on a
+    // get in flight, until they are released.  Released gets are answered with
+    // the current value, except those of channels since cleared.
+    pub fn hold_gets(&self, pv: &str)
+    {
+        self.update(pv, |mock_pv| {
+            mock_pv.hold_gets = true;
+            0
+        });
+    }
+
+    pub fn release_gets(&self, pv: &str)
+    {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let mock_pv = state.pvs.get_mut(pv)
+                .unwrap_or_else(|| panic!("PV {} not defined", pv));
+            mock_pv.hold_gets = false;
+            let channels = &state.channels;
+            let (released, held) = std::mem::take(&mut state.held_gets)
+                .into_iter()
+                .partition(|get| channels[&get.chid].name == pv);
+            state.held_gets = held;
+            let mock_pv = &state.pvs[pv];
+            released.into_iter().map(|get: HeldGet| event_callback(
+                mock_pv, mock_pv.get_error, get.datatype, get.count,
+                get.handler, get.usr, get.chid)).collect()
+        };
+        self.invoke(callbacks);
+    }
+
+    // Adds a connected PV as seen by a client, reporting the given field type
+    // and element count whatever the type of the value.  Used for replay.
+    pub(crate) fn add_recorded(
+        &self, pv: &str, value: PvValue, field_type: c_short, count: usize)
+    {
+        self.state.lock().unwrap().pvs.insert(pv.to_owned(), MockPv {
+            value,
+            count: Some(count),
+            field_type: Some(field_type),
+            connected: false,
+            get_error: None,
+            put_error: None,
+            subscribe_error: None,
+            hold_gets: false,
+        });
+        self.set_connected(pv, true);
+    }
+
+    pub(crate) fn update_value(
+        &self, pv: &str, update: impl FnOnce(&mut PvValue) -> u32)
+    {
+        self.update(pv, |mock_pv| update(&mut mock_pv.value));
+    }
+
+    pub(crate) fn channel_name(&self, id: ChanId) -> Option<String>
+    {
+        let state = self.state.lock().unwrap();
+        state.channels.get(&(id.0 as usize)).map(|c| c.name.clone())
+    }
+
+    // Looks up the connected PV for a channel
+    fn with_pv<R>(&self, id: ChanId, action: impl FnOnce(&mut MockPv) -> R)
+        -> Option<R>
+    {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let channel = state.channels.get(&(id.0 as usize))?;
+        state.pvs.get_mut(&channel.name)
+            .filter(|mock_pv| mock_pv.connected)
+            .map(action)
+    }
+}
+
+
+impl Backend for MockBackend {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int
+    {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let chid = state.next_id;
+            let channel = MockChannel {
+                name: pv.to_owned(), on_connect, usr: UserPointer(usr) };
+            let connected = state.pvs.get(pv).is_some_and(|pv| pv.connected);
+            let callbacks = if connected {
+                vec![connect_callback(chid, &channel, CA_OP_CONN_UP)]
+            } else {
+                vec![]
+            };
+            state.channels.insert(chid, channel);
+            *id = chan_id(chid);
+            callbacks
+        };
+        self.invoke(callbacks);
+        ECA_NORMAL as c_int
+    }
+
+    fn clear_channel(&self, id: ChanId) -> c_int
+    {
+        let mut state = self.state.lock().unwrap();
+        let chid = id.0 as usize;
+        state.channels.remove(&chid);
+        state.subscriptions.retain(|_, s| s.chid != chid);
+        state.held_gets.retain(|get| get.chid != chid);
+        drop(state);
+        // Wait for any callback in progress to complete
+        drop(self.callback.lock().unwrap());
+        ECA_NORMAL as c_int
+    }
+
+    fn field_type(&self, id: ChanId) -> c_short
+    {
+        self.with_pv(id, |pv|
+            pv.field_type.unwrap_or_else(|| pv.value.native_type().dbr_type()))
+            .unwrap_or(TYPENOTCONN)
+    }
+
+    fn element_count(&self, id: ChanId) -> c_ulong
+    {
+        self.with_pv(id, |pv|
+            pv.count.unwrap_or_else(|| pv.value.native_count()) as c_ulong)
+            .unwrap_or(0)
+    }
+
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let get = HeldGet {
+            chid: id.0 as usize,
+            datatype: datatype as u16,
+            count: count as usize,
+            handler,
+            usr: UserPointer(usr),
+        };
+        let callback = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let mock_pv = state.channels.get(&get.chid)
+                .and_then(|channel| state.pvs.get(&channel.name))
+                .filter(|mock_pv| mock_pv.connected);
+            match mock_pv {
+                Some(mock_pv) if mock_pv.hold_gets => {
+                    state.held_gets.push(get);
+                    None
+                },
+                Some(mock_pv) => Some(event_callback(
+                    mock_pv, mock_pv.get_error, get.datatype, get.count,
+                    get.handler, get.usr, get.chid)),
+                None => return ECA_DISCONN as c_int,
+            }
+        };
+        self.invoke(callback.into_iter().collect());
+        ECA_NORMAL as c_int
+    }
+
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let (datatype, count) = (datatype as u16, count as usize);
+        let size = match dbr_size_n(datatype, count) {
+            Some(size) => size,
+            None => return ECA_BADTYPE as c_int,
+        };
+        // Decode the value as the server would from network byte order
+        let mut dbr = unsafe {
+            std::slice::from_raw_parts(value as *const u8, size).to_vec() };
+        swap_dbr(datatype, count, &mut dbr);
+
+        let status = self.with_pv(id, |pv| {
+            let value = PvValue::decode(datatype, count, &dbr)
+                .and_then(|value| convert(&value.value,
+                    pv.value.native_type(), &pv.value.ctrl));
+            match (pv.put_error, value) {
+                (Some(status), _) => Err(status),
+                (None, Some(value)) => Ok(value),
+                (None, None) => Err(ECA_BADTYPE),
+            }
+        });
+        let status = match status {
+            Some(Ok(value)) => {
+                let name = {
+                    let state = self.state.lock().unwrap();
+                    state.channels[&(id.0 as usize)].name.clone()
+                };
+                self.update(&name, |mock_pv| {
+                    mock_pv.value.value = value;
+                    mock_pv.value.timestamp = SystemTime::now();
+                    DBE_VALUE | DBE_LOG
+                });
+                ECA_NORMAL
+            },
+            Some(Err(status)) => status,
+            None => return ECA_DISCONN as c_int,
+        };
+        self.invoke(vec![Callback::Event(
+            handler, UserPointer(usr), id, status, datatype, count, None)]);
+        ECA_NORMAL as c_int
+    }
+
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int
+    {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let name = &state.channels[&(id.0 as usize)].name;
+            let error = state.pvs.get(name)
+                .and_then(|pv| pv.subscribe_error);
+            if let Some(status) = error {
+                return status as c_int;
+            }
+
+            state.next_id += 1;
+            let subid = state.next_id;
+            let subscription = MockSubscription {
+                chid: id.0 as usize,
+                datatype: datatype as u16,
+                count: count as usize,
+                mask: mask as u32,
+                handler,
+                usr: UserPointer(usr),
+            };
+
+            // Every subscription starts with an update of the current value
+            let name = &state.channels[&subscription.chid].name;
+            let callbacks = match state.pvs.get(name) {
+                Some(pv) if pv.connected => vec![event_callback(
+                    pv, None, subscription.datatype, subscription.count,
+                    handler, subscription.usr, subscription.chid)],
+                _ => vec![],
+            };
+            state.subscriptions.insert(subid, subscription);
+            *evid = EvId(subid as *const c_void);
+            callbacks
+        };
+        self.invoke(callbacks);
+        ECA_NORMAL as c_int
+    }
+
+    fn clear_subscription(&self, evid: EvId) -> c_int
+    {
+        self.state.lock().unwrap().subscriptions.remove(&(evid.0 as usize));
+        // Wait for any callback in progress to complete
+        drop(self.callback.lock().unwrap());
+        ECA_NORMAL as c_int
+    }
+
+    fn flush_io(&self) -> c_int
+    {
+        ECA_NORMAL as c_int
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    use super::MockBackend;
+    use crate::caget::{CA, CaCtrl, CaGetCore, CaGetCount, WithNativeType};
+    use crate::cainfo::try_cainfo_timeout;
+    use crate::camonitor::{CaMonitor, CaMonitorEvents, MonitorEvent};
+    use crate::caput::CaPut;
+    use crate::channel;
+    use crate::channel::ConnectionState;
+    use crate::caunion::{BasicDbrType, CaUnionVec};
+    use crate::db_access::{CtrlLimits, StatusSeverity};
+    use crate::dbr::FloatCtrl;
+    use crate::error::CaError;
+    use crate::protocol::status::{ECA_GETFAIL, ECA_NORDACCESS};
+    use crate::timestamp::EpicsTime;
+    use crate::timer;
+
+    const MAJOR: StatusSeverity = StatusSeverity { status: 3, severity: 2 };
+
+    fn float_ctrl(units: &str, precision: i16) -> FloatCtrl<f64>
+    {
+        FloatCtrl {
+            units: units.to_owned(),
+            precision,
+            limits: CtrlLimits {
+                upper_disp_limit: 10.0, lower_disp_limit: 0.0,
+                upper_alarm_limit: 9.0, upper_warning_limit: 8.0,
+                lower_warning_limit: 2.0, lower_alarm_limit: 1.0,
+                upper_ctrl_limit: 10.0, lower_ctrl_limit: 0.0,
+            },
+        }
+    }
+
+    #[test]
+    fn preloaded_get()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.5f64);
+        mock.set_alarm("TEST:AI", 9.5f64, MAJOR);
+        mock.set_ctrl("TEST:AI", float_ctrl("mm", 3));
+        mock.add_pv("TEST:WAVEFORM", vec![1i32, 2, 3]);
+        block_on(async {
+            let (value, alarm, CaCtrl(ctrl)) =
+                <(f64, StatusSeverity, CaCtrl<FloatCtrl<f64>>)>
+                    ::caget("TEST:AI").await;
+            assert_eq!(value, 9.5);
+            assert_eq!((alarm.status, alarm.severity), (3, 2));
+            assert_eq!(ctrl.units, "mm");
+            assert_eq!(ctrl.precision, 3);
+            assert_eq!(ctrl.limits.upper_alarm_limit, 9.0);
+
+            assert_eq!(Vec::<i32>::caget("TEST:WAVEFORM").await, [1, 2, 3]);
+            let (_, _, count) =
+                channel::connect("TEST:WAVEFORM").await.unwrap();
+            assert_eq!(count, 3);
+            mock.set_count("TEST:WAVEFORM", 2);
+            let (_, _, count) =
+                channel::connect("TEST:WAVEFORM").await.unwrap();
+            assert_eq!(count, 2);
+        });
+    }
+
+    // A count limits arrays read, as for caget -#, but never extends them
+    #[test]
+    fn counted_get()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:WAVEFORM", vec![1i32, 2, 3]);
+        block_on(async {
+            let values = Vec::<i32>::try_caget_count("TEST:WAVEFORM", 2).await;
+            assert_eq!(values.unwrap(), [1, 2]);
+            let values = Vec::<i32>::try_caget_count("TEST:WAVEFORM", 0).await;
+            assert_eq!(values.unwrap(), [1, 2, 3]);
+            let values = Vec::<i32>::try_caget_count("TEST:WAVEFORM", 9).await;
+            assert_eq!(values.unwrap(), [1, 2, 3]);
+
+            let got = <WithNativeType<(CaUnionVec, StatusSeverity, EpicsTime)>>
+                ::try_caget_count("TEST:WAVEFORM", 1).await.unwrap();
+            match got.value.0 {
+                CaUnionVec::CaLong(values) => assert_eq!(values, [1]),
+                other => panic!("Unexpected {:?}", other),
+            }
+            assert_eq!((got.native_type, got.count),
+                (BasicDbrType::DbrLong, 3));
+        });
+    }
+
+    #[test]
+    fn string_ctrl_time()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        let time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        mock.add_pv("TEST:SI", "text".to_owned());
+        mock.set_with_time("TEST:SI", "text".to_owned(), None, time);
+        block_on(async {
+            // Strings report their timestamp in place of control information
+            let (value, _, CaCtrl(stamp)) =
+                <(String, StatusSeverity, CaCtrl<SystemTime>)>
+                    ::caget("TEST:SI").await;
+            assert_eq!(value, "text");
+            assert_eq!(stamp, time);
+            let (_, _, stamp) =
+                <(String, StatusSeverity, EpicsTime)>::caget("TEST:SI").await;
+            assert_eq!(stamp, EpicsTime::from(time));
+        });
+    }
+
+    #[test]
+    fn invalid_names()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        block_on(async {
+            for pv in ["A\0B", ""] {
+                assert!(matches!(f64::try_caget(pv).await,
+                    Err(CaError::InvalidName(_))));
+                assert!(matches!(f64::try_caput(pv, 1.0).await,
+                    Err(CaError::InvalidName(_))));
+                assert!(matches!(f64::try_camonitor(pv).await,
+                    Err(CaError::InvalidName(_))));
+            }
+        });
+    }
+
+    #[test]
+    fn disconnect_seen_by_monitor()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.0f64);
+        block_on(async {
+            let mut monitor = f64::camonitor_events("TEST:AI").await;
+            assert_eq!(monitor.next().await, Some(MonitorEvent::Update(1.0)));
+            mock.disconnect("TEST:AI");
+            assert_eq!(monitor.next().await, Some(MonitorEvent::Disconnected));
+            mock.set("TEST:AI", 2.0f64);
+            mock.reconnect("TEST:AI");
+            assert!(matches!(monitor.next().await,
+                Some(MonitorEvent::Reconnected(_, 1))));
+            assert_eq!(monitor.next().await, Some(MonitorEvent::Update(2.0)));
+        });
+    }
+
+    #[test]
+    fn scripted_errors()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.0f64);
+        block_on(async {
+            mock.fail_gets("TEST:AI", Some(ECA_GETFAIL));
+            assert_eq!(f64::try_caget("TEST:AI").await,
+                Err(CaError::Status(ECA_GETFAIL)));
+            mock.fail_gets("TEST:AI", None);
+            assert_eq!(f64::try_caget("TEST:AI").await, Ok(1.0));
+
+            mock.fail_subscriptions("TEST:AI", Some(ECA_NORDACCESS));
+            assert!(matches!(f64::try_camonitor("TEST:AI").await,
+                Err(CaError::Status(ECA_NORDACCESS))));
+        });
+    }
+
+    // Gets can be given up on while in flight, and are answered safely after
+    // the future waiting for them has gone.
+    #[test]
+    fn held_gets_time_out()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.0f64);
+        mock.hold_gets("TEST:AI");
+        let delay = Duration::from_millis(20);
+        block_on(async {
+            assert_eq!(
+                timer::timeout(delay, f64::try_caget("TEST:AI")).await, None);
+            assert_eq!(try_cainfo_timeout("TEST:AI", delay).await.unwrap_err(),
+                CaError::Timeout(ConnectionState::Connected));
+
+            let (channel, _, _) = channel::connect("TEST:AI").await.unwrap();
+            let get = timer::timeout(delay, f64::caget_core(&channel)).await;
+            assert!(get.is_none());
+            mock.release_gets("TEST:AI");
+            assert_eq!(f64::caget_core(&channel).await, Ok(1.0));
+        });
+    }
+}
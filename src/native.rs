@@ -0,0 +1,1245 @@
+// Native implementation of the Channel Access client
+//
+// This implements the channel operations of the Backend trait directly on top
+// of std::net, so that the library can be built and used without EPICS base.
+// As for a libca context with preemptive callbacks enabled all callbacks are
+// invoked from background threads: one thread handles name searches, and each
+// TCP virtual circuit has its own reader and writer threads.
+//
+// The asynchronous interface is the same as for libca: each request registers
+// a callback which wakes the future waiting for it, so the futures can be run
+// on any executor.  Blocking sockets on background threads are the natural fit
+// for this, as non-blocking I/O would need a reactor driving it and so tie the
+// library to a particular runtime.  The cost is two threads per server.
+// Messages are queued for the writer thread, so that sending never blocks
+// whatever locks the sender holds.
+//
+// As libca does, a circuit from which nothing has been received for a while is
+// sent an echo request, and is disconnected if this is not answered in time.
+// This detects servers which have gone away without closing the connection,
+// for instance on a hard reboot, so that channels are searched for again.
+//
+// Errors not associated with any request, such as failure to connect to a
+// server or an error message from a server, are passed to the on_error handler
+// of the configuration if one is given.
+//
+// The default context takes its configuration from the standard environment
+// variables EPICS_CA_ADDR_LIST, EPICS_CA_AUTO_ADDR_LIST and
+// EPICS_CA_SERVER_PORT.  Further contexts with their own configuration can be
+// created and installed for a thread, for instance to talk to a test server.
+
+use std::{env, io, ptr, thread, time};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use libc::{c_char, c_short, c_int, c_long, c_ulong, c_void};
+use socket2::SockRef;
+
+use crate::backend;
+use crate::backend::{
+    Backend, BackendGuard, ConnectArgs, ConnectHandler, EventHandler};
+use crate::cadef::{
+    ChanId, EvId, event_handler_args, CA_OP_CONN_UP, CA_OP_CONN_DOWN};
+use crate::error::CaError;
+use crate::protocol::*;
+use crate::protocol::command::*;
+use crate::protocol::status::*;
+
+// Search requests are repeated with an interval which doubles from the minimum
+// to the maximum, and is reset whenever a new search is started.
+const SEARCH_MIN: time::Duration = time::Duration::from_millis(30);
+const SEARCH_MAX: time::Duration = time::Duration::from_secs(5);
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// An echo is sent once a circuit has been silent for ECHO_PERIOD, and the
+// circuit is closed if there is no reply within ECHO_TIMEOUT.  These are the
+// libca defaults.  A circuit which accepts nothing written to it for as long is
+// also closed.
+const ECHO_PERIOD: time::Duration = time::Duration::from_secs(30);
+const ECHO_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+
+// A server which falls this far behind reading our messages is disconnected
+const MAX_BACKLOG: usize = 1 << 24;
+
+// Value of ca_field_type for a disconnected channel
+const TYPENOTCONN: c_short = -1;
+
+
+// User context pointer passed through to callbacks
+#[derive(Clone, Copy)]
+struct UserPointer(*const c_void);
+unsafe impl Send for UserPointer { }
+unsafe impl Sync for UserPointer { }
+
+
+// -----------------------------------------------------------------------------
+// Configuration
+
+pub type ErrorHandler = fn(error: &CaError);
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    // Addresses to which name searches are sent
+    pub addr_list: Vec<SocketAddr>,
+    // Called from a background thread with errors not reported to a request
+    pub on_error: Option<ErrorHandler>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> ClientConfig
+    {
+        let port = env::var("EPICS_CA_SERVER_PORT").ok()
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(CA_SERVER_PORT);
+        let mut addr_list = parse_addr_list(
+            &env::var("EPICS_CA_ADDR_LIST").unwrap_or_default(), port);
+        let auto_addr_list = env::var("EPICS_CA_AUTO_ADDR_LIST")
+            .map(|auto| !auto.eq_ignore_ascii_case("no"))
+            .unwrap_or(true);
+        if auto_addr_list {
+            addr_list.push(SocketAddr::from((Ipv4Addr::BROADCAST, port)));
+        }
+        ClientConfig { addr_list, on_error: None }
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Channels, subscriptions and circuits
+
+#[derive(Clone)]
+enum Connection {
+    Searching,                  // Waiting for search reply
+    Creating(Arc<Circuit>),     // Waiting for create channel response
+    Connected {
+        circuit: Arc<Circuit>,
+        sid: u32,
+        field_type: u16,
+        count: u32,
+    },
+    Cleared,                    // Channel has been cleared by the user
+}
+
+struct ChannelState {
+    connection: Connection,
+    access: u32,
+    subscriptions: Vec<Arc<Subscription>>,
+}
+
+struct NativeChannel {
+    cid: u32,
+    name: String,
+    puser: UserPointer,
+    on_connect: ConnectHandler,
+    state: Mutex<ChannelState>,
+    // Held while the connection callback or the callback of a get or put runs,
+    // so that clearing the channel can wait for any callback in progress.
+    callback: Mutex<()>,
+}
+
+struct Subscription {
+    subid: u32,
+    channel: Arc<NativeChannel>,
+    datatype: u16,
+    count: u32,
+    mask: u16,
+    handler: EventHandler,
+    usr: UserPointer,
+    // Reset when the subscription is cleared.  Held while the callback runs so
+    // that clearing the subscription waits for any callback in progress.
+    active: Mutex<bool>,
+}
+
+// Outstanding get or put request
+struct PendingIo {
+    channel: Arc<NativeChannel>,
+    handler: EventHandler,
+    usr: UserPointer,
+}
+
+struct CircuitState {
+    stream: Option<TcpStream>,
+    outgoing: Vec<u8>,          // Messages waiting for the writer thread
+    dead: bool,
+    pending: HashMap<u32, PendingIo>,
+    last_received: time::Instant,
+    echo_sent: bool,
+}
+
+struct Circuit {
+    address: SocketAddr,
+    state: Mutex<CircuitState>,
+    // Signalled when messages are queued or the circuit is closed
+    ready: Condvar,
+}
+
+
+impl NativeChannel {
+    fn chid(&self) -> ChanId
+    {
+        ChanId(self as *const NativeChannel as *const c_void)
+    }
+
+    fn connection(&self) -> Connection
+    {
+        self.state.lock().unwrap().connection.clone()
+    }
+
+    fn invoke_connect(&self, op: c_long)
+    {
+        let _callback = self.callback.lock().unwrap();
+        if let Connection::Cleared = self.connection() {
+            return;
+        }
+        (self.on_connect)(ConnectArgs { usr: self.puser.0, chid: self.chid(), op });
+    }
+}
+
+impl PendingIo {
+    // As for connection callbacks, nothing is delivered once the channel has
+    // been cleared.
+    fn complete(self, status: u32, datatype: u16, count: u32, payload: &[u8])
+    {
+        let _callback = self.channel.callback.lock().unwrap();
+        if let Connection::Cleared = self.channel.connection() {
+            return;
+        }
+        invoke_handler(self.handler, self.usr, self.channel.chid(),
+            status, datatype, count, payload);
+    }
+}
+
+impl Subscription {
+    fn event_add(&self, sid: u32) -> Vec<u8>
+    {
+        // The payload is low, high and timeout (all unused) followed by the
+        // event mask and padding.
+        let mut payload = vec![0; 12];
+        payload.extend_from_slice(&self.mask.to_be_bytes());
+        payload.extend_from_slice(&[0, 0]);
+        message(
+            &Header::new(CA_PROTO_EVENT_ADD,
+                self.datatype, self.count, sid, self.subid),
+            &payload)
+    }
+
+    fn deliver(&self, status: u32, datatype: u16, count: u32, payload: &[u8])
+    {
+        let active = self.active.lock().unwrap();
+        if *active {
+            invoke_handler(self.handler, self.usr, self.channel.chid(),
+                status, datatype, count, payload);
+        }
+    }
+}
+
+impl Circuit {
+    fn new(address: SocketAddr) -> Circuit
+    {
+        Circuit {
+            address,
+            state: Mutex::new(CircuitState {
+                stream: None,
+                outgoing: Vec::new(),
+                dead: false,
+                pending: HashMap::new(),
+                last_received: time::Instant::now(),
+                echo_sent: false,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    // Messages are queued, and are written once the circuit is connected
+    fn send(&self, message: &[u8])
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.dead {
+            return;
+        }
+        if state.outgoing.len() + message.len() > MAX_BACKLOG {
+            drop(state);
+            self.close();
+        } else {
+            state.outgoing.extend_from_slice(message);
+            self.ready.notify_one();
+        }
+    }
+
+    // Stops the writer thread, and closing the socket causes the reader thread
+    // to exit
+    fn close(&self)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.dead = true;
+        if let Some(stream) = &state.stream {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+        self.ready.notify_one();
+    }
+
+    // Called by the reader thread for every message received
+    fn received(&self)
+    {
+        let mut state = self.state.lock().unwrap();
+        state.last_received = time::Instant::now();
+        state.echo_sent = false;
+    }
+
+    fn add_pending(&self, ioid: u32, pending: PendingIo)
+    {
+        self.state.lock().unwrap().pending.insert(ioid, pending);
+    }
+
+    fn take_pending(&self, ioid: u32) -> Option<PendingIo>
+    {
+        self.state.lock().unwrap().pending.remove(&ioid)
+    }
+
+    // Removes all the requests outstanding on the given channel
+    fn take_channel_pending(&self, channel: &NativeChannel) -> Vec<PendingIo>
+    {
+        let mut state = self.state.lock().unwrap();
+        let ioids: Vec<u32> = state.pending.iter()
+            .filter(|(_, pending)| ptr::eq(pending.channel.as_ref(), channel))
+            .map(|(&ioid, _)| ioid)
+            .collect();
+        ioids.iter().filter_map(|ioid| state.pending.remove(ioid)).collect()
+    }
+
+    // Queues the handshake ahead of any messages already sent and starts the
+    // writer thread
+    fn start(self: &Arc<Self>, stream: &TcpStream) -> io::Result<()>
+    {
+        let writer = stream.try_clone()?;
+        writer.set_write_timeout(Some(ECHO_PERIOD + ECHO_TIMEOUT))?;
+        let mut state = self.state.lock().unwrap();
+        if state.dead {
+            return Err(io::ErrorKind::ConnectionAborted.into());
+        }
+        let mut buffer = Vec::new();
+        put_message(&mut buffer,
+            &Header::new(CA_PROTO_VERSION, 0, CA_MINOR_VERSION as u32, 0, 0),
+            &[]);
+        put_message(&mut buffer,
+            &Header::new(CA_PROTO_CLIENT_NAME, 0, 0, 0, 0),
+            &string_payload(&user_name()));
+        put_message(&mut buffer,
+            &Header::new(CA_PROTO_HOST_NAME, 0, 0, 0, 0),
+            &string_payload(&host_name()));
+        buffer.append(&mut state.outgoing);
+        state.outgoing = buffer;
+        state.stream = Some(stream.try_clone()?);
+        state.last_received = time::Instant::now();
+        drop(state);
+
+        let circuit = self.clone();
+        thread::spawn(move || circuit.writer_thread(writer));
+        Ok(())
+    }
+
+    // Returns the queued messages once there are any, adding an echo request
+    // if the circuit has been silent for too long.  Returns None when the
+    // circuit is closed or the echo has not been answered.
+    fn next_messages(&self) -> Option<Vec<u8>>
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.dead {
+                return None;
+            }
+            let silent = state.last_received.elapsed();
+            if !state.echo_sent  &&  silent >= ECHO_PERIOD {
+                state.echo_sent = true;
+                put_message(&mut state.outgoing,
+                    &Header::new(CA_PROTO_ECHO, 0, 0, 0, 0), &[]);
+            }
+            if !state.outgoing.is_empty() {
+                return Some(std::mem::take(&mut state.outgoing));
+            }
+            let deadline = if state.echo_sent {
+                ECHO_PERIOD + ECHO_TIMEOUT
+            } else {
+                ECHO_PERIOD
+            };
+            if silent >= deadline {
+                return None;
+            }
+            state = self.ready.wait_timeout(state, deadline - silent)
+                .unwrap().0;
+        }
+    }
+
+    fn writer_thread(&self, mut stream: TcpStream)
+    {
+        while let Some(messages) = self.next_messages() {
+            if stream.write_all(&messages).is_err() {
+                break;
+            }
+        }
+        self.close();
+    }
+}
+
+
+fn user_name() -> String
+{
+    env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_default()
+}
+
+fn host_name() -> String
+{
+    let mut buffer = [0u8; 256];
+    let rc = unsafe {
+        libc::gethostname(buffer.as_mut_ptr() as *mut c_char, buffer.len()) };
+    if rc == 0 {
+        payload_string(&buffer)
+    } else {
+        "localhost".to_owned()
+    }
+}
+
+
+fn invoke_handler(
+    handler: EventHandler, usr: UserPointer, channel: ChanId,
+    status: u32, datatype: u16, count: u32, payload: &[u8])
+{
+    let mut status = status;
+    let mut buffer = None;
+    if status == ECA_NORMAL  &&  !payload.is_empty() {
+        buffer = dbr_to_host(datatype, count, payload);
+        if buffer.is_none() {
+            status = ECA_BADTYPE;
+        }
+    }
+    let dbr = match &buffer {
+        Some(buffer) => buffer.as_ptr() as *const c_void,
+        None => ptr::null(),
+    };
+    handler(event_handler_args {
+        usr: usr.0,
+        channel,
+        datatype: datatype as c_long,
+        count: count as c_long,
+        dbr,
+        status: status as c_int,
+    });
+}
+
+
+// -----------------------------------------------------------------------------
+// Context
+
+struct ContextState {
+    channels: HashMap<u32, Arc<NativeChannel>>,
+    subscriptions: HashMap<u32, Arc<Subscription>>,
+    circuits: HashMap<SocketAddr, Arc<Circuit>>,
+    restart_search: bool,
+}
+
+struct ContextInner {
+    config: ClientConfig,
+    udp: UdpSocket,
+    next_id: AtomicU32,
+    shutdown: AtomicBool,
+    state: Mutex<ContextState>,
+}
+
+// Lock ordering: the context state must be taken before any channel state,
+// which in turn must be taken before any circuit state.
+
+impl ContextInner {
+    fn new(config: ClientConfig) -> io::Result<ContextInner>
+    {
+        let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        udp.set_broadcast(true)?;
+        Ok(ContextInner {
+            config,
+            udp,
+            next_id: AtomicU32::new(1),
+            shutdown: AtomicBool::new(false),
+            state: Mutex::new(ContextState {
+                channels: HashMap::new(),
+                subscriptions: HashMap::new(),
+                circuits: HashMap::new(),
+                restart_search: false,
+            }),
+        })
+    }
+
+    fn next_id(&self) -> u32
+    {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Wakes the search thread by sending it an empty datagram
+    fn restart_search(&self)
+    {
+        self.state.lock().unwrap().restart_search = true;
+        if let Ok(address) = self.udp.local_addr() {
+            let wake = SocketAddr::from((Ipv4Addr::LOCALHOST, address.port()));
+            let _ = self.udp.send_to(&[], wake);
+        }
+    }
+
+    fn report(&self, error: CaError)
+    {
+        if let Some(on_error) = self.config.on_error {
+            on_error(&error);
+        }
+    }
+
+    fn take_restart(&self) -> bool
+    {
+        let mut state = self.state.lock().unwrap();
+        std::mem::replace(&mut state.restart_search, false)
+    }
+
+
+    // - Searching -------------------------------------------------------------
+
+    fn send_searches(&self)
+    {
+        let searching: Vec<(u32, String)> = {
+            let state = self.state.lock().unwrap();
+            state.channels.values()
+                .filter(|channel|
+                    matches!(channel.connection(), Connection::Searching))
+                .map(|channel| (channel.cid, channel.name.clone()))
+                .collect()
+        };
+
+        let version = message(
+            &Header::new(CA_PROTO_VERSION, 0, CA_MINOR_VERSION as u32, 0, 0),
+            &[]);
+        let mut datagrams = Vec::new();
+        let mut datagram = version.clone();
+        for (cid, name) in searching {
+            let search = message(
+                &Header::new(CA_PROTO_SEARCH,
+                    DONT_REPLY, CA_MINOR_VERSION as u32, cid, cid),
+                &string_payload(&name));
+            if datagram.len() + search.len() > MAX_UDP_SEND
+                &&  datagram.len() > version.len()
+            {
+                datagrams.push(std::mem::replace(&mut datagram, version.clone()));
+            }
+            datagram.extend_from_slice(&search);
+        }
+        if datagram.len() > version.len() {
+            datagrams.push(datagram);
+        }
+
+        for datagram in &datagrams {
+            for address in &self.config.addr_list {
+                let _ = self.udp.send_to(datagram, address);
+            }
+        }
+    }
+
+    fn search_reply(self: &Arc<Self>, datagram: &[u8], from: SocketAddr)
+    {
+        for reply in parse_datagram(datagram) {
+            let header = reply.header;
+            if header.command != CA_PROTO_SEARCH {
+                continue;
+            }
+
+            // The server address is in param1 unless this is all ones, in
+            // which case we use the address the reply came from.
+            let ip = match (header.param1, from) {
+                (0xFFFF_FFFF, SocketAddr::V4(from)) => *from.ip(),
+                (ip, _) => Ipv4Addr::from(ip),
+            };
+            let address = SocketAddr::from((ip, header.data_type));
+
+            let mut state = self.state.lock().unwrap();
+            if let Some(channel) = state.channels.get(&header.param2).cloned() {
+                let mut channel_state = channel.state.lock().unwrap();
+                if let Connection::Searching = channel_state.connection {
+                    let circuit = self.circuit(&mut state, address);
+                    channel_state.connection =
+                        Connection::Creating(circuit.clone());
+                    circuit.send(&message(
+                        &Header::new(CA_PROTO_CREATE_CHAN,
+                            0, 0, channel.cid, CA_MINOR_VERSION as u32),
+                        &string_payload(&channel.name)));
+                }
+            }
+        }
+    }
+
+    fn search_thread(self: Arc<Self>)
+    {
+        let mut buffer = vec![0; 0x10000];
+        let mut period = SEARCH_MIN;
+        let mut next_search = time::Instant::now();
+        while !self.shutdown.load(Ordering::Relaxed) {
+            let now = time::Instant::now();
+            if self.take_restart() {
+                period = SEARCH_MIN;
+                next_search = now;
+            }
+            if now >= next_search {
+                self.send_searches();
+                next_search = now + period;
+                period = (period * 2).min(SEARCH_MAX);
+            }
+
+            let timeout = next_search.saturating_duration_since(now)
+                .max(time::Duration::from_millis(1));
+            if let Err(error) = self.udp.set_read_timeout(Some(timeout)) {
+                self.report(CaError::Io(
+                    format!("Search timeout failed: {}", error)));
+            }
+            match self.udp.recv_from(&mut buffer) {
+                Ok((length, from)) => self.search_reply(&buffer[..length], from),
+                Err(error) => match error.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => { },
+                    _ => {
+                        self.report(CaError::Io(
+                            format!("Search receive failed: {}", error)));
+                        thread::sleep(period);
+                    },
+                },
+            }
+        }
+    }
+
+
+    // Stops the search thread and closes all circuits.  By now all channels
+    // have been cleared.
+    fn stop(&self)
+    {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.restart_search();
+        let circuits: Vec<Arc<Circuit>> =
+            self.state.lock().unwrap().circuits.values().cloned().collect();
+        for circuit in circuits {
+            circuit.close();
+        }
+    }
+
+
+    // - Circuits --------------------------------------------------------------
+
+    // Returns the circuit for the given server, creating it if necessary
+    fn circuit(self: &Arc<Self>, state: &mut ContextState, address: SocketAddr)
+        -> Arc<Circuit>
+    {
+        state.circuits.entry(address).or_insert_with(|| {
+            let circuit = Arc::new(Circuit::new(address));
+            let context = self.clone();
+            let thread_circuit = circuit.clone();
+            thread::spawn(move || context.circuit_thread(thread_circuit));
+            circuit
+        }).clone()
+    }
+
+    fn circuit_thread(self: Arc<Self>, circuit: Arc<Circuit>)
+    {
+        let stream = TcpStream::connect_timeout(&circuit.address, CONNECT_TIMEOUT)
+            .and_then(|stream| {
+                stream.set_nodelay(true)?;
+                SockRef::from(&stream).set_keepalive(true)?;
+                circuit.start(&stream)?;
+                Ok(stream)
+            });
+        match stream {
+            Ok(mut stream) =>
+                while let Ok(message) = read_message(&mut stream) {
+                    circuit.received();
+                    self.dispatch(&circuit, message);
+                },
+            Err(error) =>
+                self.report(CaError::Io(format!(
+                    "Unable to connect to {}: {}", circuit.address, error))),
+        }
+        self.circuit_down(&circuit);
+    }
+
+    fn circuit_down(&self, circuit: &Arc<Circuit>)
+    {
+        circuit.close();
+        let pending: Vec<PendingIo> = {
+            let mut state = circuit.state.lock().unwrap();
+            state.stream = None;
+            state.pending.drain().map(|(_, pending)| pending).collect()
+        };
+
+        let channels: Vec<Arc<NativeChannel>> = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(current) = state.circuits.get(&circuit.address) {
+                if Arc::ptr_eq(current, circuit) {
+                    state.circuits.remove(&circuit.address);
+                }
+            }
+            state.channels.values().filter(|channel|
+                match channel.connection() {
+                    Connection::Creating(current) |
+                    Connection::Connected { circuit: current, .. } =>
+                        Arc::ptr_eq(&current, circuit),
+                    _ => false,
+                }
+            ).cloned().collect()
+        };
+
+        for channel in channels {
+            self.disconnect(&channel);
+        }
+        for pending in pending {
+            pending.complete(ECA_DISCONN, 0, 0, &[]);
+        }
+    }
+
+    // Returns channel to searching, notifying the user if it was connected and
+    // failing any gets and puts still outstanding on it
+    fn disconnect(&self, channel: &NativeChannel)
+    {
+        let circuit = {
+            let mut state = channel.state.lock().unwrap();
+            match std::mem::replace(
+                &mut state.connection, Connection::Searching)
+            {
+                Connection::Connected { circuit, .. } => Some(circuit),
+                Connection::Creating(_) => None,
+                connection => {
+                    state.connection = connection;
+                    None
+                },
+            }
+        };
+        if let Some(circuit) = circuit {
+            channel.invoke_connect(CA_OP_CONN_DOWN);
+            for pending in circuit.take_channel_pending(channel) {
+                pending.complete(ECA_DISCONN, 0, 0, &[]);
+            }
+            self.restart_search();
+        }
+    }
+
+    fn lookup_channel(&self, cid: u32) -> Option<Arc<NativeChannel>>
+    {
+        self.state.lock().unwrap().channels.get(&cid).cloned()
+    }
+
+    fn dispatch(&self, circuit: &Arc<Circuit>, message: Message)
+    {
+        let header = message.header;
+        match header.command {
+            CA_PROTO_CREATE_CHAN =>
+                if let Some(channel) = self.lookup_channel(header.param1) {
+                    self.channel_connected(circuit, &channel, &header);
+                },
+            CA_PROTO_ACCESS_RIGHTS =>
+                if let Some(channel) = self.lookup_channel(header.param1) {
+                    channel.state.lock().unwrap().access = header.param2;
+                },
+            CA_PROTO_CREATE_CH_FAIL =>
+                if let Some(channel) = self.lookup_channel(header.param1) {
+                    self.disconnect(&channel);
+                },
+            CA_PROTO_SERVER_DISCONN =>
+                if let Some(channel) = self.lookup_channel(header.param1) {
+                    self.disconnect(&channel);
+                },
+            CA_PROTO_READ_NOTIFY | CA_PROTO_WRITE_NOTIFY =>
+                if let Some(pending) = circuit.take_pending(header.param2) {
+                    // A successful get must carry a value for its handler
+                    let status =
+                        if header.command == CA_PROTO_READ_NOTIFY  &&
+                           header.param1 == ECA_NORMAL  &&
+                           message.payload.is_empty()
+                        {
+                            ECA_BADCOUNT
+                        } else {
+                            header.param1
+                        };
+                    pending.complete(status,
+                        header.data_type, header.count, &message.payload);
+                },
+            CA_PROTO_EVENT_ADD => {
+                let subscription = self.state.lock().unwrap()
+                    .subscriptions.get(&header.param2).cloned();
+                // An empty payload confirms cancellation of the subscription
+                if let Some(subscription) = subscription {
+                    if !message.payload.is_empty() {
+                        subscription.deliver(header.param1,
+                            header.data_type, header.count, &message.payload);
+                    }
+                }
+            },
+            CA_PROTO_ERROR => self.error_response(circuit, &message),
+            _ => { },
+        }
+    }
+
+    fn channel_connected(
+        &self, circuit: &Arc<Circuit>, channel: &NativeChannel,
+        header: &Header)
+    {
+        {
+            let mut state = channel.state.lock().unwrap();
+            match &state.connection {
+                Connection::Creating(current)
+                    if Arc::ptr_eq(current, circuit) => { },
+                _ => return,
+            }
+            let sid = header.param2;
+            state.connection = Connection::Connected {
+                circuit: circuit.clone(),
+                sid,
+                field_type: header.data_type,
+                count: header.count,
+            };
+            // Reinstate any subscriptions
+            for subscription in &state.subscriptions {
+                circuit.send(&subscription.event_add(sid));
+            }
+        }
+        channel.invoke_connect(CA_OP_CONN_UP);
+    }
+
+    // The error payload contains the header of the failing request followed by
+    // an error message.  Failed gets and puts are completed with the error
+    // status and failed subscriptions are passed the status as an update, as
+    // libca does.  Anything else is reported to the error handler.
+    fn error_response(&self, circuit: &Circuit, message: &Message)
+    {
+        let request = parse_datagram(&message.payload);
+        let status = message.header.param2;
+        match request.first().map(|request| request.header) {
+            Some(Header { command, param2: ioid, .. })
+                if command == CA_PROTO_READ_NOTIFY  ||
+                   command == CA_PROTO_WRITE_NOTIFY =>
+                if let Some(pending) = circuit.take_pending(ioid) {
+                    pending.complete(status, 0, 0, &[]);
+                },
+            Some(Header { command: CA_PROTO_EVENT_ADD, param2: subid, .. }) => {
+                let subscription = self.state.lock().unwrap()
+                    .subscriptions.get(&subid).cloned();
+                if let Some(subscription) = subscription {
+                    subscription.deliver(status, 0, 0, &[]);
+                }
+            },
+            _ => {
+                let text = message.payload.get(16..).unwrap_or(&[]);
+                self.report(CaError::Server(status, format!(
+                    "CA error from {}: {}",
+                    circuit.address, payload_string(text))));
+            },
+        }
+    }
+
+
+    // - Channels and subscriptions --------------------------------------------
+
+    fn create_channel(
+        &self, name: String, on_connect: ConnectHandler, puser: UserPointer)
+        -> Arc<NativeChannel>
+    {
+        let channel = Arc::new(NativeChannel {
+            cid: self.next_id(),
+            name,
+            puser,
+            on_connect,
+            state: Mutex::new(ChannelState {
+                connection: Connection::Searching,
+                access: 0,
+                subscriptions: Vec::new(),
+            }),
+            callback: Mutex::new(()),
+        });
+        self.state.lock().unwrap().channels.insert(channel.cid, channel.clone());
+        self.restart_search();
+        channel
+    }
+
+    // Gets and puts still outstanding are failed before the channel is marked
+    // as cleared, so that their handlers can release what they hold
+    fn clear_channel(&self, channel: &NativeChannel)
+    {
+        self.state.lock().unwrap().channels.remove(&channel.cid);
+        if let Connection::Connected { circuit, .. } = channel.connection() {
+            for pending in circuit.take_channel_pending(channel) {
+                pending.complete(ECA_DISCONN, 0, 0, &[]);
+            }
+        }
+        let (connection, subscriptions) = {
+            let mut state = channel.state.lock().unwrap();
+            (std::mem::replace(&mut state.connection, Connection::Cleared),
+             std::mem::take(&mut state.subscriptions))
+        };
+        for subscription in subscriptions {
+            self.state.lock().unwrap()
+                .subscriptions.remove(&subscription.subid);
+            *subscription.active.lock().unwrap() = false;
+        }
+        if let Connection::Connected { circuit, sid, .. } = connection {
+            circuit.send(&message(
+                &Header::new(CA_PROTO_CLEAR_CHANNEL, 0, 0, sid, channel.cid),
+                &[]));
+        }
+        // Wait for any callback in progress to complete
+        drop(channel.callback.lock().unwrap());
+    }
+
+    fn create_subscription(
+        &self, channel: Arc<NativeChannel>, datatype: u16, count: u32,
+        mask: u16, handler: EventHandler, usr: UserPointer)
+        -> Arc<Subscription>
+    {
+        let subscription = Arc::new(Subscription {
+            subid: self.next_id(),
+            channel,
+            datatype, count, mask,
+            handler, usr,
+            active: Mutex::new(true),
+        });
+        self.state.lock().unwrap().subscriptions.insert(
+            subscription.subid, subscription.clone());
+
+        let mut state = subscription.channel.state.lock().unwrap();
+        state.subscriptions.push(subscription.clone());
+        if let Connection::Connected { circuit, sid, .. } = &state.connection {
+            circuit.send(&subscription.event_add(*sid));
+        }
+        drop(state);
+        subscription
+    }
+
+    fn clear_subscription(&self, subscription: &Arc<Subscription>)
+    {
+        self.state.lock().unwrap().subscriptions.remove(&subscription.subid);
+        {
+            let mut state = subscription.channel.state.lock().unwrap();
+            state.subscriptions.retain(|s| !Arc::ptr_eq(s, subscription));
+            if let Connection::Connected { circuit, sid, .. } =
+                &state.connection
+            {
+                circuit.send(&message(
+                    &Header::new(CA_PROTO_EVENT_CANCEL,
+                        subscription.datatype, subscription.count,
+                        *sid, subscription.subid),
+                    &[]));
+            }
+        }
+        // Wait for any callback in progress to complete
+        *subscription.active.lock().unwrap() = false;
+    }
+}
+
+
+
+
+// -----------------------------------------------------------------------------
+// Public context
+
+// A client context with its own search socket and circuits.  Channels created
+// through a context keep it alive; the background threads are stopped once the
+// last reference is dropped.
+pub struct Context {
+    inner: Arc<ContextInner>,
+}
+
+impl Context {
+    pub fn new(config: ClientConfig) -> io::Result<Arc<Context>>
+    {
+        let inner = Arc::new(ContextInner::new(config)?);
+        let search = inner.clone();
+        thread::spawn(move || search.search_thread());
+        Ok(Arc::new(Context { inner }))
+    }
+
+    // Addresses to which this context sends name searches
+    pub fn addr_list(&self) -> &[SocketAddr]
+    {
+        &self.inner.config.addr_list
+    }
+
+    // Uses this context for all channels subsequently created on this thread
+    // until the returned guard is dropped.
+    pub fn install(self: &Arc<Self>) -> BackendGuard
+    {
+        backend::install(self.clone())
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self)
+    {
+        self.inner.stop();
+    }
+}
+
+impl std::fmt::Debug for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result
+    {
+        f.debug_struct("Context")
+            .field("addr_list", &self.inner.config.addr_list)
+            .finish()
+    }
+}
+
+// The context used by default, configured from the environment
+static DEFAULT: OnceLock<Arc<Context>> = OnceLock::new();
+
+pub fn default_context() -> Arc<Context>
+{
+    DEFAULT.get_or_init(||
+        Context::new(ClientConfig::default())
+            .expect("Unable to create CA context")
+    ).clone()
+}
+
+
+// Recovers a new reference to the channel from its identifier
+unsafe fn channel_arc(id: ChanId) -> Arc<NativeChannel>
+{
+    let channel = id.0 as *const NativeChannel;
+    Arc::increment_strong_count(channel);
+    Arc::from_raw(channel)
+}
+
+unsafe fn channel_ref<'a>(id: ChanId) -> &'a NativeChannel
+{
+    &*(id.0 as *const NativeChannel)
+}
+
+// Returns the circuit and server id of a connected channel
+fn connected(channel: &NativeChannel) -> Option<(Arc<Circuit>, u32)>
+{
+    match channel.connection() {
+        Connection::Connected { circuit, sid, .. } => Some((circuit, sid)),
+        _ => None,
+    }
+}
+
+
+// Copies the value to be written into network byte order
+unsafe fn put_payload(datatype: c_long, count: c_ulong, value: *const c_void)
+    -> Option<Vec<u8>>
+{
+    let size = dbr_size_n(datatype as u16, count as usize)?;
+    let mut payload =
+        std::slice::from_raw_parts(value as *const u8, size).to_vec();
+    swap_dbr(datatype as u16, count as usize, &mut payload);
+    Some(payload)
+}
+
+
+impl Backend for Context {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int
+    {
+        let channel = self.inner.create_channel(
+            pv.to_string(), on_connect, UserPointer(usr));
+        *id = ChanId(Arc::into_raw(channel) as *const c_void);
+        ECA_NORMAL as c_int
+    }
+
+    fn clear_channel(&self, id: ChanId) -> c_int
+    {
+        let channel = unsafe { Arc::from_raw(id.0 as *const NativeChannel) };
+        self.inner.clear_channel(&channel);
+        ECA_NORMAL as c_int
+    }
+
+    fn field_type(&self, id: ChanId) -> c_short
+    {
+        match unsafe { channel_ref(id) }.connection() {
+            Connection::Connected { field_type, .. } => field_type as c_short,
+            _ => TYPENOTCONN,
+        }
+    }
+
+    fn element_count(&self, id: ChanId) -> c_ulong
+    {
+        match unsafe { channel_ref(id) }.connection() {
+            Connection::Connected { count, .. } => count as c_ulong,
+            _ => 0,
+        }
+    }
+
+    fn host_name(&self, id: ChanId) -> String
+    {
+        match unsafe { channel_ref(id) }.connection() {
+            Connection::Connected { circuit, .. } =>
+                circuit.address.to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn access_rights(&self, id: ChanId) -> u32
+    {
+        unsafe { channel_ref(id) }.state.lock().unwrap().access
+    }
+
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let channel = unsafe { channel_arc(id) };
+        match connected(&channel) {
+            Some((circuit, sid)) => {
+                let ioid = self.inner.next_id();
+                circuit.add_pending(ioid, PendingIo {
+                    channel, handler, usr: UserPointer(usr) });
+                circuit.send(&message(
+                    &Header::new(CA_PROTO_READ_NOTIFY,
+                        datatype as u16, count as u32, sid, ioid),
+                    &[]));
+                ECA_NORMAL as c_int
+            },
+            None => ECA_DISCONN as c_int,
+        }
+    }
+
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let channel = unsafe { channel_arc(id) };
+        let payload = match unsafe { put_payload(datatype, count, value) } {
+            Some(payload) => payload,
+            None => return ECA_BADTYPE as c_int,
+        };
+        match connected(&channel) {
+            Some((circuit, sid)) => {
+                let ioid = self.inner.next_id();
+                circuit.add_pending(ioid, PendingIo {
+                    channel, handler, usr: UserPointer(usr) });
+                circuit.send(&message(
+                    &Header::new(CA_PROTO_WRITE_NOTIFY,
+                        datatype as u16, count as u32, sid, ioid),
+                    &payload));
+                ECA_NORMAL as c_int
+            },
+            None => ECA_DISCONN as c_int,
+        }
+    }
+
+    fn array_put(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void) -> c_int
+    {
+        let channel = unsafe { channel_ref(id) };
+        let payload = match unsafe { put_payload(datatype, count, value) } {
+            Some(payload) => payload,
+            None => return ECA_BADTYPE as c_int,
+        };
+        match connected(channel) {
+            Some((circuit, sid)) => {
+                let ioid = self.inner.next_id();
+                circuit.send(&message(
+                    &Header::new(CA_PROTO_WRITE,
+                        datatype as u16, count as u32, sid, ioid),
+                    &payload));
+                ECA_NORMAL as c_int
+            },
+            None => ECA_DISCONN as c_int,
+        }
+    }
+
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int
+    {
+        let subscription = self.inner.create_subscription(
+            unsafe { channel_arc(id) }, datatype as u16, count as u32,
+            mask as u16, handler, UserPointer(usr));
+        *evid = EvId(Arc::into_raw(subscription) as *const c_void);
+        ECA_NORMAL as c_int
+    }
+
+    fn clear_subscription(&self, evid: EvId) -> c_int
+    {
+        let subscription =
+            unsafe { Arc::from_raw(evid.0 as *const Subscription) };
+        self.inner.clear_subscription(&subscription);
+        ECA_NORMAL as c_int
+    }
+
+    // Messages are written as soon as they are queued, so there is nothing to
+    // flush
+    fn flush_io(&self) -> c_int
+    {
+        ECA_NORMAL as c_int
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    use crate::caget::{CA, CaCtrl};
+    use crate::caput::CaPut;
+    use crate::camonitor::CaMonitor;
+    use crate::db_access::StatusSeverity;
+    use crate::dbr::FloatCtrl;
+    use crate::test_ioc::TestIoc;
+
+    const DB: &str = r#"
+        record(ao, "TEST:AO") {
+            field(VAL, "1.5")
+            field(EGU, "mm")
+            field(PREC, "2")
+        }
+        record(stringout, "TEST:STRING") { field(VAL, "hello") }
+        record(waveform, "TEST:WAVEFORM") {
+            field(FTVL, "LONG")
+            field(NELM, "4")
+        }
+    "#;
+
+    #[test]
+    fn get()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        block_on(async {
+            assert_eq!(f64::caget("TEST:AO").await, 1.5);
+            assert_eq!(String::caget("TEST:STRING").await, "hello");
+            assert_eq!(Vec::<i32>::caget("TEST:WAVEFORM").await, [0; 4]);
+            let (_value, _alarm, CaCtrl(ctrl)) =
+                <(f64, StatusSeverity, CaCtrl<FloatCtrl<f64>>)>
+                    ::caget("TEST:AO").await;
+            assert_eq!(ctrl.units, "mm");
+            assert_eq!(ctrl.precision, 2);
+        });
+    }
+
+    #[test]
+    fn put()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        block_on(async {
+            f64::caput("TEST:AO", 2.5).await;
+            assert_eq!(f64::caget("TEST:AO").await, 2.5);
+            Vec::<i32>::caput("TEST:WAVEFORM", vec![1, 2, 3, 4]).await;
+            assert_eq!(Vec::<i32>::caget("TEST:WAVEFORM").await, [1, 2, 3, 4]);
+        });
+    }
+
+    #[test]
+    fn monitor()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        block_on(async {
+            let mut monitor = f64::camonitor("TEST:AO").await;
+            assert_eq!(monitor.next().await, Some(1.5));
+            f64::caput("TEST:AO", 3.0).await;
+            assert_eq!(monitor.next().await, Some(3.0));
+            f64::caput("TEST:AO", 4.0).await;
+            assert_eq!(monitor.next().await, Some(4.0));
+        });
+    }
+}
@@ -0,0 +1,378 @@
+// Channel Access wire protocol
+//
+// Message headers and DBR payload layouts as they appear on the network.  All
+// multi-byte quantities are big endian on the wire, and all payloads are padded
+// to a multiple of 8 bytes.
+
+use std::io;
+use std::io::Read;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+
+pub const CA_MINOR_VERSION: u16 = 13;
+pub const CA_SERVER_PORT: u16 = 5064;
+pub const CA_REPEATER_PORT: u16 = 5065;
+
+// Largest datagram we will send when searching
+#[allow(dead_code)]
+pub const MAX_UDP_SEND: usize = 1024;
+
+// Parses an address list as given in EPICS_CA_ADDR_LIST, using the given port
+// for entries which do not name one.  Entries which cannot be resolved to an
+// IPv4 address are ignored.
+pub fn parse_addr_list(list: &str, port: u16) -> Vec<SocketAddr>
+{
+    list.split_whitespace().filter_map(|entry| {
+        let entry =
+            if entry.contains(':') {
+                entry.to_owned()
+            } else {
+                format!("{}:{}", entry, port)
+            };
+        entry.to_socket_addrs().ok()?.find(SocketAddr::is_ipv4)
+    }).collect()
+}
+
+
+#[allow(dead_code)]
+pub mod command {
+    pub const CA_PROTO_VERSION: u16 = 0;
+    pub const CA_PROTO_EVENT_ADD: u16 = 1;
+    pub const CA_PROTO_EVENT_CANCEL: u16 = 2;
+    pub const CA_PROTO_WRITE: u16 = 4;
+    pub const CA_PROTO_SEARCH: u16 = 6;
+    pub const CA_PROTO_EVENTS_OFF: u16 = 8;
+    pub const CA_PROTO_EVENTS_ON: u16 = 9;
+    pub const CA_PROTO_ERROR: u16 = 11;
+    pub const CA_PROTO_CLEAR_CHANNEL: u16 = 12;
+    pub const CA_PROTO_RSRV_IS_UP: u16 = 13;
+    pub const CA_PROTO_NOT_FOUND: u16 = 14;
+    pub const CA_PROTO_READ_NOTIFY: u16 = 15;
+    pub const CA_PROTO_REPEATER_CONFIRM: u16 = 17;
+    pub const CA_PROTO_CREATE_CHAN: u16 = 18;
+    pub const CA_PROTO_WRITE_NOTIFY: u16 = 19;
+    pub const CA_PROTO_CLIENT_NAME: u16 = 20;
+    pub const CA_PROTO_HOST_NAME: u16 = 21;
+    pub const CA_PROTO_ACCESS_RIGHTS: u16 = 22;
+    pub const CA_PROTO_ECHO: u16 = 23;
+    pub const CA_PROTO_CREATE_CH_FAIL: u16 = 26;
+    pub const CA_PROTO_SERVER_DISCONN: u16 = 27;
+}
+
+// Flag in data_type field of CA_PROTO_SEARCH asking for no reply on failure
+#[allow(dead_code)]
+pub const DONT_REPLY: u16 = 5;
+
+// Access rights bits returned by CA_PROTO_ACCESS_RIGHTS
+pub const CA_ACCESS_READ: u32 = 1;
+pub const CA_ACCESS_WRITE: u32 = 2;
+
+// Status codes returned in CA responses, as defined in caerr.h
+pub mod status {
+    pub const ECA_NORMAL: u32 = 1;
+    pub const ECA_BADTYPE: u32 = 114;
+    pub const ECA_GETFAIL: u32 = 152;
+    pub const ECA_PUTFAIL: u32 = 160;
+    pub const ECA_BADCOUNT: u32 = 176;
+    pub const ECA_BADSTR: u32 = 186;
+    pub const ECA_DISCONN: u32 = 192;
+    pub const ECA_NORDACCESS: u32 = 368;
+    pub const ECA_NOWTACCESS: u32 = 376;
+}
+
+
+// -----------------------------------------------------------------------------
+// Message headers
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Header {
+    pub command: u16,
+    pub data_type: u16,
+    pub count: u32,
+    pub param1: u32,
+    pub param2: u32,
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub header: Header,
+    pub payload: Vec<u8>,
+}
+
+impl Header {
+    pub fn new(command: u16, data_type: u16, count: u32,
+        param1: u32, param2: u32) -> Header
+    {
+        Header { command, data_type, count, param1, param2 }
+    }
+}
+
+
+fn padded_length(length: usize) -> usize
+{
+    (length + 7) & !7
+}
+
+// Appends a complete message to buffer, padding the payload as required and
+// using the extended header format for large payloads.
+pub fn put_message(buffer: &mut Vec<u8>, header: &Header, payload: &[u8])
+{
+    let size = padded_length(payload.len());
+    buffer.extend_from_slice(&header.command.to_be_bytes());
+    if size >= 0xFFFF  ||  header.count >= 0xFFFF {
+        buffer.extend_from_slice(&0xFFFFu16.to_be_bytes());
+        buffer.extend_from_slice(&header.data_type.to_be_bytes());
+        buffer.extend_from_slice(&0u16.to_be_bytes());
+        buffer.extend_from_slice(&header.param1.to_be_bytes());
+        buffer.extend_from_slice(&header.param2.to_be_bytes());
+        buffer.extend_from_slice(&(size as u32).to_be_bytes());
+        buffer.extend_from_slice(&header.count.to_be_bytes());
+    } else {
+        buffer.extend_from_slice(&(size as u16).to_be_bytes());
+        buffer.extend_from_slice(&header.data_type.to_be_bytes());
+        buffer.extend_from_slice(&(header.count as u16).to_be_bytes());
+        buffer.extend_from_slice(&header.param1.to_be_bytes());
+        buffer.extend_from_slice(&header.param2.to_be_bytes());
+    }
+    buffer.extend_from_slice(payload);
+    buffer.resize(buffer.len() + size - payload.len(), 0);
+}
+
+pub fn message(header: &Header, payload: &[u8]) -> Vec<u8>
+{
+    let mut buffer = Vec::new();
+    put_message(&mut buffer, header, payload);
+    buffer
+}
+
+// Strings in payloads are null terminated and then padded
+#[allow(dead_code)]
+pub fn string_payload(string: &str) -> Vec<u8>
+{
+    let mut payload = string.as_bytes().to_vec();
+    payload.push(0);
+    payload
+}
+
+pub fn payload_string(payload: &[u8]) -> String
+{
+    let string = payload.split(|x| *x == 0).next().unwrap_or(payload);
+    String::from_utf8_lossy(string).into_owned()
+}
+
+
+fn be_u16(bytes: &[u8]) -> u16
+{
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be_u32(bytes: &[u8]) -> u32
+{
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Parses a header from the start of buffer, returning the header, the size of
+// the header, and the size of the payload.  Returns None if the buffer is too
+// short to hold a complete header.
+fn parse_header(buffer: &[u8]) -> Option<(Header, usize, usize)>
+{
+    if buffer.len() < 16 {
+        return None;
+    }
+    let mut header = Header {
+        command: be_u16(&buffer[0..]),
+        data_type: be_u16(&buffer[4..]),
+        count: be_u16(&buffer[6..]) as u32,
+        param1: be_u32(&buffer[8..]),
+        param2: be_u32(&buffer[12..]),
+    };
+    let size = be_u16(&buffer[2..]);
+    if size == 0xFFFF  &&  header.count == 0 {
+        if buffer.len() < 24 {
+            return None;
+        }
+        header.count = be_u32(&buffer[20..]);
+        Some((header, 24, be_u32(&buffer[16..]) as usize))
+    } else {
+        Some((header, 16, size as usize))
+    }
+}
+
+// Reads a single message from a stream
+pub fn read_message(stream: &mut impl Read) -> io::Result<Message>
+{
+    let mut buffer = [0; 24];
+    stream.read_exact(&mut buffer[..16])?;
+    let (header, _, size) = match parse_header(&buffer[..16]) {
+        Some(result) => result,
+        None => {
+            // Extended header, need to read the rest
+            stream.read_exact(&mut buffer[16..])?;
+            parse_header(&buffer).unwrap()
+        },
+    };
+    let mut payload = vec![0; size];
+    stream.read_exact(&mut payload)?;
+    Ok(Message { header, payload })
+}
+
+// Splits a datagram into its component messages, ignoring any truncated message
+// at the end.
+pub fn parse_datagram(mut datagram: &[u8]) -> Vec<Message>
+{
+    let mut messages = Vec::new();
+    while let Some((header, header_size, size)) = parse_header(datagram) {
+        if datagram.len() < header_size + size {
+            break;
+        }
+        let payload = datagram[header_size..header_size + size].to_vec();
+        messages.push(Message { header, payload });
+        datagram = &datagram[header_size + size..];
+    }
+    messages
+}
+
+
+// -----------------------------------------------------------------------------
+// DBR layouts
+//
+// Each DBR type is a fixed header followed by an array of values.  For byte
+// order conversion we describe each header as a list of (field size, repeat
+// count) pairs and each value as a single such pair.
+
+pub type Fields = &'static [(usize, usize)];
+
+pub struct DbrLayout {
+    pub header: Fields,
+    pub value: (usize, usize),
+}
+
+// Fields common to all but the plain types
+const STS: (usize, usize) = (2, 2);
+const STAMP: (usize, usize) = (4, 2);
+const UNITS: (usize, usize) = (1, 8);
+const PRECISION: (usize, usize) = (2, 2);       // Precision and padding
+const ENUM_STRINGS: (usize, usize) = (1, 16 * 26);
+
+// The seven basic value types in order of their type codes
+const VALUES: [(usize, usize); 7] = [
+    (1, 40),    // STRING
+    (2, 1),     // SHORT
+    (4, 1),     // FLOAT
+    (2, 1),     // ENUM
+    (1, 1),     // CHAR
+    (4, 1),     // LONG
+    (8, 1),     // DOUBLE
+];
+
+const HEADERS: [[Fields; 7]; 5] = [
+    // Plain
+    [&[], &[], &[], &[], &[], &[], &[]],
+    // STS
+    [&[STS], &[STS], &[STS], &[STS], &[STS, (1, 1)], &[STS], &[STS, (4, 1)]],
+    // TIME
+    [
+        &[STS, STAMP],
+        &[STS, STAMP, (2, 1)],
+        &[STS, STAMP],
+        &[STS, STAMP, (2, 1)],
+        &[STS, STAMP, (2, 1), (1, 1)],
+        &[STS, STAMP],
+        &[STS, STAMP, (4, 1)],
+    ],
+    // GR
+    [
+        &[STS],
+        &[STS, UNITS, (2, 6)],
+        &[STS, PRECISION, UNITS, (4, 6)],
+        &[STS, (2, 1), ENUM_STRINGS],
+        &[STS, UNITS, (1, 6), (1, 1)],
+        &[STS, UNITS, (4, 6)],
+        &[STS, PRECISION, UNITS, (8, 6)],
+    ],
+    // CTRL
+    [
+        &[STS],
+        &[STS, UNITS, (2, 8)],
+        &[STS, PRECISION, UNITS, (4, 8)],
+        &[STS, (2, 1), ENUM_STRINGS],
+        &[STS, UNITS, (1, 8), (1, 1)],
+        &[STS, UNITS, (4, 8)],
+        &[STS, PRECISION, UNITS, (8, 8)],
+    ],
+];
+
+pub fn dbr_layout(datatype: u16) -> Option<DbrLayout>
+{
+    let datatype = datatype as usize;
+    if datatype < 35 {
+        Some(DbrLayout {
+            header: HEADERS[datatype / 7][datatype % 7],
+            value: VALUES[datatype % 7],
+        })
+    } else {
+        None
+    }
+}
+
+fn fields_size(fields: Fields) -> usize
+{
+    fields.iter().map(|(size, repeat)| size * repeat).sum()
+}
+
+// Computes the size of a DBR with the given number of elements, or None if the
+// datatype is not recognised.  As for dbr_size_n a count of zero is treated as
+// a single element.
+pub fn dbr_size_n(datatype: u16, count: usize) -> Option<usize>
+{
+    dbr_layout(datatype).map(|layout| {
+        let (size, repeat) = layout.value;
+        fields_size(layout.header) + size * repeat * count.max(1)
+    })
+}
+
+fn swap_fields(buffer: &mut [u8], fields: &[(usize, usize)]) -> usize
+{
+    let mut offset = 0;
+    for &(size, repeat) in fields {
+        for _ in 0..repeat {
+            if size > 1  &&  cfg!(target_endian = "little") {
+                buffer[offset..offset + size].reverse();
+            }
+            offset += size;
+        }
+    }
+    offset
+}
+
+// Converts a DBR between network and host byte order in place.  The conversion
+// is its own inverse.  Returns false if the datatype is not recognised or the
+// buffer is too short.
+pub fn swap_dbr(datatype: u16, count: usize, buffer: &mut [u8]) -> bool
+{
+    match (dbr_layout(datatype), dbr_size_n(datatype, count)) {
+        (Some(layout), Some(size)) if size <= buffer.len() => {
+            let offset = swap_fields(buffer, layout.header);
+            let (size, repeat) = layout.value;
+            let value = [(size, repeat * count.max(1))];
+            swap_fields(&mut buffer[offset..], &value);
+            true
+        },
+        _ => false,
+    }
+}
+
+// Copies a DBR payload into suitably aligned storage in host byte order.  If
+// the payload is shorter than expected, as can happen for empty arrays, the
+// rest is zero filled.
+pub fn dbr_to_host(datatype: u16, count: u32, payload: &[u8]) -> Option<Vec<u64>>
+{
+    let size = dbr_size_n(datatype, count as usize)?;
+    let mut buffer = vec![0u64; size.div_ceil(8)];
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, size) };
+    let length = size.min(payload.len());
+    bytes[..length].copy_from_slice(&payload[..length]);
+    swap_dbr(datatype, count as usize, bytes);
+    Some(buffer)
+}
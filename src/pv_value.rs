@@ -0,0 +1,566 @@
+// Complete state of a PV: value, alarm, timestamp and control metadata
+//
+// This is what a server publishes, and it can be encoded into any of the DBR
+// formats on the wire, converting the value as required.  Values received from
+// the wire can be decoded back into this form.
+
+use std::time::SystemTime;
+
+use crate::db_access::{StatusSeverity, CtrlLimits, EpicsTimeStamp};
+use crate::db_access::{
+    MAX_STRING_SIZE, MAX_UNITS_SIZE, MAX_ENUM_STRING_SIZE, MAX_ENUM_STATES};
+use crate::dbr::{CaEnum, FixedCtrl, FloatCtrl, from_raw_stamp, to_raw_stamp};
+use crate::caunion::{CaUnionVec, BasicDbrType};
+
+
+// DBR classes in order of their type codes, GR is the remaining class
+const CLASS_PLAIN: u16 = 0;
+const CLASS_STS: u16 = 1;
+const CLASS_TIME: u16 = 2;
+const CLASS_CTRL: u16 = 4;
+
+
+// Control metadata held in a form which can be converted to any of the DBR
+// control formats.
+#[derive(Clone, Debug)]
+pub struct PvCtrl {
+    pub units: String,
+    pub precision: i16,
+    pub limits: CtrlLimits<f64>,
+    pub enum_strings: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PvValue {
+    pub value: CaUnionVec,
+    pub status: StatusSeverity,
+    pub timestamp: SystemTime,
+    pub ctrl: PvCtrl,
+}
+
+
+fn map_limits<T, U>(limits: &CtrlLimits<T>, f: impl Fn(T) -> U)
+    -> CtrlLimits<U>
+    where T: Copy + Send, U: Copy + Send
+{
+    CtrlLimits {
+        upper_disp_limit: f(limits.upper_disp_limit),
+        lower_disp_limit: f(limits.lower_disp_limit),
+        upper_alarm_limit: f(limits.upper_alarm_limit),
+        upper_warning_limit: f(limits.upper_warning_limit),
+        lower_warning_limit: f(limits.lower_warning_limit),
+        lower_alarm_limit: f(limits.lower_alarm_limit),
+        upper_ctrl_limit: f(limits.upper_ctrl_limit),
+        lower_ctrl_limit: f(limits.lower_ctrl_limit),
+    }
+}
+
+impl Default for PvCtrl {
+    fn default() -> PvCtrl
+    {
+        PvCtrl {
+            units: String::new(),
+            precision: 0,
+            limits: CtrlLimits {
+                upper_disp_limit: 0.0, lower_disp_limit: 0.0,
+                upper_alarm_limit: 0.0, upper_warning_limit: 0.0,
+                lower_warning_limit: 0.0, lower_alarm_limit: 0.0,
+                upper_ctrl_limit: 0.0, lower_ctrl_limit: 0.0,
+            },
+            enum_strings: Vec::new(),
+        }
+    }
+}
+
+impl<T> From<FixedCtrl<T>> for PvCtrl where T: Copy + Send + Into<f64> {
+    fn from(ctrl: FixedCtrl<T>) -> PvCtrl
+    {
+        PvCtrl {
+            units: ctrl.units,
+            limits: map_limits(&ctrl.limits, T::into),
+            .. PvCtrl::default()
+        }
+    }
+}
+
+impl<T> From<FloatCtrl<T>> for PvCtrl where T: Copy + Send + Into<f64> {
+    fn from(ctrl: FloatCtrl<T>) -> PvCtrl
+    {
+        PvCtrl {
+            units: ctrl.units,
+            precision: ctrl.precision,
+            limits: map_limits(&ctrl.limits, T::into),
+            .. PvCtrl::default()
+        }
+    }
+}
+
+// Enumeration state strings
+impl From<Vec<String>> for PvCtrl {
+    fn from(enum_strings: Vec<String>) -> PvCtrl
+    {
+        PvCtrl { enum_strings, .. PvCtrl::default() }
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Value conversion
+
+pub fn union_type(value: &CaUnionVec) -> BasicDbrType
+{
+    match value {
+        CaUnionVec::CaString(_) => BasicDbrType::DbrString,
+        CaUnionVec::CaEnum(_)   => BasicDbrType::DbrEnum,
+        CaUnionVec::CaChar(_)   => BasicDbrType::DbrChar,
+        CaUnionVec::CaShort(_)  => BasicDbrType::DbrShort,
+        CaUnionVec::CaLong(_)   => BasicDbrType::DbrLong,
+        CaUnionVec::CaFloat(_)  => BasicDbrType::DbrFloat,
+        CaUnionVec::CaDouble(_) => BasicDbrType::DbrDouble,
+    }
+}
+
+pub fn union_len(value: &CaUnionVec) -> usize
+{
+    match value {
+        CaUnionVec::CaString(v) => v.len(),
+        CaUnionVec::CaEnum(v)   => v.len(),
+        CaUnionVec::CaChar(v)   => v.len(),
+        CaUnionVec::CaShort(v)  => v.len(),
+        CaUnionVec::CaLong(v)   => v.len(),
+        CaUnionVec::CaFloat(v)  => v.len(),
+        CaUnionVec::CaDouble(v) => v.len(),
+    }
+}
+
+fn to_numbers(value: &CaUnionVec, ctrl: &PvCtrl) -> Option<Vec<f64>>
+{
+    match value {
+        CaUnionVec::CaString(v) => v.iter().map(|s| {
+            let s = s.trim();
+            match ctrl.enum_strings.iter().position(|e| e == s) {
+                Some(index) => Some(index as f64),
+                None => s.parse().ok(),
+            }
+        }).collect(),
+        CaUnionVec::CaEnum(v)   => Some(v.iter().map(|e| e.0 as f64).collect()),
+        CaUnionVec::CaChar(v)   => Some(v.iter().map(|&x| x as f64).collect()),
+        CaUnionVec::CaShort(v)  => Some(v.iter().map(|&x| x as f64).collect()),
+        CaUnionVec::CaLong(v)   => Some(v.iter().map(|&x| x as f64).collect()),
+        CaUnionVec::CaFloat(v)  => Some(v.iter().map(|&x| x as f64).collect()),
+        CaUnionVec::CaDouble(v) => Some(v.clone()),
+    }
+}
+
+fn to_strings(value: &CaUnionVec, ctrl: &PvCtrl) -> Vec<String>
+{
+    let precision = ctrl.precision.max(0) as usize;
+    match value {
+        CaUnionVec::CaString(v) => v.clone(),
+        CaUnionVec::CaEnum(v)   => v.iter().map(|e|
+            match ctrl.enum_strings.get(e.0 as usize) {
+                Some(label) => label.clone(),
+                None => e.0.to_string(),
+            }).collect(),
+        CaUnionVec::CaChar(v)   => v.iter().map(u8::to_string).collect(),
+        CaUnionVec::CaShort(v)  => v.iter().map(i16::to_string).collect(),
+        CaUnionVec::CaLong(v)   => v.iter().map(i32::to_string).collect(),
+        CaUnionVec::CaFloat(v)  =>
+            v.iter().map(|x| format!("{:.*}", precision, x)).collect(),
+        CaUnionVec::CaDouble(v) =>
+            v.iter().map(|x| format!("{:.*}", precision, x)).collect(),
+    }
+}
+
+// Converts value to the requested type, returns None if this is not possible,
+// for instance if a string cannot be parsed as a number or a number is out of
+// range of an integer type.  This is used for puts.
+pub fn convert(value: &CaUnionVec, target: BasicDbrType, ctrl: &PvCtrl)
+    -> Option<CaUnionVec>
+{
+    convert_numbers(value, target, ctrl, true)
+}
+
+// As for convert, but numbers out of range of an integer type are saturated
+// rather than rejected, as they are when a record is read as another type.
+fn convert_saturating(
+    value: &CaUnionVec, target: BasicDbrType, ctrl: &PvCtrl)
+    -> Option<CaUnionVec>
+{
+    convert_numbers(value, target, ctrl, false)
+}
+
+fn convert_numbers(
+    value: &CaUnionVec, target: BasicDbrType, ctrl: &PvCtrl, checked: bool)
+    -> Option<CaUnionVec>
+{
+    if union_type(value) == target {
+        return Some(value.clone());
+    }
+
+    // Integers are truncated towards zero, and if checked must then be in
+    // range.  NaN is never in range.
+    macro_rules! integers {
+        ($type:ty) => {
+            to_numbers(value, ctrl)?.into_iter().map(|x| {
+                let x = x.trunc();
+                let in_range =
+                    x >= <$type>::MIN as f64  &&  x <= <$type>::MAX as f64;
+                if checked && !in_range { None } else { Some(x as $type) }
+            }).collect::<Option<Vec<$type>>>()?
+        }
+    }
+    Some(match target {
+        BasicDbrType::DbrString => CaUnionVec::CaString(to_strings(value, ctrl)),
+        BasicDbrType::DbrEnum   =>
+            CaUnionVec::CaEnum(integers!(u16).into_iter().map(CaEnum).collect()),
+        BasicDbrType::DbrChar   => CaUnionVec::CaChar(integers!(u8)),
+        BasicDbrType::DbrShort  => CaUnionVec::CaShort(integers!(i16)),
+        BasicDbrType::DbrLong   => CaUnionVec::CaLong(integers!(i32)),
+        BasicDbrType::DbrFloat  => CaUnionVec::CaFloat(
+            to_numbers(value, ctrl)?.into_iter().map(|x| x as f32).collect()),
+        BasicDbrType::DbrDouble =>
+            CaUnionVec::CaDouble(to_numbers(value, ctrl)?),
+    })
+}
+
+// Truncates or zero pads value to the given length
+pub fn resize(value: &mut CaUnionVec, length: usize)
+{
+    match value {
+        CaUnionVec::CaString(v) => v.resize(length, String::new()),
+        CaUnionVec::CaEnum(v)   => v.resize(length, CaEnum(0)),
+        CaUnionVec::CaChar(v)   => v.resize(length, 0),
+        CaUnionVec::CaShort(v)  => v.resize(length, 0),
+        CaUnionVec::CaLong(v)   => v.resize(length, 0),
+        CaUnionVec::CaFloat(v)  => v.resize(length, 0.0),
+        CaUnionVec::CaDouble(v) => v.resize(length, 0.0),
+    }
+}
+
+fn basic_type(code: u16) -> BasicDbrType
+{
+    match code {
+        0 => BasicDbrType::DbrString,
+        1 => BasicDbrType::DbrShort,
+        2 => BasicDbrType::DbrFloat,
+        3 => BasicDbrType::DbrEnum,
+        4 => BasicDbrType::DbrChar,
+        5 => BasicDbrType::DbrLong,
+        _ => BasicDbrType::DbrDouble,
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Encoding to the wire
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn pad(&mut self, length: usize) { self.0.resize(self.0.len() + length, 0) }
+    fn i16(&mut self, x: i16) { self.0.extend_from_slice(&x.to_be_bytes()) }
+    fn u32(&mut self, x: u32) { self.0.extend_from_slice(&x.to_be_bytes()) }
+
+    fn string(&mut self, string: &str, length: usize)
+    {
+        let bytes = string.as_bytes();
+        let count = bytes.len().min(length - 1);
+        self.0.extend_from_slice(&bytes[..count]);
+        self.pad(length - count);
+    }
+
+    // Writes a number using the representation of the given basic type
+    fn number(&mut self, basic: BasicDbrType, x: f64)
+    {
+        match basic {
+            BasicDbrType::DbrChar   => self.0.push(x as u8),
+            BasicDbrType::DbrShort  => self.i16(x as i16),
+            BasicDbrType::DbrLong   => self.u32(x as i32 as u32),
+            BasicDbrType::DbrFloat  =>
+                self.0.extend_from_slice(&(x as f32).to_be_bytes()),
+            BasicDbrType::DbrDouble => self.0.extend_from_slice(&x.to_be_bytes()),
+            _ => self.i16(x as i16),
+        }
+    }
+
+    fn values(&mut self, value: &CaUnionVec)
+    {
+        match value {
+            CaUnionVec::CaString(v) =>
+                v.iter().for_each(|s| self.string(s, MAX_STRING_SIZE)),
+            CaUnionVec::CaEnum(v) =>
+                v.iter().for_each(|e| self.0.extend_from_slice(&e.0.to_be_bytes())),
+            CaUnionVec::CaChar(v) => self.0.extend_from_slice(v),
+            CaUnionVec::CaShort(v) => v.iter().for_each(|&x| self.i16(x)),
+            CaUnionVec::CaLong(v) => v.iter().for_each(|&x| self.u32(x as u32)),
+            CaUnionVec::CaFloat(v) => v.iter().for_each(|x|
+                self.0.extend_from_slice(&x.to_be_bytes())),
+            CaUnionVec::CaDouble(v) => v.iter().for_each(|x|
+                self.0.extend_from_slice(&x.to_be_bytes())),
+        }
+    }
+}
+
+impl PvValue {
+    pub fn new(value: CaUnionVec) -> PvValue
+    {
+        PvValue {
+            value,
+            status: StatusSeverity { status: 0, severity: 0 },
+            timestamp: SystemTime::now(),
+            ctrl: PvCtrl::default(),
+        }
+    }
+
+    pub fn native_type(&self) -> BasicDbrType
+    {
+        union_type(&self.value)
+    }
+
+    pub fn native_count(&self) -> usize
+    {
+        union_len(&self.value)
+    }
+
+    // Encodes the value in network byte order in the format for the given DBR
+    // type code.  A count of zero requests the current length of the value.
+    // Returns the encoded DBR and the number of elements actually encoded, or
+    // None if the conversion is not possible.
+    pub fn encode(&self, datatype: u16, count: usize) -> Option<(Vec<u8>, usize)>
+    {
+        if datatype >= 35 {
+            return None;
+        }
+        let class = datatype / 7;
+        let basic = basic_type(datatype % 7);
+
+        let mut value = convert_saturating(&self.value, basic, &self.ctrl)?;
+        let count = if count == 0 { union_len(&value) } else { count };
+        resize(&mut value, count);
+
+        let mut writer = Writer(Vec::new());
+        if class != CLASS_PLAIN {
+            writer.i16(self.status.status);
+            writer.i16(self.status.severity);
+        }
+        match (class, basic) {
+            (CLASS_PLAIN, _) => { },
+            (CLASS_STS, BasicDbrType::DbrChar) => writer.pad(1),
+            (CLASS_STS, BasicDbrType::DbrDouble) => writer.pad(4),
+            (CLASS_STS, _) => { },
+            (CLASS_TIME, _) => {
+                let stamp = to_raw_stamp(self.timestamp);
+                writer.u32(stamp.secs);
+                writer.u32(stamp.nsec);
+                match basic {
+                    BasicDbrType::DbrShort | BasicDbrType::DbrEnum =>
+                        writer.pad(2),
+                    BasicDbrType::DbrChar => writer.pad(3),
+                    BasicDbrType::DbrDouble => writer.pad(4),
+                    _ => { },
+                }
+            },
+            (_, BasicDbrType::DbrString) => { },
+            (_, BasicDbrType::DbrEnum) => {
+                let strings = &self.ctrl.enum_strings;
+                let count = strings.len().min(MAX_ENUM_STATES);
+                writer.i16(count as i16);
+                for string in &strings[..count] {
+                    writer.string(string, MAX_ENUM_STRING_SIZE);
+                }
+                writer.pad(
+                    (MAX_ENUM_STATES - count) * MAX_ENUM_STRING_SIZE);
+            },
+            (_, _) => {
+                if let BasicDbrType::DbrFloat | BasicDbrType::DbrDouble = basic {
+                    writer.i16(self.ctrl.precision);
+                    writer.pad(2);
+                }
+                writer.string(&self.ctrl.units, MAX_UNITS_SIZE);
+                let limits = &self.ctrl.limits;
+                writer.number(basic, limits.upper_disp_limit);
+                writer.number(basic, limits.lower_disp_limit);
+                writer.number(basic, limits.upper_alarm_limit);
+                writer.number(basic, limits.upper_warning_limit);
+                writer.number(basic, limits.lower_warning_limit);
+                writer.number(basic, limits.lower_alarm_limit);
+                if class == CLASS_CTRL {
+                    writer.number(basic, limits.upper_ctrl_limit);
+                    writer.number(basic, limits.lower_ctrl_limit);
+                }
+                if let BasicDbrType::DbrChar = basic {
+                    writer.pad(1);
+                }
+            },
+        }
+        writer.values(&value);
+        Some((writer.0, count))
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Decoding from the wire
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> Option<&'a [u8]>
+    {
+        if self.0.len() < length {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()>
+    {
+        self.bytes(length).map(|_| ())
+    }
+
+    fn i16(&mut self) -> Option<i16>
+    {
+        self.bytes(2).map(|b| i16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32>
+    {
+        self.bytes(4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self, length: usize) -> Option<String>
+    {
+        let bytes = self.bytes(length)?;
+        let string = bytes.split(|x| *x == 0).next().unwrap_or(bytes);
+        Some(String::from_utf8_lossy(string).into_owned())
+    }
+
+    fn number(&mut self, basic: BasicDbrType) -> Option<f64>
+    {
+        let mut value = CaUnionVec::CaDouble(Vec::new());
+        self.values(basic, 1, &mut value)?;
+        to_numbers(&value, &PvCtrl::default())?.first().cloned()
+    }
+
+    fn values(&mut self, basic: BasicDbrType, count: usize,
+        value: &mut CaUnionVec) -> Option<()>
+    {
+        macro_rules! values {
+            ($variant:ident, $size:expr, $convert:expr) => {
+                CaUnionVec::$variant(self.bytes($size * count)?
+                    .chunks($size).map($convert).collect())
+            }
+        }
+        *value = match basic {
+            BasicDbrType::DbrString => CaUnionVec::CaString(
+                (0..count).map(|_| self.string(MAX_STRING_SIZE))
+                    .collect::<Option<_>>()?),
+            BasicDbrType::DbrEnum => values!(CaEnum, 2,
+                |b| CaEnum(u16::from_be_bytes([b[0], b[1]]))),
+            BasicDbrType::DbrChar => values!(CaChar, 1, |b| b[0]),
+            BasicDbrType::DbrShort => values!(CaShort, 2,
+                |b| i16::from_be_bytes([b[0], b[1]])),
+            BasicDbrType::DbrLong => values!(CaLong, 4,
+                |b| i32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+            BasicDbrType::DbrFloat => values!(CaFloat, 4,
+                |b| f32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+            BasicDbrType::DbrDouble => values!(CaDouble, 8, |b| {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(b);
+                f64::from_be_bytes(bytes)
+            }),
+        };
+        Some(())
+    }
+}
+
+impl PvValue {
+    // Decodes a DBR in network byte order.  Fields not present in the given
+    // format are left with default values.
+    pub fn decode(datatype: u16, count: usize, dbr: &[u8]) -> Option<PvValue>
+    {
+        if datatype >= 35 {
+            return None;
+        }
+        let class = datatype / 7;
+        let basic = basic_type(datatype % 7);
+        let mut result = PvValue::new(CaUnionVec::CaDouble(Vec::new()));
+        let mut reader = Reader(dbr);
+
+        if class != CLASS_PLAIN {
+            result.status = StatusSeverity {
+                status: reader.i16()?,
+                severity: reader.i16()?,
+            };
+        }
+        match (class, basic) {
+            (CLASS_PLAIN, _) => { },
+            (CLASS_STS, BasicDbrType::DbrChar) => reader.skip(1)?,
+            (CLASS_STS, BasicDbrType::DbrDouble) => reader.skip(4)?,
+            (CLASS_STS, _) => { },
+            (CLASS_TIME, _) => {
+                let secs = reader.u32()?;
+                let nsec = reader.u32()?;
+                result.timestamp = from_raw_stamp(&EpicsTimeStamp { secs, nsec });
+                match basic {
+                    BasicDbrType::DbrShort | BasicDbrType::DbrEnum =>
+                        reader.skip(2)?,
+                    BasicDbrType::DbrChar => reader.skip(3)?,
+                    BasicDbrType::DbrDouble => reader.skip(4)?,
+                    _ => { },
+                }
+            },
+            (_, BasicDbrType::DbrString) => { },
+            (_, BasicDbrType::DbrEnum) => {
+                let count = (reader.i16()?.max(0) as usize).min(MAX_ENUM_STATES);
+                let strings = (0..MAX_ENUM_STATES)
+                    .map(|_| reader.string(MAX_ENUM_STRING_SIZE))
+                    .collect::<Option<Vec<_>>>()?;
+                result.ctrl.enum_strings = strings[..count].to_vec();
+            },
+            (_, _) => {
+                let ctrl = &mut result.ctrl;
+                if let BasicDbrType::DbrFloat | BasicDbrType::DbrDouble = basic {
+                    ctrl.precision = reader.i16()?;
+                    reader.skip(2)?;
+                }
+                ctrl.units = reader.string(MAX_UNITS_SIZE)?;
+                let limits = &mut ctrl.limits;
+                limits.upper_disp_limit = reader.number(basic)?;
+                limits.lower_disp_limit = reader.number(basic)?;
+                limits.upper_alarm_limit = reader.number(basic)?;
+                limits.upper_warning_limit = reader.number(basic)?;
+                limits.lower_warning_limit = reader.number(basic)?;
+                limits.lower_alarm_limit = reader.number(basic)?;
+                if class == CLASS_CTRL {
+                    limits.upper_ctrl_limit = reader.number(basic)?;
+                    limits.lower_ctrl_limit = reader.number(basic)?;
+                }
+                if let BasicDbrType::DbrChar = basic {
+                    reader.skip(1)?;
+                }
+            },
+        }
+        reader.values(basic, count, &mut result.value)?;
+        Some(result)
+    }
+
+    // Updates this value with the fields present in a DBR of the given type
+    // decoded by decode, leaving the other fields unchanged.
+    pub fn merge(&mut self, datatype: u16, decoded: PvValue)
+    {
+        let class = datatype / 7;
+        self.value = decoded.value;
+        if class != CLASS_PLAIN {
+            self.status = decoded.status;
+        }
+        if class == CLASS_TIME {
+            self.timestamp = decoded.timestamp;
+        } else if class > CLASS_TIME {
+            self.ctrl = decoded.ctrl;
+        }
+    }
+}
@@ -0,0 +1,552 @@
+// PV names
+//
+// A channel name consists of a record name, optionally followed by a field
+// name, the $ modifier selecting long string access to the field, and a JSON
+// map of channel filters:
+//
+//      SR-DI-DCCT-01:SIGNAL.EGU
+//      TS:ARCHIVE.NAME$
+//      SR-DI-DCCT-01:SIGNAL.{"dbnd":{"abs":0.5}}
+//
+// PvName::parse checks each part and explains any problem found, and PvName
+// can also be used to name other fields of the same record:
+//
+//      let pv = PvName::parse("SR-DI-DCCT-01:SIGNAL")?;
+//      let units = String::caget(&pv.with_field("EGU")?).await;
+//
+// From EPICS 3.15 the server can filter updates before they are sent, and
+// PvName builds the filters, rejecting invalid arguments with InvalidName:
+//
+//      let pv = PvName::new("SR-DI-DCCT-01:SIGNAL")
+//          .deadband(0.5)?.decimate(10)?;
+//      let mut monitor = f64::camonitor(&pv).await;
+//
+// monitors "SR-DI-DCCT-01:SIGNAL.{"dbnd":{"abs":0.5},"dec":{"n":10}}".  The
+// monitors take a PvName directly, and as a PvName dereferences to the full
+// channel name it can also be given wherever a PV name is expected.  Filters
+// are applied by the server in the order given.
+
+use std::{fmt, ops, str};
+
+use crate::error::CaError;
+
+
+// Selects when updates pass the sync filter, relative to a named state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    Before,     // The last update before the state becomes true
+    First,      // The first update while the state is true
+    While,      // All updates while the state is true
+    Last,       // The last update while the state is true
+    After,      // The first update after the state becomes false
+    Unless,     // All updates while the state is false
+}
+
+impl SyncMode {
+    fn name(self) -> &'static str
+    {
+        match self {
+            SyncMode::Before => "before",
+            SyncMode::First => "first",
+            SyncMode::While => "while",
+            SyncMode::Last => "last",
+            SyncMode::After => "after",
+            SyncMode::Unless => "unless",
+        }
+    }
+}
+
+
+// Record names are limited to PVNAME_STRINGSZ - 1 characters, and should only
+// use these characters besides letters and digits.
+const MAX_RECORD_NAME: usize = 60;
+const RECORD_NAME_PUNCTUATION: &str = "_-+:[]<>;";
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PvName {
+    record: String,
+    field: Option<String>,
+    long_string: bool,      // $ modifier after the field name
+    filters: Vec<String>,   // Each filter as "key":{parameters}
+    full: String,           // Complete channel name
+}
+
+impl PvName {
+    pub fn parse(name: &str) -> Result<PvName, CaError>
+    {
+        let invalid = |reason: String| CaError::InvalidName(
+            format!("Invalid PV name {:?}: {}", name, reason));
+
+        // Any filters start at the first brace, after the field name or a dot
+        let (channel, filter) =
+            name.split_at(name.find('{').unwrap_or(name.len()));
+        let filters = if filter.is_empty() {
+            Vec::new()
+        } else {
+            check_filter(filter).map_err(invalid)?;
+            let filter = filter[1 .. filter.len() - 1].trim();
+            if filter.is_empty() { Vec::new() } else { vec![filter.to_owned()] }
+        };
+        let channel = match channel.strip_suffix('.') {
+            Some(record) if !filter.is_empty() => record,
+            _ => channel,
+        };
+
+        let (record, field) = match channel.split_once('.') {
+            Some((record, field)) => (record, Some(field)),
+            None => (channel, None),
+        };
+        check_record(record).map_err(invalid)?;
+        let (field, long_string) = match field {
+            Some(field) => {
+                let (field, long_string) = match field.strip_suffix('$') {
+                    Some(field) => (field, true),
+                    None => (field, false),
+                };
+                check_field(field).map_err(invalid)?;
+                (Some(field.to_owned()), long_string)
+            },
+            None => (None, false),
+        };
+
+        Ok(PvName::from_parts(record, field, long_string, filters))
+    }
+
+    // As for parse, but panics if the name is not valid.  This is intended for
+    // names fixed in the program.
+    pub fn new(name: &str) -> PvName
+    {
+        PvName::parse(name).unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    pub fn record(&self) -> &str
+    {
+        &self.record
+    }
+
+    pub fn field(&self) -> Option<&str>
+    {
+        self.field.as_deref()
+    }
+
+    pub fn is_long_string(&self) -> bool
+    {
+        self.long_string
+    }
+
+    // The channel name without any filters
+    pub fn base_name(&self) -> &str
+    {
+        let end = self.full.find('{').unwrap_or(self.full.len());
+        self.full[.. end].trim_end_matches('.')
+    }
+
+    // Another field of the same record, without modifier or filters
+    pub fn with_field(&self, field: &str) -> Result<PvName, CaError>
+    {
+        check_field(field).map_err(|reason| self.invalid(reason))?;
+        Ok(PvName::from_parts(
+            &self.record, Some(field.to_owned()), false, Vec::new()))
+    }
+
+    // The record itself, without field, modifier or filters
+    pub fn without_field(&self) -> PvName
+    {
+        PvName::from_parts(&self.record, None, false, Vec::new())
+    }
+
+    // Reads the field as a long string, see CaLongString.  Only possible if a
+    // field is named.
+    pub fn long_string(mut self) -> Result<PvName, CaError>
+    {
+        if self.field.is_none() {
+            return Err(self.invalid(
+                "long string access needs a field name".to_owned()));
+        }
+        self.long_string = true;
+        self.update();
+        Ok(self)
+    }
+
+    // Only passes updates differing by more than the given amount from the
+    // last update sent
+    pub fn deadband(self, delta: f64) -> Result<PvName, CaError>
+    {
+        if !(delta.is_finite() && delta >= 0.0) {
+            return Err(
+                self.invalid(format!("invalid deadband {}", delta)));
+        }
+        Ok(self.filter("dbnd", format!("{{\"abs\":{}}}", delta)))
+    }
+
+    // As for deadband, but as a percentage of the last update sent
+    pub fn deadband_relative(self, percent: f64) -> Result<PvName, CaError>
+    {
+        if !(percent.is_finite() && percent >= 0.0) {
+            return Err(self.invalid(
+                format!("invalid relative deadband {}", percent)));
+        }
+        Ok(self.filter("dbnd", format!("{{\"rel\":{}}}", percent)))
+    }
+
+    // Only passes every nth update
+    pub fn decimate(self, n: u32) -> Result<PvName, CaError>
+    {
+        if n < 1 {
+            return Err(self.invalid(
+                "decimation must be at least 1".to_owned()));
+        }
+        Ok(self.filter("dec", format!("{{\"n\":{}}}", n)))
+    }
+
+    // Only sends elements start to end inclusive of an array.  Negative
+    // indices count back from the end of the array, so -1 is the last element.
+    pub fn array(self, start: i64, end: i64) -> PvName
+    {
+        self.array_filter(start, 1, end)
+    }
+
+    // As for array, sending every step element from start
+    pub fn array_step(self, start: i64, step: u32, end: i64)
+        -> Result<PvName, CaError>
+    {
+        if step < 1 {
+            return Err(self.invalid(
+                "array step must be at least 1".to_owned()));
+        }
+        Ok(self.array_filter(start, step, end))
+    }
+
+    // Timestamps values with the time they are read rather than the record
+    // timestamp, for instance for fields not updated when the record processes
+    pub fn timestamp(self) -> PvName
+    {
+        self.filter("ts", "{}".to_owned())
+    }
+
+    // Passes updates according to the named state, as defined by a state
+    // record on the same server
+    pub fn sync(self, mode: SyncMode, state: &str) -> Result<PvName, CaError>
+    {
+        if state.is_empty() {
+            return Err(self.invalid("sync state must be named".to_owned()));
+        }
+        Ok(self.filter("sync",
+            format!("{{\"{}\":{}}}", mode.name(), json_string(state))))
+    }
+
+    fn array_filter(self, start: i64, step: u32, end: i64) -> PvName
+    {
+        self.filter("arr",
+            format!("{{\"s\":{},\"i\":{},\"e\":{}}}", start, step, end))
+    }
+
+    fn invalid(&self, reason: String) -> CaError
+    {
+        CaError::InvalidName(
+            format!("Invalid PV name {:?}: {}", self.full, reason))
+    }
+
+    fn filter(mut self, key: &str, parameters: String) -> PvName
+    {
+        self.filters.push(format!("\"{}\":{}", key, parameters));
+        self.update();
+        self
+    }
+
+    fn from_parts(
+        record: &str, field: Option<String>, long_string: bool,
+        filters: Vec<String>) -> PvName
+    {
+        let mut pv = PvName {
+            record: record.to_owned(),
+            field,
+            long_string,
+            filters,
+            full: String::new(),
+        };
+        pv.update();
+        pv
+    }
+
+    fn update(&mut self)
+    {
+        let mut full = self.record.clone();
+        if let Some(field) = &self.field {
+            full.push('.');
+            full.push_str(field);
+            if self.long_string {
+                full.push('$');
+            }
+        }
+        if !self.filters.is_empty() {
+            // The filters follow the field name, or a bare dot if there is none
+            if self.field.is_none() {
+                full.push('.');
+            }
+            full.push('{');
+            full.push_str(&self.filters.join(","));
+            full.push('}');
+        }
+        self.full = full;
+    }
+}
+
+
+fn check_record(record: &str) -> Result<(), String>
+{
+    if record.is_empty() {
+        return Err("record name is empty".to_owned());
+    }
+    let length = record.chars().count();
+    if length > MAX_RECORD_NAME {
+        return Err(format!(
+            "record name has {} characters, the limit is {}",
+            length, MAX_RECORD_NAME));
+    }
+    match record.chars().find(|&c|
+        !c.is_ascii_alphanumeric() && !RECORD_NAME_PUNCTUATION.contains(c))
+    {
+        Some(c) => Err(format!(
+            "record name contains {:?}, only letters, digits and {} are \
+             allowed", c, RECORD_NAME_PUNCTUATION)),
+        None => Ok(()),
+    }
+}
+
+// The only check made on names given as strings, which are otherwise passed to
+// the server as they are.  This rejects the names which cannot be sent at all.
+pub fn check_channel_name(name: &str) -> Result<(), CaError>
+{
+    let reason = if name.is_empty() {
+        "name is empty"
+    } else if name.contains('\0') {
+        "name contains NUL"
+    } else {
+        return Ok(());
+    };
+    Err(CaError::InvalidName(format!("Invalid PV name {:?}: {}", name, reason)))
+}
+
+// Field names are upper case letters and digits, starting with a letter
+fn check_field(field: &str) -> Result<(), String>
+{
+    if field.is_empty() {
+        Err("field name is empty".to_owned())
+    } else if !field.starts_with(|c: char| c.is_ascii_uppercase()) {
+        Err(format!("field name {:?} must start with a capital letter", field))
+    } else if let Some(c) = field.chars()
+        .find(|&c| !c.is_ascii_uppercase() && !c.is_ascii_digit())
+    {
+        Err(format!("field name {:?} contains {:?}, only capital letters \
+            and digits are allowed", field, c))
+    } else {
+        Ok(())
+    }
+}
+
+// Checks that the filter is a single JSON map, with balanced brackets outside
+// of strings.  The filters themselves are checked by the server.
+fn check_filter(filter: &str) -> Result<(), String>
+{
+    let mut depth: Vec<char> = Vec::new();
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in filter.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => { },
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth.push('}'),
+            '[' => depth.push(']'),
+            '}' | ']' => {
+                if depth.pop() != Some(c) {
+                    return Err(format!("unexpected {:?} in filter", c));
+                }
+                if depth.is_empty() && index + 1 < filter.len() {
+                    return Err("text follows the end of the filter".to_owned());
+                }
+            },
+            _ => { },
+        }
+    }
+    if in_string {
+        Err("unterminated string in filter".to_owned())
+    } else if !depth.is_empty() {
+        Err("filter is not closed".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+// Quotes a string for JSON
+fn json_string(string: &str) -> String
+{
+    let mut result = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if c.is_control() =>
+                result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+
+impl ops::Deref for PvName {
+    type Target = str;
+
+    fn deref(&self) -> &str
+    {
+        &self.full
+    }
+}
+
+impl AsRef<str> for PvName {
+    fn as_ref(&self) -> &str
+    {
+        &self.full
+    }
+}
+
+impl fmt::Display for PvName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        f.write_str(&self.full)
+    }
+}
+
+impl str::FromStr for PvName {
+    type Err = CaError;
+
+    fn from_str(name: &str) -> Result<Self, CaError> { PvName::parse(name) }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    use super::{PvName, SyncMode};
+    use crate::camonitor::CaMonitor;
+    use crate::error::CaError;
+    use crate::mock::MockBackend;
+
+    #[test]
+    fn filters()
+    {
+        let pv = PvName::new("SR-DI-DCCT-01:SIGNAL")
+            .deadband(0.5).unwrap().decimate(10).unwrap();
+        assert_eq!(&*pv,
+            "SR-DI-DCCT-01:SIGNAL.{\"dbnd\":{\"abs\":0.5},\"dec\":{\"n\":10}}");
+        assert_eq!(pv.base_name(), "SR-DI-DCCT-01:SIGNAL");
+        let pv = PvName::new("TS:WF.VAL").array(0, 99)
+            .sync(SyncMode::While, "gate").unwrap();
+        assert_eq!(&*pv, "TS:WF.VAL{\"arr\":{\"s\":0,\"i\":1,\"e\":99},\
+            \"sync\":{\"while\":\"gate\"}}");
+        let units = pv.with_field("EGU").unwrap();
+        assert_eq!(&*units, "TS:WF.EGU");
+        assert_eq!(&*units.long_string().unwrap(), "TS:WF.EGU$");
+    }
+
+    #[test]
+    fn invalid_arguments()
+    {
+        let pv = PvName::new("TS:AI");
+        let invalid = |result: Result<PvName, CaError>|
+            matches!(result, Err(CaError::InvalidName(_)));
+        assert!(invalid(pv.with_field("egu")));
+        assert!(invalid(pv.clone().long_string()));
+        assert!(invalid(pv.clone().deadband(-1.0)));
+        assert!(invalid(pv.clone().deadband_relative(f64::NAN)));
+        assert!(invalid(pv.clone().decimate(0)));
+        assert!(invalid(pv.clone().array_step(0, 0, -1)));
+        assert!(invalid(pv.sync(SyncMode::First, "")));
+    }
+
+    #[test]
+    fn names_parsed()
+    {
+        let pv = PvName::parse("SR-DI-DCCT-01:SIGNAL").unwrap();
+        assert_eq!((pv.record(), pv.field()), ("SR-DI-DCCT-01:SIGNAL", None));
+        let pv = PvName::parse("TS:AI.EGU").unwrap();
+        assert_eq!((pv.record(), pv.field()), ("TS:AI", Some("EGU")));
+        assert!(!pv.is_long_string());
+        let pv = PvName::parse("TS:ARCHIVE.NAME$").unwrap();
+        assert_eq!((pv.field(), pv.is_long_string()), (Some("NAME"), true));
+        assert_eq!(&*pv, "TS:ARCHIVE.NAME$");
+
+        // Filters follow a bare dot or the field name, and an empty filter is
+        // dropped along with its dot
+        let pv = PvName::parse("TS:AI.{\"dec\":{\"n\":2}}").unwrap();
+        assert_eq!((pv.field(), pv.base_name()), (None, "TS:AI"));
+        let pv = PvName::parse("TS:AI.VAL{\"ts\":{}}").unwrap();
+        assert_eq!((pv.field(), pv.base_name()), (Some("VAL"), "TS:AI.VAL"));
+        assert_eq!(&*PvName::parse("TS:AI.{}").unwrap(), "TS:AI");
+
+        // Record names may be up to 60 characters using this punctuation
+        assert!(PvName::parse(&"A".repeat(60)).is_ok());
+        assert!(PvName::parse("A_b-C+1:[2]<3>;").is_ok());
+    }
+
+    #[test]
+    fn names_rejected()
+    {
+        let reason = |name: &str| match PvName::parse(name) {
+            Err(CaError::InvalidName(message)) => message,
+            result => panic!("{:?} gave {:?}", name, result),
+        };
+        assert_eq!(reason(""), "Invalid PV name \"\": record name is empty");
+        assert_eq!(reason(&"A".repeat(61)), format!(
+            "Invalid PV name {:?}: record name has 61 characters, the limit \
+             is 60", "A".repeat(61)));
+        assert_eq!(reason("TS AI"),
+            "Invalid PV name \"TS AI\": record name contains ' ', only \
+             letters, digits and _-+:[]<>; are allowed");
+        assert!(reason("TS:AI$").contains("record name contains '$'"));
+        assert!(reason("TS:AI.VAL.EGU").contains("contains '.'"));
+
+        assert_eq!(reason("TS:AI."),
+            "Invalid PV name \"TS:AI.\": field name is empty");
+        assert_eq!(reason("TS:AI.egu"), "Invalid PV name \"TS:AI.egu\": \
+            field name \"egu\" must start with a capital letter");
+        assert!(reason("TS:AI.EGu")
+            .contains("field name \"EGu\" contains 'u'"));
+        assert!(reason("TS:AI.$").contains("field name is empty"));
+
+        assert!(reason("TS:AI.{\"dec\":{\"n\":2}")
+            .ends_with("filter is not closed"));
+        assert!(reason("TS:AI.{\"dec\":{\"n\":2}}}")
+            .ends_with("text follows the end of the filter"));
+        assert!(reason("TS:AI.{\"arr\":{\"s\":[1}}")
+            .ends_with("unexpected '}' in filter"));
+        assert!(reason("TS:AI.{\"ts\":{}} ")
+            .ends_with("text follows the end of the filter"));
+        assert!(reason("TS:AI.{\"sync\":{\"while\":\"gate}}")
+            .ends_with("unterminated string in filter"));
+    }
+
+    #[test]
+    fn monitor_by_name()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 1.5f64);
+        block_on(async {
+            let mut monitor = f64::camonitor(PvName::new("TEST:AI")).await;
+            assert_eq!(monitor.next().await, Some(1.5));
+        });
+    }
+}
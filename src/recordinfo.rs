@@ -0,0 +1,196 @@
+// Reading the standard fields of a record
+//
+// Gathers the description, units, limits, alarm settings and alarm state of a
+// record in one request:
+//
+//      let info = RecordInfo::caget("SR-DI-DCCT-01:SIGNAL").await;
+//
+// Every field is searched for at once and then read concurrently, each on its
+// own channel.  Fields common to all records must be present, but the remaining
+// fields are only found on some record types and are None where the record has
+// no such field.  Any field or filter given with the record name is ignored.
+
+use std::time::Duration;
+use async_trait::async_trait;
+
+use crate::caget::{CaGetCore, CA};
+use crate::channel::Channel;
+use crate::db_access::StatusSeverity;
+use crate::error::CaError;
+use crate::pvname::PvName;
+use crate::timer;
+
+
+// There is no reply to a search for a missing field, so once the common fields
+// have connected we only wait this long for the rest to connect.  Fields are
+// searched for together so those present normally connect at the same time.
+const OPTIONAL_FIELD_GRACE: Duration = Duration::from_millis(200);
+
+// An alarm limit together with the severity raised when it is crossed
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlarmLimit {
+    pub limit: f64,
+    pub severity: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct RecordInfo {
+    pub name: String,
+    pub record_type: String,            // RTYP
+    pub description: String,            // DESC
+    pub scan: String,                   // SCAN
+    pub alarm: StatusSeverity,          // STAT and SEVR
+    pub units: Option<String>,          // EGU
+    pub precision: Option<i16>,         // PREC
+    pub display_high: Option<f64>,      // HOPR
+    pub display_low: Option<f64>,       // LOPR
+    pub hihi: Option<AlarmLimit>,       // HIHI and HHSV
+    pub high: Option<AlarmLimit>,       // HIGH and HSV
+    pub low: Option<AlarmLimit>,        // LOW and LSV
+    pub lolo: Option<AlarmLimit>,       // LOLO and LLSV
+}
+
+
+// The channel, if it connects within the grace period
+async fn present(channel: &Channel) -> Option<&Channel>
+{
+    timer::timeout(OPTIONAL_FIELD_GRACE, channel.wait_connect()).await?;
+    Some(channel)
+}
+
+// Only fields which are present are read, but then must be read successfully
+async fn optional_field<T: CaGetCore>(channel: Option<&Channel>)
+    -> Result<Option<T>, CaError>
+{
+    match channel {
+        Some(channel) => Ok(Some(T::caget_core(channel).await?)),
+        None => Ok(None),
+    }
+}
+
+fn alarm_limit(limit: Option<f64>, severity: Option<String>)
+    -> Option<AlarmLimit>
+{
+    Some(AlarmLimit { limit: limit?, severity: severity? })
+}
+
+
+#[async_trait(?Send)]
+impl CA for RecordInfo {
+    async fn try_caget(pv: &str) -> Result<Self, CaError> {
+        let record = PvName::parse(pv)?.without_field();
+        let open = |field| record.with_field(field).and_then(Channel::new);
+        let (rtyp, desc, scan, stat, sevr) = (
+            open("RTYP")?, open("DESC")?, open("SCAN")?,
+            open("STAT")?, open("SEVR")?);
+        let (egu, prec, hopr, lopr) =
+            (open("EGU")?, open("PREC")?, open("HOPR")?, open("LOPR")?);
+        let (hihi, high, low, lolo) =
+            (open("HIHI")?, open("HIGH")?, open("LOW")?, open("LOLO")?);
+        let (hhsv, hsv, lsv, llsv) =
+            (open("HHSV")?, open("HSV")?, open("LSV")?, open("LLSV")?);
+
+        futures::join!(
+            rtyp.wait_connect(), desc.wait_connect(), scan.wait_connect(),
+            stat.wait_connect(), sevr.wait_connect());
+        let (egu, prec, hopr, lopr, hihi, high, low, lolo,
+             hhsv, hsv, lsv, llsv) = futures::join!(
+            present(&egu), present(&prec), present(&hopr), present(&lopr),
+            present(&hihi), present(&high), present(&low), present(&lolo),
+            present(&hhsv), present(&hsv), present(&lsv), present(&llsv));
+
+        let (record_type, description, scan, status, severity,
+             units, precision, display_high, display_low,
+             hihi, high, low, lolo, hhsv, hsv, lsv, llsv) = futures::join!(
+            String::caget_core(&rtyp),
+            String::caget_core(&desc),
+            String::caget_core(&scan),
+            i16::caget_core(&stat),
+            i16::caget_core(&sevr),
+            optional_field::<String>(egu),
+            optional_field::<i16>(prec),
+            optional_field::<f64>(hopr),
+            optional_field::<f64>(lopr),
+            optional_field::<f64>(hihi),
+            optional_field::<f64>(high),
+            optional_field::<f64>(low),
+            optional_field::<f64>(lolo),
+            optional_field::<String>(hhsv),
+            optional_field::<String>(hsv),
+            optional_field::<String>(lsv),
+            optional_field::<String>(llsv));
+
+        Ok(RecordInfo {
+            name: record.record().to_owned(),
+            record_type: record_type?,
+            description: description?,
+            scan: scan?,
+            alarm: StatusSeverity { status: status?, severity: severity? },
+            units: units?,
+            precision: precision?,
+            display_high: display_high?,
+            display_low: display_low?,
+            hihi: alarm_limit(hihi?, hhsv?),
+            high: alarm_limit(high?, hsv?),
+            low: alarm_limit(low?, lsv?),
+            lolo: alarm_limit(lolo?, llsv?),
+        })
+    }
+}
+
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::{AlarmLimit, RecordInfo};
+    use crate::caget::CA;
+    use crate::test_ioc::TestIoc;
+
+    const DB: &str = include_str!("../test-ioc.db");
+
+    fn minor(limit: f64) -> Option<AlarmLimit>
+    {
+        Some(AlarmLimit { limit, severity: "MINOR".to_owned() })
+    }
+
+    #[test]
+    fn calc_record()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        let info = block_on(RecordInfo::caget("SR-DI-DCCT-01:SIGNAL.VAL"));
+        assert_eq!(info.name, "SR-DI-DCCT-01:SIGNAL");
+        assert_eq!(info.record_type, "calc");
+        assert_eq!(info.scan, ".1 second");
+        assert_eq!((info.alarm.status, info.alarm.severity), (0, 0));
+        assert_eq!(info.units.as_deref(), Some("mA"));
+        assert_eq!(info.precision, Some(2));
+        assert_eq!((info.display_low, info.display_high),
+            (Some(0.0), Some(500.0)));
+        assert_eq!(info.high, minor(350.0));
+        assert_eq!(info.low, minor(10.0));
+        assert_eq!(info.hihi.map(|hihi| hihi.severity).as_deref(),
+            Some("NO_ALARM"));
+    }
+
+    // A stringin record has none of the optional fields
+    #[test]
+    fn missing_fields()
+    {
+        let ioc = TestIoc::server(r#"
+            record(stringin, "TEST:NAME") {
+                field(DESC, "Name")
+                field(VAL, "value")
+            }
+        "#).unwrap();
+        let _context = ioc.install();
+        let info = block_on(RecordInfo::caget("TEST:NAME"));
+        assert_eq!(info.record_type, "stringin");
+        assert_eq!(info.description, "Name");
+        assert_eq!(info.scan, "Passive");
+        assert_eq!(info.units, None);
+        assert_eq!(info.precision, None);
+        assert_eq!(info.hihi, None);
+    }
+}
@@ -0,0 +1,809 @@
+// Recording and replay of CA sessions
+//
+// A Recorder passes all channel operations through to the CA library while
+// writing every connect, get response, put completion, monitor update and
+// disconnect to a file.  A Replay serves a recorded session back through the
+// library without an IOC, so that tests can run offline against real machine
+// behaviour.
+//
+// Plain and status gets and subscriptions are recorded with their timestamps
+// by requesting the corresponding DBR_TIME type from the server.  The file has
+// one event per line, giving the event, seconds since recording started and
+// the PV name, followed by:
+//
+//  connect     field type and element count on first connecting a channel
+//  reconnect   field type and element count on connecting again
+//  disconnect  nothing
+//  put         status
+//  get         status and, if successful, the DBR type, element count and DBR
+//  monitor     in network byte order as hex
+//
+// On replay each PV connects as first recorded, and gets and puts complete in
+// turn with the recorded responses.  Monitor updates and later disconnects and
+// reconnects are delivered in recorded order one at a time by calling step,
+// except that the update starting each new subscription or reconnection is
+// delivered immediately.
+
+use std::{fmt, fs, io, mem, path};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use libc::{c_int, c_long, c_short, c_ulong, c_void};
+
+use crate::backend;
+use crate::backend::{
+    Backend, BackendGuard, ConnectArgs, ConnectHandler, EventHandler};
+use crate::cadef::{
+    ChanId, EvId, event_handler_args, voidp_to_ref, CA_OP_CONN_UP};
+use crate::protocol::{dbr_size_n, dbr_to_host, swap_dbr};
+use crate::protocol::status::*;
+use crate::pv_value::{PvValue, PvCtrl, convert, resize};
+use crate::caunion::{CaUnionVec, get_field_type};
+use crate::mock::MockBackend;
+use crate::camonitor::{DBE_VALUE, DBE_LOG, DBE_ALARM, DBE_PROPERTY};
+
+
+// DBR_TIME type corresponding to plain and status types
+fn time_type(datatype: u16) -> u16
+{
+    if datatype < 14 { 14 + datatype % 7 } else { datatype }
+}
+
+fn to_hex(bytes: &[u8]) -> String
+{
+    let mut hex = String::with_capacity(2 * bytes.len());
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+// Fails if the string has an odd length or contains non hex digits
+fn from_hex(hex: &str) -> Option<Vec<u8>>
+{
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+
+// -----------------------------------------------------------------------------
+// Recording
+
+struct Log {
+    start: Instant,
+    file: Mutex<fs::File>,
+}
+
+impl Log {
+    // Each event is written with a single write so that the file is complete
+    // however the process ends.
+    fn write(&self, event: &str, pv: &str, details: fmt::Arguments)
+    {
+        let line = format!("{} {:.6} {} {}\n",
+            event, self.start.elapsed().as_secs_f64(), pv, details);
+        let _ = self.file.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
+struct RecordedChannel {
+    log: Arc<Log>,
+    inner: Arc<dyn Backend>,
+    name: String,
+    on_connect: ConnectHandler,
+    usr: *const c_void,
+    connected: AtomicBool,      // Set once the channel has first connected
+}
+
+fn record_connect(args: ConnectArgs)
+{
+    let channel: &RecordedChannel = unsafe { voidp_to_ref(args.usr) };
+    if args.op == CA_OP_CONN_UP {
+        let event = if channel.connected.swap(true, Ordering::Relaxed) {
+            "reconnect"
+        } else {
+            "connect"
+        };
+        channel.log.write(event, &channel.name, format_args!("{} {}",
+            channel.inner.field_type(args.chid),
+            channel.inner.element_count(args.chid)));
+    } else {
+        channel.log.write("disconnect", &channel.name, format_args!(""));
+    }
+    (channel.on_connect)(ConnectArgs { usr: channel.usr, ..args });
+}
+
+// A get, put or subscription in progress
+struct Request {
+    log: Arc<Log>,
+    event: &'static str,
+    name: String,
+    datatype: u16,
+    handler: EventHandler,
+    usr: *const c_void,
+}
+
+// The caller's pointer is only passed back to its handler
+unsafe impl Send for Request { }
+
+// Gets and puts awaiting their response, held by address with the address of
+// their channel.  Whoever removes a request from here owns it: its callback,
+// or the recorder when the channel is cleared as no callback will then arrive.
+// The callback never touches the request otherwise, so the two cannot race.
+#[derive(Default)]
+struct Pending {
+    requests: HashMap<usize, (usize, Box<Request>)>,
+    // Number of callbacks completing a request for each channel
+    completing: HashMap<usize, usize>,
+}
+
+// The requests of all recorders are held together, as a callback can only find
+// them by the address it is passed.
+fn pending() -> &'static (Mutex<Pending>, Condvar)
+{
+    static PENDING: OnceLock<(Mutex<Pending>, Condvar)> = OnceLock::new();
+    PENDING.get_or_init(Default::default)
+}
+
+impl Request {
+    // Records the response and passes it on converted back to the requested
+    // type.
+    fn complete(&self, args: event_handler_args)
+    {
+        let (datatype, count) = (args.datatype as u16, args.count as usize);
+        let status = args.status as u32;
+        let dbr = match dbr_size_n(datatype, count) {
+            Some(size) if status == ECA_NORMAL  &&  !args.dbr.is_null() => {
+                let mut dbr = unsafe { std::slice::from_raw_parts(
+                    args.dbr as *const u8, size).to_vec() };
+                swap_dbr(datatype, count, &mut dbr);
+                Some(dbr)
+            },
+            _ => None,
+        };
+        match &dbr {
+            Some(dbr) => self.log.write(self.event, &self.name, format_args!(
+                "{} {} {} {}", status, datatype, count, to_hex(dbr))),
+            None => self.log.write(
+                self.event, &self.name, format_args!("{}", status)),
+        }
+
+        let converted = dbr.filter(|_| datatype != self.datatype)
+            .and_then(|dbr| PvValue::decode(datatype, count, &dbr))
+            .and_then(|value| value.encode(self.datatype, count))
+            .and_then(|(dbr, count)|
+                Some((dbr_to_host(self.datatype, count as u32, &dbr)?, count)));
+        let (dbr, count) = match &converted {
+            Some((dbr, count)) => (dbr.as_ptr() as *const c_void, *count),
+            None => (args.dbr, count),
+        };
+        (self.handler)(event_handler_args {
+            usr: self.usr,
+            datatype: self.datatype as c_long,
+            count: count as c_long,
+            dbr,
+            ..args
+        });
+    }
+}
+
+extern "C" fn record_event(args: event_handler_args)
+{
+    let request: &Request = unsafe { voidp_to_ref(args.usr) };
+    request.complete(args);
+}
+
+extern "C" fn record_once(args: event_handler_args)
+{
+    let (lock, done) = pending();
+    let (channel, request) = {
+        let mut pending = lock.lock().unwrap();
+        match pending.requests.remove(&(args.usr as usize)) {
+            Some((channel, request)) => {
+                *pending.completing.entry(channel).or_default() += 1;
+                (channel, request)
+            },
+            // Abandoned as its channel has been cleared
+            None => return,
+        }
+    };
+    request.complete(args);
+    drop(request);
+
+    let mut pending = lock.lock().unwrap();
+    if let Some(count) = pending.completing.get_mut(&channel) {
+        *count -= 1;
+        if *count == 0 {
+            pending.completing.remove(&channel);
+        }
+    }
+    done.notify_all();
+}
+
+
+pub struct Recorder {
+    log: Arc<Log>,
+    inner: Arc<dyn Backend>,
+    channels: Mutex<HashMap<usize, Box<RecordedChannel>>>,
+    subscriptions: Mutex<HashMap<usize, Box<Request>>>,
+}
+
+// The raw pointers held are only used to pass back to the callbacks
+unsafe impl Send for Recorder { }
+unsafe impl Sync for Recorder { }
+
+impl Recorder {
+    // Creates the file and records all channels created on this thread while
+    // the recorder is installed.  Channels are passed through to the backend
+    // current when the recorder was created, normally the CA library.
+    pub fn create(path: impl AsRef<path::Path>) -> io::Result<Arc<Recorder>>
+    {
+        let mut file = fs::File::create(path)?;
+        file.write_all(b"# Channel Access session\n")?;
+        Ok(Arc::new(Recorder {
+            log: Arc::new(Log { start: Instant::now(), file: Mutex::new(file) }),
+            inner: backend::current(),
+            channels: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    pub fn install(self: &Arc<Self>) -> BackendGuard
+    {
+        backend::install(self.clone())
+    }
+
+    fn channel_name(&self, id: ChanId) -> String
+    {
+        self.channels.lock().unwrap()[&(id.0 as usize)].name.clone()
+    }
+
+    // Channels are identified in the pending requests by the address of their
+    // record, which is unique across recorders
+    fn channel_address(&self, id: ChanId) -> usize
+    {
+        let channels = self.channels.lock().unwrap();
+        channels[&(id.0 as usize)].as_ref() as *const RecordedChannel as usize
+    }
+
+    fn request(&self, event: &'static str, id: ChanId, datatype: c_long,
+        handler: EventHandler, usr: *const c_void) -> Box<Request>
+    {
+        Box::new(Request {
+            log: self.log.clone(),
+            event,
+            name: self.channel_name(id),
+            datatype: datatype as u16,
+            handler,
+            usr,
+        })
+    }
+
+    // Registers a get or put before it is passed on, as the response may
+    // arrive before the backend returns.  Returns the address identifying the
+    // request, which must not be dereferenced.
+    fn request_once(&self, event: &'static str, id: ChanId, datatype: c_long,
+        handler: EventHandler, usr: *const c_void) -> *const c_void
+    {
+        let request = self.request(event, id, datatype, handler, usr);
+        let address = request.as_ref() as *const Request as usize;
+        pending().0.lock().unwrap().requests.insert(
+            address, (self.channel_address(id), request));
+        address as *const c_void
+    }
+
+    // Frees a request which will never be completed
+    fn abandon(&self, request: *const c_void)
+    {
+        let removed =
+            pending().0.lock().unwrap().requests.remove(&(request as usize));
+        drop(removed);
+    }
+}
+
+impl Backend for Recorder {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int
+    {
+        let channel = Box::new(RecordedChannel {
+            log: self.log.clone(),
+            inner: self.inner.clone(),
+            name: pv.to_owned(),
+            on_connect,
+            usr,
+            connected: AtomicBool::new(false),
+        });
+        // Hold the lock so that the channel is registered before any request
+        let mut channels = self.channels.lock().unwrap();
+        let rc = self.inner.create_channel(
+            pv, record_connect, channel.as_ref() as *const _ as *const c_void,
+            id);
+        if rc == ECA_NORMAL as c_int {
+            channels.insert(id.0 as usize, channel);
+        }
+        rc
+    }
+
+    fn clear_channel(&self, id: ChanId) -> c_int
+    {
+        let rc = self.inner.clear_channel(id);
+        let channel = self.channels.lock().unwrap().remove(&(id.0 as usize));
+        if let Some(channel) = &channel {
+            // Free the requests left, then wait for any callback already
+            // completing a request so that none runs after we return.
+            let address = channel.as_ref() as *const RecordedChannel as usize;
+            let (lock, done) = pending();
+            let mut pending = lock.lock().unwrap();
+            let (abandoned, requests): (HashMap<_, _>, HashMap<_, _>) =
+                mem::take(&mut pending.requests).into_iter()
+                    .partition(|(_, (channel, _))| *channel == address);
+            pending.requests = requests;
+            while pending.completing.contains_key(&address) {
+                pending = done.wait(pending).unwrap();
+            }
+            drop(pending);
+            drop(abandoned);
+        }
+        rc
+    }
+
+    fn field_type(&self, id: ChanId) -> c_short
+    {
+        self.inner.field_type(id)
+    }
+
+    fn element_count(&self, id: ChanId) -> c_ulong
+    {
+        self.inner.element_count(id)
+    }
+
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let request = self.request_once("get", id, datatype, handler, usr);
+        let rc = self.inner.array_get_callback(
+            time_type(datatype as u16) as c_long, count, id,
+            record_once, request);
+        if rc != ECA_NORMAL as c_int {
+            self.abandon(request);
+        }
+        rc
+    }
+
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let request = self.request_once("put", id, datatype, handler, usr);
+        let rc = self.inner.array_put_callback(
+            datatype, count, id, value, record_once, request);
+        if rc != ECA_NORMAL as c_int {
+            self.abandon(request);
+        }
+        rc
+    }
+
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int
+    {
+        let request = self.request("monitor", id, datatype, handler, usr);
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let rc = self.inner.create_subscription(
+            time_type(datatype as u16) as c_long, count, id, mask,
+            record_event, request.as_ref() as *const _ as *const c_void, evid);
+        if rc == ECA_NORMAL as c_int {
+            subscriptions.insert(evid.0 as usize, request);
+        }
+        rc
+    }
+
+    fn clear_subscription(&self, evid: EvId) -> c_int
+    {
+        let rc = self.inner.clear_subscription(evid);
+        self.subscriptions.lock().unwrap().remove(&(evid.0 as usize));
+        rc
+    }
+
+    fn flush_io(&self) -> c_int
+    {
+        self.inner.flush_io()
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Replay
+
+// Recorded response: either an error status or a decoded DBR
+type Response = Result<(u16, PvValue), u32>;
+
+enum Event {
+    Connect(usize),
+    Disconnect,
+    Monitor(Response),
+}
+
+#[derive(Default)]
+struct ReplayState {
+    gets: HashMap<String, VecDeque<Response>>,
+    puts: HashMap<String, VecDeque<u32>>,
+    timeline: VecDeque<(String, Event)>,
+}
+
+pub struct Replay {
+    mock: Arc<MockBackend>,
+    state: Mutex<ReplayState>,
+}
+
+fn parse_response(fields: &[&str]) -> Option<Response>
+{
+    let status: u32 = fields.first()?.parse().ok()?;
+    if status == ECA_NORMAL {
+        let datatype: u16 = fields.get(1)?.parse().ok()?;
+        let count: usize = fields.get(2)?.parse().ok()?;
+        let dbr = from_hex(fields.get(3)?)?;
+        Some(Ok((datatype, PvValue::decode(datatype, count, &dbr)?)))
+    } else {
+        Some(Err(status))
+    }
+}
+
+// Placeholder value for a PV before any value has been seen
+fn initial_value(field_type: c_short, count: usize) -> PvValue
+{
+    let mut value = CaUnionVec::CaDouble(vec![]);
+    if let Some(field_type) = get_field_type(field_type) {
+        value = convert(&value, field_type, &PvCtrl::default()).unwrap();
+    }
+    resize(&mut value, count);
+    PvValue::new(value)
+}
+
+fn invoke_failed(
+    handler: EventHandler, usr: *const c_void, id: ChanId,
+    datatype: c_long, count: c_ulong, status: u32)
+{
+    handler(event_handler_args {
+        usr,
+        channel: id,
+        datatype,
+        count: count as c_long,
+        dbr: std::ptr::null(),
+        status: status as c_int,
+    });
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<path::Path>) -> io::Result<Arc<Replay>>
+    {
+        let text = fs::read_to_string(path)?;
+        let replay = Replay {
+            mock: MockBackend::new(),
+            state: Mutex::new(ReplayState::default()),
+        };
+        // Channels to the same PV all report the same connection changes, so
+        // we track the state of each PV to replay each change once.
+        let mut connected = HashMap::new();
+        let mut online = HashMap::new();
+        {
+            let mut state = replay.state.lock().unwrap();
+            for (number, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty()  ||  line.starts_with('#') {
+                    continue;
+                }
+                let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Invalid session at line {}", number + 1));
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 3 {
+                    return Err(invalid());
+                }
+                let (event, pv, fields) = (fields[0], fields[2], &fields[3..]);
+                let pv = pv.to_owned();
+                match event {
+                    "connect" | "reconnect" => {
+                        let field_type: c_short = fields.first()
+                            .and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+                        let count = fields.get(1)
+                            .and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+                        // The first connection is made when the channel is
+                        // created, later ones are replayed in turn.
+                        if !connected.contains_key(&pv) {
+                            connected.insert(pv.clone(), (field_type, count));
+                            online.insert(pv, true);
+                        } else if event == "reconnect"  &&  !online[&pv] {
+                            online.insert(pv.clone(), true);
+                            state.timeline.push_back(
+                                (pv, Event::Connect(count)));
+                        }
+                    },
+                    "disconnect" => {
+                        if online.get(&pv) == Some(&true) {
+                            online.insert(pv.clone(), false);
+                            state.timeline.push_back((pv, Event::Disconnect));
+                        }
+                    },
+                    "get" => {
+                        let response =
+                            parse_response(fields).ok_or_else(invalid)?;
+                        state.gets.entry(pv).or_default().push_back(response);
+                    },
+                    "put" => {
+                        let status = fields.first()
+                            .and_then(|x| x.parse().ok()).ok_or_else(invalid)?;
+                        state.puts.entry(pv).or_default().push_back(status);
+                    },
+                    "monitor" => {
+                        let response =
+                            parse_response(fields).ok_or_else(invalid)?;
+                        state.timeline.push_back((pv, Event::Monitor(response)));
+                    },
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+        for (pv, (field_type, count)) in connected {
+            replay.mock.add_recorded(
+                &pv, initial_value(field_type, count), field_type, count);
+        }
+        Ok(Arc::new(replay))
+    }
+
+    pub fn install(self: &Arc<Self>) -> BackendGuard
+    {
+        backend::install(self.clone())
+    }
+
+    // A new subscription or a reconnection starts with an update of the current
+    // value, so we take the next recorded update for the PV as this value.
+    fn take_update(&self, pv: &str)
+    {
+        let update = {
+            let mut state = self.state.lock().unwrap();
+            let timeline = &mut state.timeline;
+            match timeline.iter().position(|(name, _)| name == pv) {
+                Some(index) => match timeline[index] {
+                    (_, Event::Monitor(Ok(_))) => timeline.remove(index),
+                    _ => None,
+                },
+                None => None,
+            }
+        };
+        if let Some((_, Event::Monitor(Ok((datatype, value))))) = update {
+            self.mock.update_value(pv, |current| {
+                current.merge(datatype, value);
+                0
+            });
+        }
+    }
+
+    // Delivers the next recorded monitor update, disconnect or reconnect.
+    // Returns false once the recording is exhausted.
+    pub fn step(&self) -> bool
+    {
+        let event = self.state.lock().unwrap().timeline.pop_front();
+        match event {
+            Some((pv, Event::Connect(count))) => {
+                self.mock.set_count(&pv, count);
+                self.take_update(&pv);
+                self.mock.reconnect(&pv);
+            },
+            Some((pv, Event::Disconnect)) => self.mock.disconnect(&pv),
+            Some((pv, Event::Monitor(Ok((datatype, value))))) => {
+                // Deliver every recorded update to every subscription
+                self.mock.update_value(&pv, |current| {
+                    current.merge(datatype, value);
+                    DBE_VALUE | DBE_LOG | DBE_ALARM | DBE_PROPERTY
+                });
+            },
+            Some((_, Event::Monitor(Err(_)))) => { },
+            None => return false,
+        }
+        true
+    }
+}
+
+impl Backend for Replay {
+    fn create_channel(
+        &self, pv: &str, on_connect: ConnectHandler, usr: *const c_void,
+        id: &mut ChanId) -> c_int
+    {
+        self.mock.create_channel(pv, on_connect, usr, id)
+    }
+
+    fn clear_channel(&self, id: ChanId) -> c_int
+    {
+        self.mock.clear_channel(id)
+    }
+
+    fn field_type(&self, id: ChanId) -> c_short
+    {
+        self.mock.field_type(id)
+    }
+
+    fn element_count(&self, id: ChanId) -> c_ulong
+    {
+        self.mock.element_count(id)
+    }
+
+    // Once the recorded responses are exhausted gets return the last value
+    fn array_get_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let response = self.mock.channel_name(id).and_then(|pv| {
+            let mut state = self.state.lock().unwrap();
+            let response = state.gets.get_mut(&pv)?.pop_front()?;
+            Some((pv, response))
+        });
+        match response {
+            Some((pv, Ok((recorded, value)))) => {
+                self.mock.update_value(&pv, |current| {
+                    current.merge(recorded, value);
+                    0
+                });
+            },
+            Some((_, Err(status))) => {
+                invoke_failed(handler, usr, id, datatype, count, status);
+                return ECA_NORMAL as c_int;
+            },
+            None => { },
+        }
+        self.mock.array_get_callback(datatype, count, id, handler, usr)
+    }
+
+    fn array_put_callback(
+        &self, datatype: c_long, count: c_ulong, id: ChanId,
+        value: *const c_void,
+        handler: EventHandler, usr: *const c_void) -> c_int
+    {
+        let status = self.mock.channel_name(id).and_then(|pv| {
+            self.state.lock().unwrap().puts.get_mut(&pv)?.pop_front()
+        });
+        match status {
+            Some(status) if status != ECA_NORMAL => {
+                invoke_failed(handler, usr, id, datatype, count, status);
+                ECA_NORMAL as c_int
+            },
+            _ => self.mock.array_put_callback(
+                datatype, count, id, value, handler, usr),
+        }
+    }
+
+    fn create_subscription(
+        &self, datatype: c_long, count: c_ulong, id: ChanId, mask: c_long,
+        handler: EventHandler, usr: *const c_void, evid: &mut EvId) -> c_int
+    {
+        if let Some(pv) = self.mock.channel_name(id) {
+            self.take_update(&pv);
+        }
+        self.mock.create_subscription(
+            datatype, count, id, mask, handler, usr, evid)
+    }
+
+    fn clear_subscription(&self, evid: EvId) -> c_int
+    {
+        self.mock.clear_subscription(evid)
+    }
+
+    fn flush_io(&self) -> c_int
+    {
+        self.mock.flush_io()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use futures::{FutureExt, StreamExt};
+    use futures::executor::block_on;
+
+    use super::{Recorder, Replay};
+    use crate::caget::CA;
+    use crate::camonitor::{CaMonitorEvents, MonitorEvent};
+    use crate::caput::CaPut;
+    use crate::db_access::StatusSeverity;
+    use crate::mock::MockBackend;
+
+    type Reading = (f64, StatusSeverity, SystemTime);
+
+    // Values, alarms and timestamps in a form that can be compared
+    fn unpack(event: MonitorEvent<Reading>)
+        -> MonitorEvent<(f64, (i16, i16), SystemTime)>
+    {
+        match event {
+            MonitorEvent::Update((value, alarm, stamp)) =>
+                MonitorEvent::Update(
+                    (value, ({alarm.status}, {alarm.severity}), stamp)),
+            MonitorEvent::Disconnected => MonitorEvent::Disconnected,
+            MonitorEvent::Reconnected(field_type, count) =>
+                MonitorEvent::Reconnected(field_type, count),
+        }
+    }
+
+    async fn read() -> (Reading, Reading, usize)
+    {
+        let first = Reading::caget("TEST:AI").await;
+        f64::caput("TEST:AI", 2.5).await;
+        let second = Reading::caget("TEST:AI").await;
+        let count = Vec::<i32>::caget("TEST:WF").await.len();
+        (first, second, count)
+    }
+
+    #[test]
+    fn round_trip()
+    {
+        let path = env::temp_dir().join(
+            format!("ca-session-{}.log", std::process::id()));
+        let stamp =
+            |n: u64| UNIX_EPOCH + Duration::new(1_700_000_000 + n, 250_000);
+        let minor = StatusSeverity { status: 3, severity: 1 };
+
+        let mock = MockBackend::new();
+        let mock_guard = mock.install();
+        mock.add_pv("TEST:AI", 0.0f64);
+        mock.set_with_time("TEST:AI", 1.5f64, Some(minor), stamp(0));
+        mock.add_pv("TEST:WF", vec![1i32, 2, 3]);
+
+        let recorder = Recorder::create(&path).unwrap();
+        let recorder_guard = recorder.install();
+        let (recorded, events) = block_on(async {
+            let recorded = read().await;
+            let mut monitor = Reading::camonitor_events("TEST:AI").await;
+            let mut events = vec![monitor.next().await.unwrap()];
+            mock.set_with_time("TEST:AI", 3.5f64, None, stamp(1));
+            events.push(monitor.next().await.unwrap());
+            mock.disconnect("TEST:AI");
+            events.push(monitor.next().await.unwrap());
+            mock.set_with_time("TEST:AI", 4.5f64, None, stamp(2));
+            mock.reconnect("TEST:AI");
+            events.push(monitor.next().await.unwrap());
+            events.push(monitor.next().await.unwrap());
+            mock.set_with_time("TEST:AI", 5.5f64, None, stamp(3));
+            events.push(monitor.next().await.unwrap());
+            (recorded, events)
+        });
+        drop(recorder_guard);
+        drop(mock_guard);
+        let (first, second, count) = recorded;
+        assert_eq!((first.0, first.1.severity, first.2),
+            (1.5, 1, stamp(0)));
+        assert_eq!((second.0, count), (2.5, 3));
+        assert!(matches!(events[2], MonitorEvent::Disconnected));
+        assert!(matches!(events[3], MonitorEvent::Reconnected(_, 1)));
+
+        let replay = Replay::load(&path).unwrap();
+        let _guard = replay.install();
+        block_on(async {
+            let (first, second, count) = read().await;
+            assert_eq!(
+                (first.0, {first.1.status}, {first.1.severity}, first.2),
+                (recorded.0.0, minor.status, minor.severity, recorded.0.2));
+            assert_eq!((second.0, second.2), (recorded.1.0, recorded.1.2));
+            assert_eq!(count, recorded.2);
+
+            // The initial update arrives at once, then each step delivers one
+            // recorded event, with a reconnection bringing its update.
+            let mut monitor = Reading::camonitor_events("TEST:AI").await;
+            let mut replayed = vec![monitor.next().await.unwrap()];
+            while replay.step() {
+                while let Some(Some(event)) = monitor.next().now_or_never() {
+                    replayed.push(event);
+                }
+            }
+            let expected: Vec<_> = events.into_iter().map(unpack).collect();
+            let replayed: Vec<_> = replayed.into_iter().map(unpack).collect();
+            assert_eq!(replayed, expected);
+        });
+        let _ = std::fs::remove_file(&path);
+    }
+}
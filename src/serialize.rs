@@ -0,0 +1,146 @@
+// Serialization support, enabled by the serde feature
+//
+// Most types simply derive Serialize and Deserialize where they are defined.
+// The exceptions are here.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::db_access::StatusSeverity;
+
+
+// StatusSeverity is packed to match the wire format, and the derived code would
+// take references to its unaligned fields.  Instead the fields are copied out
+// to an ordinary struct of the same shape.
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "StatusSeverity")]
+struct AlignedStatusSeverity {
+    status: i16,
+    severity: i16,
+}
+
+impl Serialize for StatusSeverity {
+    fn serialize<S: Serializer>(&self, serializer: S)
+        -> Result<S::Ok, S::Error>
+    {
+        let StatusSeverity { status, severity } = *self;
+        AlignedStatusSeverity { status, severity }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusSeverity {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+        -> Result<Self, D::Error>
+    {
+        let AlignedStatusSeverity { status, severity } =
+            AlignedStatusSeverity::deserialize(deserializer)?;
+        Ok(StatusSeverity { status, severity })
+    }
+}
+
+
+// Timestamps as RFC 3339 strings with nanoseconds, for instance
+// "2020-03-11T09:41:27.123456789Z", rather than serde's own representation of
+// SystemTime.  This is also for use with timestamps returned alongside values:
+//
+//      #[serde(with = "epics_ca::rfc3339")]
+//      timestamp: SystemTime,
+pub mod rfc3339 {
+    use std::time::SystemTime;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S)
+        -> Result<S::Ok, S::Error>
+    {
+        serializer.collect_str(&humantime::format_rfc3339_nanos(*time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D)
+        -> Result<SystemTime, D::Error>
+    {
+        let string = String::deserialize(deserializer)?;
+        humantime::parse_rfc3339_weak(&string).map_err(de::Error::custom)
+    }
+}
+
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use serde::{Deserialize, Serialize};
+    use serde::de::DeserializeOwned;
+
+    use crate::caunion::CaUnion;
+    use crate::db_access::{CtrlLimits, StatusSeverity};
+    use crate::dbr::{CaEnum, FloatCtrl};
+
+    // Checks the serialized form, and that it reads back to the same value
+    fn round_trip<T>(value: &T, json: &str) -> T
+        where T: Serialize + DeserializeOwned
+    {
+        assert_eq!(serde_json::to_string(value).unwrap(), json);
+        let result: T = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_string(&result).unwrap(), json);
+        result
+    }
+
+    #[test]
+    fn status_severity()
+    {
+        let alarm = round_trip(
+            &StatusSeverity { status: 3, severity: 2 },
+            r#"{"status":3,"severity":2}"#);
+        let StatusSeverity { status, severity } = alarm;
+        assert_eq!((status, severity), (3, 2));
+    }
+
+    #[test]
+    fn values()
+    {
+        assert_eq!(round_trip(&CaEnum(2), "2").0, 2);
+        round_trip(&CaUnion::CaDouble(1.5), r#"{"CaDouble":1.5}"#);
+        round_trip(&CaUnion::CaEnum(CaEnum(1)), r#"{"CaEnum":1}"#);
+        round_trip(&CaUnion::CaString("text".to_owned()),
+            r#"{"CaString":"text"}"#);
+    }
+
+    #[test]
+    fn ctrl()
+    {
+        let limits = CtrlLimits {
+            upper_disp_limit: 10.0, lower_disp_limit: -10.0,
+            upper_alarm_limit: 9.0, upper_warning_limit: 8.0,
+            lower_warning_limit: -8.0, lower_alarm_limit: -9.0,
+            upper_ctrl_limit: 5.0, lower_ctrl_limit: -5.0,
+        };
+        let limits_json = concat!(
+            r#"{"upper_disp_limit":10.0,"lower_disp_limit":-10.0,"#,
+            r#""upper_alarm_limit":9.0,"upper_warning_limit":8.0,"#,
+            r#""lower_warning_limit":-8.0,"lower_alarm_limit":-9.0,"#,
+            r#""upper_ctrl_limit":5.0,"lower_ctrl_limit":-5.0}"#);
+        round_trip(&limits, limits_json);
+
+        let ctrl = FloatCtrl { units: "mA".to_owned(), precision: 3, limits };
+        let ctrl = round_trip(&ctrl, &format!(
+            r#"{{"units":"mA","precision":3,"limits":{}}}"#, limits_json));
+        assert_eq!((ctrl.units.as_str(), ctrl.precision), ("mA", 3));
+        assert_eq!(ctrl.limits.upper_ctrl_limit, 5.0);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Stamped {
+        #[serde(with = "super::rfc3339")]
+        timestamp: SystemTime,
+    }
+
+    #[test]
+    fn rfc3339()
+    {
+        let timestamp = UNIX_EPOCH + Duration::new(1_583_919_687, 123_456_789);
+        let stamped = round_trip(&Stamped { timestamp },
+            r#"{"timestamp":"2020-03-11T09:41:27.123456789Z"}"#);
+        assert_eq!(stamped.timestamp, timestamp);
+        assert!(serde_json::from_str::<Stamped>(
+            r#"{"timestamp":"yesterday"}"#).is_err());
+    }
+}
@@ -0,0 +1,1016 @@
+// Channel Access server
+//
+// Publishes PVs registered by the application.  Each PV holds a complete
+// PvValue which is served to clients in whatever format they request.  Name
+// searches are answered on UDP, and beacons announce the server to clients on
+// the beacon address list so that they search again for channels they could
+// not find when it starts or restarts.  Each client connection is handled by
+// its own thread, with a second thread writing the messages queued for it.  Put
+// handlers are async functions which are run to completion on a separate
+// thread before the put is acknowledged.  Puts to each PV are completed one at
+// a time in the order received.
+
+use std::{env, io, thread};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io::Write;
+use std::marker::PhantomData;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex, Weak, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use futures::executor::block_on;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::protocol::*;
+use crate::protocol::command::*;
+use crate::protocol::status::*;
+use crate::pv_value::{PvValue, PvCtrl, convert, resize, union_len};
+use crate::caunion::CaUnionVec;
+use crate::db_access::StatusSeverity;
+use crate::dbr::DbrMap;
+use crate::error::CaError;
+use crate::camonitor::{DBE_VALUE, DBE_LOG, DBE_ALARM, DBE_PROPERTY};
+
+
+// -----------------------------------------------------------------------------
+// Value types which can be published
+
+// Any of the basic DBR types can be published, either as a scalar or as an
+// array.  The value held for a scalar is a single element array.
+pub trait ServerValue: Sized + Send + 'static {
+    fn into_union(self) -> CaUnionVec;
+    fn from_union(value: CaUnionVec) -> Option<Self>;
+}
+
+impl<T> ServerValue for Vec<T>
+    where T: DbrMap + 'static,
+          Vec<T>: Into<CaUnionVec> + TryFrom<CaUnionVec>
+{
+    fn into_union(self) -> CaUnionVec { self.into() }
+    fn from_union(value: CaUnionVec) -> Option<Self> {
+        Self::try_from(value).ok()
+    }
+}
+
+impl<T> ServerValue for T
+    where T: DbrMap + 'static,
+          Vec<T>: Into<CaUnionVec> + TryFrom<CaUnionVec>
+{
+    fn into_union(self) -> CaUnionVec { vec![self].into() }
+    fn from_union(value: CaUnionVec) -> Option<Self> {
+        Vec::<T>::try_from(value).ok()?.into_iter().next()
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Published PVs
+
+type PutFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type PutHandler = Arc<dyn Fn(CaUnionVec) -> PutFuture + Send + Sync>;
+
+struct Subscriber {
+    client: Weak<Client>,
+    subid: u32,
+    datatype: u16,
+    count: usize,
+    mask: u32,
+}
+
+struct PvState {
+    value: PvValue,
+    // Element count reported to clients, puts are truncated to this length
+    count: usize,
+    subscribers: Vec<Subscriber>,
+    put_handler: Option<PutHandler>,
+}
+
+// A put waiting for its handler to be run
+struct Put {
+    value: CaUnionVec,
+    handler: Option<PutHandler>,
+    complete: Box<dyn FnOnce(u32) + Send>,
+}
+
+// Puts waiting to be completed, and whether a thread is completing them
+#[derive(Default)]
+struct PutQueue {
+    puts: VecDeque<Put>,
+    running: bool,
+}
+
+struct Pv {
+    name: String,
+    state: Mutex<PvState>,
+    puts: Mutex<PutQueue>,
+}
+
+impl Pv {
+    // Applies an update and posts the events it returns to all interested
+    // subscribers.
+    fn update(&self, update: impl FnOnce(&mut PvValue) -> u32)
+    {
+        let mut state = self.state.lock().unwrap();
+        let events = update(&mut state.value);
+        state.subscribers.retain(|s| s.client.strong_count() > 0);
+        for subscriber in &state.subscribers {
+            if subscriber.mask & events != 0 {
+                if let Some(client) = subscriber.client.upgrade() {
+                    client.send_update(&state.value, subscriber);
+                }
+            }
+        }
+    }
+
+    // Updates the value, timestamp and optionally the alarm state
+    fn set_value(&self, value: CaUnionVec,
+        alarm: Option<StatusSeverity>, timestamp: SystemTime)
+    {
+        self.update(|pv| {
+            let mut events = DBE_VALUE | DBE_LOG;
+            if let Some(alarm) = alarm {
+                let (status, severity) = (pv.status.status, pv.status.severity);
+                if alarm.status != status  ||  alarm.severity != severity {
+                    events |= DBE_ALARM;
+                }
+                pv.status = alarm;
+            }
+            pv.value = value;
+            pv.timestamp = timestamp;
+            events
+        });
+    }
+
+    fn read(&self, datatype: u16, count: usize) -> Option<(Vec<u8>, usize)>
+    {
+        self.state.lock().unwrap().value.encode(datatype, count)
+    }
+
+    // Queues a put, starting a thread to complete the queued puts if there is
+    // not one already.  The thread exits once the queue is empty.
+    fn put(self: &Arc<Self>, put: Put)
+    {
+        let mut queue = self.puts.lock().unwrap();
+        queue.puts.push_back(put);
+        if !queue.running {
+            queue.running = true;
+            let pv = self.clone();
+            thread::spawn(move || pv.put_thread());
+        }
+    }
+
+    fn put_thread(&self)
+    {
+        loop {
+            let put = {
+                let mut queue = self.puts.lock().unwrap();
+                match queue.puts.pop_front() {
+                    Some(put) => put,
+                    None => {
+                        queue.running = false;
+                        return;
+                    },
+                }
+            };
+            let result = match put.handler {
+                Some(handler) => block_on(handler(put.value.clone())),
+                None => Ok(()),
+            };
+            match result {
+                Ok(()) => {
+                    self.set_value(put.value, None, SystemTime::now());
+                    (put.complete)(ECA_NORMAL);
+                },
+                Err(_) => (put.complete)(ECA_PUTFAIL),
+            }
+        }
+    }
+}
+
+
+// Handle to a published PV returned by Server::add_pv
+pub struct PvHandle<T> {
+    pv: Arc<Pv>,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for PvHandle<T> {
+    fn clone(&self) -> Self
+    {
+        PvHandle { pv: self.pv.clone(), _type: PhantomData }
+    }
+}
+
+impl<T: ServerValue> PvHandle<T> {
+    pub fn name(&self) -> &str
+    {
+        &self.pv.name
+    }
+
+    // Fails if the value held cannot be read as T, reporting an empty value as
+    // a bad count
+    pub fn get(&self) -> Result<T, CaError>
+    {
+        let value = self.pv.state.lock().unwrap().value.value.clone();
+        let status =
+            if union_len(&value) == 0 { ECA_BADCOUNT } else { ECA_BADTYPE };
+        T::from_union(value).ok_or(CaError::Status(status))
+    }
+
+    pub fn get_alarm(&self) -> StatusSeverity
+    {
+        self.pv.state.lock().unwrap().value.status
+    }
+
+    // Updates the value with the current time, leaving the alarm unchanged
+    pub fn set(&self, value: T)
+    {
+        self.pv.set_value(value.into_union(), None, SystemTime::now());
+    }
+
+    pub fn set_alarm(&self, value: T, alarm: StatusSeverity)
+    {
+        self.pv.set_value(value.into_union(), Some(alarm), SystemTime::now());
+    }
+
+    pub fn set_with_time(
+        &self, value: T, alarm: StatusSeverity, timestamp: SystemTime)
+    {
+        self.pv.set_value(value.into_union(), Some(alarm), timestamp);
+    }
+
+    // Updates the control metadata: units, precision, limits, or the enum
+    // strings.  For example pass a FloatCtrl for a floating point PV.
+    pub fn set_ctrl(&self, ctrl: impl Into<PvCtrl>)
+    {
+        let ctrl = ctrl.into();
+        self.pv.update(|pv| {
+            pv.ctrl = ctrl;
+            DBE_PROPERTY
+        });
+    }
+
+    // Installs an async handler called for each put from a client with the
+    // value converted to the type of this PV.  If the handler succeeds the PV
+    // is updated with the new value, otherwise the put fails.  Without a
+    // handler all puts are accepted.
+    pub fn on_put<F, R>(&self, handler: F)
+        where
+            F: Fn(T) -> R + Send + Sync + 'static,
+            R: Future<Output = Result<(), String>> + Send + 'static
+    {
+        let handler: PutHandler = Arc::new(move |value| {
+            match T::from_union(value) {
+                Some(value) => Box::pin(handler(value)),
+                None => Box::pin(async { Err("Invalid value".to_owned()) }),
+            }
+        });
+        self.pv.state.lock().unwrap().put_handler = Some(handler);
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Client connections
+
+// Messages are queued for each client and written by its own thread, so that a
+// client which is slow to read never holds up updates to a PV or other
+// clients.  A client which falls too far behind is disconnected.
+const MAX_BACKLOG: usize = 1 << 24;
+
+#[derive(Default)]
+struct ClientQueue {
+    messages: Vec<u8>,
+    closed: bool,
+}
+
+struct Client {
+    stream: TcpStream,
+    queue: Mutex<ClientQueue>,
+    ready: Condvar,
+}
+
+impl Client {
+    fn send(&self, message: &[u8])
+    {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        if queue.messages.len() + message.len() > MAX_BACKLOG {
+            drop(queue);
+            self.close();
+        } else {
+            queue.messages.extend_from_slice(message);
+            self.ready.notify_one();
+        }
+    }
+
+    // Stops the writer thread, and shutting down the socket stops the reader
+    fn close(&self)
+    {
+        self.queue.lock().unwrap().closed = true;
+        self.ready.notify_one();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    fn writer_thread(&self, mut stream: TcpStream)
+    {
+        loop {
+            let messages = {
+                let mut queue = self.queue.lock().unwrap();
+                while queue.messages.is_empty()  &&  !queue.closed {
+                    queue = self.ready.wait(queue).unwrap();
+                }
+                if queue.closed {
+                    return;
+                }
+                std::mem::take(&mut queue.messages)
+            };
+            if stream.write_all(&messages).is_err() {
+                self.close();
+                return;
+            }
+        }
+    }
+
+    fn send_update(&self, value: &PvValue, subscriber: &Subscriber)
+    {
+        let message = match value.encode(subscriber.datatype, subscriber.count)
+        {
+            Some((dbr, count)) => message(
+                &Header::new(CA_PROTO_EVENT_ADD, subscriber.datatype,
+                    count as u32, ECA_NORMAL, subscriber.subid),
+                &dbr),
+            // An empty payload signals cancellation, so send a minimal one
+            None => message(
+                &Header::new(CA_PROTO_EVENT_ADD, subscriber.datatype,
+                    subscriber.count as u32, ECA_GETFAIL, subscriber.subid),
+                &[0; 8]),
+        };
+        self.send(&message);
+    }
+}
+
+
+struct Session {
+    server: Arc<ServerInner>,
+    client: Arc<Client>,
+    next_sid: u32,
+    channels: HashMap<u32, (Arc<Pv>, u32)>,     // sid => (PV, cid)
+    subscriptions: HashMap<u32, u32>,           // subid => sid
+}
+
+impl Session {
+    fn reply(&self, header: Header, payload: &[u8])
+    {
+        self.client.send(&message(&header, payload));
+    }
+
+    fn channel(&self, sid: u32) -> Option<Arc<Pv>>
+    {
+        self.channels.get(&sid).map(|(pv, _)| pv.clone())
+    }
+
+    fn dispatch(&mut self, message: Message)
+    {
+        let header = message.header;
+        match header.command {
+            CA_PROTO_CREATE_CHAN => self.create_channel(&header, &message),
+            CA_PROTO_READ_NOTIFY => self.read_notify(&header),
+            CA_PROTO_WRITE | CA_PROTO_WRITE_NOTIFY =>
+                self.write(&header, &message.payload),
+            CA_PROTO_EVENT_ADD => self.event_add(&header, &message.payload),
+            CA_PROTO_EVENT_CANCEL => {
+                self.cancel_subscription(header.param2);
+                self.reply(Header::new(CA_PROTO_EVENT_ADD,
+                    header.data_type, header.count,
+                    header.param1, header.param2), &[]);
+            },
+            CA_PROTO_CLEAR_CHANNEL => {
+                let sid = header.param1;
+                let subscriptions: Vec<u32> = self.subscriptions.iter()
+                    .filter(|(_, s)| **s == sid)
+                    .map(|(subid, _)| *subid).collect();
+                for subid in subscriptions {
+                    self.cancel_subscription(subid);
+                }
+                self.channels.remove(&sid);
+                self.reply(header, &[]);
+            },
+            CA_PROTO_ECHO => self.reply(header, &[]),
+            _ => { },
+        }
+    }
+
+    fn create_channel(&mut self, header: &Header, message: &Message)
+    {
+        let cid = header.param1;
+        let name = payload_string(&message.payload);
+        match self.server.lookup(&name) {
+            Some(pv) => {
+                let (datatype, count) = {
+                    let state = pv.state.lock().unwrap();
+                    (state.value.native_type().dbr_type() as u16, state.count)
+                };
+                let sid = self.next_sid;
+                self.next_sid += 1;
+                self.channels.insert(sid, (pv, cid));
+                self.reply(Header::new(CA_PROTO_ACCESS_RIGHTS, 0, 0,
+                    cid, CA_ACCESS_READ | CA_ACCESS_WRITE), &[]);
+                self.reply(Header::new(CA_PROTO_CREATE_CHAN,
+                    datatype, count as u32, cid, sid), &[]);
+            },
+            None =>
+                self.reply(
+                    Header::new(CA_PROTO_CREATE_CH_FAIL, 0, 0, cid, 0), &[]),
+        }
+    }
+
+    fn read_notify(&self, header: &Header)
+    {
+        let (datatype, ioid) = (header.data_type, header.param2);
+        let result = self.channel(header.param1)
+            .ok_or(ECA_DISCONN)
+            .and_then(|pv|
+                pv.read(datatype, header.count as usize).ok_or(ECA_BADTYPE));
+        match result {
+            Ok((dbr, count)) =>
+                self.reply(Header::new(CA_PROTO_READ_NOTIFY,
+                    datatype, count as u32, ECA_NORMAL, ioid), &dbr),
+            Err(status) =>
+                self.reply(Header::new(CA_PROTO_READ_NOTIFY,
+                    datatype, header.count, status, ioid), &[]),
+        }
+    }
+
+    // Puts are converted to the native type of the PV and then completed on a
+    // separate thread so that the put handler can take as long as it needs.
+    // Values out of range of the native type are rejected, and arrays longer
+    // than the PV are truncated.
+    fn write(&self, header: &Header, payload: &[u8])
+    {
+        let header = *header;
+        let notify = header.command == CA_PROTO_WRITE_NOTIFY;
+        let client = self.client.clone();
+        let complete = move |status| {
+            if notify {
+                client.send(&message(&Header::new(CA_PROTO_WRITE_NOTIFY,
+                    header.data_type, header.count, status, header.param2),
+                    &[]));
+            }
+        };
+
+        let pv = match self.channel(header.param1) {
+            Some(pv) => pv,
+            None => return complete(ECA_DISCONN),
+        };
+        if header.count == 0 {
+            return complete(ECA_BADCOUNT);
+        }
+        let put = {
+            let state = pv.state.lock().unwrap();
+            PvValue::decode(header.data_type, header.count as usize, payload)
+                .and_then(|value| convert(&value.value,
+                    state.value.native_type(), &state.value.ctrl))
+                .map(|mut value| {
+                    if union_len(&value) > state.count {
+                        resize(&mut value, state.count);
+                    }
+                    (value, state.put_handler.clone())
+                })
+        };
+        match put {
+            Some((value, handler)) =>
+                pv.put(Put { value, handler, complete: Box::new(complete) }),
+            None => complete(ECA_BADTYPE),
+        }
+    }
+
+    fn event_add(&mut self, header: &Header, payload: &[u8])
+    {
+        let (sid, subid) = (header.param1, header.param2);
+        let pv = match self.channel(sid) {
+            Some(pv) => pv,
+            None => return,
+        };
+        // The event mask follows the low, high and timeout fields
+        let mask = match payload.get(12..14) {
+            Some(mask) => u16::from_be_bytes([mask[0], mask[1]]) as u32,
+            None => 0,
+        };
+        let subscriber = Subscriber {
+            client: Arc::downgrade(&self.client),
+            subid,
+            datatype: header.data_type,
+            count: header.count as usize,
+            mask: if mask == 0 { DBE_VALUE | DBE_ALARM } else { mask },
+        };
+
+        // Every subscription starts with an update of the current value
+        let mut state = pv.state.lock().unwrap();
+        self.client.send_update(&state.value, &subscriber);
+        state.subscribers.push(subscriber);
+        self.subscriptions.insert(subid, sid);
+    }
+
+    fn cancel_subscription(&mut self, subid: u32)
+    {
+        if let Some(sid) = self.subscriptions.remove(&subid) {
+            if let Some(pv) = self.channel(sid) {
+                let client = Arc::downgrade(&self.client);
+                pv.state.lock().unwrap().subscribers.retain(|s|
+                    !(s.subid == subid  &&  Weak::ptr_eq(&s.client, &client)));
+            }
+        }
+    }
+}
+
+
+// -----------------------------------------------------------------------------
+// Server
+
+// Beacons are sent with an interval which doubles from the minimum to the
+// maximum, as for the servers of EPICS base.
+const BEACON_MIN: Duration = Duration::from_millis(20);
+const BEACON_MAX: Duration = Duration::from_secs(15);
+
+pub struct ServerConfig {
+    pub address: IpAddr,
+    // If zero an unused port is chosen, see Server::port
+    pub port: u16,
+    // Addresses to which beacons are sent, none are sent if empty
+    pub beacon_addr_list: Vec<SocketAddr>,
+}
+
+// Reads the first of the given environment variables which is set
+fn env_var(names: &[&str]) -> Option<String>
+{
+    names.iter().find_map(|name| env::var(name).ok())
+}
+
+// The configuration is taken from the environment variables used by the
+// servers of EPICS base, falling back to the client variables where those
+// servers do.
+impl Default for ServerConfig {
+    fn default() -> ServerConfig
+    {
+        let port = env_var(&["EPICS_CAS_SERVER_PORT", "EPICS_CA_SERVER_PORT"])
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(CA_SERVER_PORT);
+        let beacon_port =
+            env_var(&["EPICS_CAS_BEACON_PORT", "EPICS_CA_REPEATER_PORT"])
+            .and_then(|port| port.parse().ok())
+            .unwrap_or(CA_REPEATER_PORT);
+        let mut beacon_addr_list = parse_addr_list(
+            &env_var(&["EPICS_CAS_BEACON_ADDR_LIST", "EPICS_CA_ADDR_LIST"])
+                .unwrap_or_default(),
+            beacon_port);
+        let auto_addr_list = env_var(
+                &["EPICS_CAS_AUTO_BEACON_ADDR_LIST", "EPICS_CA_AUTO_ADDR_LIST"])
+            .map(|auto| !auto.eq_ignore_ascii_case("no"))
+            .unwrap_or(true);
+        if auto_addr_list {
+            beacon_addr_list.push(
+                SocketAddr::from((Ipv4Addr::BROADCAST, beacon_port)));
+        }
+        ServerConfig {
+            address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port,
+            beacon_addr_list,
+        }
+    }
+}
+
+struct ServerInner {
+    address: SocketAddr,
+    pvs: Mutex<HashMap<String, Arc<Pv>>>,
+    clients: Mutex<Vec<Weak<Client>>>,
+    running: AtomicBool,
+}
+
+// The beacon thread stops when the sender is dropped with the server
+pub struct Server {
+    inner: Arc<ServerInner>,
+    _beacon_stop: mpsc::Sender<()>,
+}
+
+impl ServerInner {
+    fn lookup(&self, name: &str) -> Option<Arc<Pv>>
+    {
+        self.pvs.lock().unwrap().get(name).cloned()
+    }
+
+    fn search_thread(self: Arc<Self>, udp: UdpSocket)
+    {
+        let mut buffer = vec![0; 0x10000];
+        while self.running.load(Ordering::Relaxed) {
+            if let Ok((length, from)) = udp.recv_from(&mut buffer) {
+                if let Some(reply) = self.search_reply(&buffer[..length]) {
+                    let _ = udp.send_to(&reply, from);
+                }
+            }
+        }
+    }
+
+    // Sends a beacon to each address in turn, with a count identifying each
+    // beacon, until the server is stopped.  The server address is left as zero
+    // unless bound to a single IPv4 address, telling clients to use the address
+    // the beacon came from.
+    fn beacon_thread(
+        address: SocketAddr, addr_list: Vec<SocketAddr>, udp: UdpSocket,
+        stop: mpsc::Receiver<()>)
+    {
+        let server_ip = match address.ip() {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        };
+        let mut interval = BEACON_MIN;
+        let mut beacon_id: u32 = 0;
+        loop {
+            let beacon = message(
+                &Header::new(CA_PROTO_RSRV_IS_UP, CA_MINOR_VERSION,
+                    address.port() as u32, beacon_id, server_ip),
+                &[]);
+            for to in &addr_list {
+                let _ = udp.send_to(&beacon, to);
+            }
+            beacon_id = beacon_id.wrapping_add(1);
+            if let Err(mpsc::RecvTimeoutError::Disconnected) =
+                stop.recv_timeout(interval)
+            {
+                break;
+            }
+            interval = (interval * 2).min(BEACON_MAX);
+        }
+    }
+
+    // Replies to all the searches for PVs we know about.  All ones as the
+    // server address tells the client to use the address the reply came from.
+    fn search_reply(&self, datagram: &[u8]) -> Option<Vec<u8>>
+    {
+        let mut reply = message(
+            &Header::new(CA_PROTO_VERSION, 0, CA_MINOR_VERSION as u32, 0, 0),
+            &[]);
+        let empty_length = reply.len();
+        for search in parse_datagram(datagram) {
+            if search.header.command == CA_PROTO_SEARCH  &&
+               self.lookup(&payload_string(&search.payload)).is_some()
+            {
+                put_message(&mut reply,
+                    &Header::new(CA_PROTO_SEARCH, self.address.port(), 0,
+                        0xFFFF_FFFF, search.header.param2),
+                    &CA_MINOR_VERSION.to_be_bytes());
+            }
+        }
+        if reply.len() > empty_length { Some(reply) } else { None }
+    }
+
+    fn listen_thread(self: Arc<Self>, listener: TcpListener)
+    {
+        for stream in listener.incoming() {
+            if !self.running.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Ok(stream) = stream {
+                let server = self.clone();
+                thread::spawn(move || server.client_thread(stream));
+            }
+        }
+    }
+
+    fn client_thread(self: Arc<Self>, mut stream: TcpStream)
+    {
+        let (client, writer) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(shutdown), Ok(writer)) => (Arc::new(Client {
+                stream: shutdown,
+                queue: Mutex::new(ClientQueue::default()),
+                ready: Condvar::new(),
+            }), writer),
+            _ => return,
+        };
+        {
+            let mut clients = self.clients.lock().unwrap();
+            clients.retain(|client| client.strong_count() > 0);
+            clients.push(Arc::downgrade(&client));
+        }
+        let _ = stream.set_nodelay(true);
+        let writer_client = client.clone();
+        thread::spawn(move || writer_client.writer_thread(writer));
+        client.send(&message(
+            &Header::new(CA_PROTO_VERSION, 0, CA_MINOR_VERSION as u32, 0, 0),
+            &[]));
+
+        let mut session = Session {
+            server: self,
+            client,
+            next_sid: 1,
+            channels: HashMap::new(),
+            subscriptions: HashMap::new(),
+        };
+        while let Ok(message) = read_message(&mut stream) {
+            session.dispatch(message);
+        }
+        session.client.close();
+    }
+}
+
+// Binds the socket for name searches, allowing other servers on the same host
+// to share the port, as the servers of EPICS base do, so that each can answer
+// the searches broadcast to it.
+fn bind_search(address: SocketAddr) -> io::Result<UdpSocket>
+{
+    let socket = Socket::new(
+        Domain::for_address(address), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    Ok(socket.into())
+}
+
+impl Server {
+    // Starts the server listening for searches and connections.  PVs can be
+    // added at any time.
+    pub fn start(config: ServerConfig) -> io::Result<Server>
+    {
+        // The TCP and UDP ports must be the same, so if we have been asked to
+        // choose one let TCP choose.
+        let listener = TcpListener::bind((config.address, config.port))?;
+        let address = listener.local_addr()?;
+        let udp = bind_search(address)?;
+
+        let inner = Arc::new(ServerInner {
+            address,
+            pvs: Mutex::new(HashMap::new()),
+            clients: Mutex::new(Vec::new()),
+            running: AtomicBool::new(true),
+        });
+        let server = inner.clone();
+        thread::spawn(move || server.search_thread(udp));
+        let server = inner.clone();
+        thread::spawn(move || server.listen_thread(listener));
+
+        let (beacon_stop, stop) = mpsc::channel();
+        if !config.beacon_addr_list.is_empty() {
+            let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+            udp.set_broadcast(true)?;
+            let addr_list = config.beacon_addr_list;
+            thread::spawn(move ||
+                ServerInner::beacon_thread(address, addr_list, udp, stop));
+        }
+        Ok(Server { inner, _beacon_stop: beacon_stop })
+    }
+
+    pub fn port(&self) -> u16
+    {
+        self.inner.address.port()
+    }
+
+    // Publishes a new PV with the given initial value, replacing any existing
+    // PV of the same name.  The type and length of the value determine the
+    // native type and element count of the PV.
+    pub fn add_pv<T: ServerValue>(&self, name: &str, value: T) -> PvHandle<T>
+    {
+        let value = PvValue::new(value.into_union());
+        let pv = Arc::new(Pv {
+            name: name.to_owned(),
+            state: Mutex::new(PvState {
+                count: value.native_count().max(1),
+                value,
+                subscribers: Vec::new(),
+                put_handler: None,
+            }),
+            puts: Mutex::new(PutQueue::default()),
+        });
+        self.inner.pvs.lock().unwrap().insert(name.to_owned(), pv.clone());
+        PvHandle { pv, _type: PhantomData }
+    }
+
+    // Existing connections to the PV are unaffected
+    pub fn remove_pv(&self, name: &str)
+    {
+        self.inner.pvs.lock().unwrap().remove(name);
+    }
+}
+
+// Stops the server and drops all client connections.  The search and listen
+// threads are woken so that they can see that we're stopping, by sending to the
+// address the server is bound to, or to loopback if bound to all interfaces.
+impl Drop for Server {
+    fn drop(&mut self)
+    {
+        self.inner.running.store(false, Ordering::Relaxed);
+        let mut address = self.inner.address;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let any = match address {
+            SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+        };
+        if let Ok(udp) = UdpSocket::bind((any, 0)) {
+            let _ = udp.send_to(&[], address);
+        }
+        let _ = TcpStream::connect(address);
+        for client in self.inner.clients.lock().unwrap().drain(..) {
+            if let Some(client) = client.upgrade() {
+                client.close();
+            }
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use futures::executor::block_on;
+    use socket2::{Domain, Socket, Type};
+
+    use super::{Server, ServerConfig};
+    use crate::cadef::{event_handler_args, ref_to_voidp, voidp_to_ref};
+    use crate::caget::CA;
+    use crate::caput::CaPut;
+    use crate::channel;
+    use crate::db_access::dbr_type_code::DBR_DOUBLE;
+    use crate::error::CaError;
+    use crate::native::{ClientConfig, Context};
+    use crate::protocol::parse_datagram;
+    use crate::protocol::command::CA_PROTO_RSRV_IS_UP;
+    use crate::protocol::status::{ECA_BADTYPE, ECA_DISCONN, ECA_NORMAL};
+    use crate::timer;
+
+    fn start() -> (Server, Arc<Context>)
+    {
+        let server = Server::start(ServerConfig {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            beacon_addr_list: Vec::new(),
+        }).unwrap();
+        let context = Context::new(ClientConfig {
+            addr_list: vec![
+                SocketAddr::from((Ipv4Addr::LOCALHOST, server.port()))],
+            on_error: None,
+        }).unwrap();
+        (server, context)
+    }
+
+    // The first put is held up by its handler, but must still be handled
+    // before the second
+    #[test]
+    fn puts_complete_in_order()
+    {
+        let (server, context) = start();
+        let _context = context.install();
+        let pv = server.add_pv("TEST:ORDER", 0.0);
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let order = handled.clone();
+        pv.on_put(move |value: f64| {
+            let order = order.clone();
+            async move {
+                if value == 1.0 {
+                    timer::sleep(Duration::from_millis(50)).await;
+                }
+                order.lock().unwrap().push(value);
+                Ok(())
+            }
+        });
+        block_on(async {
+            f64::try_caput_nowait("TEST:ORDER", 1.0).await.unwrap();
+            f64::caput("TEST:ORDER", 2.0).await;
+        });
+        assert_eq!(*handled.lock().unwrap(), [1.0, 2.0]);
+        assert_eq!(pv.get().unwrap(), 2.0);
+    }
+
+    // Beacons carry the server port and count up from zero
+    #[test]
+    fn beacons_sent()
+    {
+        let listener = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server = Server::start(ServerConfig {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            beacon_addr_list: vec![listener.local_addr().unwrap()],
+        }).unwrap();
+        let mut buffer = [0; 64];
+        for beacon_id in 0..3 {
+            let (length, _) = listener.recv_from(&mut buffer).unwrap();
+            let header = parse_datagram(&buffer[..length])[0].header;
+            assert_eq!(header.command, CA_PROTO_RSRV_IS_UP);
+            assert_eq!(header.count, server.port() as u32);
+            assert_eq!(header.param1, beacon_id);
+            assert_eq!(header.param2, u32::from(Ipv4Addr::LOCALHOST));
+        }
+    }
+
+    // The search port can be shared with another server on the same host
+    #[test]
+    fn search_port_shared()
+    {
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let other = Socket::new(Domain::IPV4, Type::DGRAM, None).unwrap();
+        other.set_reuse_address(true).unwrap();
+        other.bind(&address.into()).unwrap();
+        let port = other.local_addr().unwrap().as_socket().unwrap().port();
+        Server::start(ServerConfig {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port,
+            beacon_addr_list: Vec::new(),
+        }).unwrap();
+    }
+
+    // A put still outstanding when its channel is cleared is failed, so that
+    // its handler can release what it holds
+    #[test]
+    fn outstanding_put_failed_on_clear()
+    {
+        extern "C" fn handler(args: event_handler_args)
+        {
+            let status: &AtomicI32 = unsafe { voidp_to_ref(args.usr) };
+            status.store(args.status, Ordering::SeqCst);
+        }
+
+        let (server, context) = start();
+        let _context = context.install();
+        let pv = server.add_pv("TEST:SLOW", 0.0);
+        pv.on_put(|_value: f64| async {
+            timer::sleep(Duration::from_millis(200)).await;
+            Ok(())
+        });
+        let (channel, _, _) = block_on(channel::connect("TEST:SLOW")).unwrap();
+        let status = AtomicI32::new(0);
+        let value = 1.0f64;
+        let rc = channel.backend.array_put_callback(
+            DBR_DOUBLE as i64, 1, channel.id, ref_to_voidp(&value),
+            handler, ref_to_voidp(&status));
+        assert_eq!(rc as u32, ECA_NORMAL);
+        drop(channel);
+        assert_eq!(status.load(Ordering::SeqCst) as u32, ECA_DISCONN);
+    }
+
+    // The threads of a server bound to a single address other than
+    // 127.0.0.1 must still be woken and stop when it is dropped
+    #[test]
+    fn stopped_on_specific_address()
+    {
+        let server = Server::start(ServerConfig {
+            address: Ipv4Addr::new(127, 0, 0, 2).into(),
+            port: 0,
+            beacon_addr_list: Vec::new(),
+        }).unwrap();
+        let inner = Arc::downgrade(&server.inner);
+        drop(server);
+        let start = Instant::now();
+        while inner.strong_count() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5),
+                "Server threads still running");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn out_of_range_put_rejected()
+    {
+        let (server, context) = start();
+        let _context = context.install();
+        let pv = server.add_pv("TEST:SHORT", 5i16);
+        block_on(async {
+            assert_eq!(i32::try_caput("TEST:SHORT", 70000).await,
+                Err(CaError::Status(ECA_BADTYPE)));
+            assert_eq!(f64::try_caput("TEST:SHORT", -1e10).await,
+                Err(CaError::Status(ECA_BADTYPE)));
+            i32::caput("TEST:SHORT", -300).await;
+        });
+        assert_eq!(pv.get().unwrap(), -300);
+    }
+
+    #[test]
+    fn put_truncated_to_element_count()
+    {
+        let (server, context) = start();
+        let _context = context.install();
+        let scalar = server.add_pv("TEST:SCALAR", 0.0);
+        let array = server.add_pv("TEST:ARRAY", vec![0i32; 3]);
+        block_on(async {
+            Vec::<f64>::caput("TEST:SCALAR", vec![1.0, 2.0, 3.0]).await;
+            assert_eq!(Vec::<f64>::caget("TEST:SCALAR").await, [1.0]);
+            Vec::<i32>::caput("TEST:ARRAY", vec![1, 2, 3, 4, 5]).await;
+            Vec::<i32>::caput("TEST:ARRAY", vec![6, 7]).await;
+            Vec::<i32>::caput("TEST:ARRAY", vec![8, 9, 10, 11]).await;
+        });
+        assert_eq!(scalar.get().unwrap(), 1.0);
+        assert_eq!(array.get().unwrap(), [8, 9, 10]);
+    }
+}
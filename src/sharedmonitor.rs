@@ -0,0 +1,341 @@
+// Monitors shared between subscribers
+//
+// Every shared monitor of the same PV, with the same request type and event
+// mask, is served by a single channel and subscription for the whole process:
+//
+//      let mut monitor = f64::camonitor_shared("SR-DI-DCCT-01:SIGNAL").await;
+//
+// Each update is passed on to every subscriber, and a new subscriber is given
+// the most recent update straight away rather than waiting for the next one.
+// This is forgotten when the channel disconnects, so that a subscriber joining
+// while the server is down waits for the update on reconnection.
+// The subscription and its channel are cleared when the last subscriber is
+// dropped.
+//
+// Subscribers share the raw updates and each converts them to its own type, so
+// for instance monitors of f64 and of u32 share one DBR_DOUBLE subscription.
+
+use std::{any, collections, pin, sync, task};
+use async_trait::async_trait;
+use futures::stream::Stream;
+
+use crate::backend;
+use crate::cadef;
+use crate::dbr::Dbr;
+use crate::callback;
+use crate::channel;
+use crate::caget::{CaResult, GetResult};
+use crate::camonitor::{Monitor, DBE_VALUE, DBE_ALARM};
+use crate::error::CaError;
+
+
+// Subscriptions are only shared between channels on the same backend, which is
+// identified by its address.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Key {
+    backend: usize,
+    pv: String,
+    datatype: i64,
+    count: u64,
+    mask: u32,
+}
+
+// The registry only holds weak references, so that dropping the last
+// subscriber releases the subscription, which then removes its own entry.
+type Registry =
+    collections::HashMap<Key, sync::Weak<dyn any::Any + Send + Sync>>;
+
+fn registry() -> &'static sync::Mutex<Registry>
+{
+    static REGISTRY: sync::OnceLock<sync::Mutex<Registry>> =
+        sync::OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+// The key determines the type of the update, so the downcast cannot fail
+fn lookup<U>(registry: &Registry, key: &Key) -> Option<sync::Arc<Shared<U>>>
+    where U: Clone + Send + 'static
+{
+    let shared = registry.get(key)?.upgrade()?;
+    Some(shared.downcast().ok().unwrap())
+}
+
+
+// Raw value together with its status, timestamp or control information
+type Update<T> = (
+    <T as CaResult>::Value, <<T as CaResult>::Dbr as Dbr>::ExtraType);
+
+struct Fanout<U: Send> {
+    latest: Option<U>,
+    subscribers: Vec<sync::Weak<callback::AsyncStream<U>>>,
+}
+
+impl<U: Clone + Send> Fanout<U> {
+    fn publish(&mut self, update: U)
+    {
+        self.subscribers.retain(|subscriber|
+            match subscriber.upgrade() {
+                Some(stream) => { stream.push(update.clone()); true },
+                None => false,
+            });
+        self.latest = Some(update);
+    }
+
+    fn subscribe(&mut self, stream: &sync::Arc<callback::AsyncStream<U>>)
+    {
+        if let Some(latest) = &self.latest {
+            stream.push(latest.clone());
+        }
+        self.subscribers.push(sync::Arc::downgrade(stream));
+    }
+}
+
+extern "C" fn shared_callback<D, V>(args: cadef::event_handler_args)
+    where D: Dbr, V: GetResult<D> + Clone, D::ExtraType: Clone
+{
+    let fanout: &sync::Mutex<Fanout<(V, D::ExtraType)>> =
+        unsafe { cadef::voidp_to_ref(args.usr) };
+    if args.status == cadef::ECA_NORMAL {
+        let dbr: &D = unsafe { cadef::voidp_to_ref(args.dbr) };
+        let value = V::get_result(dbr, args.count as usize);
+        fanout.lock().unwrap().publish((value, dbr.get_extra()));
+    }
+}
+
+
+// The channel and subscription shared by all subscribers.  The fanout stays at
+// a fixed address inside the Arc, which is given to the backend as the
+// subscription's argument.  The key is set once the subscription is registered.
+struct Shared<U: Send> {
+    fanout: sync::Mutex<Fanout<U>>,
+    subscription: sync::Mutex<(Box<channel::Channel>, cadef::EvId)>,
+    key: sync::OnceLock<Key>,
+}
+
+impl<V, E> Shared<(V, E)>
+    where V: Clone + Send + 'static, E: Clone + Send + 'static
+{
+    fn new<D>(channel: Box<channel::Channel>, mask: u32)
+        -> Result<sync::Arc<Shared<(V, E)>>, CaError>
+        where D: Dbr<ExtraType = E>, V: GetResult<D>
+    {
+        let shared = sync::Arc::new(Shared {
+            fanout: sync::Mutex::new(
+                Fanout { latest: None, subscribers: Vec::new() }),
+            subscription: sync::Mutex::new((channel, cadef::EV_ID_VOID)),
+            key: sync::OnceLock::new(),
+        });
+        {
+            let mut subscription = shared.subscription.lock().unwrap();
+            let (channel, evid) = &mut *subscription;
+            let watcher: sync::Arc<dyn channel::ConnectionWatcher> =
+                shared.clone();
+            channel.watch(sync::Arc::downgrade(&watcher));
+            let rc = channel.backend.create_subscription(
+                D::DATATYPE as i64, V::COUNT, channel.id, mask as i64,
+                shared_callback::<D, V>,
+                cadef::ref_to_voidp(&shared.fanout), evid);
+            CaError::check(rc)?;
+            channel.backend.flush_io();
+        }
+        Ok(shared)
+    }
+}
+
+impl<U: Send> channel::ConnectionWatcher for Shared<U> {
+    fn connection_changed(&self, event: channel::ConnectionEvent)
+    {
+        if let channel::ConnectionEvent::Disconnected = event {
+            self.fanout.lock().unwrap().latest = None;
+        }
+    }
+}
+
+// If the subscription was never created there is nothing to clear.  The
+// registry entry is only removed if it has not already been replaced by a new
+// subscription for the same key.
+impl<U: Send> Drop for Shared<U> {
+    fn drop(&mut self)
+    {
+        if let Some(key) = self.key.get() {
+            let mut registry = registry().lock().unwrap();
+            if registry.get(key).is_some_and(|entry| entry.strong_count() == 0)
+            {
+                registry.remove(key);
+            }
+        }
+        let (channel, evid) = &*self.subscription.lock().unwrap();
+        if !evid.0.is_null() {
+            let _ = channel.backend.clear_subscription(*evid);
+        }
+    }
+}
+
+
+// A single subscriber, converting each shared update to T.  Values which cannot
+// be converted to T are skipped.
+struct Subscriber<T: CaResult> {
+    stream: sync::Arc<callback::AsyncStream<Update<T>>>,
+    _shared: sync::Arc<Shared<Update<T>>>,
+}
+
+impl<T: CaResult> Stream for Subscriber<T> {
+    type Item = T;
+
+    fn poll_next(self: pin::Pin<&mut Self>, context: &mut task::Context)
+        -> task::Poll<Option<T>>
+    {
+        loop {
+            match self.stream.poll_next(context) {
+                task::Poll::Ready(Some((value, extra))) =>
+                    if let Ok(result) = T::assemble(value, extra) {
+                        return task::Poll::Ready(Some(result));
+                    },
+                task::Poll::Ready(None) => return task::Poll::Ready(None),
+                task::Poll::Pending => return task::Poll::Pending,
+            }
+        }
+    }
+}
+
+async fn subscribe<T>(pv: &str, mask: u32) -> Result<Subscriber<T>, CaError>
+    where T: CaResult + 'static, T::Value: Clone + 'static,
+          <T::Dbr as Dbr>::ExtraType: Clone + 'static
+{
+    let key = Key {
+        backend: sync::Arc::as_ptr(&backend::current()) as *const () as usize,
+        pv: pv.to_owned(),
+        datatype: T::Dbr::DATATYPE as i64,
+        count: T::Value::COUNT,
+        mask,
+    };
+
+    let found = lookup(&registry().lock().unwrap(), &key);
+    let shared = match found {
+        Some(shared) => shared,
+        None => {
+            let (channel, _datatype, _count) = channel::connect(pv).await?;
+            // Another subscriber may have got there first while we were
+            // connecting, in which case our channel is simply dropped.
+            let mut registry = registry().lock().unwrap();
+            match lookup(&registry, &key) {
+                Some(shared) => shared,
+                None => {
+                    let shared =
+                        Shared::<Update<T>>::new::<T::Dbr>(channel, mask)?;
+                    registry.insert(
+                        key.clone(), sync::Arc::downgrade(&shared) as _);
+                    shared.key.set(key).ok();
+                    shared
+                },
+            }
+        },
+    };
+
+    let stream = sync::Arc::new(callback::AsyncStream::new());
+    shared.fanout.lock().unwrap().subscribe(&stream);
+    Ok(Subscriber { stream, _shared: shared })
+}
+
+
+// -----------------------------------------------------------------------------
+// camonitor_shared
+
+#[async_trait(?Send)]
+pub trait CaSharedMonitor: Sized {
+    async fn try_camonitor_shared_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>;
+
+    async fn try_camonitor_shared(pv: impl AsRef<str>)
+        -> Result<Monitor<Self>, CaError>
+    {
+        Self::try_camonitor_shared_mask(pv, DBE_VALUE | DBE_ALARM).await
+    }
+
+    async fn camonitor_shared_mask(pv: impl AsRef<str>, mask: u32)
+        -> Monitor<Self>
+    {
+        let pv = pv.as_ref();
+        match Self::try_camonitor_shared_mask(pv, mask).await {
+            Ok(monitor) => monitor,
+            Err(error) => panic!("camonitor {} failed: {}", pv, error),
+        }
+    }
+
+    async fn camonitor_shared(pv: impl AsRef<str>) -> Monitor<Self> {
+        Self::camonitor_shared_mask(pv, DBE_VALUE | DBE_ALARM).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> CaSharedMonitor for T
+    where T: CaResult + 'static, T::Value: Clone + 'static,
+          <T::Dbr as Dbr>::ExtraType: Clone + 'static
+{
+    async fn try_camonitor_shared_mask(pv: impl AsRef<str>, mask: u32)
+        -> Result<Monitor<Self>, CaError>
+    {
+        Ok(Monitor::new(subscribe::<T>(pv.as_ref(), mask).await?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+    use futures::executor::block_on;
+
+    use super::{CaSharedMonitor, registry};
+    use crate::mock::MockBackend;
+
+    fn registered(pv: &str) -> usize
+    {
+        registry().lock().unwrap().keys().filter(|key| key.pv == pv).count()
+    }
+
+    #[test]
+    fn released_with_last_subscriber()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:SHARED", 1.0f64);
+        block_on(async {
+            let mut first = f64::camonitor_shared("TEST:SHARED").await;
+            let mut second = u32::camonitor_shared("TEST:SHARED").await;
+            assert_eq!(first.next().await, Some(1.0));
+            assert_eq!(second.next().await, Some(1));
+            assert_eq!(registered("TEST:SHARED"), 1);
+
+            drop(first);
+            assert_eq!(registered("TEST:SHARED"), 1);
+            mock.set("TEST:SHARED", 2.0f64);
+            assert_eq!(second.next().await, Some(2));
+            drop(second);
+            assert_eq!(registered("TEST:SHARED"), 0);
+        });
+    }
+
+    // A subscriber joining while the channel is disconnected waits for the
+    // update on reconnection
+    #[test]
+    fn latest_forgotten_on_disconnect()
+    {
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:SHARED", 1.0f64);
+        block_on(async {
+            let mut first = f64::camonitor_shared("TEST:SHARED").await;
+            assert_eq!(first.next().await, Some(1.0));
+            mock.disconnect("TEST:SHARED");
+            let mut second = f64::camonitor_shared("TEST:SHARED").await;
+            assert_eq!(second.next().now_or_never(), None);
+
+            mock.set("TEST:SHARED", 2.0f64);
+            mock.reconnect("TEST:SHARED");
+            assert_eq!(first.next().await, Some(2.0));
+            assert_eq!(second.next().await, Some(2.0));
+            let mut third = f64::camonitor_shared("TEST:SHARED").await;
+            assert_eq!(third.next().await, Some(2.0));
+        });
+    }
+}
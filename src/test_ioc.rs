@@ -0,0 +1,566 @@
+// Test fixture running an isolated IOC
+//
+// A TestIoc serves the records of a .db file on its own port on the loopback
+// interface, and provides a client Context which only searches that port.  Any
+// number of fixtures can run at the same time, for instance in parallel
+// integration tests, without seeing each other's PVs or those on the network.
+// The IOC is stopped when the fixture is dropped.
+//
+// The IOC is either a real softIoc from EPICS base, or our own Server loaded
+// with the same records.  The latter only understands the common record types
+// and serves their initial values and standard fields: nothing is processed,
+// though puts are accepted and update the value.
+
+use std::{env, fs, io, thread, time};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::backend::BackendGuard;
+use crate::native::{ClientConfig, Context};
+use crate::server::{Server, ServerConfig};
+use crate::pv_value::PvCtrl;
+use crate::dbr::CaEnum;
+use crate::db_access::{ALARM_STATUS_NAMES, ALARM_SEVERITY_NAMES};
+
+
+// How long to wait for softIoc to start listening
+const START_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// How many times to start softIoc if its port is taken before it can bind it
+const START_ATTEMPTS: usize = 3;
+
+enum Ioc {
+    SoftIoc { child: Child, db_file: PathBuf, port: u16 },
+    Server(Server),
+}
+
+pub struct TestIoc {
+    ioc: Ioc,
+    context: Arc<Context>,
+}
+
+impl TestIoc {
+    // Runs softIoc with the given database.  softIoc is taken from
+    // $EPICS_BASE/bin/$EPICS_HOST_ARCH if these are set, otherwise from PATH.
+    pub fn soft_ioc(db: &str) -> io::Result<TestIoc>
+    {
+        for _ in 1..START_ATTEMPTS {
+            match TestIoc::start_soft_ioc(db) {
+                Err(error) if error.kind() == io::ErrorKind::AddrInUse => { },
+                result => return result,
+            }
+        }
+        TestIoc::start_soft_ioc(db)
+    }
+
+    fn start_soft_ioc(db: &str) -> io::Result<TestIoc>
+    {
+        let db_file = temp_db_file()?;
+        fs::write(&db_file, db)?;
+        let port = free_port()?;
+        let repeater_port = free_port()?;
+        let loopback = Ipv4Addr::LOCALHOST.to_string();
+        let child = Command::new(soft_ioc_path())
+            .arg("-S")
+            .arg("-d").arg(&db_file)
+            .env("EPICS_CA_SERVER_PORT", port.to_string())
+            .env("EPICS_CAS_SERVER_PORT", port.to_string())
+            .env("EPICS_CA_REPEATER_PORT", repeater_port.to_string())
+            .env("EPICS_CAS_INTF_ADDR_LIST", &loopback)
+            .env("EPICS_CAS_BEACON_ADDR_LIST", &loopback)
+            .env("EPICS_CAS_AUTO_BEACON_ADDR_LIST", "NO")
+            .env("EPICS_CA_ADDR_LIST", &loopback)
+            .env("EPICS_CA_AUTO_ADDR_LIST", "NO")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn();
+        let child = match child {
+            Ok(child) => child,
+            Err(error) => {
+                let _ = fs::remove_file(&db_file);
+                return Err(error);
+            },
+        };
+        // Construct the fixture now so that the IOC is cleaned up on failure
+        let mut ioc = TestIoc::new(Ioc::SoftIoc { child, db_file, port })?;
+        ioc.wait_started()?;
+        Ok(ioc)
+    }
+
+    // Serves the given database with our own server
+    pub fn server(db: &str) -> io::Result<TestIoc>
+    {
+        let records = parse_db(db)?;
+        let server = Server::start(ServerConfig {
+            address: Ipv4Addr::LOCALHOST.into(),
+            port: 0,
+            beacon_addr_list: Vec::new(),
+        })?;
+        for record in &records {
+            add_record(&server, record)?;
+        }
+        TestIoc::new(Ioc::Server(server))
+    }
+
+    fn new(ioc: Ioc) -> io::Result<TestIoc>
+    {
+        let port = match &ioc {
+            Ioc::SoftIoc { port, .. } => *port,
+            Ioc::Server(server) => server.port(),
+        };
+        let context = Context::new(ClientConfig {
+            addr_list: vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+            on_error: None,
+        })?;
+        Ok(TestIoc { ioc, context })
+    }
+
+    // Waits for softIoc to accept connections.  If it fails to start and its
+    // port has since been taken by another process this is reported as
+    // AddrInUse, so that it can be started again on another port.
+    fn wait_started(&mut self) -> io::Result<()>
+    {
+        let port = self.port();
+        let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+        let deadline = time::Instant::now() + START_TIMEOUT;
+        let Ioc::SoftIoc { child, .. } = &mut self.ioc else { return Ok(()) };
+        let error = loop {
+            if TcpStream::connect(address).is_ok() {
+                return Ok(());
+            }
+            if let Some(status) = child.try_wait()? {
+                break io::Error::other(format!("softIoc exited: {}", status));
+            }
+            if time::Instant::now() >= deadline {
+                break io::Error::new(
+                    io::ErrorKind::TimedOut, "softIoc did not start");
+            }
+            thread::sleep(time::Duration::from_millis(50));
+        };
+
+        let _ = child.kill();
+        let _ = child.wait();
+        if TcpListener::bind((Ipv4Addr::LOCALHOST, port)).is_err() {
+            Err(io::Error::new(io::ErrorKind::AddrInUse,
+                format!("softIoc port {} taken by another process", port)))
+        } else {
+            Err(error)
+        }
+    }
+
+    // The port on which the IOC serves both searches and connections
+    pub fn port(&self) -> u16
+    {
+        self.context.addr_list()[0].port()
+    }
+
+    // A client context which only sees this IOC
+    pub fn context(&self) -> &Arc<Context>
+    {
+        &self.context
+    }
+
+    // Uses the context of this IOC for all channels subsequently created on
+    // this thread until the returned guard is dropped.
+    pub fn install(&self) -> BackendGuard
+    {
+        self.context.install()
+    }
+}
+
+impl Drop for TestIoc {
+    fn drop(&mut self)
+    {
+        if let Ioc::SoftIoc { child, db_file, .. } = &mut self.ioc {
+            let _ = child.kill();
+            let _ = child.wait();
+            let _ = fs::remove_file(db_file);
+        }
+    }
+}
+
+
+fn soft_ioc_path() -> PathBuf
+{
+    match (env::var_os("EPICS_BASE"), env::var_os("EPICS_HOST_ARCH")) {
+        (Some(base), Some(arch)) =>
+            PathBuf::from(base).join("bin").join(arch).join("softIoc"),
+        _ => PathBuf::from("softIoc"),
+    }
+}
+
+fn temp_db_file() -> io::Result<PathBuf>
+{
+    static NEXT: AtomicU32 = AtomicU32::new(0);
+    let name = format!("ca-test-ioc-{}-{}.db",
+        std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+    Ok(env::temp_dir().join(name))
+}
+
+// Finds a port free for both TCP and UDP on the loopback interface.  Another
+// process could take it before the IOC does, in which case soft_ioc tries
+// again with another port.
+fn free_port() -> io::Result<u16>
+{
+    for _ in 0..100 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+        let port = listener.local_addr()?.port();
+        if UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).is_ok() {
+            return Ok(port);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, "No free port found"))
+}
+
+
+// -----------------------------------------------------------------------------
+// Database parsing
+
+struct Record {
+    record_type: String,
+    name: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Record {
+    fn field(&self, name: &str) -> Option<&str>
+    {
+        self.fields.iter().rev()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn number(&self, name: &str) -> io::Result<Option<f64>>
+    {
+        match self.field(name) {
+            Some(value) if !value.trim().is_empty() =>
+                value.trim().parse().map(Some).map_err(|_|
+                    invalid(format!("{}.{}: invalid number {:?}",
+                        self.name, name, value))),
+            _ => Ok(None),
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Splits a database into quoted strings, bare words and punctuation
+fn tokenise(db: &str) -> io::Result<Vec<String>>
+{
+    let mut tokens = Vec::new();
+    let mut chars = db.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' =>
+                while chars.next_if(|&c| c != '\n').is_some() { },
+            '"' => {
+                let mut token = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') =>
+                            token.extend(chars.next()),
+                        Some(c) => token.push(c),
+                        None => return Err(invalid(
+                            "Unterminated string in database".to_owned())),
+                    }
+                }
+                tokens.push(token);
+            },
+            '(' | ')' | '{' | '}' | ',' => tokens.push(c.to_string()),
+            c if c.is_whitespace() => { },
+            c => {
+                let mut token = c.to_string();
+                while let Some(c) = chars.next_if(|&c|
+                    !c.is_whitespace() && !"(){},\"#".contains(c))
+                {
+                    token.push(c);
+                }
+                tokens.push(token);
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+// Parses the record definitions of a database.  Anything other than record
+// and field definitions, such as info and alias, is ignored.
+fn parse_db(db: &str) -> io::Result<Vec<Record>>
+{
+    let tokens = tokenise(db)?;
+    let mut tokens = tokens.iter().map(String::as_str);
+    let mut records = Vec::new();
+
+    // Returns the arguments of the parenthesised list following a keyword
+    fn arguments<'a>(tokens: &mut impl Iterator<Item = &'a str>)
+        -> io::Result<Vec<&'a str>>
+    {
+        if tokens.next() != Some("(") {
+            return Err(invalid("Expected ( in database".to_owned()));
+        }
+        let mut arguments = Vec::new();
+        loop {
+            match tokens.next() {
+                Some(")") => return Ok(arguments),
+                Some(",") => { },
+                Some(token) => arguments.push(token),
+                None => return Err(invalid(
+                    "Unexpected end of database".to_owned())),
+            }
+        }
+    }
+
+    while let Some(keyword) = tokens.next() {
+        let args = arguments(&mut tokens)?;
+        let mut record = match (keyword, args.as_slice()) {
+            ("record" | "grecord", [record_type, name]) => Some(Record {
+                record_type: record_type.to_string(),
+                name: name.to_string(),
+                fields: Vec::new(),
+            }),
+            ("record" | "grecord", _) =>
+                return Err(invalid(format!("Invalid record {:?}", args))),
+            _ => None,
+        };
+
+        let mut peek = tokens.clone();
+        if peek.next() == Some("{") {
+            tokens = peek;
+            loop {
+                match tokens.next() {
+                    Some("}") => break,
+                    Some(keyword) => {
+                        let args = arguments(&mut tokens)?;
+                        if let (Some(record), "field", [field, value]) =
+                            (&mut record, keyword, args.as_slice())
+                        {
+                            record.fields.push(
+                                (field.to_string(), value.to_string()));
+                        }
+                    },
+                    None => return Err(invalid(
+                        "Unexpected end of database".to_owned())),
+                }
+            }
+        }
+        records.extend(record);
+    }
+    Ok(records)
+}
+
+// The choices of the menus served by add_fields, with the alarm menus from
+// db_access.rs and the scan menu from menuScan.dbd.  As for a softIoc, only
+// the first 16 alarm states are available as strings over Channel Access.
+const SCAN: [&str; 10] = [
+    "Passive", "Event", "I/O Intr", "10 second", "5 second", "2 second",
+    "1 second", ".5 second", ".2 second", ".1 second"];
+
+const ENUM_FIELDS: [&str; 16] = [
+    "ZRST", "ONST", "TWST", "THST", "FRST", "FVST", "SXST", "SVST",
+    "EIST", "NIST", "TEST", "ELST", "TVST", "TTST", "FTST", "FFST"];
+
+fn analog_ctrl(record: &Record) -> io::Result<PvCtrl>
+{
+    let mut ctrl = PvCtrl {
+        units: record.field("EGU").unwrap_or("").to_owned(),
+        precision: record.number("PREC")?.unwrap_or(0.0) as i16,
+        .. PvCtrl::default()
+    };
+    let limits = &mut ctrl.limits;
+    limits.upper_disp_limit = record.number("HOPR")?.unwrap_or(0.0);
+    limits.lower_disp_limit = record.number("LOPR")?.unwrap_or(0.0);
+    limits.upper_alarm_limit = record.number("HIHI")?.unwrap_or(0.0);
+    limits.upper_warning_limit = record.number("HIGH")?.unwrap_or(0.0);
+    limits.lower_warning_limit = record.number("LOW")?.unwrap_or(0.0);
+    limits.lower_alarm_limit = record.number("LOLO")?.unwrap_or(0.0);
+    limits.upper_ctrl_limit =
+        record.number("DRVH")?.unwrap_or(limits.upper_disp_limit);
+    limits.lower_ctrl_limit =
+        record.number("DRVL")?.unwrap_or(limits.lower_disp_limit);
+    Ok(ctrl)
+}
+
+fn add_record(server: &Server, record: &Record) -> io::Result<()>
+{
+    let name = record.name.as_str();
+    let value = |default| record.number("VAL").map(|v| v.unwrap_or(default));
+    match record.record_type.as_str() {
+        "ai" | "ao" | "calc" | "calcout" =>
+            server.add_pv(name, value(0.0)?).set_ctrl(analog_ctrl(record)?),
+        "longin" | "longout" =>
+            server.add_pv(name, value(0.0)? as i32)
+                .set_ctrl(analog_ctrl(record)?),
+        "bi" | "bo" => {
+            let strings = ["ZNAM", "ONAM"].iter()
+                .map(|field| record.field(field).unwrap_or("").to_owned())
+                .collect::<Vec<String>>();
+            server.add_pv(name, CaEnum(value(0.0)? as u16)).set_ctrl(strings);
+        },
+        "mbbi" | "mbbo" => {
+            let mut strings = ENUM_FIELDS.iter()
+                .map(|field| record.field(field).unwrap_or("").to_owned())
+                .collect::<Vec<String>>();
+            while strings.last().is_some_and(String::is_empty) {
+                strings.pop();
+            }
+            server.add_pv(name, CaEnum(value(0.0)? as u16)).set_ctrl(strings);
+        },
+        "stringin" | "stringout" => {
+            let value = record.field("VAL").unwrap_or("").to_owned();
+            server.add_pv(name, value);
+        },
+        "waveform" | "aai" | "aao" => {
+            let nelm = record.number("NELM")?.unwrap_or(1.0) as usize;
+            match record.field("FTVL").unwrap_or("STRING") {
+                "CHAR" | "UCHAR" => { server.add_pv(name, vec![0u8; nelm]); },
+                "SHORT" | "USHORT" => { server.add_pv(name, vec![0i16; nelm]); },
+                "LONG" | "ULONG" => { server.add_pv(name, vec![0i32; nelm]); },
+                "FLOAT" => { server.add_pv(name, vec![0f32; nelm]); },
+                "DOUBLE" => { server.add_pv(name, vec![0f64; nelm]); },
+                "STRING" => {
+                    server.add_pv(name, vec![String::new(); nelm]);
+                },
+                ftvl => return Err(invalid(format!(
+                    "{}: unsupported FTVL {}", name, ftvl))),
+            }
+        },
+        record_type => return Err(invalid(format!(
+            "{}: unsupported record type {}", name, record_type))),
+    }
+    add_fields(server, record)
+}
+
+// Serves the fields common to all records, and the display and alarm fields of
+// the analog records.  Menu fields are served as enums with the menu choices as
+// their strings, as for DBF_MENU fields of a softIoc.  The alarm state is never
+// raised.
+fn add_fields(server: &Server, record: &Record) -> io::Result<()>
+{
+    let name = record.name.as_str();
+    let string = |field: &str, default: &str| {
+        let value = record.field(field).unwrap_or(default).to_owned();
+        server.add_pv(&format!("{}.{}", name, field), value);
+    };
+    let menu = |field: &str, choices: &[&str]| {
+        let value = record.field(field).unwrap_or(choices[0]);
+        let index = choices.iter().position(|&choice| choice == value)
+            .ok_or_else(|| invalid(format!(
+                "{}.{}: invalid choice {:?}", name, field, value)))?;
+        let strings = choices.iter().map(|&choice| choice.to_owned())
+            .collect::<Vec<String>>();
+        server.add_pv(&format!("{}.{}", name, field), CaEnum(index as u16))
+            .set_ctrl(strings);
+        Ok::<_, io::Error>(())
+    };
+    server.add_pv(&format!("{}.RTYP", name), record.record_type.clone());
+    string("DESC", "");
+    menu("SCAN", &SCAN)?;
+    menu("STAT", &ALARM_STATUS_NAMES)?;
+    menu("SEVR", &ALARM_SEVERITY_NAMES)?;
+
+    match record.record_type.as_str() {
+        "ai" | "ao" | "calc" | "calcout" | "longin" | "longout" => {
+            string("EGU", "");
+            if !record.record_type.starts_with("long") {
+                let precision = record.number("PREC")?.unwrap_or(0.0);
+                server.add_pv(&format!("{}.PREC", name), precision as i16);
+            }
+            for field in ["HOPR", "LOPR", "HIHI", "HIGH", "LOW", "LOLO"] {
+                let value = record.number(field)?.unwrap_or(0.0);
+                server.add_pv(&format!("{}.{}", name, field), value);
+            }
+            for field in ["HHSV", "HSV", "LSV", "LLSV"] {
+                menu(field, &ALARM_SEVERITY_NAMES)?;
+            }
+        },
+        _ => { },
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener, UdpSocket};
+    use futures::executor::block_on;
+
+    use super::{TestIoc, free_port, parse_db};
+    use crate::caget::CA;
+    use crate::caenum::LabelledEnum;
+    use crate::dbr::CaEnum;
+
+    const DB: &str = r#"
+        # Comments and anything other than records are ignored
+        record(ai, "TEST:AI") {
+            field(VAL, "2.5")
+            field(EGU, "mA")
+            field(SCAN, "1 second")
+            field(HHSV, "MAJOR")
+            info(autosaveFields, "VAL")
+        }
+        alias("TEST:AI", "TEST:ALIAS")
+        record(mbbi, "TEST:MBBI") {
+            field(ZRST, "Off")
+            field(ONST, "On")
+            field(VAL, "1")
+        }
+        record(stringin, "TEST:STRING") { field(VAL, "a \"quoted\" value") }
+    "#;
+
+    #[test]
+    fn parse()
+    {
+        let records = parse_db(DB).unwrap();
+        let names: Vec<&str> =
+            records.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, ["TEST:AI", "TEST:MBBI", "TEST:STRING"]);
+        assert_eq!(records[0].record_type, "ai");
+        assert_eq!(records[0].field("SCAN"), Some("1 second"));
+        assert_eq!(records[2].field("VAL"), Some("a \"quoted\" value"));
+        assert!(parse_db("record(ai, \"TEST:AI\") { field(VAL, \"1)").is_err());
+    }
+
+    #[test]
+    fn free_port_is_free()
+    {
+        let port = free_port().unwrap();
+        TcpListener::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+        UdpSocket::bind((Ipv4Addr::LOCALHOST, port)).unwrap();
+    }
+
+    #[test]
+    fn server_fixture()
+    {
+        let ioc = TestIoc::server(DB).unwrap();
+        let _context = ioc.install();
+        block_on(async {
+            assert_eq!(f64::caget("TEST:AI").await, 2.5);
+            assert_eq!(String::caget("TEST:AI.RTYP").await, "ai");
+            assert_eq!(String::caget("TEST:AI.EGU").await, "mA");
+            assert_eq!(String::caget("TEST:MBBI").await, "On");
+            assert_eq!(String::caget("TEST:STRING").await,
+                "a \"quoted\" value");
+
+            // Menu fields are enums labelled with their choices
+            assert_eq!(CaEnum::caget("TEST:AI.STAT").await.0, 0);
+            let sevr = LabelledEnum::caget("TEST:AI.SEVR").await;
+            assert_eq!(sevr.label.as_deref(), Some("NO_ALARM"));
+            assert_eq!(String::caget("TEST:AI.SCAN").await, "1 second");
+            assert_eq!(String::caget("TEST:AI.HHSV").await, "MAJOR");
+            assert_eq!(String::caget("TEST:AI.LLSV").await, "NO_ALARM");
+        });
+    }
+
+    #[test]
+    fn invalid_menu_choice()
+    {
+        let db = r#"record(ai, "TEST:AI") { field(SCAN, "Sometimes") }"#;
+        assert!(TestIoc::server(db).is_err());
+    }
+}
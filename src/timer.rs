@@ -0,0 +1,152 @@
+// Timer shared by all delays
+//
+// Waiting for a fixed time, as when giving up on a field which may not exist,
+// is served by a single background thread holding the pending deadlines in
+// order.  This is started on first use and runs for the life of the process,
+// so a delay costs a queue entry rather than a thread of its own:
+//
+//      timer::sleep(Duration::from_millis(200)).await;
+//
+// timeout gives up on a future, for instance a search for a field, once the
+// delay has passed:
+//
+//      let connected = timer::timeout(delay, channel.wait_connect()).await;
+
+use std::{cmp, collections, future, sync, thread};
+use std::time::{Duration, Instant};
+use futures::FutureExt;
+use futures::channel::oneshot;
+
+
+struct Deadline {
+    deadline: Instant,
+    sender: oneshot::Sender<()>,
+}
+
+// Ordered so that the earliest deadline is at the top of the heap
+impl Ord for Deadline {
+    fn cmp(&self, other: &Self) -> cmp::Ordering
+    {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering>
+    {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool
+    {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Deadline { }
+
+
+struct Timer {
+    deadlines: sync::Mutex<collections::BinaryHeap<Deadline>>,
+    changed: sync::Condvar,
+}
+
+fn timer() -> &'static Timer
+{
+    static TIMER: sync::OnceLock<Timer> = sync::OnceLock::new();
+    let mut started = false;
+    let timer = TIMER.get_or_init(|| {
+        started = true;
+        Timer {
+            deadlines: sync::Mutex::new(collections::BinaryHeap::new()),
+            changed: sync::Condvar::new(),
+        }
+    });
+    if started {
+        thread::spawn(move || timer_thread(timer));
+    }
+    timer
+}
+
+// Completes each delay as its deadline passes.  Delays which have been dropped
+// are simply discarded when their time comes.
+fn timer_thread(timer: &'static Timer)
+{
+    let mut deadlines = timer.deadlines.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        while deadlines.peek().is_some_and(|entry| entry.deadline <= now) {
+            let _ = deadlines.pop().unwrap().sender.send(());
+        }
+        deadlines = match deadlines.peek() {
+            Some(entry) => {
+                let delay = entry.deadline - now;
+                timer.changed.wait_timeout(deadlines, delay).unwrap().0
+            },
+            None => timer.changed.wait(deadlines).unwrap(),
+        };
+    }
+}
+
+// Completes once the given time has passed.  A delay too long to be
+// represented never completes.
+pub async fn sleep(delay: Duration)
+{
+    let deadline = match Instant::now().checked_add(delay) {
+        Some(deadline) => deadline,
+        None => return future::pending().await,
+    };
+    let (sender, receiver) = oneshot::channel();
+    let timer = timer();
+    timer.deadlines.lock().unwrap().push(Deadline { deadline, sender });
+    timer.changed.notify_one();
+    let _ = receiver.await;
+}
+
+// Completes with the result of the future, or None if the delay passes first
+pub async fn timeout<F: future::Future>(delay: Duration, future: F)
+    -> Option<F::Output>
+{
+    futures::select! {
+        result = future.fuse() => Some(result),
+        _ = sleep(delay).fuse() => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::executor::block_on;
+    use futures::future::{join, pending};
+
+    use super::{sleep, timeout};
+
+    #[test]
+    fn delays_complete_in_order()
+    {
+        let start = Instant::now();
+        let done = std::sync::Mutex::new(Vec::new());
+        let wait = |millis| {
+            let done = &done;
+            async move {
+                sleep(Duration::from_millis(millis)).await;
+                done.lock().unwrap().push(millis);
+            }
+        };
+        block_on(join(wait(60), wait(20)));
+        assert!(start.elapsed() >= Duration::from_millis(60));
+        assert_eq!(*done.lock().unwrap(), [20, 60]);
+    }
+
+    #[test]
+    fn timeout_gives_up()
+    {
+        let delay = Duration::from_millis(20);
+        assert_eq!(block_on(timeout(delay, async { 1 })), Some(1));
+        assert_eq!(block_on(timeout(delay, pending::<()>())), None);
+        assert_eq!(block_on(timeout(Duration::MAX, async { 2 })), Some(2));
+    }
+}
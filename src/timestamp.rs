@@ -0,0 +1,274 @@
+// EPICS timestamps
+//
+// EPICS timestamps count seconds and nanoseconds from the EPICS epoch of 1st
+// January 1990 UTC.  Values are normally returned with their timestamp as a
+// SystemTime, but EpicsTime can be asked for instead to keep the timestamp
+// exactly as sent:
+//
+//      let (value, severity, time) =
+//          <(f64, StatusSeverity, EpicsTime)>::caget(pv).await;
+//
+// Some timing systems use the low bits of the nanoseconds as a user tag, for
+// instance to carry a pulse ID, which can be recovered from EpicsTime.
+//
+// A record which has never processed has a zero timestamp, which would be read
+// as 1st January 1990, and is normally in UDF alarm.  Asking for the timestamp
+// as an Option gives None for such values.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::db_access::{EpicsTimeStamp, StatusSeverity};
+
+
+// Seconds from the UNIX epoch of 1970 to the EPICS epoch of 1990
+const EPICS_EPOCH_SECS: u64 = 631152000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EpicsTime {
+    pub secs: u32,      // Seconds since the EPICS epoch
+    pub nsec: u32,      // Nanoseconds within the second, including any tag
+}
+
+impl EpicsTime {
+    pub fn new(secs: u32, nsec: u32) -> EpicsTime
+    {
+        EpicsTime { secs, nsec }
+    }
+
+    // False for the zero timestamp of a record which has never processed
+    pub fn is_valid(&self) -> bool
+    {
+        self.secs != 0 || self.nsec != 0
+    }
+
+    // The user tag held in the given number of low bits of the nanoseconds
+    pub fn user_tag(&self, bits: u32) -> u32
+    {
+        self.nsec & tag_mask(bits)
+    }
+
+    // The timestamp with the user tag cleared from the nanoseconds
+    pub fn without_user_tag(&self, bits: u32) -> EpicsTime
+    {
+        EpicsTime { secs: self.secs, nsec: self.nsec & !tag_mask(bits) }
+    }
+}
+
+fn tag_mask(bits: u32) -> u32
+{
+    assert!(bits <= 32, "User tag of {} bits is too large", bits);
+    ((1u64 << bits) - 1) as u32
+}
+
+
+impl From<&EpicsTimeStamp> for EpicsTime {
+    fn from(stamp: &EpicsTimeStamp) -> Self
+    {
+        EpicsTime { secs: stamp.secs, nsec: stamp.nsec }
+    }
+}
+
+impl From<EpicsTime> for EpicsTimeStamp {
+    fn from(time: EpicsTime) -> Self
+    {
+        EpicsTimeStamp { secs: time.secs, nsec: time.nsec }
+    }
+}
+
+impl From<EpicsTime> for SystemTime {
+    fn from(time: EpicsTime) -> Self
+    {
+        let duration = Duration::new(time.secs as u64, time.nsec);
+        let epics_epoch = Duration::new(EPICS_EPOCH_SECS, 0);
+        UNIX_EPOCH.checked_add(epics_epoch).unwrap()
+            .checked_add(duration).unwrap()
+    }
+}
+
+// Times before the EPICS epoch are clipped to the epoch, and times beyond the
+// last EPICS timestamp to that timestamp
+impl From<SystemTime> for EpicsTime {
+    fn from(time: SystemTime) -> Self
+    {
+        let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        match duration.checked_sub(Duration::from_secs(EPICS_EPOCH_SECS)) {
+            None => EpicsTime { secs: 0, nsec: 0 },
+            Some(since) => match u32::try_from(since.as_secs()) {
+                Ok(secs) => EpicsTime { secs, nsec: since.subsec_nanos() },
+                Err(_) => EpicsTime { secs: u32::MAX, nsec: 999_999_999 },
+            },
+        }
+    }
+}
+
+// Nanoseconds of a second or more, which a server should never send, are
+// carried into the seconds as for SystemTime
+#[cfg(feature = "chrono")]
+impl From<EpicsTime> for chrono::DateTime<chrono::Utc> {
+    fn from(time: EpicsTime) -> Self
+    {
+        let secs = EPICS_EPOCH_SECS as i64 + time.secs as i64 +
+            (time.nsec / 1_000_000_000) as i64;
+        chrono::DateTime::from_timestamp(secs, time.nsec % 1_000_000_000)
+            .unwrap()
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<EpicsTime> for time::OffsetDateTime {
+    fn from(time: EpicsTime) -> Self
+    {
+        let secs = EPICS_EPOCH_SECS as i128 + time.secs as i128;
+        time::OffsetDateTime::from_unix_timestamp_nanos(
+            secs * 1_000_000_000 + time.nsec as i128).unwrap()
+    }
+}
+
+// Shown as an RFC 3339 UTC time with nanoseconds
+impl fmt::Display for EpicsTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        humantime::format_rfc3339_nanos(SystemTime::from(*self)).fmt(f)
+    }
+}
+
+
+// Timestamps can be returned with values as either SystemTime or EpicsTime, or
+// as an Option of either which is None for undefined values.
+pub trait Timestamp: Send + 'static {
+    fn from_epics(time: EpicsTime, alarm: &StatusSeverity) -> Self;
+}
+
+impl Timestamp for SystemTime {
+    fn from_epics(time: EpicsTime, _alarm: &StatusSeverity) -> Self
+    {
+        time.into()
+    }
+}
+
+impl Timestamp for EpicsTime {
+    fn from_epics(time: EpicsTime, _alarm: &StatusSeverity) -> Self
+    {
+        time
+    }
+}
+
+impl<T: Timestamp> Timestamp for Option<T> {
+    fn from_epics(time: EpicsTime, alarm: &StatusSeverity) -> Self
+    {
+        if time.is_valid() && !alarm.is_undefined() {
+            Some(T::from_epics(time, alarm))
+        } else {
+            None
+        }
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use futures::executor::block_on;
+
+    use super::EpicsTime;
+    use crate::caget::CA;
+    use crate::db_access::{StatusSeverity, UDF_ALARM};
+    use crate::mock::MockBackend;
+
+    #[test]
+    fn epoch_offset()
+    {
+        let epoch = UNIX_EPOCH + Duration::from_secs(631152000);
+        assert_eq!(SystemTime::from(EpicsTime::new(0, 0)), epoch);
+        assert_eq!(EpicsTime::from(epoch + Duration::new(12, 345)),
+            EpicsTime::new(12, 345));
+        assert_eq!(EpicsTime::from(epoch + Duration::from_millis(500)),
+            EpicsTime::new(0, 500_000_000));
+        // Times before the epoch or beyond the last timestamp are clipped
+        assert_eq!(EpicsTime::from(UNIX_EPOCH), EpicsTime::new(0, 0));
+        assert_eq!(EpicsTime::from(epoch + Duration::from_secs(1 << 32)),
+            EpicsTime::new(u32::MAX, 999_999_999));
+        assert_eq!(EpicsTime::new(0, 0).to_string(),
+            "1990-01-01T00:00:00.000000000Z");
+    }
+
+    #[test]
+    fn user_tags()
+    {
+        let time = EpicsTime::new(10, 123_456_789);
+        assert_eq!(time.user_tag(0), 0);
+        assert_eq!(time.without_user_tag(0), time);
+        assert_eq!(time.user_tag(8), 123_456_789 & 0xFF);
+        assert_eq!(time.without_user_tag(8),
+            EpicsTime::new(10, 123_456_789 & !0xFF));
+        assert_eq!(time.user_tag(32), 123_456_789);
+        assert_eq!(time.without_user_tag(32), EpicsTime::new(10, 0));
+    }
+
+    // Nanoseconds beyond a second are carried into the seconds
+    #[test]
+    fn system_time_conversion()
+    {
+        let time = EpicsTime::new(100, 2_500_000_000);
+        assert_eq!(SystemTime::from(time),
+            SystemTime::from(EpicsTime::new(102, 500_000_000)));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn chrono_conversion()
+    {
+        use chrono::{DateTime, Utc};
+        let time = DateTime::<Utc>::from(EpicsTime::new(1, 5));
+        assert_eq!(time.to_rfc3339(), "1990-01-01T00:00:01.000000005+00:00");
+        assert_eq!(DateTime::<Utc>::from(EpicsTime::new(100, 2_500_000_000)),
+            DateTime::<Utc>::from(EpicsTime::new(102, 500_000_000)));
+        assert_eq!(DateTime::<Utc>::from(EpicsTime::new(u32::MAX, u32::MAX)),
+            DateTime::<Utc>::from(SystemTime::from(
+                EpicsTime::new(u32::MAX, u32::MAX))));
+    }
+
+    #[cfg(feature = "time")]
+    #[test]
+    fn time_conversion()
+    {
+        use time::OffsetDateTime;
+        let time = OffsetDateTime::from(EpicsTime::new(1, 5));
+        assert_eq!(time.unix_timestamp(), 631152001);
+        assert_eq!(time.nanosecond(), 5);
+        assert_eq!(OffsetDateTime::from(EpicsTime::new(100, 2_500_000_000)),
+            OffsetDateTime::from(EpicsTime::new(102, 500_000_000)));
+    }
+
+    // Optional timestamps are None for a record which has never processed,
+    // shown either by a zero timestamp or by UDF alarm
+    #[test]
+    fn optional_timestamps()
+    {
+        type Timed = (f64, StatusSeverity, Option<SystemTime>);
+        let mock = MockBackend::new();
+        let _guard = mock.install();
+        mock.add_pv("TEST:AI", 0.0);
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        mock.set_with_time("TEST:AI", 1.0, None, time);
+        assert_eq!(block_on(Timed::caget("TEST:AI")).2, Some(time));
+
+        let epoch = SystemTime::from(EpicsTime::new(0, 0));
+        mock.set_with_time("TEST:AI", 2.0, None, epoch);
+        let (value, _, timestamp) = block_on(Timed::caget("TEST:AI"));
+        assert_eq!((value, timestamp), (2.0, None));
+
+        let undefined = StatusSeverity { status: UDF_ALARM, severity: 3 };
+        mock.set_alarm("TEST:AI", 3.0, undefined);
+        let (value, _, timestamp) = block_on(Timed::caget("TEST:AI"));
+        assert_eq!((value, timestamp), (3.0, None));
+
+        let cleared = StatusSeverity { status: 0, severity: 0 };
+        mock.set_with_time("TEST:AI", 4.0, Some(cleared), time);
+        assert_eq!(block_on(Timed::caget("TEST:AI")).2, Some(time));
+    }
+}